@@ -0,0 +1,153 @@
+async function getSites(sites) {
+    while (!window.nostr) {
+        await new Promise(r => setTimeout(r, 0));
+    }
+    let endpoint = `${API_BASE_URL}/api/sites`;
+    sites.length = 0;
+    let response = await fetch(endpoint, {
+        headers: {'Authorization': await getNostrAuthHeader(window.location.protocol + endpoint, 'GET')},
+    });
+    for (const s of await response.json()) {
+        sites.push(s);
+    }
+}
+
+async function saveSite(domain, invite) {
+    let endpoint = `${API_BASE_URL}/api/sites`;
+    let response = await fetch(endpoint, {
+        method: 'POST',
+        headers: {
+            'Accept': 'application/json',
+            'Content-Type': 'application/json',
+            'Authorization': await getNostrAuthHeader(window.location.protocol + endpoint, 'POST'),
+        },
+        body: JSON.stringify({
+            domain: domain,
+            invite: invite === '' ? null : invite,
+        }),
+    });
+    if (response.status === 202) {
+        alert("Your site will be created once approved by the operator.");
+    } else if (!response.ok) {
+        alert(`Error creating site: ${response.status} ${await response.text()}`);
+    }
+}
+
+function getSiteApiBaseUrl(siteDomain) {
+    if (API_BASE_URL.startsWith("//localhost:")) {
+        return `http:${API_BASE_URL}`;
+    } else {
+        return `${window.location.protocol}//${siteDomain}`;
+    }
+}
+
+const SETTINGS = ['title', 'base_url', 'theme', 'default_language', 'timezone'];
+
+async function getSettings(site, settings, themes) {
+    themes.length = 0;
+
+    let endpoint = `${API_BASE_URL}/api/sites/${site.domain}/config`;
+    let response = await fetch(endpoint, {
+        headers: {'Authorization': await getNostrAuthHeader(window.location.protocol + endpoint, 'GET')},
+    });
+    let result = await response.json();
+    for (const key of SETTINGS) {
+        settings[key] = result['config'][key] ?? '';
+    }
+    for (const t of result['available_themes']) {
+        themes.push(t);
+    }
+}
+
+async function getStats(site, stats) {
+    let endpoint = `${API_BASE_URL}/api/sites/${site.domain}/stats`;
+    let response = await fetch(endpoint, {
+        headers: {'Authorization': await getNostrAuthHeader(window.location.protocol + endpoint, 'GET')},
+    });
+    Object.assign(stats, await response.json());
+}
+
+async function downloadTraffic(site) {
+    let endpoint = `${API_BASE_URL}/api/sites/${site.domain}/traffic`;
+    let response = await fetch(endpoint, {
+        headers: {'Authorization': await getNostrAuthHeader(window.location.protocol + endpoint, 'GET')},
+    });
+    let link = document.createElement('a');
+    link.href = URL.createObjectURL(await response.blob());
+    link.download = `${site.domain}-traffic.csv`;
+    link.click();
+    URL.revokeObjectURL(link.href);
+}
+
+async function downloadExport(site) {
+    let endpoint = `${API_BASE_URL}/api/sites/${site.domain}/export`;
+    let response = await fetch(endpoint, {
+        headers: {'Authorization': await getNostrAuthHeader(window.location.protocol + endpoint, 'GET')},
+    });
+    if (!response.ok) {
+        alert("Error exporting the site!");
+        return;
+    }
+    let link = document.createElement('a');
+    link.href = URL.createObjectURL(await response.blob());
+    link.download = `${site.domain}.zip`;
+    link.click();
+    URL.revokeObjectURL(link.href);
+}
+
+async function saveSettings(site, settings) {
+    let changes = {};
+    for (const key of SETTINGS) {
+        // empty fields fall back to the defaults
+        changes[key] = settings[key] === '' ? null : settings[key];
+    }
+    let endpoint = `${API_BASE_URL}/api/sites/${site.domain}/config`;
+    let response = await fetch(endpoint, {
+        method: 'PUT',
+        headers: {
+            'Accept': 'application/json',
+            'Content-Type': 'application/json',
+            'Authorization': await getNostrAuthHeader(window.location.protocol + endpoint, 'PUT'),
+        },
+        body: JSON.stringify(changes),
+    });
+    if (response.ok) {
+        alert("Settings saved!");
+    } else {
+        alert(`Error saving settings: ${await response.text()}`);
+    }
+}
+
+async function previewTheme(site, theme) {
+    let url = `${getSiteApiBaseUrl(site.domain)}/?preview_theme=${encodeURIComponent(theme)}`;
+    let response = await fetch(url, {
+        headers: {'Authorization': await getNostrAuthHeader(url, 'GET')},
+    });
+    if (!response.ok) {
+        alert("Error previewing theme!");
+        return;
+    }
+    let preview = window.open();
+    preview.document.write(await response.text());
+    preview.document.close();
+}
+
+async function deleteSite(site) {
+    let endpoint = `${API_BASE_URL}/api/sites/${site.domain}`;
+    let response = await fetch(endpoint, {
+        method: 'DELETE',
+        headers: {'Authorization': await getNostrAuthHeader(window.location.protocol + endpoint, 'DELETE')},
+    });
+    if (response.status != 428 || !confirm(`Delete ${site.domain}? It will be archived first.`)) {
+        return false;
+    }
+    endpoint = `${endpoint}?confirm=${(await response.json())['confirm']}`;
+    response = await fetch(endpoint, {
+        method: 'DELETE',
+        headers: {'Authorization': await getNostrAuthHeader(window.location.protocol + endpoint, 'DELETE')},
+    });
+    if (!response.ok) {
+        alert("Error deleting site!");
+    }
+    return response.ok;
+}
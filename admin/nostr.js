@@ -0,0 +1,23 @@
+const hexes = Array.from({ length: 256 }, (_, i) => i.toString(16).padStart(2, '0'));
+
+function bytesToHex(bytes) {
+    let hex = '';
+    for (let i = 0; i < bytes.length; i++) {
+        hex += hexes[bytes[i]];
+    }
+    return hex;
+}
+
+async function getEvent(kind, content, tags) {
+    let event = {'kind': kind, 'content': content, 'tags': tags};
+    event.pubkey = await window.nostr.getPublicKey();
+    event.created_at = Math.round(new Date().getTime() / 1000);
+    serialized = JSON.stringify([0, event.pubkey, event.created_at, event.kind, event.tags, event.content]);
+    event.id = bytesToHex(new Uint8Array(await window.crypto.subtle.digest("SHA-256", (new TextEncoder()).encode(serialized))));
+    return await window.nostr.signEvent(event);
+}
+
+async function getNostrAuthHeader(url, method) {
+    let authEvent = await getEvent(27235, "", [['u', url], ['method', method]]);
+    return `Nostr ${btoa(JSON.stringify(authEvent))}`;
+}
@@ -1,18 +1,58 @@
-use std::fs;
+use std::{
+    collections::hash_map::DefaultHasher,
+    fs,
+    hash::{Hash, Hasher},
+    path::Path,
+};
+
+const ADMIN_DIR: &str = "admin";
 
 fn main() {
-    println!("cargo:rerun-if-changed=build.rs,admin/index.html");
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed={}", ADMIN_DIR);
+
+    // the files of the admin interface, embedded in the binary and served under `/.admin/`
+    let mut admin_files = fs::read_dir(ADMIN_DIR)
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .filter(|p| p.is_file())
+        .collect::<Vec<_>>();
+    admin_files.sort();
 
-    let admin_index_html = fs::read_to_string("admin/index.html").unwrap();
+    // changes whenever any of the files does, so that browsers can cache them until then
+    let mut hasher = DefaultHasher::new();
+    let mut files = String::new();
+    for path in &admin_files {
+        let name = path.file_name().unwrap().to_str().unwrap();
+        let path = fs::canonicalize(path).unwrap();
+        (name, fs::read(&path).unwrap()).hash(&mut hasher);
+        files.push_str(&format!(
+            "    ({:?}, include_bytes!({:?})),\n",
+            name,
+            path.display().to_string()
+        ));
+    }
 
     let out_dir = std::env::var_os("OUT_DIR").unwrap();
 
-    std::fs::write(
-        std::path::Path::new(&out_dir).join("admin.rs"),
-        r##"
-pub const INDEX_HTML: &str = r#"%%index_html%%"#;
-"##
-        .replace("%%index_html%%", &admin_index_html),
+    fs::write(
+        Path::new(&out_dir).join("admin.rs"),
+        format!(
+            r##"
+pub const VERSION: &str = "{:016x}";
+
+pub static FILES: &[(&str, &[u8])] = &[
+{}];
+
+pub const INDEX_HTML: &str = include_str!({:?});
+"##,
+            hasher.finish(),
+            files,
+            fs::canonicalize(Path::new(ADMIN_DIR).join("index.html"))
+                .unwrap()
+                .display()
+                .to_string()
+        ),
     )
     .unwrap();
 }
@@ -0,0 +1,708 @@
+//! A typed client for the HTTP API of a [Servus](https://github.com/servuscms/servus) server,
+//! as described by the OpenAPI document it serves at `/api/openapi.json`.
+//!
+//! Requests are authorized using [NIP-98](https://github.com/nostr-protocol/nips/blob/master/98.md)
+//! (or, for the Blossom API, a kind 24242 event), signed by the key the client is created with.
+//!
+//! ```no_run
+//! # async_std::task::block_on(async {
+//! let client = servus_client::Client::new("https://servus.example.com", "nsec1...")?;
+//! for site in client.get_sites().await? {
+//!     let stats = client.get_site_stats(&site.domain).await?;
+//!     println!("{}: {} posts", site.domain, stats.posts);
+//! }
+//! # Ok::<(), servus_client::Error>(())
+//! # });
+//! ```
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::NaiveDateTime;
+use http_client::{h1::H1Client, HttpClient};
+use http_types::{Body, Method, Request, Response, StatusCode, Url};
+use secp256k1::{KeyPair, Message, Secp256k1};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Map, Value as JsonValue};
+use sha2::{Digest, Sha256};
+use std::{
+    fmt,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const EVENT_KIND_BLOSSOM: u64 = 24242;
+const EVENT_KIND_AUTH: u64 = 27235;
+
+/// How long the authorization of a Blossom request is valid for, in seconds.
+const BLOSSOM_AUTH_TTL: u64 = 60;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The key is neither a secret key in hex nor an `nsec`.
+    InvalidKey,
+    InvalidUrl(String),
+    /// The request could not be sent, or the response could not be read.
+    Http(http_types::Error),
+    /// The server answered with an error, along with the body of its response.
+    Status(StatusCode, String),
+    /// The response is not what was expected.
+    Json(serde_json::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::InvalidKey => write!(f, "Invalid secret key"),
+            Error::InvalidUrl(e) => write!(f, "Invalid URL: {}", e),
+            Error::Http(e) => write!(f, "{}", e),
+            Error::Status(status, body) if body.is_empty() => write!(f, "{}", status),
+            Error::Status(status, body) => write!(f, "{}: {}", status, body),
+            Error::Json(e) => write!(f, "Unexpected response: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<http_types::Error> for Error {
+    fn from(e: http_types::Error) -> Self {
+        Error::Http(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Json(e)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// A site, as listed by [`Client::get_sites`]. Its owner and its traffic are only listed for the operator.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Site {
+    pub domain: String,
+    #[serde(default)]
+    pub pubkey: Option<String>,
+    #[serde(default)]
+    pub traffic: Option<TrafficSummary>,
+}
+
+/// Whether a site was created right away or is waiting for the operator's approval.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NewSite {
+    Created,
+    Pending,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+pub struct Counters {
+    pub requests: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+/// The traffic of a site today and over the last 30 days (including today).
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct TrafficSummary {
+    pub today: Counters,
+    pub last_30_days: Counters,
+}
+
+/// How much the render cache of a site holds and how often it was used, since the content last changed.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct RenderCacheStats {
+    pub pages: usize,
+    pub size: usize, // in bytes
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// An overview of a site, as shown by the admin interface.
+#[derive(Clone, Debug, Deserialize)]
+pub struct SiteStats {
+    pub posts: usize,
+    pub pages: usize,
+    pub notes: usize,
+    pub drafts: usize,
+    pub events: usize,
+    pub last_event_at: Option<i64>,
+    pub blobs: usize,    // uploaded files
+    pub blobs_size: u64, // in bytes
+    pub render_cache: RenderCacheStats,
+    pub traffic: TrafficSummary,
+}
+
+/// Limits set by the operator, which the site's owner can't change.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct Quota {
+    /// Disk space the events and the uploaded files can take, in bytes.
+    pub storage_bytes: Option<u64>,
+}
+
+/// The `_config.toml` of a site, and the themes it can use.
+#[derive(Clone, Debug, Deserialize)]
+pub struct SiteConfig {
+    pub config: Map<String, JsonValue>,
+    pub available_themes: Vec<String>,
+}
+
+/// A domain being attached to a site, once `token` is published in a TXT record named `record`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct DomainVerification {
+    pub domain: String,
+    pub record: String,
+    pub token: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct SiteDomains {
+    pub aliases: Vec<String>,
+    pub canonical_domain: Option<String>,
+    pub verifications: Vec<DomainVerification>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Theme {
+    pub name: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    Posts,
+    Pages,
+    /// Only for [`Client::get_public_resources`].
+    Notes,
+}
+
+impl Kind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Kind::Posts => "posts",
+            Kind::Pages => "pages",
+            Kind::Notes => "notes",
+        }
+    }
+}
+
+/// A post, page or note, as exposed by the public JSON API.
+#[derive(Clone, Debug, Deserialize)]
+pub struct PublicResource {
+    pub title: String,
+    pub slug: String,
+    pub url: String,
+    pub summary: Option<String>,
+    pub date: NaiveDateTime,
+    /// Sanitized.
+    pub html: String,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct Invite {
+    pub code: String,
+    pub created_at: i64,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct PendingSite {
+    pub domain: String,
+    pub pubkey: String,
+    pub requested_at: i64,
+}
+
+/// An uploaded file.
+#[derive(Clone, Debug, Deserialize)]
+pub struct BlobDescriptor {
+    pub sha256: String,
+    #[serde(rename = "type")]
+    pub content_type: String,
+    pub size: u64,
+    pub url: String,
+    /// Once pinned to IPFS.
+    #[serde(default)]
+    pub cid: Option<String>,
+}
+
+fn parse_secret_key(secret_key: &str) -> Option<KeyPair> {
+    let secret_key = secret_key.trim().to_lowercase();
+    let secp = Secp256k1::signing_only();
+    if secret_key.starts_with("nsec1") {
+        let (hrp, data) = bech32::decode(&secret_key).ok()?;
+        if hrp.as_str() != "nsec" {
+            return None;
+        }
+        KeyPair::from_seckey_slice(&secp, &data).ok()
+    } else {
+        KeyPair::from_seckey_str(&secp, &secret_key).ok()
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// A client for a Servus server, signing its requests using a Nostr key.
+pub struct Client {
+    base_url: Url,
+    keypair: KeyPair,
+    http: H1Client,
+}
+
+impl Client {
+    /// A client for the server at `base_url`, such as `https://servus.example.com`,
+    /// signing its requests using `secret_key`, in hex or as an `nsec`.
+    /// The Blossom methods act on the site served at `base_url`.
+    pub fn new(base_url: &str, secret_key: &str) -> Result<Self> {
+        let base_url = Url::parse(&format!("{}/", base_url.trim_end_matches('/')))
+            .map_err(|e| Error::InvalidUrl(e.to_string()))?;
+        if base_url.cannot_be_a_base() || !["https", "http"].contains(&base_url.scheme()) {
+            return Err(Error::InvalidUrl(base_url.to_string()));
+        }
+        let keypair = parse_secret_key(secret_key).ok_or(Error::InvalidKey)?;
+
+        Ok(Client {
+            base_url,
+            keypair,
+            http: H1Client::new(),
+        })
+    }
+
+    /// The pubkey the requests are signed by, in hex.
+    pub fn pubkey(&self) -> String {
+        self.keypair.x_only_public_key().0.to_string()
+    }
+
+    /// The URL of `segments` (each of them escaped) under the base URL.
+    fn url(&self, segments: &[&str]) -> Url {
+        let mut url = self.base_url.clone();
+        url.path_segments_mut()
+            .unwrap()
+            .pop_if_empty()
+            .extend(segments);
+        url
+    }
+
+    /// The `Authorization` header for an event of `kind`, as base64.
+    fn sign(&self, kind: u64, tags: &[[&str; 2]], content: &str) -> String {
+        let pubkey = self.pubkey();
+        let created_at = now();
+        let canonical = json!([0, pubkey, created_at, kind, tags, content]).to_string();
+        let hash = Sha256::digest(canonical.as_bytes());
+        let sig = Secp256k1::signing_only()
+            .sign_schnorr_no_aux_rand(&Message::from_slice(&hash).unwrap(), &self.keypair);
+        let event = json!({
+            "id": to_hex(&hash),
+            "pubkey": pubkey,
+            "created_at": created_at,
+            "kind": kind,
+            "tags": tags,
+            "content": content,
+            "sig": sig.to_string(),
+        });
+
+        format!("Nostr {}", STANDARD.encode(event.to_string()))
+    }
+
+    async fn send(&self, request: Request) -> Result<Response> {
+        let mut response = self.http.send(request).await?;
+        // read the body in any case, so that the connection can be reused
+        let body = response.body_bytes().await?;
+        if !response.status().is_success() {
+            let body = String::from_utf8_lossy(&body).trim().to_owned();
+            return Err(Error::Status(response.status(), body));
+        }
+        response.set_body(body);
+
+        Ok(response)
+    }
+
+    /// Sends a request authorized using NIP-98.
+    async fn request(&self, method: Method, url: Url, body: Option<Body>) -> Result<Response> {
+        let auth = self.sign(
+            EVENT_KIND_AUTH,
+            &[["u", url.as_str()], ["method", method.as_ref()]],
+            "",
+        );
+        let mut request = Request::new(method, url);
+        request.insert_header("Authorization", auth);
+        if let Some(body) = body {
+            request.set_body(body);
+        }
+        self.send(request).await
+    }
+
+    /// Sends a request authorized using NIP-98, with `body` as JSON, returning the response as JSON.
+    async fn request_json<T: DeserializeOwned>(
+        &self,
+        method: Method,
+        url: Url,
+        body: Option<JsonValue>,
+    ) -> Result<T> {
+        let body = body.map(|b| Body::from_json(&b)).transpose()?;
+        let mut response = self.request(method, url, body).await?;
+        Ok(serde_json::from_str(&response.body_string().await?)?)
+    }
+
+    async fn request_bytes(&self, method: Method, url: Url) -> Result<Vec<u8>> {
+        let mut response = self.request(method, url, None).await?;
+        Ok(response.body_bytes().await?)
+    }
+
+    /// The sites owned by the client's key or, for the operator, all the sites.
+    pub async fn get_sites(&self) -> Result<Vec<Site>> {
+        self.request_json(Method::Get, self.url(&["api", "sites"]), None)
+            .await
+    }
+
+    /// Creates a site owned by the client's key, using `invite` if the server requires approval.
+    pub async fn create_site(&self, domain: &str, invite: Option<&str>) -> Result<NewSite> {
+        let body = Body::from_json(&json!({"domain": domain, "invite": invite}))?;
+        let response = self
+            .request(Method::Post, self.url(&["api", "sites"]), Some(body))
+            .await?;
+        Ok(match response.status() {
+            StatusCode::Accepted => NewSite::Pending,
+            _ => NewSite::Created,
+        })
+    }
+
+    /// Deletes a site, archiving it first unless `archive` is false.
+    pub async fn delete_site(&self, domain: &str, archive: bool) -> Result<()> {
+        let mut url = self.url(&["api", "sites", domain]);
+        if !archive {
+            url.query_pairs_mut().append_pair("archive", "false");
+        }
+        // the first request only gets the token confirming the deletion
+        let confirm = match self.request(Method::Delete, url.clone(), None).await {
+            Err(Error::Status(StatusCode::PreconditionRequired, body)) => {
+                serde_json::from_str::<JsonValue>(&body)?["confirm"]
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_owned()
+            }
+            result => return result.map(|_| ()),
+        };
+        url.query_pairs_mut().append_pair("confirm", &confirm);
+        self.request(Method::Delete, url, None).await.map(|_| ())
+    }
+
+    /// Copies a site to `new_domain`.
+    pub async fn clone_site(&self, domain: &str, new_domain: &str) -> Result<()> {
+        let url = self.url(&["api", "sites", domain, "clone"]);
+        let body = Body::from_json(&json!({"domain": new_domain}))?;
+        self.request(Method::Post, url, Some(body))
+            .await
+            .map(|_| ())
+    }
+
+    pub async fn get_site_stats(&self, domain: &str) -> Result<SiteStats> {
+        let url = self.url(&["api", "sites", domain, "stats"]);
+        self.request_json(Method::Get, url, None).await
+    }
+
+    /// The traffic of a site, as CSV, one line per day.
+    pub async fn get_site_traffic(&self, domain: &str) -> Result<String> {
+        let url = self.url(&["api", "sites", domain, "traffic"]);
+        let mut response = self.request(Method::Get, url, None).await?;
+        Ok(response.body_string().await?)
+    }
+
+    /// The site as it is served, as a `.zip`.
+    pub async fn export_site(&self, domain: &str) -> Result<Vec<u8>> {
+        let url = self.url(&["api", "sites", domain, "export"]);
+        self.request_bytes(Method::Get, url).await
+    }
+
+    /// The `_content` directory of a site, without the uploaded files, as a `.tar.gz`.
+    pub async fn get_site_content(&self, domain: &str) -> Result<Vec<u8>> {
+        let url = self.url(&["api", "sites", domain, "content"]);
+        self.request_bytes(Method::Get, url).await
+    }
+
+    /// Replaces the content of a site by what `archive` (a `.tar.gz`) holds.
+    pub async fn put_site_content(&self, domain: &str, archive: Vec<u8>) -> Result<()> {
+        let url = self.url(&["api", "sites", domain, "content"]);
+        let mut body = Body::from_bytes(archive);
+        body.set_mime("application/gzip");
+        self.request(Method::Put, url, Some(body)).await.map(|_| ())
+    }
+
+    /// Pushes the history of a site to its git remote.
+    pub async fn git_push(&self, domain: &str) -> Result<()> {
+        let url = self.url(&["api", "sites", domain, "git", "push"]);
+        self.request(Method::Post, url, None).await.map(|_| ())
+    }
+
+    /// Pulls the history of a site from its git remote, returning whether anything changed.
+    pub async fn git_pull(&self, domain: &str) -> Result<bool> {
+        let url = self.url(&["api", "sites", domain, "git", "pull"]);
+        let response: JsonValue = self.request_json(Method::Post, url, None).await?;
+        Ok(response["updated"].as_bool().unwrap_or_default())
+    }
+
+    pub async fn get_site_config(&self, domain: &str) -> Result<SiteConfig> {
+        let url = self.url(&["api", "sites", domain, "config"]);
+        self.request_json(Method::Get, url, None).await
+    }
+
+    /// Changes the keys of the site's config that `changes` contains, removing the ones set to `null`.
+    pub async fn update_site_config(
+        &self,
+        domain: &str,
+        changes: Map<String, JsonValue>,
+    ) -> Result<()> {
+        let url = self.url(&["api", "sites", domain, "config"]);
+        let body = Body::from_json(&changes)?;
+        self.request(Method::Put, url, Some(body)).await.map(|_| ())
+    }
+
+    pub async fn get_site_domains(&self, domain: &str) -> Result<SiteDomains> {
+        let url = self.url(&["api", "sites", domain, "domains"]);
+        self.request_json(Method::Get, url, None).await
+    }
+
+    /// Starts attaching `new_domain` to a site, returning how to verify it.
+    pub async fn add_site_domain(
+        &self,
+        domain: &str,
+        new_domain: &str,
+    ) -> Result<DomainVerification> {
+        let url = self.url(&["api", "sites", domain, "domains"]);
+        self.request_json(Method::Post, url, Some(json!({"domain": new_domain})))
+            .await
+    }
+
+    /// Verifies `new_domain`, redirecting the site to it if `redirect`, and returns the domains of the site.
+    pub async fn verify_site_domain(
+        &self,
+        domain: &str,
+        new_domain: &str,
+        redirect: bool,
+    ) -> Result<Vec<String>> {
+        let url = self.url(&["api", "sites", domain, "domains", new_domain, "verify"]);
+        let response: JsonValue = self
+            .request_json(Method::Post, url, Some(json!({"redirect": redirect})))
+            .await?;
+        Ok(serde_json::from_value(response["aliases"].clone())?)
+    }
+
+    pub async fn remove_site_domain(&self, domain: &str, alias: &str) -> Result<()> {
+        let url = self.url(&["api", "sites", domain, "domains", alias]);
+        self.request(Method::Delete, url, None).await.map(|_| ())
+    }
+
+    /// The resources of `kind` of a site, newest first, which needs no authorization.
+    pub async fn get_public_resources(
+        &self,
+        domain: &str,
+        kind: Kind,
+    ) -> Result<Vec<PublicResource>> {
+        let url = self.url(&["api", "sites", domain, "public", kind.as_str()]);
+        let mut response = self.send(Request::new(Method::Get, url)).await?;
+        Ok(serde_json::from_str(&response.body_string().await?)?)
+    }
+
+    /// The file of a post or page: YAML front matter followed by markdown.
+    pub async fn get_content(&self, domain: &str, kind: Kind, slug: &str) -> Result<String> {
+        let url = self.url(&["api", "sites", domain, kind.as_str(), slug]);
+        let mut response = self.request(Method::Get, url, None).await?;
+        Ok(response.body_string().await?)
+    }
+
+    /// Creates or replaces a post or page.
+    pub async fn put_content(
+        &self,
+        domain: &str,
+        kind: Kind,
+        slug: &str,
+        text: &str,
+    ) -> Result<()> {
+        let url = self.url(&["api", "sites", domain, kind.as_str(), slug]);
+        let mut body = Body::from_string(text.to_owned());
+        body.set_mime("text/markdown");
+        self.request(Method::Put, url, Some(body)).await.map(|_| ())
+    }
+
+    pub async fn delete_content(&self, domain: &str, kind: Kind, slug: &str) -> Result<()> {
+        let url = self.url(&["api", "sites", domain, kind.as_str(), slug]);
+        self.request(Method::Delete, url, None).await.map(|_| ())
+    }
+
+    pub async fn get_themes(&self) -> Result<Vec<Theme>> {
+        self.request_json(Method::Get, self.url(&["api", "themes"]), None)
+            .await
+    }
+
+    /// The quota of a site, for its owner or the operator.
+    pub async fn get_site_quota(&self, domain: &str) -> Result<Quota> {
+        let url = self.url(&["api", "sites", domain, "quota"]);
+        self.request_json(Method::Get, url, None).await
+    }
+
+    /// Sets the quota of a site, which only the operator can do.
+    pub async fn set_site_quota(&self, domain: &str, quota: &Quota) -> Result<Quota> {
+        let url = self.url(&["api", "sites", domain, "quota"]);
+        self.request_json(Method::Put, url, Some(json!(quota)))
+            .await
+    }
+
+    /// The unused invite codes (operator only).
+    pub async fn get_invites(&self) -> Result<Vec<Invite>> {
+        self.request_json(Method::Get, self.url(&["api", "invites"]), None)
+            .await
+    }
+
+    /// Creates an invite code (operator only).
+    pub async fn create_invite(&self) -> Result<Invite> {
+        self.request_json(Method::Post, self.url(&["api", "invites"]), None)
+            .await
+    }
+
+    /// Revokes an invite code (operator only).
+    pub async fn delete_invite(&self, code: &str) -> Result<()> {
+        let url = self.url(&["api", "invites", code]);
+        self.request(Method::Delete, url, None).await.map(|_| ())
+    }
+
+    /// The sites waiting for approval (operator only).
+    pub async fn get_pending_sites(&self) -> Result<Vec<PendingSite>> {
+        self.request_json(Method::Get, self.url(&["api", "pending-sites"]), None)
+            .await
+    }
+
+    /// Approves a site, which is then created (operator only).
+    pub async fn approve_pending_site(&self, domain: &str) -> Result<()> {
+        let url = self.url(&["api", "pending-sites", domain]);
+        self.request(Method::Post, url, None).await.map(|_| ())
+    }
+
+    /// Rejects a site waiting for approval (operator only).
+    pub async fn reject_pending_site(&self, domain: &str) -> Result<()> {
+        let url = self.url(&["api", "pending-sites", domain]);
+        self.request(Method::Delete, url, None).await.map(|_| ())
+    }
+
+    /// Uploads a file to the site served at the base URL, using the Blossom API.
+    pub async fn upload(&self, data: Vec<u8>, content_type: &str) -> Result<BlobDescriptor> {
+        let hash = to_hex(&Sha256::digest(&data));
+        let expiration = (now() + BLOSSOM_AUTH_TTL).to_string();
+        let auth = self.sign(
+            EVENT_KIND_BLOSSOM,
+            &[["t", "upload"], ["x", &hash], ["expiration", &expiration]],
+            "Upload file",
+        );
+        let mut request = Request::new(Method::Put, self.url(&["upload"]));
+        request.set_body(data);
+        request.insert_header("Authorization", auth);
+        request.insert_header("Content-Type", content_type);
+        let mut response = self.send(request).await?;
+        Ok(serde_json::from_str(&response.body_string().await?)?)
+    }
+
+    /// The files uploaded to the site served at the base URL.
+    pub async fn list_blobs(&self) -> Result<Vec<BlobDescriptor>> {
+        let url = self.url(&["list", &self.pubkey()]);
+        let mut response = self.send(Request::new(Method::Get, url)).await?;
+        Ok(serde_json::from_str(&response.body_string().await?)?)
+    }
+
+    /// Deletes a file uploaded to the site served at the base URL.
+    pub async fn delete_blob(&self, sha256: &str) -> Result<()> {
+        let expiration = (now() + BLOSSOM_AUTH_TTL).to_string();
+        let auth = self.sign(
+            EVENT_KIND_BLOSSOM,
+            &[["t", "delete"], ["x", sha256], ["expiration", &expiration]],
+            "Delete file",
+        );
+        let mut request = Request::new(Method::Delete, self.url(&[sha256]));
+        request.insert_header("Authorization", auth);
+        self.send(request).await.map(|_| ())
+    }
+
+    /// The OpenAPI document describing the API.
+    pub async fn get_openapi(&self) -> Result<JsonValue> {
+        let url = self.url(&["api", "openapi.json"]);
+        let mut response = self.send(Request::new(Method::Get, url)).await?;
+        Ok(serde_json::from_str(&response.body_string().await?)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use secp256k1::{schnorr::Signature, XOnlyPublicKey};
+    use std::str::FromStr;
+
+    const SECRET_KEY: &str = "0000000000000000000000000000000000000000000000000000000000000001";
+
+    #[test]
+    fn test_new() {
+        let client = Client::new("https://example.com/servus/", SECRET_KEY).unwrap();
+        assert_eq!(
+            client.pubkey(),
+            "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798"
+        );
+        assert!(matches!(
+            Client::new("https://example.com", "npub1"),
+            Err(Error::InvalidKey)
+        ));
+        assert!(matches!(
+            Client::new("example.com", SECRET_KEY),
+            Err(Error::InvalidUrl(_))
+        ));
+    }
+
+    #[test]
+    fn test_url() {
+        let client = Client::new("https://example.com/servus", SECRET_KEY).unwrap();
+        assert_eq!(
+            client
+                .url(&["api", "sites", "example.com", "posts", "a/b c"])
+                .as_str(),
+            "https://example.com/servus/api/sites/example.com/posts/a%2Fb%20c"
+        );
+    }
+
+    #[test]
+    fn test_sign() {
+        let client = Client::new("https://example.com", SECRET_KEY).unwrap();
+        let auth = client.sign(
+            EVENT_KIND_AUTH,
+            &[["u", "https://example.com/api/sites"], ["method", "GET"]],
+            "",
+        );
+        let event: JsonValue = serde_json::from_slice(
+            &STANDARD
+                .decode(auth.strip_prefix("Nostr ").unwrap())
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(event["kind"], EVENT_KIND_AUTH);
+        assert_eq!(
+            event["tags"][0],
+            json!(["u", "https://example.com/api/sites"])
+        );
+
+        let canonical = json!([
+            0,
+            event["pubkey"],
+            event["created_at"],
+            event["kind"],
+            event["tags"],
+            event["content"]
+        ])
+        .to_string();
+        let hash = Sha256::digest(canonical.as_bytes());
+        assert_eq!(event["id"], to_hex(&hash));
+        let pubkey = XOnlyPublicKey::from_str(event["pubkey"].as_str().unwrap()).unwrap();
+        let sig = Signature::from_str(event["sig"].as_str().unwrap()).unwrap();
+        assert!(Secp256k1::verification_only()
+            .verify_schnorr(&sig, &Message::from_slice(&hash).unwrap(), &pubkey)
+            .is_ok());
+    }
+}
@@ -0,0 +1,692 @@
+use async_std::{future, io::ReadExt, net::ToSocketAddrs, task};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use http_client::{h1::H1Client, HttpClient};
+use http_types::{
+    url::{Host, Position},
+    Method, Request as HttpRequest, Url,
+};
+use rsa::{
+    pkcs1::DecodeRsaPublicKey,
+    pkcs1v15::{Signature, SigningKey, VerifyingKey},
+    pkcs8::{DecodePrivateKey, DecodePublicKey, EncodePrivateKey, EncodePublicKey, LineEnding},
+    signature::{SignatureEncoding, Signer, Verifier},
+    RsaPrivateKey, RsaPublicKey,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value as JsonValue};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs, io,
+    path::Path,
+    sync::Mutex,
+    time::Duration,
+};
+use tide::log;
+
+use crate::{
+    logging, paths,
+    resource::{ContentSource, ResourceKind},
+    site::Site,
+    utils,
+};
+
+pub const ACTOR_PATH: &str = "/activitypub/actor";
+pub const OUTBOX_PATH: &str = "/activitypub/outbox";
+pub const INBOX_PATH: &str = "/activitypub/inbox";
+pub const FOLLOWERS_PATH: &str = "/activitypub/followers";
+
+pub const ACTIVITY_JSON: &str = "application/activity+json";
+
+const DEFAULT_USERNAME: &str = "blog";
+
+const PUBLIC: &str = "https://www.w3.org/ns/activitystreams#Public";
+
+/// How many of the latest posts are listed in the outbox.
+const OUTBOX_MAX_ITEMS: usize = 20;
+
+/// How long to wait for another server to answer.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The largest document (such as an actor) read from another server.
+const MAX_DOCUMENT_SIZE: u64 = 1024 * 1024;
+
+/// How far the date of a signed request may be from now, so that captured requests cannot be replayed later on.
+const MAX_CLOCK_SKEW_SECONDS: i64 = 5 * 60;
+
+const KEY_BITS: usize = 2048;
+const KEY_FILENAME: &str = "key.pem";
+const FOLLOWERS_FILENAME: &str = "followers.json";
+
+/// The `[activitypub]` table of a site's `_config.toml`, having the site's posts followed from Mastodon and the like.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ActivityPubConfig {
+    /// The name the site is followed as, such as `blog` for `@blog@<domain>`. Defaults to `blog`.
+    pub username: Option<String>,
+}
+
+impl ActivityPubConfig {
+    pub fn get_username(&self) -> &str {
+        self.username.as_deref().unwrap_or(DEFAULT_USERNAME)
+    }
+}
+
+/// Held while the key is generated or the followers are updated.
+static LOCK: Mutex<()> = Mutex::new(());
+
+fn invalid_data(error: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+fn permission_denied(error: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, error)
+}
+
+fn get_dir(domain: &str) -> String {
+    format!("{}/{}/_activitypub", paths::sites(), domain)
+}
+
+/// The URL of one of the site's ActivityPub endpoints (unlike the permalinks of pages, without a trailing slash).
+fn get_url(site: &Site, path: &str) -> String {
+    format!("{}{}", site.config.base_url.trim_end_matches('/'), path)
+}
+
+fn get_key_id(site: &Site) -> String {
+    format!("{}#main-key", get_url(site, ACTOR_PATH))
+}
+
+/// The key the site signs its requests with, generated the first time it is needed.
+fn get_private_key(domain: &str) -> io::Result<RsaPrivateKey> {
+    let _lock = LOCK.lock().unwrap();
+    let path = format!("{}/{}", get_dir(domain), KEY_FILENAME);
+    if Path::new(&path).exists() {
+        let pem = fs::read_to_string(&path)?;
+        return RsaPrivateKey::from_pkcs8_pem(&pem).map_err(|e| invalid_data(&e.to_string()));
+    }
+
+    log::info!("Generating ActivityPub key of site: {}...", domain);
+    let key = RsaPrivateKey::new(&mut rand::thread_rng(), KEY_BITS).map_err(io::Error::other)?;
+    let pem = key.to_pkcs8_pem(LineEnding::LF).map_err(io::Error::other)?;
+    fs::create_dir_all(get_dir(domain))?;
+    fs::write(format!("{}.part", path), pem.as_bytes())?;
+    fs::rename(format!("{}.part", path), &path)?;
+
+    Ok(key)
+}
+
+/// The followers of the site: the ID of each actor, with the inbox activities are delivered to.
+fn read_followers(domain: &str) -> BTreeMap<String, String> {
+    fs::read_to_string(format!("{}/{}", get_dir(domain), FOLLOWERS_FILENAME))
+        .ok()
+        .and_then(|json| serde_json::from_str(&json).ok())
+        .unwrap_or_default()
+}
+
+fn update_followers(domain: &str, f: impl FnOnce(&mut BTreeMap<String, String>)) -> io::Result<()> {
+    let _lock = LOCK.lock().unwrap();
+    let mut followers = read_followers(domain);
+    f(&mut followers);
+    fs::create_dir_all(get_dir(domain))?;
+    let path = format!("{}/{}", get_dir(domain), FOLLOWERS_FILENAME);
+    fs::write(
+        format!("{}.part", path),
+        serde_json::to_string_pretty(&followers)?,
+    )?;
+    fs::rename(format!("{}.part", path), path)
+}
+
+fn format_date(date: &NaiveDateTime) -> String {
+    date.format("%Y-%m-%dT%H:%M:%SZ").to_string()
+}
+
+/// The WebFinger document of the site's actor, if `resource` (such as `acct:blog@example.com`) is the site's.
+pub fn render_webfinger(site: &Site, resource: &str) -> Option<JsonValue> {
+    let config = site.config.activitypub.as_ref()?;
+    let (username, domain) = resource.strip_prefix("acct:")?.split_once('@')?;
+    let host = Url::parse(&site.config.base_url)
+        .ok()?
+        .host_str()?
+        .to_owned();
+    if username != config.get_username()
+        || !(domain == host
+            || domain == site.domain
+            || site.config.aliases.iter().any(|a| a == domain))
+    {
+        return None;
+    }
+
+    let actor = get_url(site, ACTOR_PATH);
+    Some(json!({
+        "subject": format!("acct:{}@{}", username, host),
+        "aliases": [actor],
+        "links": [
+            {"rel": "self", "type": ACTIVITY_JSON, "href": actor},
+            {"rel": "http://webfinger.net/rel/profile-page", "type": "text/html", "href": site.config.base_url},
+        ],
+    }))
+}
+
+/// The actor the site's posts are followed as.
+pub fn render_actor(site: &Site) -> io::Result<JsonValue> {
+    let config = site.config.activitypub.clone().unwrap_or_default();
+    let public_key = RsaPublicKey::from(get_private_key(&site.domain)?)
+        .to_public_key_pem(LineEnding::LF)
+        .map_err(io::Error::other)?;
+    let actor = get_url(site, ACTOR_PATH);
+    let mut document = json!({
+        "@context": ["https://www.w3.org/ns/activitystreams", "https://w3id.org/security/v1"],
+        "id": actor,
+        "type": "Person",
+        "preferredUsername": config.get_username(),
+        "name": site.config.title.clone().unwrap_or(site.domain.to_owned()),
+        "url": site.config.base_url,
+        "inbox": get_url(site, INBOX_PATH),
+        "outbox": get_url(site, OUTBOX_PATH),
+        "followers": get_url(site, FOLLOWERS_PATH),
+        "manuallyApprovesFollowers": false,
+        "discoverable": true,
+        "publicKey": {
+            "id": get_key_id(site),
+            "owner": actor,
+            "publicKeyPem": public_key,
+        },
+    });
+    if let Some(description) = site
+        .config
+        .extra
+        .get("description")
+        .and_then(|d| d.as_str())
+    {
+        document["summary"] = json!(description);
+    }
+
+    Ok(document)
+}
+
+/// The `Create` activity of the post read from the event with ID `event_id`, if it is a post.
+fn render_create(site: &Site, event_id: &str) -> Option<JsonValue> {
+    let site_content = site.get_content();
+    let resource = site_content.resources.values().find(|r| {
+        r.kind == ResourceKind::Post
+            && !r.noindex
+            && matches!(&r.content_source, ContentSource::Event(id) if id == event_id)
+    })?;
+
+    Some(to_create(site, &resource.to_public(site, &site_content)))
+}
+
+fn to_create(site: &Site, post: &crate::resource::PublicResource) -> JsonValue {
+    let actor = get_url(site, ACTOR_PATH);
+    let followers = get_url(site, FOLLOWERS_PATH);
+    let published = format_date(&post.date);
+    let mut article = json!({
+        "id": post.url,
+        "type": "Article",
+        "name": post.title,
+        "content": post.html,
+        "url": post.url,
+        "published": published,
+        "attributedTo": actor,
+        "to": [PUBLIC],
+        "cc": [followers],
+    });
+    if let Some(summary) = &post.summary {
+        article["summary"] = json!(summary);
+    }
+
+    json!({
+        "id": format!("{}#create", post.url),
+        "type": "Create",
+        "actor": actor,
+        "published": published,
+        "to": [PUBLIC],
+        "cc": [followers],
+        "object": article,
+    })
+}
+
+/// The site's latest posts, as `Create` activities.
+pub fn render_outbox(site: &Site) -> JsonValue {
+    let site_content = site.get_content();
+    let mut posts = site_content
+        .resources
+        .values()
+        .filter(|r| r.kind == ResourceKind::Post && !r.noindex)
+        .collect::<Vec<_>>();
+    posts.sort_by_key(|r| std::cmp::Reverse(r.date));
+    let items = posts
+        .iter()
+        .take(OUTBOX_MAX_ITEMS)
+        .map(|r| to_create(site, &r.to_public(site, &site_content)))
+        .collect::<Vec<_>>();
+
+    json!({
+        "@context": "https://www.w3.org/ns/activitystreams",
+        "id": get_url(site, OUTBOX_PATH),
+        "type": "OrderedCollection",
+        "totalItems": posts.len(),
+        "orderedItems": items,
+    })
+}
+
+/// How many followers the site has (but not who they are).
+pub fn render_followers(site: &Site) -> JsonValue {
+    json!({
+        "@context": "https://www.w3.org/ns/activitystreams",
+        "id": get_url(site, FOLLOWERS_PATH),
+        "type": "OrderedCollection",
+        "totalItems": read_followers(&site.domain).len(),
+    })
+}
+
+/// The string signed by an HTTP signature (see draft-cavage-http-signatures), given the names of the signed headers.
+fn get_signing_string<'a>(
+    signed_headers: &[&str],
+    request_target: &str,
+    get_header: impl Fn(&str) -> Option<&'a str>,
+) -> Option<String> {
+    signed_headers
+        .iter()
+        .map(|name| match *name {
+            "(request-target)" => Some(format!("{}: {}", name, request_target)),
+            _ => get_header(name).map(|value| format!("{}: {}", name, value.trim())),
+        })
+        .collect::<Option<Vec<_>>>()
+        .map(|lines| lines.join("\n"))
+}
+
+/// The parameters of a `Signature` header, such as `keyId` and `signature`.
+fn parse_signature_header(header: &str) -> HashMap<&str, &str> {
+    header
+        .split(',')
+        .filter_map(|param| param.trim().split_once('='))
+        .map(|(name, value)| (name, value.trim_matches('"')))
+        .collect()
+}
+
+fn get_digest(body: &[u8]) -> String {
+    format!("SHA-256={}", STANDARD.encode(Sha256::digest(body)))
+}
+
+/// A request to `url`, signed by the site's key.
+fn build_signed_request(
+    method: Method,
+    url: &Url,
+    body: Option<Vec<u8>>,
+    key: &RsaPrivateKey,
+    key_id: &str,
+) -> HttpRequest {
+    let mut request = HttpRequest::new(method, url.clone());
+    let host = match url.port() {
+        Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+        None => url.host_str().unwrap_or_default().to_owned(),
+    };
+    let date = Utc::now().format("%a, %d %b %Y %H:%M:%S GMT").to_string();
+    let request_target = format!(
+        "{} {}",
+        method.to_string().to_lowercase(),
+        &url[Position::BeforePath..Position::AfterQuery]
+    );
+    let mut headers = vec![("host", host), ("date", date)];
+    if let Some(body) = &body {
+        headers.push(("digest", get_digest(body)));
+    }
+
+    let signed_headers = ["(request-target)"]
+        .into_iter()
+        .chain(headers.iter().map(|(name, _)| *name))
+        .collect::<Vec<_>>();
+    let signing_string = get_signing_string(&signed_headers, &request_target, |name| {
+        headers
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, v)| v.as_str())
+    })
+    .unwrap();
+    let signature = SigningKey::<Sha256>::new(key.clone()).sign(signing_string.as_bytes());
+
+    for (name, value) in &headers {
+        request.insert_header(*name, value.as_str());
+    }
+    request.insert_header(
+        "Signature",
+        format!(
+            "keyId=\"{}\",algorithm=\"rsa-sha256\",headers=\"{}\",signature=\"{}\"",
+            key_id,
+            signed_headers.join(" "),
+            STANDARD.encode(signature.to_bytes())
+        ),
+    );
+    request.insert_header("Accept", ACTIVITY_JSON);
+    if let Some(body) = body {
+        request.insert_header("Content-Type", ACTIVITY_JSON);
+        request.set_body(body);
+    }
+
+    request
+}
+
+/// Fails unless all the addresses `url` points to are reachable on the internet, so that other servers
+/// (which the URLs of keys, actors and inboxes come from) cannot have requests made to the network of this one.
+async fn check_public_url(url: &Url) -> io::Result<()> {
+    if !["https", "http"].contains(&url.scheme()) {
+        return Err(invalid_data("Unsupported URL"));
+    }
+    let port = url.port_or_known_default().unwrap_or(443);
+    let ips = match url.host() {
+        Some(Host::Ipv4(ip)) => vec![ip.into()],
+        Some(Host::Ipv6(ip)) => vec![ip.into()],
+        Some(Host::Domain(domain)) => (domain, port)
+            .to_socket_addrs()
+            .await?
+            .map(|a| a.ip())
+            .collect(),
+        None => vec![],
+    };
+    if ips.is_empty() || !ips.into_iter().all(utils::is_global_ip) {
+        return Err(permission_denied(&format!("Not a public address: {}", url)));
+    }
+
+    Ok(())
+}
+
+async fn send(request: HttpRequest) -> io::Result<http_types::Response> {
+    check_public_url(request.url()).await?;
+    let url = request.url().to_string();
+    let response = future::timeout(REQUEST_TIMEOUT, H1Client::new().send(request))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, format!("Timed out: {}", url)))?
+        .map_err(|e| io::Error::other(format!("{}: {}", url, e)))?;
+    if !response.status().is_success() {
+        return Err(io::Error::other(format!("{}: {}", url, response.status())));
+    }
+
+    Ok(response)
+}
+
+/// Fetches the document at `url`, asking for it as the site (which some servers require).
+async fn fetch(site: &Site, url: &str) -> io::Result<JsonValue> {
+    let url = Url::parse(url).map_err(|e| invalid_data(&e.to_string()))?;
+    let key = get_key(site).await?;
+    let request = build_signed_request(Method::Get, &url, None, &key, &get_key_id(site));
+    let mut response = send(request).await?;
+
+    let mut body = vec![];
+    response
+        .take_body()
+        .take(MAX_DOCUMENT_SIZE + 1)
+        .read_to_end(&mut body)
+        .await?;
+    if body.len() as u64 > MAX_DOCUMENT_SIZE {
+        return Err(invalid_data(&format!("Too large: {}", url)));
+    }
+
+    serde_json::from_slice(&body).map_err(|e| invalid_data(&e.to_string()))
+}
+
+async fn deliver(site: &Site, inbox: &str, activity: &JsonValue) -> io::Result<()> {
+    let url = Url::parse(inbox).map_err(|e| invalid_data(&e.to_string()))?;
+    let key = get_key(site).await?;
+    let body = serde_json::to_vec(activity)?;
+    let request = build_signed_request(Method::Post, &url, Some(body), &key, &get_key_id(site));
+    send(request).await.map(|_| ())
+}
+
+async fn get_key(site: &Site) -> io::Result<RsaPrivateKey> {
+    let domain = site.domain.to_owned();
+    logging::spawn_blocking(move || get_private_key(&domain)).await
+}
+
+/// Fails unless the signature covers the request target, the body and a date close enough to `now`,
+/// so that it cannot be used for another request, nor again later on.
+fn check_signed_headers(
+    signed_headers: &[&str],
+    headers: &HashMap<String, String>,
+    body: &[u8],
+    now: DateTime<Utc>,
+) -> io::Result<()> {
+    if !signed_headers.contains(&"(request-target)") {
+        return Err(permission_denied("The request target is not signed"));
+    }
+    if !signed_headers.contains(&"digest")
+        || headers.get("digest").map(|d| d.as_str()) != Some(get_digest(body).as_str())
+    {
+        return Err(permission_denied("The body is not signed"));
+    }
+    let date = headers
+        .get("date")
+        .filter(|_| signed_headers.contains(&"date"))
+        .and_then(|d| DateTime::parse_from_rfc2822(d).ok())
+        .ok_or_else(|| permission_denied("The date is not signed"))?;
+    if (now - date.with_timezone(&Utc)).num_seconds().abs() > MAX_CLOCK_SKEW_SECONDS {
+        return Err(permission_denied("The date is too far from now"));
+    }
+
+    Ok(())
+}
+
+/// The actor that signed the request, given its headers (with lowercase names), once the signature is verified.
+async fn verify_signature(
+    site: &Site,
+    request_target: &str,
+    headers: &HashMap<String, String>,
+    body: &[u8],
+) -> io::Result<JsonValue> {
+    let params = parse_signature_header(
+        headers
+            .get("signature")
+            .ok_or_else(|| permission_denied("No signature"))?,
+    );
+    let key_id = params
+        .get("keyId")
+        .ok_or_else(|| permission_denied("No key ID"))?;
+    let signature = params
+        .get("signature")
+        .and_then(|s| STANDARD.decode(s).ok())
+        .and_then(|s| Signature::try_from(s.as_slice()).ok())
+        .ok_or_else(|| permission_denied("Invalid signature"))?;
+    let signed_headers = params
+        .get("headers")
+        .unwrap_or(&"date")
+        .split_whitespace()
+        .collect::<Vec<_>>();
+    check_signed_headers(&signed_headers, headers, body, Utc::now())?;
+    let signing_string = get_signing_string(&signed_headers, request_target, |name| {
+        headers.get(name).map(|v| v.as_str())
+    })
+    .ok_or_else(|| permission_denied("A signed header is missing"))?;
+
+    // the key ID points to either the actor or the key itself
+    let document = fetch(site, key_id).await?;
+    let (key, actor) = if document.get("publicKeyPem").is_some() {
+        let owner = document["owner"].as_str().unwrap_or_default().to_owned();
+        (document, fetch(site, &owner).await?)
+    } else {
+        let keys = match &document["publicKey"] {
+            JsonValue::Array(keys) => keys.clone(),
+            key => vec![key.clone()],
+        };
+        let key = keys
+            .into_iter()
+            .find(|k| k["id"].as_str() == Some(key_id))
+            .ok_or_else(|| permission_denied("Unknown key"))?;
+        (key, document)
+    };
+    if key["owner"] != actor["id"] {
+        return Err(permission_denied("The key is not the actor's"));
+    }
+    let pem = key["publicKeyPem"].as_str().unwrap_or_default();
+    let public_key = RsaPublicKey::from_public_key_pem(pem)
+        .or_else(|_| RsaPublicKey::from_pkcs1_pem(pem))
+        .map_err(|_| permission_denied("Invalid key"))?;
+    VerifyingKey::<Sha256>::new(public_key)
+        .verify(signing_string.as_bytes(), &signature)
+        .map_err(|_| permission_denied("Invalid signature"))?;
+
+    Ok(actor)
+}
+
+/// Handles an activity sent to the site's inbox: follows and unfollows, the rest being ignored.
+pub async fn receive(
+    site: &Site,
+    request_target: &str,
+    headers: &HashMap<String, String>,
+    body: &[u8],
+) -> io::Result<()> {
+    let activity: JsonValue = serde_json::from_slice(body)?;
+    let actor_id = activity["actor"]
+        .as_str()
+        .ok_or_else(|| invalid_data("No actor"))?;
+    let is_follow = |object: &JsonValue| {
+        object["type"] == "Follow" && object["object"] == get_url(site, ACTOR_PATH)
+    };
+
+    match activity["type"].as_str() {
+        Some("Follow") if is_follow(&activity) => {
+            let actor = verify_signature(site, request_target, headers, body).await?;
+            if actor["id"] != actor_id {
+                return Err(permission_denied("Not signed by the actor"));
+            }
+            let inbox = actor["inbox"]
+                .as_str()
+                .ok_or_else(|| invalid_data("No inbox"))?
+                .to_owned();
+            let shared_inbox = actor["endpoints"]["sharedInbox"].as_str().unwrap_or(&inbox);
+            {
+                let (domain, actor_id) = (site.domain.to_owned(), actor_id.to_owned());
+                let shared_inbox = shared_inbox.to_owned();
+                logging::spawn_blocking(move || {
+                    update_followers(&domain, |followers| {
+                        followers.insert(actor_id, shared_inbox);
+                    })
+                })
+                .await?;
+            }
+            log::info!("New follower of {}: {}.", site.domain, actor_id);
+
+            let accept = json!({
+                "@context": "https://www.w3.org/ns/activitystreams",
+                "id": format!("{}#accepts/{}", get_url(site, ACTOR_PATH), Utc::now().timestamp_millis()),
+                "type": "Accept",
+                "actor": get_url(site, ACTOR_PATH),
+                "object": activity,
+            });
+            let site = site.clone();
+            task::spawn(async move {
+                if let Err(e) = deliver(&site, &inbox, &accept).await {
+                    log::warn!("Cannot accept follow of {}: {}", site.domain, e);
+                }
+            });
+        }
+        Some("Undo") if is_follow(&activity["object"]) => {
+            let actor = verify_signature(site, request_target, headers, body).await?;
+            if actor["id"] != actor_id {
+                return Err(permission_denied("Not signed by the actor"));
+            }
+            let (domain, actor_id) = (site.domain.to_owned(), actor_id.to_owned());
+            logging::spawn_blocking(move || {
+                update_followers(&domain, |followers| {
+                    followers.remove(&actor_id);
+                })
+            })
+            .await?;
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+/// Delivers the post read from the event with ID `event_id` to the site's followers, in the background.
+pub fn deliver_post_in_background(site: &Site, event_id: &str) {
+    let (site, event_id) = (site.clone(), event_id.to_owned());
+    task::spawn(async move {
+        let (activity, followers) = {
+            let site = site.clone();
+            logging::spawn_blocking(move || {
+                let mut activity = render_create(&site, &event_id)?;
+                activity["@context"] = json!("https://www.w3.org/ns/activitystreams");
+                Some((activity, read_followers(&site.domain)))
+            })
+            .await
+        }
+        .unwrap_or_default();
+
+        let mut inboxes = followers.into_values().collect::<Vec<_>>();
+        inboxes.sort();
+        inboxes.dedup();
+        for inbox in inboxes {
+            if let Err(e) = deliver(&site, &inbox, &activity).await {
+                log::warn!("Cannot deliver post of {} to {}: {}", site.domain, inbox, e);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_signature_header() {
+        let params = parse_signature_header(
+            "keyId=\"https://example.com/users/alice#main-key\",algorithm=\"rsa-sha256\",headers=\"(request-target) host date digest\",signature=\"YWJj==\"",
+        );
+        assert_eq!(params["keyId"], "https://example.com/users/alice#main-key");
+        assert_eq!(params["headers"], "(request-target) host date digest");
+        assert_eq!(params["signature"], "YWJj==");
+    }
+
+    #[test]
+    fn test_signed_request() {
+        let key = RsaPrivateKey::new(&mut rand::thread_rng(), 512).unwrap();
+        let url = Url::parse("https://example.com:8443/inbox?a=b").unwrap();
+        let body = b"{}".to_vec();
+        let request = build_signed_request(Method::Post, &url, Some(body.clone()), &key, "key");
+        let header = |name: &str| request.header(name).map(|v| v.last().as_str());
+        assert_eq!(header("host"), Some("example.com:8443"));
+        assert_eq!(header("digest"), Some(get_digest(&body).as_str()));
+
+        let params = parse_signature_header(header("signature").unwrap());
+        let signed_headers = params["headers"].split(' ').collect::<Vec<_>>();
+        assert_eq!(
+            signed_headers,
+            ["(request-target)", "host", "date", "digest"]
+        );
+        let signing_string =
+            get_signing_string(&signed_headers, "post /inbox?a=b", header).unwrap();
+        let signature =
+            Signature::try_from(STANDARD.decode(params["signature"]).unwrap().as_slice()).unwrap();
+        assert!(VerifyingKey::<Sha256>::new(RsaPublicKey::from(&key))
+            .verify(signing_string.as_bytes(), &signature)
+            .is_ok());
+        assert!(get_signing_string(&["(request-target)", "x-missing"], "post /", header).is_none());
+    }
+
+    #[test]
+    fn test_check_signed_headers() {
+        let body = b"{}";
+        let now = Utc::now();
+        let headers = HashMap::from([
+            ("digest".to_string(), get_digest(body)),
+            (
+                "date".to_string(),
+                now.format("%a, %d %b %Y %H:%M:%S GMT").to_string(),
+            ),
+        ]);
+        let all = ["(request-target)", "host", "date", "digest"];
+        assert!(check_signed_headers(&all, &headers, body, now).is_ok());
+        assert!(check_signed_headers(&all, &headers, b"{\"a\":1}", now).is_err());
+        for missing in ["(request-target)", "date", "digest"] {
+            let signed = all
+                .into_iter()
+                .filter(|h| *h != missing)
+                .collect::<Vec<_>>();
+            assert!(check_signed_headers(&signed, &headers, body, now).is_err());
+        }
+        // a request captured an hour ago
+        let later = now + chrono::Duration::hours(1);
+        assert!(check_signed_headers(&all, &headers, body, later).is_err());
+    }
+}
@@ -0,0 +1,126 @@
+use std::fmt;
+use tide::log;
+
+/// Who, besides the owner of a site, can do something to it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Access {
+    Owner,
+    OwnerOrOperator,
+    /// Reserved to the server's operators, such as setting a site's quota.
+    Operator,
+}
+
+/// Who made a request that was authorized.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Role {
+    Owner,
+    Operator,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Role::Owner => write!(f, "owner"),
+            Role::Operator => write!(f, "operator"),
+        }
+    }
+}
+
+/// Whether two keys are the same, taking as long whatever the first differing character,
+/// so that how long a check takes says nothing about the key being checked against.
+pub fn keys_equal(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Whether `pubkey`, who made a request, is allowed `access` to a site owned by `site_pubkey`, and as whom, or why not.
+pub fn check(
+    pubkey: Option<&str>,
+    site_pubkey: Option<&str>,
+    operator_pubkeys: &[String],
+    access: Access,
+) -> Result<Role, &'static str> {
+    let pubkey = pubkey.ok_or("missing or invalid authorization")?;
+    let is_owner = site_pubkey.is_some_and(|site_pubkey| keys_equal(pubkey, site_pubkey));
+    let is_operator = operator_pubkeys
+        .iter()
+        .any(|operator| keys_equal(pubkey, operator));
+
+    match access {
+        Access::Owner | Access::OwnerOrOperator if is_owner => Ok(Role::Owner),
+        Access::OwnerOrOperator | Access::Operator if is_operator => Ok(Role::Operator),
+        Access::Operator => Err("not an operator's key"),
+        _ if site_pubkey.is_none() => Err("the site has no pubkey"),
+        _ => Err("not the site's key"),
+    }
+}
+
+/// Records, in the audit log, who did what to which site (if any).
+pub fn audit(pubkey: &str, role: Role, action: &str, domain: Option<&str>) {
+    log::info!(
+        "{} on {} by the {} ({}).",
+        action,
+        domain.unwrap_or("the server"),
+        role,
+        pubkey
+    );
+}
+
+/// Records, in the audit log, a request that was not authorized, who made it (if known), and why.
+pub fn audit_denied(pubkey: Option<&str>, action: &str, domain: Option<&str>, reason: &str) {
+    log::info!(
+        "Denied {} on {} to {}: {}.",
+        action,
+        domain.unwrap_or("the server"),
+        pubkey.unwrap_or("an unknown key"),
+        reason
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keys_equal() {
+        assert!(keys_equal("abc", "abc"));
+        assert!(!keys_equal("abc", "abd"));
+        assert!(!keys_equal("abc", "abcd"));
+        assert!(!keys_equal("", "a"));
+    }
+
+    #[test]
+    fn test_check() {
+        let (owner, operator) = (Some("aa"), Some("bb"));
+        let operators = ["bb".to_string(), "dd".to_string()];
+        assert_eq!(
+            check(owner, owner, &operators, Access::Owner),
+            Ok(Role::Owner)
+        );
+        assert!(check(operator, owner, &operators, Access::Owner).is_err());
+        assert_eq!(
+            check(operator, owner, &operators, Access::OwnerOrOperator),
+            Ok(Role::Operator)
+        );
+        assert!(check(owner, owner, &operators, Access::Operator).is_err());
+        assert_eq!(
+            check(operator, None, &operators, Access::Operator),
+            Ok(Role::Operator)
+        );
+        assert_eq!(
+            check(Some("cc"), None, &[], Access::Owner),
+            Err("the site has no pubkey")
+        );
+        assert!(check(None, owner, &operators, Access::OwnerOrOperator).is_err());
+        // without an operator, nobody is
+        assert!(check(Some("cc"), owner, &[], Access::Operator).is_err());
+        // any of the operators is
+        assert_eq!(
+            check(Some("dd"), owner, &operators, Access::OwnerOrOperator),
+            Ok(Role::Operator)
+        );
+    }
+}
@@ -0,0 +1,464 @@
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::{self, File},
+    io::{self, Read, Write},
+    path::{Component, Path, PathBuf},
+    process,
+};
+use walkdir::WalkDir;
+
+use crate::{paths, site, traffic, utils};
+
+const FORMAT_VERSION: u32 = 1;
+
+const MANIFEST_FILENAME: &str = "manifest.json";
+const SITE_DIR: &str = "site";
+const KEYS_DIR: &str = "keys";
+const TRAFFIC_FILENAME: &str = "traffic.csv";
+
+/// The config keys giving the paths of the site's own certificate and key, which are backed up along with the site.
+const KEY_CONFIG_KEYS: &[&str] = &["ssl_cert", "ssl_key"];
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct FileDigest {
+    pub size: u64,
+    pub sha256: String,
+}
+
+/// The first entry of a backup, listing all the other ones along with their SHA-256,
+/// so that a backup is verified as it is read, before anything is restored.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Manifest {
+    pub version: u32,
+    pub domain: String,
+    pub created_at: i64,
+    pub files: BTreeMap<String, FileDigest>,
+}
+
+impl Manifest {
+    pub fn get_size(&self) -> u64 {
+        self.files.values().map(|f| f.size).sum()
+    }
+}
+
+/// Hashes what is read through it.
+struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+    size: u64,
+}
+
+impl<R: Read> HashingReader<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+            size: 0,
+        }
+    }
+
+    fn finish(self) -> FileDigest {
+        FileDigest {
+            size: self.size,
+            sha256: utils::to_hex(&self.hasher.finalize()),
+        }
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.size += n as u64;
+        Ok(n)
+    }
+}
+
+fn get_digest(path: &Path) -> io::Result<FileDigest> {
+    let mut reader = HashingReader::new(File::open(path)?);
+    io::copy(&mut reader, &mut io::sink())?;
+    Ok(reader.finish())
+}
+
+/// `path` as the name of an entry, as long as it is a relative path which stays where it is extracted.
+fn get_entry_name(path: &Path) -> io::Result<String> {
+    let mut parts = vec![];
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => {
+                parts.push(part.to_str().ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, "Invalid file name")
+                })?)
+            }
+            Component::CurDir => {}
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid path: {}", path.display()),
+                ))
+            }
+        }
+    }
+
+    Ok(parts.join("/"))
+}
+
+/// Files which are only there while the content is being replaced or a file is being uploaded.
+fn is_transient(relative_path: &Path) -> bool {
+    relative_path.starts_with("_content.new")
+        || relative_path.starts_with("_content.old")
+        || relative_path.extension().is_some_and(|e| e == "part")
+}
+
+/// The files backed up, as their names in the backup and their paths: all of the site's directory
+/// (its config, its content, the uploaded files and their metadata, its git history),
+/// the site's own certificate and key, if any, and its traffic.
+fn get_files(domain: &str) -> io::Result<Vec<(String, PathBuf)>> {
+    let site_path = PathBuf::from(format!("{}/{}", paths::sites(), domain));
+    let mut files = vec![];
+    for entry in WalkDir::new(&site_path).sort_by_file_name() {
+        let entry = entry?;
+        let relative_path = entry.path().strip_prefix(&site_path).unwrap();
+        if entry.file_type().is_file() && !is_transient(relative_path) {
+            let name = format!("{}/{}", SITE_DIR, get_entry_name(relative_path)?);
+            files.push((name, entry.into_path()));
+        }
+    }
+
+    let config = site::read_config_table(domain)?;
+    for key in KEY_CONFIG_KEYS {
+        if let Some(path) = config.get(*key).and_then(|p| p.as_str()) {
+            files.push((format!("{}/{}", KEYS_DIR, key), PathBuf::from(path)));
+        }
+    }
+    let traffic_path = PathBuf::from(traffic::get_csv_path(domain));
+    if traffic_path.exists() {
+        files.push((TRAFFIC_FILENAME.to_owned(), traffic_path));
+    }
+
+    Ok(files)
+}
+
+fn append(
+    archive: &mut tar::Builder<impl Write>,
+    name: &str,
+    size: u64,
+    mtime: i64,
+    data: impl Read,
+) -> io::Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(size);
+    header.set_mode(0o600);
+    header.set_mtime(mtime.max(0) as u64);
+    archive.append_data(&mut header, name, data)
+}
+
+/// Writes the manifest then the `files`, as a `.tar.zst`, checking that they didn't change since the manifest was made.
+fn write_backup<W: Write>(
+    writer: W,
+    manifest: &Manifest,
+    files: &[(String, PathBuf)],
+) -> io::Result<W> {
+    let mut archive = tar::Builder::new(zstd::Encoder::new(writer, 0)?);
+    let manifest_json = serde_json::to_vec_pretty(manifest)?;
+    append(
+        &mut archive,
+        MANIFEST_FILENAME,
+        manifest_json.len() as u64,
+        manifest.created_at,
+        &manifest_json[..],
+    )?;
+    for (name, path) in files {
+        let expected = &manifest.files[name];
+        let mut reader = HashingReader::new(File::open(path)?.take(expected.size));
+        append(
+            &mut archive,
+            name,
+            expected.size,
+            manifest.created_at,
+            &mut reader,
+        )?;
+        if reader.finish() != *expected {
+            return Err(io::Error::other(format!(
+                "{} changed during the backup, try again",
+                path.display()
+            )));
+        }
+    }
+
+    archive.into_inner()?.finish()
+}
+
+/// Backs up the site to `out`, as a `.tar.zst` that [`restore_backup`] can restore, possibly on another server.
+pub fn backup_site(domain: &str, out: &Path) -> io::Result<Manifest> {
+    if !site::is_valid_domain(domain)
+        || !Path::new(&format!("{}/{}", paths::sites(), domain)).is_dir()
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("No such site: {}", domain),
+        ));
+    }
+
+    let files = get_files(domain)?;
+    let mut manifest = Manifest {
+        version: FORMAT_VERSION,
+        domain: domain.to_owned(),
+        created_at: Utc::now().timestamp(),
+        files: BTreeMap::new(),
+    };
+    for (name, path) in &files {
+        let digest = get_digest(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        manifest.files.insert(name.to_owned(), digest);
+    }
+
+    // a backup is either complete or not there at all
+    let part_path = PathBuf::from(format!("{}.part", out.display()));
+    let result = File::create(&part_path)
+        .and_then(|file| write_backup(file, &manifest, &files))
+        .and_then(|file| file.sync_all())
+        .and_then(|_| fs::rename(&part_path, out));
+    if let Err(e) = result {
+        let _ = fs::remove_file(&part_path);
+        return Err(e);
+    }
+
+    Ok(manifest)
+}
+
+/// Reads a backup, checking each file against the manifest, and writes the files under `target_path`, if given.
+/// A backup that doesn't match its manifest is `InvalidData`.
+fn read_backup(reader: impl Read, target_path: Option<&Path>) -> io::Result<Manifest> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+    let mut archive = tar::Archive::new(zstd::Decoder::new(reader)?);
+    let mut manifest: Option<Manifest> = None;
+    let mut found = BTreeSet::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let name = get_entry_name(&entry.path()?)?;
+        let Some(manifest) = &manifest else {
+            if name != MANIFEST_FILENAME {
+                return Err(invalid("Not a backup: no manifest".to_owned()));
+            }
+            let mut json = String::new();
+            entry.read_to_string(&mut json)?;
+            let m: Manifest = serde_json::from_str(&json)
+                .map_err(|e| invalid(format!("Invalid manifest: {}", e)))?;
+            if m.version != FORMAT_VERSION {
+                return Err(invalid(format!(
+                    "Unsupported backup version: {}",
+                    m.version
+                )));
+            }
+            if !site::is_valid_domain(&m.domain) {
+                return Err(invalid(format!("Invalid domain: {}", m.domain)));
+            }
+            manifest = Some(m);
+            continue;
+        };
+
+        let Some(expected) = manifest.files.get(&name) else {
+            return Err(invalid(format!("Not in the manifest: {}", name)));
+        };
+        if entry.header().entry_type() != tar::EntryType::Regular {
+            return Err(invalid(format!("Not a file: {}", name)));
+        }
+        let mut reader = HashingReader::new(&mut entry);
+        match target_path {
+            Some(target_path) => {
+                let target = target_path.join(&name);
+                if let Some(parent) = target.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                io::copy(&mut reader, &mut File::create(target)?)?;
+            }
+            None => {
+                io::copy(&mut reader, &mut io::sink())?;
+            }
+        }
+        if reader.finish() != *expected {
+            return Err(invalid(format!("Corrupted file: {}", name)));
+        }
+        found.insert(name);
+    }
+
+    let manifest = manifest.ok_or_else(|| invalid("Not a backup: no manifest".to_owned()))?;
+    if let Some(missing) = manifest.files.keys().find(|name| !found.contains(*name)) {
+        return Err(invalid(format!("Missing file: {}", missing)));
+    }
+
+    Ok(manifest)
+}
+
+/// Checks that the backup at `path` is complete and intact, without restoring anything.
+pub fn verify_backup(path: &Path) -> io::Result<Manifest> {
+    read_backup(File::open(path)?, None)
+}
+
+/// Puts the verified files extracted to `staging_path` in place, replacing the existing site if `force` is given,
+/// returning the path of the archive the replaced site was saved to, if any.
+fn install(staging_path: &Path, manifest: &Manifest, force: bool) -> io::Result<Option<String>> {
+    let domain = &manifest.domain;
+    let site_path = PathBuf::from(format!("{}/{}", paths::sites(), domain));
+    let archive_path = if site_path.exists() {
+        if !force {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!(
+                    "Site already exists: {} (use --force to replace it)",
+                    domain
+                ),
+            ));
+        }
+        site::delete_site(domain, true)?
+    } else {
+        None
+    };
+    fs::rename(staging_path.join(SITE_DIR), &site_path)?;
+
+    // the certificate and key are only put back where the config expects them if they are missing,
+    // so that newer ones (such as ones renewed since the backup) are kept
+    let config = site::read_config_table(domain)?;
+    for key in KEY_CONFIG_KEYS {
+        let backed_up = staging_path.join(KEYS_DIR).join(key);
+        let Some(path) = config.get(*key).and_then(|p| p.as_str()) else {
+            continue;
+        };
+        if backed_up.exists() && !Path::new(path).exists() {
+            if let Some(parent) = Path::new(path).parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::copy(&backed_up, path)?;
+        }
+    }
+
+    let traffic_path = staging_path.join(TRAFFIC_FILENAME);
+    if traffic_path.exists() {
+        let csv_path = PathBuf::from(traffic::get_csv_path(domain));
+        fs::create_dir_all(csv_path.parent().unwrap())?;
+        fs::copy(&traffic_path, csv_path)?;
+    }
+
+    Ok(archive_path)
+}
+
+/// Restores a site from a backup made by [`backup_site`], once the whole backup is verified.
+/// An existing site is only replaced if `force` is given, and is archived first.
+pub fn restore_backup(path: &Path, force: bool) -> io::Result<(Manifest, Option<String>)> {
+    // hidden, so that it is neither loaded nor watched as a site
+    let staging_path = PathBuf::from(format!("{}/.restore-{}", paths::sites(), process::id()));
+    if staging_path.exists() {
+        fs::remove_dir_all(&staging_path)?;
+    }
+    fs::create_dir_all(&staging_path)?;
+    let result = File::open(path)
+        .and_then(|file| read_backup(file, Some(&staging_path)))
+        .and_then(|manifest| {
+            let archive_path = install(&staging_path, &manifest, force)?;
+            Ok((manifest, archive_path))
+        });
+    fs::remove_dir_all(&staging_path)?;
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build_backup(dir: &Path, tamper: impl FnOnce(&mut Manifest)) -> Vec<u8> {
+        let files = vec![
+            ("site/_config.toml".to_owned(), dir.join("_config.toml")),
+            (
+                "site/_content/posts/hello.md".to_owned(),
+                dir.join("hello.md"),
+            ),
+        ];
+        let mut manifest = Manifest {
+            version: FORMAT_VERSION,
+            domain: "example.com".to_owned(),
+            created_at: 0,
+            files: BTreeMap::new(),
+        };
+        for (name, path) in &files {
+            manifest
+                .files
+                .insert(name.to_owned(), get_digest(path).unwrap());
+        }
+        let backup = write_backup(vec![], &manifest, &files).unwrap();
+        tamper(&mut manifest);
+
+        // the tampered manifest replaces the original one
+        let mut archive = tar::Builder::new(zstd::Encoder::new(vec![], 0).unwrap());
+        let manifest_json = serde_json::to_vec(&manifest).unwrap();
+        append(
+            &mut archive,
+            MANIFEST_FILENAME,
+            manifest_json.len() as u64,
+            0,
+            &manifest_json[..],
+        )
+        .unwrap();
+        let mut original = tar::Archive::new(zstd::Decoder::new(&backup[..]).unwrap());
+        for entry in original.entries().unwrap().skip(1) {
+            let mut entry = entry.unwrap();
+            let name = entry.path().unwrap().display().to_string();
+            append(&mut archive, &name, entry.size(), 0, &mut entry).unwrap();
+        }
+        archive.into_inner().unwrap().finish().unwrap()
+    }
+
+    #[test]
+    fn test_read_backup() {
+        let dir = std::env::temp_dir().join(format!("servus-backup-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("_config.toml"),
+            "base_url = \"https://example.com\"\n",
+        )
+        .unwrap();
+        fs::write(dir.join("hello.md"), "Hello!").unwrap();
+
+        let target = dir.join("restored");
+        let manifest = read_backup(&build_backup(&dir, |_| {})[..], Some(&target)).unwrap();
+        assert_eq!(manifest.domain, "example.com");
+        assert_eq!(manifest.get_size(), 39);
+        assert_eq!(
+            fs::read_to_string(target.join("site/_content/posts/hello.md")).unwrap(),
+            "Hello!"
+        );
+
+        let backup = build_backup(&dir, |m| {
+            m.files.get_mut("site/_config.toml").unwrap().sha256 = utils::sha256_hex(b"other");
+        });
+        let error = read_backup(&backup[..], None).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains("_config.toml"));
+
+        let backup = build_backup(&dir, |m| {
+            m.files.insert(
+                "site/_content/posts/gone.md".to_owned(),
+                FileDigest {
+                    size: 0,
+                    sha256: utils::sha256_hex(b""),
+                },
+            );
+        });
+        let error = read_backup(&backup[..], None).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Missing file: site/_content/posts/gone.md"
+        );
+
+        let backup = build_backup(&dir, |m| {
+            m.files.remove("site/_content/posts/hello.md");
+        });
+        assert!(read_backup(&backup[..], None).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,301 @@
+use chrono::Utc;
+use serde_json::json;
+use std::{
+    collections::HashMap,
+    env, fs, process,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    nostr,
+    resource::Resource,
+    site::{self, Site},
+    theme::Theme,
+    utils, Upload,
+};
+
+const DOMAIN: &str = "bench.localhost";
+const PUBKEY: &str = "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+const TAGS: &[&str] = &["nostr", "rust", "travel", "music", "photography"];
+
+/// What to benchmark, as given to `servus bench`.
+pub struct BenchOptions {
+    pub posts: usize,
+    pub rounds: usize,
+    pub uploads: usize,
+    pub upload_size: usize, // in bytes
+    pub theme: Option<String>,
+    /// The config of an existing site, whose theme and settings are used for the synthetic site.
+    pub config_path: Option<String>,
+}
+
+/// Where the synthetic site is created, so that it doesn't end up among the actual sites.
+pub fn get_sites_dir() -> String {
+    env::temp_dir()
+        .join(format!("servus-bench-{}", process::id()))
+        .display()
+        .to_string()
+}
+
+/// How long something took, over a number of runs.
+struct Timings(Vec<Duration>);
+
+impl Timings {
+    fn measure<T>(&mut self, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = f();
+        self.0.push(start.elapsed());
+        result
+    }
+
+    fn total(&self) -> Duration {
+        self.0.iter().sum()
+    }
+
+    fn per_second(&self) -> f64 {
+        self.0.len() as f64 / self.total().as_secs_f64()
+    }
+
+    fn report(&self) -> String {
+        format!(
+            "{:.1}/s, p50 {}, p99 {}, max {}",
+            self.per_second(),
+            format_duration(percentile(&self.0, 50)),
+            format_duration(percentile(&self.0, 99)),
+            format_duration(percentile(&self.0, 100)),
+        )
+    }
+}
+
+/// The duration `p` percent of the `durations` don't exceed.
+fn percentile(durations: &[Duration], p: usize) -> Duration {
+    let mut durations = durations.to_vec();
+    durations.sort();
+    let rank = (durations.len() * p).div_ceil(100).max(1);
+
+    durations.get(rank - 1).copied().unwrap_or_default()
+}
+
+fn format_duration(duration: Duration) -> String {
+    format!("{:.2} ms", duration.as_secs_f64() * 1000.0)
+}
+
+/// A long-form post, as if published by the site's author, linking to the previous one.
+fn get_synthetic_post(i: usize, created_at: i64) -> nostr::Event {
+    let d_tag = format!("post-{}", i);
+    let content = format!(
+        "Some *text* introducing post {0}, with a [link](/post-{1}) to the previous one.\n\n\
+         ## A section\n\n{2}\n\n- a list\n- of items\n\n```\nsome code\n```\n\n{2}\n",
+        i,
+        i.saturating_sub(1),
+        "Lorem ipsum dolor sit amet, consectetur adipiscing elit, sed do eiusmod tempor incididunt \
+         ut labore et dolore magna aliqua. Ut enim ad minim veniam, quis nostrud exercitation."
+    );
+
+    nostr::Event {
+        id: utils::sha256_hex(d_tag.as_bytes()),
+        pubkey: PUBKEY.to_owned(),
+        created_at,
+        kind: nostr::EVENT_KIND_LONG_FORM,
+        tags: vec![
+            vec!["d".to_owned(), d_tag],
+            vec!["title".to_owned(), format!("Post {}", i)],
+            vec!["published_at".to_owned(), created_at.to_string()],
+            vec!["t".to_owned(), TAGS[i % TAGS.len()].to_owned()],
+        ],
+        content,
+        sig: "0".repeat(128), // never checked once stored
+    }
+}
+
+/// Creates a site with `options.posts` posts, using the config of an existing site if given.
+fn create_site(options: &BenchOptions, themes: &HashMap<String, Theme>) -> Result<(), String> {
+    let site_path = format!("{}/{}", get_sites_dir(), DOMAIN);
+    let mut config = match &options.config_path {
+        Some(config_path) => fs::read_to_string(config_path)
+            .map_err(|e| format!("Cannot read {}: {}", config_path, e))?
+            .parse::<toml::Table>()
+            .map_err(|e| format!("Invalid {}: {}", config_path, e))?,
+        None => toml::Table::from_iter([("title".to_owned(), "Bench".into())]),
+    };
+    config.insert("pubkey".to_owned(), PUBKEY.into());
+    config.insert("base_url".to_owned(), format!("http://{}", DOMAIN).into());
+    if let Some(theme) = &options.theme {
+        config.insert("theme".to_owned(), theme.to_owned().into());
+    }
+    let theme = config
+        .entry("theme")
+        .or_insert("hyde".into())
+        .as_str()
+        .unwrap_or_default();
+    if !themes.contains_key(theme) {
+        return Err(format!("Unknown theme: {}", theme));
+    }
+
+    fs::create_dir_all(&site_path).map_err(|e| e.to_string())?;
+    fs::write(format!("{}/_config.toml", site_path), config.to_string())
+        .map_err(|e| e.to_string())?;
+    let now = Utc::now().timestamp();
+    for i in 0..options.posts {
+        let post = get_synthetic_post(i, now - 3600 * i as i64);
+        post.write(&format!("{}/_content/posts/post-{}.md", site_path, i))
+            .map_err(|e| e.to_string())?;
+    }
+
+    Ok(())
+}
+
+/// Renders all the resources of the site, `rounds` times.
+fn bench_render(site: &Site, language: &str, rounds: usize) -> Timings {
+    let content = site.get_content();
+    let mut resources = content.resources.values().cloned().collect::<Vec<_>>();
+    if !content.resources.contains_key("/index") {
+        resources.push(Resource::default_index());
+    }
+    let mut timings = Timings(vec![]);
+    for _ in 0..rounds {
+        for resource in &resources {
+            // a page failing to render is timed all the same
+            let _ = timings.measure(|| resource.render(site, language, 1));
+        }
+    }
+
+    timings
+}
+
+/// Runs REQ queries, such as clients send to the relay, `rounds` times each.
+fn bench_queries(site: &Site, posts: usize, rounds: usize) -> Vec<(&'static str, Timings)> {
+    let some_id = utils::sha256_hex(format!("post-{}", posts / 2).as_bytes());
+    let queries = [
+        ("latest posts", json!({"kinds": [30023], "limit": 20})),
+        ("post by id", json!({"ids": [some_id]})),
+        (
+            "posts by tag",
+            json!({"kinds": [30023], "#t": [TAGS[0]], "limit": 20}),
+        ),
+        ("all events", json!({"authors": [PUBKEY]})),
+    ];
+
+    queries
+        .into_iter()
+        .map(|(name, filter)| {
+            let filter: nostr::Filter = serde_json::from_value(filter).unwrap();
+            let mut timings = Timings(vec![]);
+            for _ in 0..rounds {
+                timings.measure(|| site.query_events(&filter));
+            }
+            (name, timings)
+        })
+        .collect()
+}
+
+/// Uploads files of `size` bytes, as the Blossom and NIP-96 APIs do.
+async fn bench_uploads(site: &Site, count: usize, size: usize) -> Result<Timings, String> {
+    let site_path = format!("{}/{}", get_sites_dir(), site.domain);
+    let png = "image/png".parse().unwrap();
+    let mut content = b"\x89PNG\r\n\x1a\n".to_vec();
+    content.resize(size.max(16), 0);
+    let mut timings = Timings(vec![]);
+    for i in 0..count {
+        // different content for each file, so that they don't end up with the same hash
+        content[8..16].copy_from_slice(&(i as u64).to_le_bytes());
+
+        let start = Instant::now();
+        let mut upload = Upload::new(&site_path).await.map_err(|e| e.to_string())?;
+        for chunk in content.chunks(64 * 1024) {
+            upload.write(chunk).await.map_err(|e| e.to_string())?;
+        }
+        upload
+            .save(&format!("http://{}", site.domain), &png)
+            .await
+            .map_err(|e| e.to_string())?;
+        timings.0.push(start.elapsed());
+    }
+
+    Ok(timings)
+}
+
+async fn run_benchmarks(
+    options: &BenchOptions,
+    themes: &HashMap<String, Theme>,
+    language: &str,
+) -> Result<(), String> {
+    create_site(options, themes)?;
+
+    let start = Instant::now();
+    let site = site::load_site(DOMAIN, themes)?;
+    let load_time = start.elapsed();
+
+    let mut uncached_site = site.clone();
+    uncached_site.config.render_cache_ttl = Some(0);
+    // the first render also prepares what the templates get, which is then shared by the other renders
+    let start = Instant::now();
+    Resource::default_index()
+        .render(&uncached_site, language, 1)
+        .map_err(|e| e.to_string())?;
+    let first_render_time = start.elapsed();
+    let uncached = bench_render(&uncached_site, language, options.rounds);
+    bench_render(&site, language, 1); // fills the cache
+    let cached = bench_render(&site, language, options.rounds);
+    let queries = bench_queries(&site, options.posts, options.rounds);
+    let uploads = bench_uploads(&site, options.uploads, options.upload_size).await?;
+
+    println!();
+    println!(
+        "Site: {} posts, theme {}, loaded in {}",
+        options.posts,
+        site.config.theme,
+        format_duration(load_time)
+    );
+    println!(
+        "Render (first page): {}",
+        format_duration(first_render_time)
+    );
+    println!("Render (uncached): {}", uncached.report());
+    println!("Render (cached): {}", cached.report());
+    for (name, timings) in queries {
+        println!("REQ ({}): {}", name, timings.report());
+    }
+    if options.uploads > 0 {
+        println!(
+            "Upload ({} KiB): {}, {:.1} MiB/s",
+            options.upload_size / 1024,
+            uploads.report(),
+            (options.uploads * options.upload_size) as f64
+                / uploads.total().as_secs_f64()
+                / (1024.0 * 1024.0)
+        );
+    }
+
+    Ok(())
+}
+
+/// Creates a synthetic site, measures how fast it is rendered, queried and uploaded to, then prints a report.
+/// The site is deleted afterwards.
+pub async fn run(
+    options: &BenchOptions,
+    themes: &HashMap<String, Theme>,
+    language: &str,
+) -> Result<(), String> {
+    let result = run_benchmarks(options, themes, language).await;
+    let _ = fs::remove_dir_all(get_sites_dir());
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percentile() {
+        let durations = (1..=200)
+            .rev()
+            .map(Duration::from_millis)
+            .collect::<Vec<_>>();
+        assert_eq!(percentile(&durations, 50), Duration::from_millis(100));
+        assert_eq!(percentile(&durations, 99), Duration::from_millis(198));
+        assert_eq!(percentile(&durations, 100), Duration::from_millis(200));
+        assert_eq!(percentile(&[], 50), Duration::ZERO);
+    }
+}
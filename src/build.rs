@@ -0,0 +1,325 @@
+use std::{
+    collections::HashSet,
+    fs,
+    io::{Cursor, Write},
+    path::{Component, Path, PathBuf},
+};
+use tide::log;
+use walkdir::WalkDir;
+use zip::{write::SimpleFileOptions, ZipWriter};
+
+use crate::{
+    encode_html,
+    error::Error,
+    paths,
+    resource::{self, Resource},
+    site::Site,
+    theme::Theme,
+};
+
+/// The standard resources, which are rendered rather than coming from the content.
+const STANDARD_RESOURCES: &[&str] = &[
+    "robots.txt",
+    ".well-known/nostr.json",
+    ".well-known/security.txt",
+    ".well-known/host-meta",
+    "sitemap.xml",
+    "atom.xml",
+    "search_index.json",
+];
+
+/// `url` as a path relative to the output directory, unless it tries to get out of it.
+fn get_relative_path(url: &str) -> Option<&Path> {
+    let path = Path::new(url.trim_matches('/'));
+    path.components()
+        .all(|c| matches!(c, Component::Normal(_)))
+        .then_some(path)
+}
+
+/// Where the page served at `url` goes, so that a static host serves it at the same URL:
+/// pages become `index.html` files in their own directory, unless the URL already names a file.
+fn get_page_path(url: &str) -> Option<PathBuf> {
+    let url = url.trim_matches('/');
+    let url = if url == "index" {
+        ""
+    } else {
+        url.strip_suffix("/index").unwrap_or(url)
+    };
+    let path = get_relative_path(url)?;
+    if path.extension().is_some() {
+        Some(path.to_owned())
+    } else {
+        Some(path.join("index.html"))
+    }
+}
+
+/// Where the site is built to.
+enum Target<'a> {
+    Dir(&'a Path),
+    Zip(&'a mut ZipWriter<Cursor<Vec<u8>>>),
+}
+
+/// Where the site is built to, keeping count of the files written
+/// and, if only the uploaded files that are linked to are kept, of the hashes found in them.
+struct Output<'a> {
+    target: Target<'a>,
+    count: usize,
+    hashes: Option<HashSet<String>>,
+}
+
+/// The SHA-256 hashes (as hex) in `text`, such as those of the uploaded files it links to.
+fn find_hashes(text: &str) -> impl Iterator<Item = &str> {
+    text.split(|c: char| !c.is_ascii_hexdigit())
+        .filter(|w| w.len() == 64)
+}
+
+impl Output<'_> {
+    fn write(&mut self, path: Option<PathBuf>, url: &str, content: &[u8]) -> Result<(), String> {
+        let Some(path) = path else {
+            log::warn!("Skipping {}!", url);
+            return Ok(());
+        };
+        if let (Some(hashes), Ok(text)) = (&mut self.hashes, std::str::from_utf8(content)) {
+            hashes.extend(find_hashes(text).map(|h| h.to_lowercase()));
+        }
+        match &mut self.target {
+            Target::Dir(out) => {
+                let path = out.join(path);
+                if let Some(dir) = path.parent() {
+                    fs::create_dir_all(dir)
+                        .map_err(|e| format!("Cannot create {}: {}", dir.display(), e))?;
+                }
+                fs::write(&path, content)
+                    .map_err(|e| format!("Cannot write {}: {}", path.display(), e))?;
+            }
+            Target::Zip(zip) => {
+                let name = path
+                    .components()
+                    .map(|c| c.as_os_str().to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join("/");
+                zip.start_file(name, SimpleFileOptions::default())
+                    .map_err(|e| e.to_string())?;
+                zip.write_all(content).map_err(|e| e.to_string())?;
+            }
+        }
+        self.count += 1;
+
+        Ok(())
+    }
+
+    /// Writes a page, which is served at `url`.
+    fn page(&mut self, url: &str, content: &[u8]) -> Result<(), String> {
+        self.write(get_page_path(url), url, content)
+    }
+
+    /// Writes a file, which is served at `url` as is.
+    fn file(&mut self, url: &str, content: &[u8]) -> Result<(), String> {
+        self.write(get_relative_path(url).map(|p| p.to_owned()), url, content)
+    }
+}
+
+/// A page sending browsers to `location`, since static hosts can't be told to redirect.
+fn render_redirect(location: &str) -> String {
+    let location = tera::escape_html(location);
+    format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><link rel=\"canonical\" href=\"{0}\"><meta http-equiv=\"refresh\" content=\"0; url={0}\"></head><body><a href=\"{0}\">{0}</a></body></html>\n",
+        location
+    )
+}
+
+/// Writes everything `site` serves, other than what is handled by the relay and the APIs, to `out`:
+/// the rendered resources, the standard resources (feed, sitemap, ...), the compiled sass,
+/// the static files of the theme and of the site, the uploaded files and the redirects.
+/// Returns the number of files written.
+pub fn build_site(
+    site: &Site,
+    theme: &Theme,
+    out: &Path,
+    language: &str,
+    charset: &str,
+) -> Result<usize, String> {
+    let mut output = Output {
+        target: Target::Dir(out),
+        count: 0,
+        hashes: None,
+    };
+    write_site(site, theme, language, charset, &mut output)?;
+
+    Ok(output.count)
+}
+
+/// Packs the site as a zip, for its owner to keep a copy of it: the same files as [`build_site`] writes,
+/// but with only the uploaded files the site links to, rather than all of them.
+pub fn export_site(
+    site: &Site,
+    theme: &Theme,
+    language: &str,
+    charset: &str,
+) -> Result<Vec<u8>, String> {
+    let mut zip = ZipWriter::new(Cursor::new(vec![]));
+    let mut output = Output {
+        target: Target::Zip(&mut zip),
+        count: 0,
+        hashes: Some(HashSet::new()),
+    };
+    write_site(site, theme, language, charset, &mut output)?;
+
+    zip.finish()
+        .map(|c| c.into_inner())
+        .map_err(|e| e.to_string())
+}
+
+fn write_site(
+    site: &Site,
+    theme: &Theme,
+    language: &str,
+    charset: &str,
+    output: &mut Output,
+) -> Result<(), String> {
+    let language = site.config.default_language.as_deref().unwrap_or(language);
+    let charset = site.config.charset.as_deref().unwrap_or(charset);
+    let site_content = site.get_content();
+
+    let mut resources = site_content.resources.iter().collect::<Vec<_>>();
+    let index_url = "/index".to_string();
+    // the same placeholder as when serving the site
+    let default_index = Resource::default_index();
+    if !site_content.resources.contains_key("/index") {
+        resources.push((&index_url, &default_index));
+    }
+    for (url, resource) in resources {
+        log::info!("Rendering {}...", url);
+        let html = resource
+            .render(site, language, 1)
+            .map_err(|e| e.to_string())?;
+        output.page(url, &encode_html(&html, charset).0)?;
+        if resource.slug == "index" {
+            // the other pagers, when the index is paginated
+            for pager in 2.. {
+                let html = match resource.render(site, language, pager) {
+                    Ok(html) => html,
+                    Err(Error::NotFound) => break,
+                    Err(e) => return Err(e.to_string()),
+                };
+                let pager_url = resource::get_pager_url(pager);
+                output.page(&pager_url, &encode_html(&html, charset).0)?;
+            }
+        }
+    }
+
+    for name in STANDARD_RESOURCES {
+        if let Some((_, content)) = resource::render_standard_resource(name, site) {
+            output.file(name, content.as_bytes())?;
+        }
+    }
+
+    for (url, content) in site.sass_resources.read().unwrap().iter() {
+        output.file(url, content.as_bytes())?;
+    }
+    for (url, content) in theme.resources.read().unwrap().iter() {
+        output.file(url, content.as_bytes())?;
+    }
+
+    let site_path = format!("{}/{}", paths::sites(), site.domain);
+    // like when serving the site, files and directories starting with `_` or `.` are not public
+    let is_public = |e: &walkdir::DirEntry| {
+        e.depth() == 0 || !e.file_name().to_string_lossy().starts_with(['_', '.'])
+    };
+    for entry in WalkDir::new(&site_path).into_iter().filter_entry(is_public) {
+        let entry = entry.map_err(|e| e.to_string())?;
+        if entry.file_type().is_file() {
+            let url = entry.path().strip_prefix(&site_path).unwrap();
+            let content = fs::read(entry.path()).map_err(|e| e.to_string())?;
+            output.file(&url.to_string_lossy(), &content)?;
+        }
+    }
+
+    let well_known_path = format!("{}/{}", site_path, resource::WELL_KNOWN_DIR);
+    for entry in WalkDir::new(&well_known_path).min_depth(1) {
+        let Ok(entry) = entry else { break };
+        if entry.file_type().is_file() {
+            let name = entry.path().strip_prefix(&well_known_path).unwrap();
+            let content = fs::read(entry.path()).map_err(|e| e.to_string())?;
+            output.file(&format!(".well-known/{}", name.to_string_lossy()), &content)?;
+        }
+    }
+
+    // uploaded files are served by hash, with or without an extension
+    let files_path = format!("{}/_content/files", site_path);
+    for entry in fs::read_dir(&files_path).into_iter().flatten().flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.len() != 64 || !name.chars().all(|c| c.is_ascii_hexdigit()) {
+            continue;
+        }
+        if output.hashes.as_ref().is_some_and(|h| !h.contains(&name)) {
+            continue;
+        }
+        let content = fs::read(entry.path()).map_err(|e| e.to_string())?;
+        output.file(&name, &content)?;
+        let extension = fs::read_to_string(format!("{}/{}.metadata.json", files_path, name))
+            .ok()
+            .and_then(|m| serde_json::from_str::<serde_json::Value>(&m).ok())
+            .and_then(|m| {
+                mime_guess::get_mime_extensions_str(m["type"].as_str()?)?
+                    .first()
+                    .map(|e| e.to_string())
+            });
+        if let Some(extension) = extension {
+            output.file(&format!("{}.{}", name, extension), &content)?;
+        }
+    }
+
+    let mut redirects = String::new();
+    for (from, to) in &site_content.redirects {
+        let location = if to.contains("://") {
+            to.to_owned()
+        } else if let Some(resource) = site_content.resources.get(to) {
+            resource.get_permalink(&site.config)
+        } else {
+            site.config.make_permalink(to)
+        };
+        redirects.push_str(&format!("{} {} 301\n", from, location));
+        output.page(from, render_redirect(&location).as_bytes())?;
+    }
+    if !redirects.is_empty() {
+        // understood by Netlify and Cloudflare Pages, which then redirect properly
+        output.file("_redirects", redirects.as_bytes())?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_page_path() {
+        let path = |url| get_page_path(url).map(|p| p.display().to_string());
+        assert_eq!(path("/index").unwrap(), "index.html");
+        assert_eq!(path("/posts/hello").unwrap(), "posts/hello/index.html");
+        assert_eq!(
+            path("/blog/2024/hello/").unwrap(),
+            "blog/2024/hello/index.html"
+        );
+        assert_eq!(path("/docs/index").unwrap(), "docs/index.html");
+        assert_eq!(path("/posts/reindex").unwrap(), "posts/reindex/index.html");
+        assert_eq!(path("/hello.html").unwrap(), "hello.html");
+        assert!(path("/../etc/passwd").is_none());
+        assert!(path("/posts/../../hello").is_none());
+
+        assert_eq!(get_relative_path("/CNAME").unwrap(), Path::new("CNAME"));
+        assert!(get_relative_path("../CNAME").is_none());
+    }
+
+    #[test]
+    fn test_find_hashes() {
+        let hash = "b1674191a88ec5cdd733e4240a81803105dc412d6c6708d53ab94fc248f4f553";
+        let html = format!(
+            "<img src=\"/{0}.png\"><a href=\"https://example.com/{0}\">{1}</a>",
+            hash, "not a hash: abcdef"
+        );
+        assert_eq!(find_hashes(&html).collect::<Vec<_>>(), vec![hash, hash]);
+    }
+}
@@ -1,45 +1,47 @@
 use serde_yaml::Value as YamlValue;
 use std::{collections::HashMap, io::BufRead};
 
-pub fn read(reader: &mut dyn BufRead) -> Option<(HashMap<String, YamlValue>, String)> {
+/// Reads a file made of YAML front matter (between `---` lines) followed by the content.
+pub fn read(reader: &mut dyn BufRead) -> Result<(HashMap<String, YamlValue>, String), String> {
+    let mut read_line = |line: &mut String| {
+        line.clear();
+        reader.read_line(line).map_err(|e| e.to_string())
+    };
+
     let mut line = String::new();
     loop {
-        line.clear();
-        let bytes = reader.read_line(&mut line).unwrap();
-        if bytes == 0 {
-            return None;
+        if read_line(&mut line)? == 0 {
+            return Err("Missing front matter".to_string());
         }
         if !line.trim_end_matches('\n').is_empty() {
             break;
         }
     }
     if line.trim_end_matches('\n') != "---" {
-        return None;
+        return Err("Missing front matter, which should start with a `---` line".to_string());
     }
     let mut yaml_front_matter = String::new();
     loop {
-        line.clear();
-        reader.read_line(&mut line).unwrap();
+        if read_line(&mut line)? == 0 {
+            return Err(
+                "Unterminated front matter, which should end with a `---` line".to_string(),
+            );
+        }
         if line.trim_end_matches('\n') == "---" {
             break;
         }
         yaml_front_matter.push_str(&line);
     }
 
-    let front_matter: HashMap<String, YamlValue> =
-        serde_yaml::from_str(&yaml_front_matter).unwrap();
+    let front_matter: HashMap<String, YamlValue> = serde_yaml::from_str(&yaml_front_matter)
+        .map_err(|e| format!("Invalid front matter: {}", e))?;
 
     let mut content = String::new();
-    loop {
-        line.clear();
-        let bytes = reader.read_line(&mut line).unwrap();
-        if bytes == 0 {
-            break;
-        }
+    while read_line(&mut line)? != 0 {
         content.push_str(&line);
     }
 
-    Some((front_matter, content))
+    Ok((front_matter, content))
 }
 
 #[cfg(test)]
@@ -145,4 +147,22 @@ Text"#;
         );
         assert_eq!(content, "Text");
     }
+
+    #[test]
+    fn test_read_errors() {
+        let read = |content: &str| read(&mut BufReader::new(content.as_bytes()));
+        assert_eq!(read("").unwrap_err(), "Missing front matter");
+        assert!(read("# title\n")
+            .unwrap_err()
+            .starts_with("Missing front matter"));
+        assert!(read("---\ntitle: x\n")
+            .unwrap_err()
+            .starts_with("Unterminated front matter"));
+        assert!(read("---\ntitle: [x\n---\n")
+            .unwrap_err()
+            .starts_with("Invalid front matter: "));
+        assert!(read("---\n- a list\n---\n")
+            .unwrap_err()
+            .starts_with("Invalid front matter: "));
+    }
 }
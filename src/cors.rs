@@ -0,0 +1,124 @@
+use serde::{Deserialize, Serialize};
+
+/// Which pages, on other sites, can use the APIs (or fetch the content) of a site from the browser:
+/// the `[cors]` of `servus.toml`, for all the sites, or the one of a site's `_config.toml`, replacing it for that site.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct CorsConfig {
+    /// Such as `https://example.com`, or `*` for any origin.
+    #[serde(default = "default_allowed_origins")]
+    pub allowed_origins: Vec<String>,
+    #[serde(default = "default_allowed_methods")]
+    pub allowed_methods: Vec<String>,
+    /// Headers the requests can have, or `*` for any (including `Authorization`).
+    #[serde(default = "default_allowed_headers")]
+    pub allowed_headers: Vec<String>,
+    /// How long browsers can reuse the answer to a preflight request, in seconds.
+    #[serde(default = "default_max_age")]
+    pub max_age: u64,
+}
+
+fn default_allowed_origins() -> Vec<String> {
+    vec!["*".to_string()]
+}
+
+fn default_allowed_methods() -> Vec<String> {
+    ["GET", "HEAD", "POST", "PUT", "DELETE"]
+        .iter()
+        .map(|m| m.to_string())
+        .collect()
+}
+
+fn default_allowed_headers() -> Vec<String> {
+    vec!["*".to_string()]
+}
+
+fn default_max_age() -> u64 {
+    86400
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        CorsConfig {
+            allowed_origins: default_allowed_origins(),
+            allowed_methods: default_allowed_methods(),
+            allowed_headers: default_allowed_headers(),
+            max_age: default_max_age(),
+        }
+    }
+}
+
+impl CorsConfig {
+    /// The `Access-Control-Allow-Origin` of the response to a request made from `origin`, unless that origin is not allowed.
+    /// Requests without an `Origin` (not made by a browser, or made from the site itself) only get one if any origin is allowed.
+    pub fn get_allowed_origin(&self, origin: Option<&str>) -> Option<String> {
+        if self.allowed_origins.iter().any(|o| o == "*") {
+            return Some("*".to_string());
+        }
+        let origin = origin?;
+        self.allowed_origins
+            .iter()
+            .any(|o| o.trim_end_matches('/').eq_ignore_ascii_case(origin))
+            .then(|| origin.to_owned())
+    }
+
+    pub fn get_allowed_methods(&self) -> String {
+        self.allowed_methods.join(",")
+    }
+
+    /// The `Access-Control-Allow-Headers` of the response to a preflight request asking for `requested_headers`.
+    pub fn get_allowed_headers(&self, requested_headers: Option<&str>) -> String {
+        if self.allowed_headers.iter().any(|h| h == "*") {
+            // browsers don't take `*` as covering `Authorization`, which most of the APIs require
+            requested_headers.unwrap_or("*").to_owned()
+        } else {
+            self.allowed_headers.join(",")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allowed_origin() {
+        let any = CorsConfig::default();
+        assert_eq!(any.get_allowed_origin(None).as_deref(), Some("*"));
+        assert_eq!(
+            any.get_allowed_origin(Some("https://example.org"))
+                .as_deref(),
+            Some("*")
+        );
+
+        let config: CorsConfig =
+            toml::from_str("allowed_origins = [\"https://example.org/\"]").unwrap();
+        assert_eq!(config.allowed_methods, default_allowed_methods());
+        assert_eq!(
+            config
+                .get_allowed_origin(Some("https://example.org"))
+                .as_deref(),
+            Some("https://example.org")
+        );
+        assert!(config
+            .get_allowed_origin(Some("https://example.com"))
+            .is_none());
+        assert!(config.get_allowed_origin(None).is_none());
+    }
+
+    #[test]
+    fn test_allowed_headers() {
+        let any = CorsConfig::default();
+        assert_eq!(
+            any.get_allowed_headers(Some("authorization,content-type")),
+            "authorization,content-type"
+        );
+        assert_eq!(any.get_allowed_headers(None), "*");
+
+        let config: CorsConfig =
+            toml::from_str("allowed_headers = [\"Authorization\", \"Content-Type\"]").unwrap();
+        assert_eq!(
+            config.get_allowed_headers(Some("x-custom")),
+            "Authorization,Content-Type"
+        );
+    }
+}
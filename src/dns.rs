@@ -0,0 +1,164 @@
+use async_std::{future, net::UdpSocket};
+use std::{fs, io, process, time::Duration};
+
+/// How long to wait for the name server to answer.
+const DNS_TIMEOUT: Duration = Duration::from_secs(5);
+
+const TYPE_TXT: u16 = 16;
+const CLASS_IN: u16 = 1;
+
+fn invalid_data(error: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+/// The first name server listed in `/etc/resolv.conf`, which is also the one the system uses.
+fn get_name_server() -> io::Result<String> {
+    let resolv_conf = fs::read_to_string("/etc/resolv.conf")?;
+    let name_server = resolv_conf
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        .map(|server| server.trim())
+        .find(|server| !server.is_empty())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No name server configured"))?;
+
+    if name_server.contains(':') {
+        Ok(format!("[{}]:53", name_server))
+    } else {
+        Ok(format!("{}:53", name_server))
+    }
+}
+
+/// A query for the TXT records of `name`, as sent over UDP (see RFC 1035).
+fn build_txt_query(id: u16, name: &str) -> io::Result<Vec<u8>> {
+    let mut query = vec![];
+    query.extend_from_slice(&id.to_be_bytes());
+    query.extend_from_slice(&[0x01, 0x00]); // recursion desired
+    query.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]); // one question
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid name"));
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&TYPE_TXT.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+
+    Ok(query)
+}
+
+/// Skips the (possibly compressed) name starting at `offset`, returning where it ends.
+fn skip_name(message: &[u8], mut offset: usize) -> io::Result<usize> {
+    loop {
+        let length = *message
+            .get(offset)
+            .ok_or_else(|| invalid_data("Truncated"))? as usize;
+        match length {
+            0 => return Ok(offset + 1),
+            l if l & 0xc0 == 0xc0 => return Ok(offset + 2), // a pointer ends the name
+            l => offset += 1 + l,
+        }
+    }
+}
+
+fn read_u16(message: &[u8], offset: usize) -> io::Result<u16> {
+    match message.get(offset..offset + 2) {
+        Some(bytes) => Ok(u16::from_be_bytes([bytes[0], bytes[1]])),
+        None => Err(invalid_data("Truncated")),
+    }
+}
+
+/// The TXT records in the answer to the query with `id`, each being the concatenation of its strings.
+fn parse_txt_response(id: u16, message: &[u8]) -> io::Result<Vec<String>> {
+    if read_u16(message, 0)? != id {
+        return Err(invalid_data("Unexpected answer"));
+    }
+    match read_u16(message, 2)? & 0x0f {
+        0 => {}
+        3 => return Ok(vec![]), // no such name
+        _ => return Err(invalid_data("Name server failure")),
+    }
+    let questions = read_u16(message, 4)?;
+    let answers = read_u16(message, 6)?;
+
+    let mut offset = 12;
+    for _ in 0..questions {
+        offset = skip_name(message, offset)? + 4;
+    }
+    let mut records = vec![];
+    for _ in 0..answers {
+        offset = skip_name(message, offset)?;
+        let record_type = read_u16(message, offset)?;
+        let length = read_u16(message, offset + 8)? as usize;
+        offset += 10;
+        let data = message
+            .get(offset..offset + length)
+            .ok_or_else(|| invalid_data("Truncated"))?;
+        offset += length;
+        if record_type != TYPE_TXT {
+            continue; // such as the CNAME the name is an alias of
+        }
+
+        let mut text = vec![];
+        let mut i = 0;
+        while i < data.len() {
+            let length = data[i] as usize;
+            text.extend_from_slice(data.get(i + 1..i + 1 + length).unwrap_or_default());
+            i += 1 + length;
+        }
+        records.push(String::from_utf8_lossy(&text).to_string());
+    }
+
+    Ok(records)
+}
+
+/// Asks the system's name server for the TXT records of `name`.
+pub async fn lookup_txt(name: &str) -> io::Result<Vec<String>> {
+    let id = (process::id() as u16) ^ (chrono::Utc::now().timestamp_subsec_nanos() as u16);
+    let query = build_txt_query(id, name)?;
+    let name_server = get_name_server()?;
+
+    let socket = UdpSocket::bind(if name_server.starts_with('[') {
+        "[::]:0"
+    } else {
+        "0.0.0.0:0"
+    })
+    .await?;
+    socket.connect(&name_server).await?;
+    socket.send(&query).await?;
+    let mut buffer = vec![0; 4096];
+    let length = future::timeout(DNS_TIMEOUT, socket.recv(&mut buffer))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "No answer from the name server"))??;
+
+    parse_txt_response(id, &buffer[..length])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_txt_response() {
+        let query = build_txt_query(42, "_servus-challenge.example.com").unwrap();
+        assert_eq!(query.len(), 12 + 31 + 4);
+
+        let mut response = query.clone();
+        response[2] = 0x81;
+        response[3] = 0x80;
+        response[7] = 2; // two answers
+                         // a CNAME, pointing to the name of the question...
+        response.extend_from_slice(&[0xc0, 12, 0, 5, 0, 1, 0, 0, 0, 60, 0, 2, 0xc0, 12]);
+        // ... and a TXT record made of two strings
+        response.extend_from_slice(&[0xc0, 12, 0, 16, 0, 1, 0, 0, 0, 60, 0, 9]);
+        response.extend_from_slice(b"\x03abc\x04defg");
+        assert_eq!(parse_txt_response(42, &response).unwrap(), vec!["abcdefg"]);
+
+        assert!(parse_txt_response(43, &response).is_err());
+        assert!(parse_txt_response(42, &response[..response.len() - 3]).is_err());
+        response[3] = 0x83; // no such name
+        assert!(parse_txt_response(42, &response).unwrap().is_empty());
+        assert!(build_txt_query(42, "example..com").is_err());
+    }
+}
@@ -0,0 +1,85 @@
+use std::fmt;
+use tide::{log, Response, StatusCode};
+
+/// Why a request could not be served, which decides the status of the response.
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// The request is malformed, such as a missing header or a body that cannot be parsed.
+    BadRequest(String),
+    NotFound,
+    /// Something is wrong on our side, such as a file that cannot be read or a broken template:
+    /// what we were doing, and why it failed.
+    Internal(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Error::BadRequest(_) => StatusCode::BadRequest,
+            Error::NotFound => StatusCode::NotFound,
+            Error::Internal(_) => StatusCode::InternalServerError,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::BadRequest(message) => write!(f, "Bad request: {}", message),
+            Error::NotFound => write!(f, "Not found"),
+            Error::Internal(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Adds what we were doing to an error, making it an [`Error::Internal`].
+pub trait Context<T> {
+    fn context<C: fmt::Display>(self, context: impl FnOnce() -> C) -> Result<T>;
+}
+
+impl<T, E: fmt::Display> Context<T> for std::result::Result<T, E> {
+    fn context<C: fmt::Display>(self, context: impl FnOnce() -> C) -> Result<T> {
+        self.map_err(|e| Error::Internal(format!("{}: {}", context(), e)))
+    }
+}
+
+/// The response to a request that failed, logging the failures on our side.
+/// The details of those are not sent, as they are of no use to the client.
+impl From<Error> for Response {
+    fn from(e: Error) -> Response {
+        match &e {
+            Error::Internal(message) => log::error!("{}", message),
+            _ => log::debug!("{}", e),
+        }
+        let mut response = Response::new(e.status());
+        if let Error::BadRequest(message) = e {
+            response.set_body(message);
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    #[test]
+    fn test_context() {
+        let result: io::Result<()> = Err(io::Error::new(io::ErrorKind::NotFound, "gone"));
+        let e = result.context(|| "Cannot read /tmp/x").unwrap_err();
+        assert_eq!(e, Error::Internal("Cannot read /tmp/x: gone".to_string()));
+        assert_eq!(e.status(), StatusCode::InternalServerError);
+
+        let response = Response::from(Error::BadRequest("Missing boundary.".to_string()));
+        assert_eq!(response.status(), StatusCode::BadRequest);
+        assert_eq!(
+            Response::from(Error::NotFound).status(),
+            StatusCode::NotFound
+        );
+    }
+}
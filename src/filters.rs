@@ -0,0 +1,264 @@
+// * Code taken from [Zola](https://www.getzola.org/) and adapted.
+// * Zola's MIT license applies. See: https://github.com/getzola/zola/blob/master/LICENSE
+
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use chrono_tz::Tz;
+use std::collections::HashMap;
+use tera::{from_value, to_value, Filter as TeraFilter, Result as TeraResult, Value as TeraValue};
+
+use crate::resource;
+
+// https://github.com/getzola/zola/blob/master/components/templates/src/filters.rs
+
+pub struct MarkdownFilter;
+
+impl TeraFilter for MarkdownFilter {
+    fn filter(
+        &self,
+        value: &TeraValue,
+        args: &HashMap<String, TeraValue>,
+    ) -> TeraResult<TeraValue> {
+        let s = from_value::<String>(value.clone())
+            .map_err(|_| "Filter `markdown` was called on an incorrect value: got a non-string")?;
+        let inline = match args.get("inline") {
+            Some(val) => from_value::<bool>(val.clone())
+                .map_err(|_| "Filter `markdown`: `inline` must be a boolean (true or false)")?,
+            None => false,
+        };
+
+        let mut html = resource::md_to_html(&s);
+        if inline {
+            html = html
+                .trim_start_matches("<p>")
+                // pulldown_cmark finishes a paragraph with `</p>\n`
+                .trim_end_matches("</p>\n")
+                .to_string();
+        }
+
+        Ok(to_value(&html).unwrap())
+    }
+
+    fn is_safe(&self) -> bool {
+        true
+    }
+}
+
+/// Same as Tera's `date`, but dates are shown in the site's timezone unless a `timezone` is passed,
+/// and in the site's date format unless a `format` is passed.
+/// NB: dates without a timezone (as all the dates of the resources) are in UTC.
+pub struct DateFilter {
+    timezone: Option<Tz>,
+    format: String,
+}
+
+impl DateFilter {
+    pub fn new(timezone: Option<Tz>, format: &str) -> Self {
+        Self {
+            timezone,
+            format: format.to_owned(),
+        }
+    }
+}
+
+impl TeraFilter for DateFilter {
+    fn filter(
+        &self,
+        value: &TeraValue,
+        args: &HashMap<String, TeraValue>,
+    ) -> TeraResult<TeraValue> {
+        let format = match args.get("format") {
+            Some(val) => from_value::<String>(val.clone())
+                .map_err(|_| "Filter `date`: `format` must be a string")?,
+            None => self.format.to_owned(),
+        };
+        let timezone = match args.get("timezone") {
+            Some(val) => {
+                let timezone = from_value::<String>(val.clone())
+                    .map_err(|_| "Filter `date`: `timezone` must be a string")?;
+                Some(
+                    timezone
+                        .parse::<Tz>()
+                        .map_err(|_| format!("Filter `date`: unknown timezone: {}", timezone))?,
+                )
+            }
+            None => self.timezone,
+        };
+
+        let date = match value {
+            TeraValue::Number(n) => match n.as_i64().and_then(|t| DateTime::from_timestamp(t, 0)) {
+                Some(date) => date,
+                None => return Err(format!("Filter `date`: invalid timestamp: {}", n).into()),
+            },
+            TeraValue::String(s) => {
+                if let Ok(date) = DateTime::parse_from_rfc3339(s) {
+                    date.with_timezone(&Utc)
+                } else if let Ok(date) = s.parse::<NaiveDateTime>() {
+                    date.and_utc()
+                } else if let Ok(date) = s.parse::<NaiveDate>() {
+                    // just a date, nothing to convert
+                    return Ok(to_value(date.format(&format).to_string()).unwrap());
+                } else {
+                    return Err(format!("Filter `date`: cannot parse date: {}", s).into());
+                }
+            }
+            _ => {
+                return Err(format!(
+                    "Filter `date` received an incorrect type for arg `value`: got `{}` but expected i64|u64|String",
+                    value
+                )
+                .into())
+            }
+        };
+
+        let formatted = match timezone {
+            Some(timezone) => date.with_timezone(&timezone).format(&format).to_string(),
+            None => date.format(&format).to_string(),
+        };
+
+        Ok(to_value(formatted).unwrap())
+    }
+}
+
+// https://html.spec.whatwg.org/multipage/syntax.html#void-elements
+const VOID_ELEMENTS: [&str; 14] = [
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "param", "source",
+    "track", "wbr",
+];
+
+/// Truncates HTML to `length` characters of text (tags not included),
+/// closing the tags left open so the markup stays valid.
+fn truncate_html(html: &str, length: usize, end: &str) -> String {
+    let mut truncated = String::new();
+    let mut open_tags: Vec<String> = vec![];
+    let mut count = 0;
+    let mut chars = html.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '<' {
+            let mut tag = String::from(c);
+            for c in chars.by_ref() {
+                tag.push(c);
+                if c == '>' {
+                    break;
+                }
+            }
+            let name = tag
+                .trim_start_matches(['<', '/'])
+                .split(|c: char| c.is_whitespace() || c == '>' || c == '/')
+                .next()
+                .unwrap_or_default()
+                .to_lowercase();
+            if tag.starts_with("</") {
+                if let Some(i) = open_tags.iter().rposition(|t| *t == name) {
+                    open_tags.truncate(i);
+                }
+            } else if !tag.ends_with("/>")
+                && !tag.starts_with("<!")
+                && !VOID_ELEMENTS.contains(&name.as_str())
+            {
+                open_tags.push(name);
+            }
+            truncated.push_str(&tag);
+            continue;
+        }
+
+        if count == length {
+            truncated.push_str(end);
+            break;
+        }
+        truncated.push(c);
+        if c == '&' {
+            // an entity counts as a single character
+            while let Some(c) =
+                chars.next_if(|c| c.is_ascii_alphanumeric() || *c == '#' || *c == ';')
+            {
+                truncated.push(c);
+                if c == ';' {
+                    break;
+                }
+            }
+        }
+        count += 1;
+    }
+
+    for tag in open_tags.iter().rev() {
+        truncated.push_str(&format!("</{}>", tag));
+    }
+
+    truncated
+}
+
+pub struct TruncateHtmlFilter;
+
+impl TeraFilter for TruncateHtmlFilter {
+    fn filter(
+        &self,
+        value: &TeraValue,
+        args: &HashMap<String, TeraValue>,
+    ) -> TeraResult<TeraValue> {
+        let s = from_value::<String>(value.clone()).map_err(|_| {
+            "Filter `truncate_html` was called on an incorrect value: got a non-string"
+        })?;
+        let length = match args.get("length") {
+            Some(val) => from_value::<usize>(val.clone())
+                .map_err(|_| "Filter `truncate_html`: `length` must be a number")?,
+            None => 255,
+        };
+        let end = match args.get("end") {
+            Some(val) => from_value::<String>(val.clone())
+                .map_err(|_| "Filter `truncate_html`: `end` must be a string")?,
+            None => "…".to_string(),
+        };
+
+        Ok(to_value(truncate_html(&s, length, &end)).unwrap())
+    }
+
+    fn is_safe(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_truncate_html() {
+        assert_eq!(truncate_html("<p>Hello</p>", 10, "…"), "<p>Hello</p>");
+        assert_eq!(
+            truncate_html("<p>Hello <b>world</b>!</p>", 8, "…"),
+            "<p>Hello <b>wo…</b></p>"
+        );
+        assert_eq!(
+            truncate_html("<p>Zürich, 東京<br>and Ελλάδα</p>", 9, "…"),
+            "<p>Zürich, 東…</p>"
+        );
+        assert_eq!(
+            truncate_html("<p>Tom &amp; Jerry &#8212; friends</p>", 7, "…"),
+            "<p>Tom &amp; J…</p>"
+        );
+        assert_eq!(
+            truncate_html("<div><p>Unclosed <em>tags <img src=\"x.png\">here", 14, "…"),
+            "<div><p>Unclosed <em>tags <img src=\"x.png\">…</em></p></div>"
+        );
+    }
+
+    #[test]
+    fn test_date_filter() {
+        let filter = DateFilter::new(Some(chrono_tz::America::New_York), "%B %-d, %Y");
+        let date = |value: &str, args: &[(&str, &str)]| {
+            let args = args
+                .iter()
+                .map(|(k, v)| (k.to_string(), to_value(v).unwrap()))
+                .collect();
+            filter.filter(&to_value(value).unwrap(), &args).unwrap()
+        };
+        // still the evening before in New York
+        assert_eq!(date("2024-03-08T02:00:00", &[]), "March 7, 2024");
+        assert_eq!(date("2024-03-08T02:00:00", &[("format", "%H:%M")]), "21:00");
+        assert_eq!(
+            date("2024-03-08T02:00:00", &[("timezone", "UTC")]),
+            "March 8, 2024"
+        );
+        assert_eq!(date("2024-03-08", &[]), "March 8, 2024");
+    }
+}
@@ -0,0 +1,242 @@
+use chrono::{DateTime, NaiveDateTime};
+use image::{codecs::jpeg::JpegEncoder, DynamicImage, ImageDecoder, ImageFormat, ImageReader};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use crate::{paths, site::Site};
+
+pub const GALLERY_URL: &str = "/gallery";
+pub const THUMBNAILS_PATH: &str = "/gallery/thumbnails/";
+
+const DEFAULT_TITLE: &str = "Gallery";
+const DEFAULT_THUMBNAIL_SIZE: u32 = 400;
+const DEFAULT_COLUMNS: u32 = 3;
+const JPEG_QUALITY: u8 = 80;
+
+/// The types of the uploaded images thumbnails are made of. SVG images are listed as well, being their own thumbnails.
+const THUMBNAIL_TYPES: [&str; 4] = ["image/jpeg", "image/png", "image/webp", "image/gif"];
+const SVG_TYPE: &str = "image/svg+xml";
+
+/// The `[gallery]` table of a site's `_config.toml`, having the images uploaded to the site listed at `/gallery/`.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct GalleryConfig {
+    /// The title of the gallery's page. Defaults to "Gallery".
+    pub title: Option<String>,
+    /// The width and height thumbnails fit in, in pixels. Defaults to 400.
+    pub thumbnail_size: Option<u32>,
+    /// The number of columns of the default layout. Defaults to 3.
+    pub columns: Option<u32>,
+}
+
+impl GalleryConfig {
+    pub fn get_title(&self) -> &str {
+        self.title.as_deref().unwrap_or(DEFAULT_TITLE)
+    }
+
+    fn get_thumbnail_size(&self) -> u32 {
+        self.thumbnail_size
+            .unwrap_or(DEFAULT_THUMBNAIL_SIZE)
+            .clamp(16, 2048)
+    }
+
+    pub fn get_columns(&self) -> u32 {
+        self.columns.unwrap_or(DEFAULT_COLUMNS).max(1)
+    }
+}
+
+/// What is kept along with an uploaded file, as far as the gallery is concerned.
+#[derive(Deserialize)]
+struct StoredMetadata {
+    sha256: String,
+    #[serde(rename = "type")]
+    content_type: String,
+    size: usize,
+    url: String,
+}
+
+/// An image of the gallery, as passed to templates.
+#[derive(Clone, Serialize)]
+pub struct GalleryImage {
+    pub sha256: String,
+    pub url: String,
+    pub thumbnail_url: String,
+    #[serde(rename = "type")]
+    pub content_type: String,
+    pub size: usize,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub date: NaiveDateTime, // when it was uploaded
+}
+
+fn get_files_dir(site: &Site) -> PathBuf {
+    PathBuf::from(format!("{}/{}/_content/files", paths::sites(), site.domain))
+}
+
+/// Changes as files are uploaded or deleted, so that a rendered gallery can be cached until then.
+pub fn get_version(site: &Site) -> u128 {
+    fs::metadata(get_files_dir(site))
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_nanos())
+}
+
+/// The images uploaded to the site, newest first, going by the metadata stored along with them.
+pub fn get_images(site: &Site) -> Vec<GalleryImage> {
+    let Ok(entries) = fs::read_dir(get_files_dir(site)) else {
+        return vec![];
+    };
+    let mut images = entries
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry
+                .file_name()
+                .to_str()?
+                .strip_suffix(".metadata.json")?
+                .to_owned();
+            let metadata = fs::read_to_string(entry.path()).ok()?;
+            let metadata = serde_json::from_str::<StoredMetadata>(&metadata).ok()?;
+            if metadata.sha256 != name
+                || !(THUMBNAIL_TYPES.contains(&metadata.content_type.as_str())
+                    || metadata.content_type == SVG_TYPE)
+            {
+                return None;
+            }
+            let path = entry.path().with_file_name(&name);
+            let modified = fs::metadata(&path).and_then(|m| m.modified()).ok()?;
+            let date = DateTime::<chrono::Utc>::from(modified).naive_utc();
+            let (width, height) = ImageReader::open(&path)
+                .ok()
+                .and_then(|r| r.with_guessed_format().ok())
+                .and_then(|r| r.into_dimensions().ok())
+                .unzip();
+            let thumbnail_url = if metadata.content_type == SVG_TYPE {
+                metadata.url.to_owned()
+            } else {
+                // a file, so without a trailing slash
+                format!(
+                    "{}{}{}",
+                    site.config.base_url.trim_end_matches('/'),
+                    THUMBNAILS_PATH,
+                    get_thumbnail_name(&metadata)
+                )
+            };
+            Some(GalleryImage {
+                sha256: metadata.sha256,
+                url: metadata.url,
+                thumbnail_url,
+                content_type: metadata.content_type,
+                size: metadata.size,
+                width,
+                height,
+                date,
+            })
+        })
+        .collect::<Vec<_>>();
+    images.sort_by_key(|i| std::cmp::Reverse(i.date));
+
+    images
+}
+
+/// Thumbnails of photos are JPEG, the others are PNG, keeping any transparency.
+fn get_thumbnail_name(metadata: &StoredMetadata) -> String {
+    match metadata.content_type.as_str() {
+        "image/jpeg" => format!("{}.jpg", metadata.sha256),
+        _ => format!("{}.png", metadata.sha256),
+    }
+}
+
+/// The thumbnail called `name` (as in [`GalleryImage::thumbnail_url`]), made the first time it is requested,
+/// then kept in the site's `_thumbnails`. None if there is no such image.
+pub fn get_thumbnail(
+    site: &Site,
+    config: &GalleryConfig,
+    name: &str,
+) -> io::Result<Option<PathBuf>> {
+    let Some((sha256, extension)) = name.split_once('.') else {
+        return Ok(None);
+    };
+    if sha256.len() != 64 || !sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Ok(None);
+    }
+    let metadata_path = get_files_dir(site).join(format!("{}.metadata.json", sha256));
+    let Ok(metadata) = fs::read_to_string(&metadata_path) else {
+        return Ok(None);
+    };
+    let metadata = serde_json::from_str::<StoredMetadata>(&metadata)?;
+    if !THUMBNAIL_TYPES.contains(&metadata.content_type.as_str())
+        || get_thumbnail_name(&metadata) != name
+    {
+        return Ok(None);
+    }
+
+    let size = config.get_thumbnail_size();
+    let dir = PathBuf::from(format!("{}/{}/_thumbnails", paths::sites(), site.domain));
+    let path = dir.join(format!("{}-{}.{}", sha256, size, extension));
+    if !path.exists() {
+        fs::create_dir_all(&dir)?;
+        make_thumbnail(&get_files_dir(site).join(sha256), &path, size)?;
+    }
+
+    Ok(Some(path))
+}
+
+fn make_thumbnail(source: &Path, path: &Path, size: u32) -> io::Result<()> {
+    let invalid = |e: image::ImageError| io::Error::new(io::ErrorKind::InvalidData, e);
+    let mut decoder = ImageReader::open(source)?
+        .with_guessed_format()?
+        .into_decoder()
+        .map_err(invalid)?;
+    let orientation = decoder.orientation().map_err(invalid)?;
+    let mut image = DynamicImage::from_decoder(decoder).map_err(invalid)?;
+    image.apply_orientation(orientation);
+    let thumbnail = image.thumbnail(size, size);
+
+    let part_path = path.with_extension("part");
+    let mut writer = BufWriter::new(fs::File::create(&part_path)?);
+    let written = match path.extension().and_then(|e| e.to_str()) {
+        Some("jpg") => DynamicImage::ImageRgb8(thumbnail.to_rgb8())
+            .write_with_encoder(JpegEncoder::new_with_quality(&mut writer, JPEG_QUALITY)),
+        _ => thumbnail.write_to(&mut writer, ImageFormat::Png),
+    }
+    .map_err(invalid)
+    .and_then(|_| writer.flush());
+    drop(writer);
+    if let Err(e) = written {
+        let _ = fs::remove_file(&part_path);
+        return Err(e);
+    }
+
+    fs::rename(part_path, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_make_thumbnail() {
+        let dir = std::env::temp_dir().join(format!("servus-gallery-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("source");
+        let image = DynamicImage::new_rgb8(800, 400);
+        image.save_with_format(&source, ImageFormat::Png).unwrap();
+
+        let path = dir.join("thumbnail.jpg");
+        make_thumbnail(&source, &path, 200).unwrap();
+        let thumbnail = image::open(&path).unwrap();
+        assert_eq!((thumbnail.width(), thumbnail.height()), (200, 100));
+        assert!(!path.with_extension("part").exists());
+
+        fs::write(&source, b"not an image").unwrap();
+        assert!(make_thumbnail(&source, &dir.join("broken.png"), 200).is_err());
+        assert!(!dir.join("broken.part").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,566 @@
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use clap::ValueEnum;
+use secp256k1::KeyPair;
+use serde::Serialize;
+use serde_json::Value as JsonValue;
+use std::{
+    fs,
+    path::{Component, Path, PathBuf},
+};
+use walkdir::WalkDir;
+
+use crate::{git, nostr, paths, site::Site};
+
+/// The static site generators whose content can be imported.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum SsgFormat {
+    Jekyll,
+    Hugo,
+    Zola,
+}
+
+impl SsgFormat {
+    /// Where the posts and pages are, relative to the root of the site.
+    fn get_content_dir(self) -> &'static str {
+        match self {
+            SsgFormat::Jekyll => "",
+            SsgFormat::Hugo | SsgFormat::Zola => "content",
+        }
+    }
+
+    /// The directories of files that are served as they are, and where they are served.
+    fn get_static_dirs(self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            SsgFormat::Jekyll => &[("assets", "assets"), ("images", "images")],
+            SsgFormat::Hugo | SsgFormat::Zola => &[("static", "")],
+        }
+    }
+}
+
+/// A post or a page, as read from the content of the other generator.
+#[derive(Debug, PartialEq)]
+struct Entry {
+    title: String,
+    date: Option<NaiveDateTime>,
+    slug: String,
+    tags: Vec<String>,
+    summary: Option<String>,
+    /// The URL it was published at by the other generator, along with its own aliases.
+    aliases: Vec<String>,
+    is_post: bool,
+    content: String,
+}
+
+/// What was imported, and what was not (as the path of the file and the reason).
+#[derive(Default)]
+pub struct ImportSummary {
+    pub posts: usize,
+    pub pages: usize,
+    pub static_files: usize,
+    pub skipped: Vec<(String, String)>,
+}
+
+/// The front matter of the posts and pages imported as plain files.
+#[derive(Serialize)]
+struct FrontMatter<'a> {
+    title: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    created_at: Option<i64>,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    tags: &'a [String],
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    aliases: &'a [String],
+}
+
+fn toml_to_json(value: toml::Value) -> JsonValue {
+    match value {
+        toml::Value::String(s) => JsonValue::String(s),
+        toml::Value::Integer(i) => i.into(),
+        toml::Value::Float(f) => f.into(),
+        toml::Value::Boolean(b) => b.into(),
+        toml::Value::Datetime(d) => JsonValue::String(d.to_string()),
+        toml::Value::Array(a) => a.into_iter().map(toml_to_json).collect(),
+        toml::Value::Table(t) => t.into_iter().map(|(k, v)| (k, toml_to_json(v))).collect(),
+    }
+}
+
+/// The front matter (TOML between `+++` lines, as used by Zola and Hugo, or YAML between `---` lines) and the rest of `text`.
+fn split_front_matter(text: &str) -> Result<(JsonValue, &str), String> {
+    let text = text.trim_start_matches('\u{feff}');
+    for (delimiter, is_toml) in [("+++", true), ("---", false)] {
+        let Some(rest) = text
+            .strip_prefix(delimiter)
+            .and_then(|r| r.strip_prefix('\n').or_else(|| r.strip_prefix("\r\n")))
+        else {
+            continue;
+        };
+        let mut end = 0;
+        for line in rest.split_inclusive('\n') {
+            if line.trim_end() == delimiter {
+                let front_matter = &rest[..end];
+                let front_matter = if is_toml {
+                    toml_to_json(toml::Value::Table(
+                        toml::from_str(front_matter)
+                            .map_err(|e| format!("Invalid front matter: {}", e))?,
+                    ))
+                } else {
+                    serde_yaml::from_str(front_matter)
+                        .map_err(|e| format!("Invalid front matter: {}", e))?
+                };
+                return Ok((front_matter, &rest[end + line.len()..]));
+            }
+            end += line.len();
+        }
+        return Err("Unterminated front matter".to_owned());
+    }
+
+    Err("No front matter".to_owned())
+}
+
+/// Dates as written by the generators, with or without a time and a timezone.
+fn parse_date(date: &str) -> Option<NaiveDateTime> {
+    let date = date.trim();
+    if let Ok(date) = DateTime::parse_from_rfc3339(date) {
+        return Some(date.naive_utc());
+    }
+    if let Ok(date) = DateTime::parse_from_str(date, "%Y-%m-%d %H:%M:%S %z") {
+        return Some(date.naive_utc());
+    }
+    for format in ["%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M"] {
+        if let Ok(date) = NaiveDateTime::parse_from_str(date, format) {
+            return Some(date);
+        }
+    }
+
+    NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .ok()
+        .and_then(|d| d.and_hms_opt(0, 0, 0))
+}
+
+/// The date a file name starts with, as in `2024-05-01-hello.md`, and the rest of the name.
+fn split_date_prefix(stem: &str) -> (Option<NaiveDate>, &str) {
+    if stem.len() > 11 && stem.is_char_boundary(10) && matches!(&stem[10..11], "-" | "_") {
+        if let Ok(date) = NaiveDate::parse_from_str(&stem[..10], "%Y-%m-%d") {
+            return (Some(date), &stem[11..]);
+        }
+    }
+
+    (None, stem)
+}
+
+fn get_str<'a>(front_matter: &'a JsonValue, key: &str) -> Option<&'a str> {
+    front_matter
+        .get(key)
+        .and_then(|v| v.as_str())
+        .filter(|v| !v.trim().is_empty())
+}
+
+/// A list, or a string of space-separated values (as Jekyll allows for tags).
+fn get_list(front_matter: &JsonValue, key: &str) -> Vec<String> {
+    match front_matter.get(key) {
+        Some(JsonValue::Array(values)) => values
+            .iter()
+            .filter_map(|v| v.as_str())
+            .map(|v| v.to_owned())
+            .collect(),
+        Some(JsonValue::String(values)) => {
+            values.split_whitespace().map(|v| v.to_owned()).collect()
+        }
+        _ => vec![],
+    }
+}
+
+/// "hello-world" as "Hello world", for entries without a title.
+fn get_title_from_slug(slug: &str) -> String {
+    let title = slug.replace(['-', '_'], " ");
+    let mut chars = title.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => title,
+    }
+}
+
+fn parse_entry(format: SsgFormat, relative_path: &Path, text: &str) -> Result<Entry, String> {
+    let (front_matter, content) = split_front_matter(text)?;
+    let stem = relative_path
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .ok_or("Invalid file name")?;
+    let dirs = relative_path
+        .parent()
+        .map(|p| {
+            p.components()
+                .filter_map(|c| match c {
+                    Component::Normal(c) => c.to_str(),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+
+    if stem == "_index" {
+        return Err("Section index".to_owned());
+    }
+    if stem == "index" && dirs.is_empty() {
+        return Err("Home page".to_owned());
+    }
+    if front_matter.get("draft").and_then(|d| d.as_bool()) == Some(true)
+        || front_matter.get("published").and_then(|p| p.as_bool()) == Some(false)
+    {
+        return Err("Draft".to_owned());
+    }
+
+    // a page bundle, as in `blog/hello/index.md`, is named after its directory
+    let (section, name) = match (stem, dirs.split_last()) {
+        ("index", Some((name, section))) => (section.to_vec(), *name),
+        _ => (dirs.clone(), stem),
+    };
+    let (name_date, name) = split_date_prefix(name);
+    let original_slug = get_str(&front_matter, "slug").unwrap_or(name);
+    let slug = slug::slugify(original_slug);
+    if slug.is_empty() {
+        return Err("No slug".to_owned());
+    }
+
+    let date = ["date", "publishDate"]
+        .iter()
+        .find_map(|key| get_str(&front_matter, key))
+        .and_then(parse_date)
+        .or_else(|| name_date.and_then(|d| d.and_hms_opt(0, 0, 0)));
+    let is_post = match format {
+        SsgFormat::Jekyll => dirs.first() == Some(&"_posts"),
+        SsgFormat::Hugo | SsgFormat::Zola => date.is_some(),
+    };
+    if is_post && date.is_none() {
+        return Err("No date".to_owned());
+    }
+
+    let old_url = match format {
+        SsgFormat::Zola => get_str(&front_matter, "path").map(|p| p.to_owned()),
+        SsgFormat::Hugo => get_str(&front_matter, "url").map(|u| u.to_owned()),
+        SsgFormat::Jekyll => get_str(&front_matter, "permalink")
+            .filter(|p| !p.contains(':'))
+            .map(|p| p.to_owned()),
+    }
+    .unwrap_or_else(|| match (format, date) {
+        (SsgFormat::Jekyll, Some(date)) if is_post => {
+            format!("/{}/{}.html", date.format("%Y/%m/%d"), original_slug)
+        }
+        (SsgFormat::Jekyll, _) => format!("/{}.html", original_slug),
+        _ => format!(
+            "/{}/",
+            section
+                .iter()
+                .copied()
+                .chain([original_slug])
+                .collect::<Vec<_>>()
+                .join("/")
+        ),
+    });
+    let mut aliases = vec![old_url];
+    aliases.extend(get_list(&front_matter, "aliases"));
+    aliases.extend(get_list(&front_matter, "redirect_from"));
+
+    let mut tags = get_list(&front_matter, "tags");
+    if let Some(taxonomies) = front_matter.get("taxonomies") {
+        tags.extend(get_list(taxonomies, "tags"));
+    }
+
+    Ok(Entry {
+        title: get_str(&front_matter, "title")
+            .map(|t| t.to_owned())
+            .unwrap_or_else(|| get_title_from_slug(&slug)),
+        date,
+        slug,
+        tags,
+        summary: ["description", "summary", "excerpt"]
+            .iter()
+            .find_map(|key| get_str(&front_matter, key))
+            .map(|s| s.to_owned()),
+        aliases,
+        is_post,
+        content: content.trim_start_matches(['\r', '\n']).to_owned(),
+    })
+}
+
+fn is_markdown(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|e| e == "md" || e == "markdown")
+}
+
+/// The files under the content directory that are imported: the markdown files, which are the posts and pages,
+/// and, for Hugo and Zola, the files next to them in page bundles.
+fn find_content(format: SsgFormat, content_path: &Path) -> Vec<PathBuf> {
+    let walker = WalkDir::new(content_path)
+        .min_depth(1)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|e| {
+            let name = e.file_name().to_string_lossy();
+            let is_dir = e.file_type().is_dir();
+            !name.starts_with('.')
+                && match format {
+                    // the rest of a Jekyll site is its layouts, config, drafts and such
+                    SsgFormat::Jekyll if e.depth() == 1 => {
+                        if is_dir {
+                            name == "_posts"
+                        } else {
+                            is_markdown(e.path())
+                                && !["readme", "license", "changelog"].contains(
+                                    &name.split('.').next().unwrap().to_lowercase().as_str(),
+                                )
+                        }
+                    }
+                    _ => true,
+                }
+        });
+
+    walker
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file())
+        .filter(|e| format != SsgFormat::Jekyll || is_markdown(e.path()))
+        .map(|e| e.path().strip_prefix(content_path).unwrap().to_owned())
+        .collect()
+}
+
+/// Copies a file to the site, unless it would replace an existing one or have a special meaning (see "Directory structure").
+fn copy_static_file(source: &Path, site_path: &Path, target: &Path) -> Result<(), String> {
+    if target
+        .components()
+        .next()
+        .is_some_and(|c| c.as_os_str().to_string_lossy().starts_with(['_', '.']))
+    {
+        return Err("Reserved name".to_owned());
+    }
+    let target = site_path.join(target);
+    if target.exists() {
+        return Err("Already exists".to_owned());
+    }
+    fs::create_dir_all(target.parent().unwrap()).map_err(|e| e.to_string())?;
+    fs::copy(source, &target).map_err(|e| e.to_string())?;
+
+    Ok(())
+}
+
+fn write_entry(entry: &Entry, site_path: &Path, keypair: Option<&KeyPair>) -> Result<(), String> {
+    let created_at = entry
+        .date
+        .map(|d| d.and_utc().timestamp())
+        .unwrap_or_else(|| Utc::now().timestamp());
+    let Some(keypair) = keypair else {
+        let path = match entry.date {
+            Some(date) if entry.is_post => format!(
+                "_content/posts/{}-{}.md",
+                date.format("%Y-%m-%d"),
+                entry.slug
+            ),
+            _ => format!("_content/pages/{}.md", entry.slug),
+        };
+        let path = site_path.join(path);
+        if path.exists() {
+            return Err("Already exists".to_owned());
+        }
+        let front_matter = FrontMatter {
+            title: &entry.title,
+            created_at: (!entry.is_post).then_some(created_at),
+            tags: &entry.tags,
+            aliases: &entry.aliases,
+        };
+        let front_matter = serde_yaml::to_string(&front_matter).map_err(|e| e.to_string())?;
+        fs::create_dir_all(path.parent().unwrap()).map_err(|e| e.to_string())?;
+        return fs::write(
+            &path,
+            format!("---\n{}---\n{}", front_matter, entry.content),
+        )
+        .map_err(|e| e.to_string());
+    };
+
+    // a long-form event, just like one published by a Nostr client: a post if it has a date, a page otherwise
+    let path = site_path.join(format!(
+        "_content/{}/{}.md",
+        if entry.is_post { "posts" } else { "pages" },
+        entry.slug
+    ));
+    if path.exists() {
+        return Err("Already exists".to_owned());
+    }
+    let mut tags = vec![
+        vec!["d".to_owned(), entry.slug.to_owned()],
+        vec!["title".to_owned(), entry.title.to_owned()],
+    ];
+    if entry.is_post {
+        tags.push(vec!["published_at".to_owned(), created_at.to_string()]);
+    }
+    if let Some(summary) = &entry.summary {
+        tags.push(vec!["summary".to_owned(), summary.to_owned()]);
+    }
+    tags.extend(
+        entry
+            .tags
+            .iter()
+            .map(|t| vec!["t".to_owned(), t.to_owned()]),
+    );
+    tags.extend(
+        entry
+            .aliases
+            .iter()
+            .map(|a| vec!["alias".to_owned(), a.to_owned()]),
+    );
+    let mut event = nostr::Event {
+        id: String::new(),
+        pubkey: String::new(),
+        created_at,
+        kind: nostr::EVENT_KIND_LONG_FORM,
+        tags,
+        content: entry.content.trim_end().to_owned(),
+        sig: String::new(),
+    };
+    event.sign(keypair);
+    event
+        .write(&path.display().to_string())
+        .map_err(|e| e.to_string())
+}
+
+/// Imports the posts, pages and static files of a site made using another generator, found at `source`,
+/// as plain files or, given the site's key, as events signed by it. Nothing existing is replaced.
+pub fn import_ssg(
+    format: SsgFormat,
+    source: &Path,
+    site: &Site,
+    keypair: Option<&KeyPair>,
+) -> Result<ImportSummary, String> {
+    let content_path = source.join(format.get_content_dir());
+    if !content_path.is_dir() {
+        return Err(format!("No content found in {}", content_path.display()));
+    }
+    if let Some(keypair) = keypair {
+        let pubkey = keypair.x_only_public_key().0.to_string();
+        if site.config.pubkey.as_ref() != Some(&pubkey) {
+            return Err("The key is not the site's".to_owned());
+        }
+    }
+
+    let site_path = PathBuf::from(format!("{}/{}", paths::sites(), site.domain));
+    let mut summary = ImportSummary::default();
+    for relative_path in find_content(format, &content_path) {
+        let path = content_path.join(&relative_path);
+        let result = if is_markdown(&relative_path) {
+            fs::read_to_string(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|text| parse_entry(format, &relative_path, &text))
+                .and_then(|entry| {
+                    write_entry(&entry, &site_path, keypair)?;
+                    if entry.is_post {
+                        summary.posts += 1;
+                    } else {
+                        summary.pages += 1;
+                    }
+                    Ok(())
+                })
+        } else {
+            copy_static_file(&path, &site_path, &relative_path).map(|_| summary.static_files += 1)
+        };
+        if let Err(reason) = result {
+            summary.skipped.push((path.display().to_string(), reason));
+        }
+    }
+
+    for (dir, target_dir) in format.get_static_dirs() {
+        let static_path = source.join(dir);
+        for entry in WalkDir::new(&static_path)
+            .sort_by_file_name()
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_type().is_file())
+        {
+            let relative_path = entry.path().strip_prefix(&static_path).unwrap();
+            match copy_static_file(
+                entry.path(),
+                &site_path,
+                &Path::new(target_dir).join(relative_path),
+            ) {
+                Ok(()) => summary.static_files += 1,
+                Err(reason) => summary
+                    .skipped
+                    .push((entry.path().display().to_string(), reason)),
+            }
+        }
+    }
+
+    // recorded at once (rather than in the background), since this runs from the command line
+    if let Some(git) = &site.config.git {
+        git::record_change(&site.domain, git, &format!("Import from {:?}", format))?;
+    }
+
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_date() {
+        let date = |d: &str| parse_date(d).map(|d| d.to_string());
+        assert_eq!(date("2024-05-01").unwrap(), "2024-05-01 00:00:00");
+        assert_eq!(
+            date("2024-05-01T10:30:00+02:00").unwrap(),
+            "2024-05-01 08:30:00"
+        );
+        assert_eq!(
+            date("2024-05-01 10:30:00 +0200").unwrap(),
+            "2024-05-01 08:30:00"
+        );
+        assert_eq!(date("2024-05-01 10:30").unwrap(), "2024-05-01 10:30:00");
+        assert!(date("May 1st").is_none());
+
+        assert_eq!(
+            split_date_prefix("2024-05-01-hello"),
+            (NaiveDate::from_ymd_opt(2024, 5, 1), "hello")
+        );
+        assert_eq!(split_date_prefix("hello"), (None, "hello"));
+    }
+
+    #[test]
+    fn test_parse_entry() {
+        let zola = "+++\ntitle = \"Hello\"\ndate = 2024-05-01\ndescription = \"A post\"\n\
+                    [taxonomies]\ntags = [\"rust\"]\n+++\n\nHello!\n";
+        let entry = parse_entry(SsgFormat::Zola, Path::new("blog/hello/index.md"), zola).unwrap();
+        assert_eq!(
+            entry,
+            Entry {
+                title: "Hello".to_owned(),
+                date: parse_date("2024-05-01"),
+                slug: "hello".to_owned(),
+                tags: vec!["rust".to_owned()],
+                summary: Some("A post".to_owned()),
+                aliases: vec!["/blog/hello/".to_owned()],
+                is_post: true,
+                content: "Hello!\n".to_owned(),
+            }
+        );
+
+        let hugo = "---\ntitle: About me\nslug: about\naliases: [/me]\n---\nAbout\n";
+        let entry = parse_entry(SsgFormat::Hugo, Path::new("about-me.md"), hugo).unwrap();
+        assert!(!entry.is_post);
+        assert_eq!(entry.slug, "about");
+        assert_eq!(entry.aliases, vec!["/about/", "/me"]);
+
+        let jekyll = "---\nlayout: post\ntags: travel food\n---\nTrip\n";
+        let entry = parse_entry(
+            SsgFormat::Jekyll,
+            Path::new("_posts/2024-05-01-my-trip.md"),
+            jekyll,
+        )
+        .unwrap();
+        assert!(entry.is_post);
+        assert_eq!(entry.title, "My trip");
+        assert_eq!(entry.tags, vec!["travel", "food"]);
+        assert_eq!(entry.aliases, vec!["/2024/05/01/my-trip.html"]);
+
+        let draft = "+++\ntitle = \"Soon\"\ndraft = true\n+++\n";
+        assert!(parse_entry(SsgFormat::Zola, Path::new("soon.md"), draft).is_err());
+        assert!(parse_entry(SsgFormat::Zola, Path::new("blog/_index.md"), draft).is_err());
+        assert!(parse_entry(SsgFormat::Hugo, Path::new("no-front-matter.md"), "Hi").is_err());
+    }
+}
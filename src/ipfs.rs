@@ -0,0 +1,99 @@
+use async_std::{fs::File, future, io::BufReader};
+use futures_util::{io::Cursor, AsyncReadExt};
+use http_client::{h1::H1Client, HttpClient};
+use http_types::{Body, Method, Request as HttpRequest, Url};
+use serde_json::Value as JsonValue;
+use std::time::Duration;
+
+/// How long to wait for the IPFS node to add (or remove) a file.
+const IPFS_TIMEOUT: Duration = Duration::from_secs(60);
+
+const BOUNDARY: &str = "servus-ipfs-boundary";
+
+/// The IPFS node (or pinning service) uploaded files are pinned to, using the Kubo RPC API.
+#[derive(Clone, Debug)]
+pub struct IpfsConfig {
+    /// Such as `http://127.0.0.1:5001/`, the API's paths being relative to it.
+    pub api_url: Url,
+    /// Sent as a bearer token, for pinning services that require one.
+    pub api_token: Option<String>,
+    /// Such as `https://ipfs.io`, for the URLs given out along with the CIDs.
+    pub gateway_url: String,
+}
+
+impl IpfsConfig {
+    fn build_request(&self, path_and_query: &str) -> Result<HttpRequest, String> {
+        let url = self
+            .api_url
+            .join(path_and_query)
+            .map_err(|e| e.to_string())?;
+        let mut request = HttpRequest::new(Method::Post, url);
+        if let Some(token) = &self.api_token {
+            request.insert_header("Authorization", format!("Bearer {}", token));
+        }
+
+        Ok(request)
+    }
+
+    /// The URL `cid` can be downloaded from, through the configured gateway.
+    pub fn get_gateway_url(&self, cid: &str) -> String {
+        format!("{}/ipfs/{}", self.gateway_url.trim_end_matches('/'), cid)
+    }
+}
+
+async fn send(request: HttpRequest) -> Result<String, String> {
+    let mut response = future::timeout(IPFS_TIMEOUT, H1Client::new().send(request))
+        .await
+        .map_err(|_| "Timed out".to_string())?
+        .map_err(|e| e.to_string())?;
+    let body = response.body_string().await.map_err(|e| e.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("{}: {}", response.status(), body.trim()));
+    }
+
+    Ok(body)
+}
+
+/// Adds the file at `path` to IPFS and pins it, returning its CID (v1).
+/// The file is streamed rather than read into memory.
+pub async fn pin(config: &IpfsConfig, path: &str) -> Result<String, String> {
+    let file = File::open(path).await.map_err(|e| e.to_string())?;
+    let size = file.metadata().await.map_err(|e| e.to_string())?.len();
+    let head = format!(
+        "--{}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"file\"\r\nContent-Type: application/octet-stream\r\n\r\n",
+        BOUNDARY
+    );
+    let tail = format!("\r\n--{}--\r\n", BOUNDARY);
+    let length = head.len() as u64 + size + tail.len() as u64;
+    let reader = Cursor::new(head.into_bytes())
+        .chain(file)
+        .chain(Cursor::new(tail.into_bytes()));
+
+    let mut request = config.build_request("api/v0/add?pin=true&cid-version=1")?;
+    request.insert_header(
+        "Content-Type",
+        format!("multipart/form-data; boundary={}", BOUNDARY),
+    );
+    request.set_body(Body::from_reader(
+        BufReader::new(reader),
+        Some(length as usize),
+    ));
+    let response = send(request).await?;
+
+    // one JSON object per line, the last one being the file's
+    let added = response
+        .lines()
+        .rev()
+        .find_map(|line| serde_json::from_str::<JsonValue>(line).ok())
+        .ok_or("Unexpected response")?;
+    added["Hash"]
+        .as_str()
+        .map(|cid| cid.to_owned())
+        .ok_or_else(|| "No CID in the response".to_string())
+}
+
+/// Unpins `cid`, leaving it to the node's garbage collection.
+pub async fn unpin(config: &IpfsConfig, cid: &str) -> Result<(), String> {
+    let request = config.build_request(&format!("api/v0/pin/rm?arg={}", cid))?;
+    send(request).await.map(|_| ())
+}
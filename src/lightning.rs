@@ -0,0 +1,120 @@
+use async_std::future;
+use http_client::{h1::H1Client, HttpClient};
+use http_types::{Method, Request as HttpRequest, Url};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value as JsonValue};
+use std::{io, time::Duration};
+
+use crate::site::{self, Site};
+
+/// How long to wait for the server of an upstream lightning address.
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What `/.well-known/lnurlp/<name>` answers with (see LUD-16), as given in the `[lightning]` table of a site's `_config.toml`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum LightningAddress {
+    /// Another lightning address, such as `me@getalby.com`, whose pay request is passed on.
+    Upstream(String),
+    /// The pay request itself (see LUD-06), such as its `callback`, `minSendable`, `maxSendable` and `metadata`.
+    Static(toml::Table),
+}
+
+fn invalid_data(error: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+/// The URL the pay request of lightning address `address` is served at.
+fn get_upstream_url(address: &str) -> Option<Url> {
+    let (name, domain) = address.split_once('@')?;
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '+'))
+        || !site::is_valid_domain(domain)
+    {
+        return None;
+    }
+
+    Url::parse(&format!("https://{}/.well-known/lnurlp/{}", domain, name)).ok()
+}
+
+async fn fetch_upstream(url: Url) -> io::Result<JsonValue> {
+    let request = HttpRequest::new(Method::Get, url);
+    let mut response = future::timeout(UPSTREAM_TIMEOUT, H1Client::new().send(request))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "Timed out"))?
+        .map_err(|e| io::Error::other(e.to_string()))?;
+    if !response.status().is_success() {
+        return Err(io::Error::other(response.status().to_string()));
+    }
+
+    response
+        .body_json()
+        .await
+        .map_err(|e| invalid_data(&e.to_string()))
+}
+
+/// The pay request of the site's lightning address `name`, if it has one.
+pub async fn get_pay_request(site: &Site, name: &str) -> io::Result<JsonValue> {
+    let address = site
+        .config
+        .lightning
+        .get(name)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Unknown lightning address"))?;
+
+    let pay_request = match address {
+        LightningAddress::Upstream(address) => {
+            let url = get_upstream_url(address)
+                .filter(|url| url.host_str() != Some(site.domain.as_str()))
+                .ok_or_else(|| invalid_data("Invalid upstream lightning address"))?;
+            fetch_upstream(url).await?
+        }
+        LightningAddress::Static(table) => {
+            let mut pay_request = serde_json::to_value(table)?;
+            if pay_request.get("tag").is_none() {
+                pay_request["tag"] = json!("payRequest");
+            }
+            pay_request
+        }
+    };
+    if pay_request["tag"] != "payRequest" {
+        return Err(invalid_data("Not a pay request"));
+    }
+
+    Ok(pay_request)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_upstream_url() {
+        assert_eq!(
+            get_upstream_url("me@getalby.com").unwrap().as_str(),
+            "https://getalby.com/.well-known/lnurlp/me"
+        );
+        for address in [
+            "me",
+            "@getalby.com",
+            "me@",
+            "m/e@getalby.com",
+            "me@localhost:8080",
+        ] {
+            assert!(get_upstream_url(address).is_none(), "{}", address);
+        }
+    }
+
+    #[test]
+    fn test_parse_config() {
+        let config: std::collections::HashMap<String, LightningAddress> = toml::from_str(
+            "me = \"me@getalby.com\"\n[tips]\ncallback = \"https://example.com/lnurlp/tips/callback\"\nminSendable = 1000",
+        )
+        .unwrap();
+        assert!(matches!(&config["me"], LightningAddress::Upstream(a) if a == "me@getalby.com"));
+        assert!(
+            matches!(&config["tips"], LightningAddress::Static(t) if t["minSendable"].as_integer() == Some(1000))
+        );
+    }
+}
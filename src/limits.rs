@@ -0,0 +1,223 @@
+use async_std::{io, task};
+use async_tungstenite::{
+    tungstenite::{handshake::derive_accept_key, protocol::Role, protocol::WebSocketConfig},
+    WebSocketStream,
+};
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use tide::{
+    http::headers::{CONNECTION, UPGRADE},
+    Middleware, Next, Request, Response, StatusCode,
+};
+use tide_websockets::WebSocketConnection;
+
+/// How large the body of a request can be, and how long the client has to send all of it.
+#[derive(Clone, Copy, Debug)]
+pub struct BodyLimits {
+    pub max_size: usize,
+    pub timeout: Duration,
+}
+
+/// The body of a request, failing once it gets larger than the limits allow, or once they don't allow waiting for it any longer,
+/// recording which (as the status of the response) in `exceeded`.
+pub struct LimitedBody<R> {
+    body: R,
+    max_size: usize,
+    size: usize,
+    deadline: Instant,
+    // only started once waiting for the body, and in a mutex so that the body can be shared
+    timer: Mutex<Option<Pin<Box<dyn Future<Output = ()> + Send>>>>,
+    exceeded: Arc<Mutex<Option<StatusCode>>>,
+}
+
+impl<R> LimitedBody<R> {
+    pub fn new(body: R, limits: BodyLimits, exceeded: Arc<Mutex<Option<StatusCode>>>) -> Self {
+        Self {
+            body,
+            max_size: limits.max_size,
+            size: 0,
+            deadline: Instant::now() + limits.timeout,
+            timer: Mutex::new(None),
+            exceeded,
+        }
+    }
+
+    fn fail(&self, status: StatusCode, message: &str) -> io::Error {
+        *self.exceeded.lock().unwrap() = Some(status);
+        let kind = match status {
+            StatusCode::RequestTimeout => io::ErrorKind::TimedOut,
+            _ => io::ErrorKind::InvalidData,
+        };
+        io::Error::new(kind, message)
+    }
+}
+
+impl<R: io::Read + Unpin> io::Read for LimitedBody<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        match Pin::new(&mut this.body).poll_read(cx, buf) {
+            Poll::Ready(Ok(length)) => {
+                this.size += length;
+                if this.size > this.max_size {
+                    Poll::Ready(Err(
+                        this.fail(StatusCode::PayloadTooLarge, "request body too large")
+                    ))
+                } else if length > 0 && Instant::now() > this.deadline {
+                    Poll::Ready(Err(
+                        this.fail(StatusCode::RequestTimeout, "request body too slow")
+                    ))
+                } else {
+                    Poll::Ready(Ok(length))
+                }
+            }
+            Poll::Pending => {
+                let deadline = this.deadline;
+                let timer = this.timer.get_mut().unwrap().get_or_insert_with(|| {
+                    Box::pin(task::sleep(
+                        deadline.saturating_duration_since(Instant::now()),
+                    ))
+                });
+                match timer.as_mut().poll(cx) {
+                    Poll::Ready(()) => Poll::Ready(Err(
+                        this.fail(StatusCode::RequestTimeout, "request body too slow")
+                    )),
+                    Poll::Pending => Poll::Pending,
+                }
+            }
+            error => error,
+        }
+    }
+}
+
+/// Upgrades requests to the WebSocket protocol, like [`tide_websockets::WebSocket`],
+/// but refusing messages (and frames) larger than `max_message_size` rather than buffering up to 64 MiB of them.
+pub struct WebSocket<H> {
+    handler: Arc<H>,
+    max_message_size: usize,
+}
+
+impl<H> WebSocket<H> {
+    pub fn new(max_message_size: usize, handler: H) -> Self {
+        Self {
+            handler: Arc::new(handler),
+            max_message_size,
+        }
+    }
+}
+
+fn header_contains<S>(
+    request: &Request<S>,
+    name: tide::http::headers::HeaderName,
+    value: &str,
+) -> bool {
+    request.header(name).is_some_and(|h| {
+        h.as_str()
+            .split(',')
+            .any(|v| v.trim().eq_ignore_ascii_case(value))
+    })
+}
+
+#[tide::utils::async_trait]
+impl<S, H, Fut> Middleware<S> for WebSocket<H>
+where
+    S: Clone + Send + Sync + 'static,
+    H: Fn(Request<S>, WebSocketConnection) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = tide::Result<()>> + Send + 'static,
+{
+    async fn handle(&self, request: Request<S>, next: Next<'_, S>) -> tide::Result {
+        if !header_contains(&request, CONNECTION, "upgrade")
+            || !header_contains(&request, UPGRADE, "websocket")
+        {
+            return Ok(next.run(request).await);
+        }
+        let Some(key) = request.header("Sec-WebSocket-Key") else {
+            return Ok(Response::new(StatusCode::BadRequest));
+        };
+
+        let mut response = Response::new(StatusCode::SwitchingProtocols);
+        response.insert_header(UPGRADE, "websocket");
+        response.insert_header(CONNECTION, "Upgrade");
+        response.insert_header(
+            "Sec-WebSocket-Accept",
+            derive_accept_key(key.as_str().as_bytes()),
+        );
+        response.insert_header("Sec-WebSocket-Version", "13");
+
+        let http_response: &mut tide::http::Response = response.as_mut();
+        let upgrade = http_response.recv_upgrade().await;
+        let handler = self.handler.clone();
+        let config = WebSocketConfig {
+            max_message_size: Some(self.max_message_size),
+            max_frame_size: Some(self.max_message_size),
+            ..Default::default()
+        };
+        task::spawn(async move {
+            match upgrade.await {
+                Some(stream) => {
+                    let stream =
+                        WebSocketStream::from_raw_socket(stream, Role::Server, Some(config)).await;
+                    handler(request, stream.into()).await
+                }
+                None => Err(tide::http::format_err!("never received an upgrade")),
+            }
+        });
+
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::io::ReadExt;
+
+    #[async_std::test]
+    async fn test_limited_body() {
+        let limits = BodyLimits {
+            max_size: 4,
+            timeout: Duration::from_secs(10),
+        };
+        let exceeded = Arc::new(Mutex::new(None));
+        let mut body = LimitedBody::new(&b"abcd"[..], limits, exceeded.clone());
+        let mut content = vec![];
+        body.read_to_end(&mut content).await.unwrap();
+        assert_eq!(content, b"abcd");
+        assert_eq!(*exceeded.lock().unwrap(), None);
+
+        let mut body = LimitedBody::new(&b"abcde"[..], limits, exceeded.clone());
+        assert!(body.read_to_end(&mut vec![]).await.is_err());
+        assert_eq!(*exceeded.lock().unwrap(), Some(StatusCode::PayloadTooLarge));
+
+        // a body that never comes
+        let limits = BodyLimits {
+            max_size: 4,
+            timeout: Duration::from_millis(50),
+        };
+        let exceeded = Arc::new(Mutex::new(None));
+        let mut body = LimitedBody::new(Never, limits, exceeded.clone());
+        let e = body.read(&mut [0; 4]).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(*exceeded.lock().unwrap(), Some(StatusCode::RequestTimeout));
+    }
+
+    struct Never;
+
+    impl io::Read for Never {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            _: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            Poll::Pending
+        }
+    }
+}
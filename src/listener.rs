@@ -0,0 +1,277 @@
+use serde::Deserialize;
+use socket2::{Domain, Socket, Type};
+use std::{
+    io,
+    net::{IpAddr, SocketAddr, TcpListener, ToSocketAddrs},
+};
+
+/// A listener, as given in `servus.toml`, such as:
+///
+/// ```toml
+/// [[listeners]]
+/// port = 443
+/// sites = ["example.com"]
+///
+/// [[listeners]]
+/// bind = "10.0.0.5"
+/// port = 8080
+/// tls = false
+/// sites = ["staging.example.com"]
+/// ```
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerConfig {
+    /// Address to listen on, defaulting to the ones passed using `--bind` (or 0.0.0.0).
+    pub bind: Option<String>,
+    pub port: u32,
+    /// Whether to use TLS, which is the default when certificates are configured.
+    pub tls: Option<bool>,
+    /// Domains of the sites served, all of them if empty.
+    #[serde(default)]
+    pub sites: Vec<String>,
+}
+
+/// A listener, with its defaults resolved.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SiteListener {
+    pub bind: String,
+    pub port: u32,
+    pub tls: bool,
+    pub sites: Vec<String>,
+}
+
+impl SiteListener {
+    /// `config` with the defaults given by the command line, failing if TLS is asked for without certificates.
+    /// Without a `bind` address, there is a listener on each of the `default_binds` addresses.
+    pub fn resolve(
+        config: ListenerConfig,
+        default_binds: &[String],
+        tls_enabled: bool,
+    ) -> Result<Vec<Self>, String> {
+        let tls = config.tls.unwrap_or(tls_enabled);
+        if tls && !tls_enabled {
+            return Err(format!(
+                "Listener on port {} uses TLS, but no certificates are configured!",
+                config.port
+            ));
+        }
+
+        let binds = match config.bind {
+            Some(bind) => vec![bind],
+            None => default_binds.to_vec(),
+        };
+        Ok(binds
+            .into_iter()
+            .map(|bind| Self {
+                bind,
+                port: config.port,
+                tls,
+                sites: config.sites.clone(),
+            })
+            .collect())
+    }
+
+    pub fn addr(&self) -> String {
+        format_addr(&self.bind, self.port)
+    }
+
+    /// Binds sockets for all the addresses the listener's address resolves to.
+    pub fn bind(&self) -> io::Result<Vec<TcpListener>> {
+        (self.bind.as_str(), self.port as u16)
+            .to_socket_addrs()?
+            .map(bind)
+            .collect()
+    }
+
+    /// Whether the connection accepted at `local_addr` was accepted by this listener.
+    fn accepts(&self, local_addr: &SocketAddr) -> bool {
+        if local_addr.port() as u32 != self.port {
+            return false;
+        }
+        match self.bind.parse::<IpAddr>() {
+            Ok(ip) => ip.is_unspecified() || ip == local_addr.ip(),
+            _ => true, // a host name, which we don't resolve again
+        }
+    }
+
+    pub fn serves(&self, domain: &str) -> bool {
+        self.sites.is_empty() || self.sites.iter().any(|s| s == domain)
+    }
+}
+
+/// `host:port`, with IPv6 addresses in brackets.
+pub fn format_addr(host: &str, port: u32) -> String {
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V6(ip)) => format!("[{}]:{}", ip, port),
+        _ => format!("{}:{}", host, port),
+    }
+}
+
+/// Splits an address given as `host`, `host:port`, `ipv6`, `[ipv6]` or `[ipv6]:port`.
+pub fn parse_bind(bind: &str) -> Result<(String, Option<u32>), String> {
+    let invalid = || format!("Invalid address: {}", bind);
+    if let Ok(ip) = bind.parse::<IpAddr>() {
+        return Ok((ip.to_string(), None));
+    }
+    if let Some(rest) = bind.strip_prefix('[') {
+        let (ip, port) = rest.split_once(']').ok_or_else(invalid)?;
+        let ip = ip.parse::<IpAddr>().map_err(|_| invalid())?;
+        let port = match port {
+            "" => None,
+            port => Some(
+                port.strip_prefix(':')
+                    .and_then(|p| p.parse().ok())
+                    .ok_or_else(invalid)?,
+            ),
+        };
+        return Ok((ip.to_string(), port));
+    }
+    match bind.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() => {
+            Ok((host.to_owned(), Some(port.parse().map_err(|_| invalid())?)))
+        }
+        Some(_) => Err(invalid()),
+        None if !bind.is_empty() => Ok((bind.to_owned(), None)),
+        None => Err(invalid()),
+    }
+}
+
+/// Binds a socket to `addr`. IPv6 sockets only accept IPv6 connections,
+/// so that `0.0.0.0` and `::` can be bound to the same port, as on dual-stack servers.
+pub fn bind(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+
+    Ok(socket.into())
+}
+
+/// Whether the site at `domain` can be reached through the connection accepted at `local_addr`.
+/// Sites can be reached through any listener when the listener is unknown.
+pub fn is_served(listeners: &[SiteListener], local_addr: Option<&str>, domain: &str) -> bool {
+    let local_addr = local_addr.and_then(|a| a.parse::<SocketAddr>().ok());
+    match local_addr.and_then(|a| listeners.iter().find(|l| l.accepts(&a))) {
+        Some(listener) => listener.serves(domain),
+        None => true,
+    }
+}
+
+/// Whether the listener a request was received by, at `local_addr`, uses TLS.
+pub fn is_tls(listeners: &[SiteListener], local_addr: Option<&str>) -> bool {
+    let local_addr = local_addr.and_then(|a| a.parse::<SocketAddr>().ok());
+    local_addr
+        .and_then(|a| listeners.iter().find(|l| l.accepts(&a)))
+        .is_some_and(|l| l.tls)
+}
+
+/// Whether the site at `domain` is served over TLS by any of the listeners.
+pub fn is_served_over_tls(listeners: &[SiteListener], domain: &str) -> bool {
+    listeners.iter().any(|l| l.tls && l.serves(domain))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn listener(bind: &str, port: u32, tls: bool, sites: &[&str]) -> SiteListener {
+        SiteListener {
+            bind: bind.to_owned(),
+            port,
+            tls,
+            sites: sites.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_resolve() {
+        let binds = ["0.0.0.0".to_owned(), "::".to_owned()];
+        let config: ListenerConfig = toml::from_str("port = 8080").unwrap();
+        assert_eq!(
+            SiteListener::resolve(config.clone(), &binds, true).unwrap(),
+            vec![
+                listener("0.0.0.0", 8080, true, &[]),
+                listener("::", 8080, true, &[])
+            ]
+        );
+        assert!(!SiteListener::resolve(config, &binds, false).unwrap()[0].tls);
+
+        let config: ListenerConfig =
+            toml::from_str("bind = \"10.0.0.5\"\nport = 443\ntls = false").unwrap();
+        assert_eq!(
+            SiteListener::resolve(config.clone(), &binds, true).unwrap(),
+            vec![listener("10.0.0.5", 443, false, &[])]
+        );
+        let config = ListenerConfig {
+            tls: Some(true),
+            ..config
+        };
+        assert!(SiteListener::resolve(config, &binds, false).is_err());
+    }
+
+    #[test]
+    fn test_parse_bind() {
+        let parse = |bind| parse_bind(bind).unwrap();
+        assert_eq!(parse("0.0.0.0"), ("0.0.0.0".to_owned(), None));
+        assert_eq!(parse("0.0.0.0:80"), ("0.0.0.0".to_owned(), Some(80)));
+        assert_eq!(parse("::"), ("::".to_owned(), None));
+        assert_eq!(parse("[::]"), ("::".to_owned(), None));
+        assert_eq!(parse("[::]:443"), ("::".to_owned(), Some(443)));
+        assert_eq!(
+            parse("[2001:DB8::1]:443"),
+            ("2001:db8::1".to_owned(), Some(443))
+        );
+        assert_eq!(parse("localhost"), ("localhost".to_owned(), None));
+        assert_eq!(
+            parse("localhost:8080"),
+            ("localhost".to_owned(), Some(8080))
+        );
+        assert!(parse_bind("[::]443").is_err());
+        assert!(parse_bind("[::1:443").is_err());
+        assert!(parse_bind("localhost:http").is_err());
+        assert!(parse_bind(":443").is_err());
+        assert!(parse_bind("").is_err());
+    }
+
+    #[test]
+    fn test_addr() {
+        assert_eq!(listener("0.0.0.0", 443, true, &[]).addr(), "0.0.0.0:443");
+        assert_eq!(listener("::", 443, true, &[]).addr(), "[::]:443");
+        assert_eq!(listener("localhost", 80, false, &[]).addr(), "localhost:80");
+    }
+
+    #[test]
+    fn test_bind_dual_stack() {
+        let v4 = bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let port = v4.local_addr().unwrap().port();
+        // fails if the IPv6 socket accepts IPv4 connections too, unless IPv6 is not available at all
+        if let Ok(v6) = bind(SocketAddr::new("::".parse().unwrap(), port)) {
+            assert_eq!(v6.local_addr().unwrap().port(), port);
+        }
+    }
+
+    #[test]
+    fn test_is_served() {
+        let listeners = [
+            listener("0.0.0.0", 443, true, &["example.com"]),
+            listener("10.0.0.5", 8080, false, &["staging.example.com"]),
+            listener("0.0.0.0", 8081, false, &[]),
+        ];
+        let is_served = |addr, domain| is_served(&listeners, Some(addr), domain);
+        assert!(is_served("1.2.3.4:443", "example.com"));
+        assert!(!is_served("1.2.3.4:443", "staging.example.com"));
+        assert!(is_served("10.0.0.5:8080", "staging.example.com"));
+        assert!(!is_served("10.0.0.5:8080", "example.com"));
+        assert!(is_served("10.0.0.5:8081", "example.com"));
+        // not one of the listeners
+        assert!(is_served("10.0.0.6:8080", "example.com"));
+        assert!(super::is_served(&listeners, None, "example.com"));
+
+        assert!(is_served_over_tls(&listeners, "example.com"));
+        assert!(!is_served_over_tls(&listeners, "staging.example.com"));
+    }
+}
@@ -0,0 +1,288 @@
+use chrono::{SecondsFormat, Utc};
+use clap::ValueEnum;
+use log::{
+    kv::{self, Key, Value, VisitSource},
+    Level, LevelFilter, Log, Metadata, Record,
+};
+use serde_json::json;
+use std::{
+    cell::RefCell,
+    cmp::Reverse,
+    future::Future,
+    io::{self, Write},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context as TaskContext, Poll},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum LogFormat {
+    /// One human readable line per message
+    Pretty,
+    /// One JSON object per line, for log collectors
+    Json,
+}
+
+/// What a message is about, added to all the messages logged while handling a request or a websocket.
+#[derive(Clone, Debug, Default)]
+pub struct LogContext {
+    pub request_id: String,
+    pub domain: Option<String>,
+    pub pubkey: Option<String>,
+}
+
+thread_local! {
+    static CONTEXT: RefCell<Option<Arc<LogContext>>> = const { RefCell::new(None) };
+}
+
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
+
+/// A new ID, unique for as long as we are running, to tell the messages of concurrent requests apart.
+pub fn next_request_id() -> String {
+    format!("{:08x}", NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed))
+}
+
+/// Runs `future` with `context` added to the messages it logs.
+pub fn with_context<F: Future>(context: Arc<LogContext>, future: F) -> WithContext<F> {
+    WithContext {
+        context,
+        future: Box::pin(future),
+    }
+}
+
+/// Runs `f` on a thread meant for blocking work (such as file I/O), keeping the current context.
+pub fn spawn_blocking<F, T>(f: F) -> async_std::task::JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let context = CONTEXT.with(|c| c.borrow().clone());
+    async_std::task::spawn_blocking(move || {
+        let previous = CONTEXT.with(|c| c.replace(context));
+        let result = f();
+        CONTEXT.with(|c| *c.borrow_mut() = previous);
+        result
+    })
+}
+
+/// A future logging with a context, set for the thread while it is being polled,
+/// since a task can move between threads and threads poll many tasks.
+pub struct WithContext<F> {
+    context: Arc<LogContext>,
+    future: Pin<Box<F>>,
+}
+
+impl<F: Future> Future for WithContext<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Self::Output> {
+        let previous = CONTEXT.with(|c| c.replace(Some(self.context.clone())));
+        let poll = self.future.as_mut().poll(cx);
+        CONTEXT.with(|c| *c.borrow_mut() = previous);
+        poll
+    }
+}
+
+/// Which levels are logged, as in `info,servus::watch=debug,tide=warn`:
+/// a default level and levels for the modules (and their submodules) matching a prefix.
+#[derive(Debug, PartialEq)]
+pub struct LogLevels {
+    default: LevelFilter,
+    modules: Vec<(String, LevelFilter)>, // longest prefix first
+}
+
+impl LogLevels {
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let mut levels = LogLevels {
+            default: LevelFilter::Info,
+            modules: vec![],
+        };
+        for directive in spec.split(',').map(|d| d.trim()).filter(|d| !d.is_empty()) {
+            let parse_level = |level: &str| {
+                level
+                    .parse::<LevelFilter>()
+                    .map_err(|_| format!("Invalid log level: {}", level))
+            };
+            match directive.split_once('=') {
+                Some((module, level)) => levels
+                    .modules
+                    .push((module.trim().to_owned(), parse_level(level.trim())?)),
+                None => levels.default = parse_level(directive)?,
+            }
+        }
+        levels
+            .modules
+            .sort_by_key(|(module, _)| Reverse(module.len()));
+
+        Ok(levels)
+    }
+
+    fn max(&self) -> LevelFilter {
+        self.modules
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, |a, b| a.max(b))
+    }
+
+    pub fn get(&self, target: &str) -> LevelFilter {
+        self.modules
+            .iter()
+            .find(|(module, _)| {
+                target == module
+                    || target
+                        .strip_prefix(module.as_str())
+                        .is_some_and(|rest| rest.starts_with("::"))
+            })
+            .map_or(self.default, |(_, level)| *level)
+    }
+}
+
+struct Logger {
+    format: LogFormat,
+    levels: LogLevels,
+}
+
+/// Collects the key-values passed to the log macros, as in `log::info!("Sent", { count: 3 })`.
+struct KeyValues(Vec<(String, String)>);
+
+impl<'kvs> VisitSource<'kvs> for KeyValues {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        self.0.push((key.to_string(), value.to_string()));
+        Ok(())
+    }
+}
+
+fn format_record(format: LogFormat, record: &Record, context: Option<&LogContext>) -> String {
+    let time = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+    let mut key_values = KeyValues(vec![]);
+    let _ = record.key_values().visit(&mut key_values);
+
+    match format {
+        LogFormat::Pretty => {
+            let mut line = format!("{} {:5} {}", time, record.level(), record.target());
+            if let Some(context) = context {
+                line.push_str(&format!(" [{}", context.request_id));
+                if let Some(domain) = &context.domain {
+                    line.push_str(&format!(" {}", domain));
+                }
+                if let Some(pubkey) = &context.pubkey {
+                    line.push_str(&format!(" {}", pubkey));
+                }
+                line.push(']');
+            }
+            line.push_str(&format!(" {}", record.args()));
+            for (key, value) in &key_values.0 {
+                line.push_str(&format!(" {}={}", key, value));
+            }
+            line
+        }
+        LogFormat::Json => {
+            let mut entry = json!({
+                "time": time,
+                "level": record.level().as_str(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+            });
+            if let Some(context) = context {
+                entry["request_id"] = json!(context.request_id);
+                if let Some(domain) = &context.domain {
+                    entry["domain"] = json!(domain);
+                }
+                if let Some(pubkey) = &context.pubkey {
+                    entry["pubkey"] = json!(pubkey);
+                }
+            }
+            for (key, value) in key_values.0 {
+                entry[key] = json!(value);
+            }
+            entry.to_string()
+        }
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.levels.get(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let context = CONTEXT.with(|c| c.borrow().clone());
+        let line = format_record(self.format, record, context.as_deref());
+        // errors and warnings go to stderr, so they stand out even when stdout is discarded
+        let _ = if record.level() <= Level::Warn {
+            writeln!(io::stderr().lock(), "{}", line)
+        } else {
+            writeln!(io::stdout().lock(), "{}", line)
+        };
+    }
+
+    fn flush(&self) {
+        let _ = io::stdout().flush();
+    }
+}
+
+/// Sets up logging in `format`, at the levels given by `levels` (see [`LogLevels::parse`]).
+pub fn init(format: LogFormat, levels: &str) -> Result<(), String> {
+    let levels = LogLevels::parse(levels)?;
+    log::set_max_level(levels.max());
+    log::set_boxed_logger(Box::new(Logger { format, levels })).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_levels() {
+        let levels = LogLevels::parse("warn, servus=info,servus::watch=debug").unwrap();
+        assert_eq!(levels.get("tide::log::middleware"), LevelFilter::Warn);
+        assert_eq!(levels.get("servus"), LevelFilter::Info);
+        assert_eq!(levels.get("servus::site"), LevelFilter::Info);
+        assert_eq!(levels.get("servus::watch"), LevelFilter::Debug);
+        assert_eq!(levels.get("servus::watcher"), LevelFilter::Info);
+        assert_eq!(levels.get("servusx"), LevelFilter::Warn);
+        assert_eq!(levels.max(), LevelFilter::Debug);
+
+        assert_eq!(
+            LogLevels::parse("").unwrap().get("servus"),
+            LevelFilter::Info
+        );
+        assert!(LogLevels::parse("servus=loud").is_err());
+    }
+
+    #[test]
+    fn test_format_record() {
+        let context = LogContext {
+            request_id: "0000002a".to_owned(),
+            domain: Some("example.com".to_owned()),
+            pubkey: None,
+        };
+        let args = format_args!("Incoming event");
+        let record = Record::builder()
+            .args(args)
+            .level(Level::Info)
+            .target("servus")
+            .build();
+
+        let line = format_record(LogFormat::Pretty, &record, Some(&context));
+        assert!(line.ends_with(" INFO  servus [0000002a example.com] Incoming event"));
+
+        let line = format_record(LogFormat::Json, &record, Some(&context));
+        let entry: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(entry["message"], "Incoming event");
+        assert_eq!(entry["level"], "INFO");
+        assert_eq!(entry["request_id"], "0000002a");
+        assert_eq!(entry["domain"], "example.com");
+        assert!(entry.get("pubkey").is_none());
+
+        let line = format_record(LogFormat::Pretty, &record, None);
+        assert!(line.ends_with(" INFO  servus Incoming event"));
+    }
+}
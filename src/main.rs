@@ -1,83 +1,479 @@
+use async_std::io::{ReadExt, WriteExt};
 use base64::{engine::general_purpose::STANDARD, Engine};
 use bytes::Bytes;
-use chrono::Utc;
-use clap::Parser;
-use futures_util::stream::once;
-use git2::Repository;
-use http_types::{mime, Method};
+use clap::{Parser, Subcommand};
+use futures_util::future;
+use http_types::{mime, url::Position, Method};
 use multer::Multipart;
 use phf::{phf_map, phf_set};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::convert::Infallible;
+use sha2::{Digest, Sha256};
 use std::{
-    collections::HashMap,
-    fs::{self, File},
-    io::{self, BufRead, BufReader, Write},
-    path::PathBuf,
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    fs,
+    future::Future,
+    io::{self, BufRead, IsTerminal, Write},
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    path::{Path, PathBuf},
+    pin::Pin,
+    process,
     str::{self, FromStr},
-    sync::{Arc, RwLock},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
 };
-use tide::{http::StatusCode, log, Request, Response};
-use tide_acme::rustls_acme::caches::DirCache;
-use tide_acme::{AcmeConfig, TideRustlsExt};
-use tide_websockets::{Message, WebSocket, WebSocketConnection};
+use tide::{
+    http::StatusCode,
+    listener::{ConcurrentListener, Listener, ToListener},
+    log, Next, Request, Response,
+};
+use tide_acme::rustls_acme::acme::{
+    LETS_ENCRYPT_PRODUCTION_DIRECTORY, LETS_ENCRYPT_STAGING_DIRECTORY,
+};
+use tide_websockets::{Message, WebSocketConnection};
+use utoipa::ToSchema;
 
 mod admin {
     include!(concat!(env!("OUT_DIR"), "/admin.rs"));
 }
 
+mod activitypub;
+mod auth;
+mod backup;
+mod bench;
+mod build;
 mod content;
+mod cors;
+mod dns;
+mod error;
+mod filters;
+mod gallery;
+mod git;
+mod import;
+mod ipfs;
+mod lightning;
+mod limits;
+mod listener;
+mod logging;
+mod newsletter;
 mod nostr;
+mod notifier;
+mod openapi;
+mod paths;
+mod registration;
 mod resource;
+mod sanitize;
 mod sass;
+mod search;
+mod security;
+mod service;
 mod site;
+mod smtp;
 mod template;
 mod theme;
+mod tls;
+mod traffic;
+mod upstream;
 mod utils;
+mod watch;
+mod webhooks;
 
-use resource::{ContentSource, Resource, ResourceKind};
-use site::Site;
+use error::Context;
+use resource::{Resource, ResourceKind};
+use site::{Site, SiteOverview};
 use theme::Theme;
-
-const THEMES_REPO: &str = "https://github.com/servus-social/themes";
+use traffic::TrafficSummary;
+use utils::Shared;
 
 #[derive(Parser)]
 struct Cli {
-    #[clap(short('e'), long)]
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Contact email, for the ACME account and the relay information document
+    #[clap(short('e'), long, env = "SERVUS_CONTACT_EMAIL")]
     contact_email: Option<String>,
 
-    #[clap(short('c'), long)]
+    /// Certificate (PEM) to use for SSL, along with --ssl-key
+    #[clap(short('c'), long, env = "SERVUS_SSL_CERT")]
     ssl_cert: Option<String>,
 
-    #[clap(short('k'), long)]
+    /// Private key (PKCS8) of the certificate given using --ssl-cert
+    #[clap(short('k'), long, env = "SERVUS_SSL_KEY")]
     ssl_key: Option<String>,
 
-    #[clap(short('s'), long)]
+    /// Obtain certificates from the Let's Encrypt staging environment using ACME
+    #[clap(short('s'), long, env = "SERVUS_SSL_ACME")]
     ssl_acme: bool,
 
-    #[clap(long)]
+    /// Obtain certificates from Let's Encrypt using ACME
+    #[clap(long, env = "SERVUS_SSL_ACME_PRODUCTION")]
     ssl_acme_production: bool,
 
-    #[clap(short('b'), long)]
-    bind: Option<String>,
+    /// Domain under which sites can be created using just a name, such as <NAME>.<TENANT_DOMAIN>
+    #[clap(long, env = "SERVUS_TENANT_DOMAIN")]
+    tenant_domain: Option<String>,
+
+    /// Site to serve for hosts that are not the domain (or an alias) of any site, such as the server's IP
+    #[clap(long, env = "SERVUS_DEFAULT_SITE")]
+    default_site: Option<String>,
+
+    /// Wildcard certificate (PEM) for *.<TENANT_DOMAIN>, along with --tenant-key
+    #[clap(long, env = "SERVUS_TENANT_CERT", requires = "tenant_key")]
+    tenant_cert: Option<String>,
+
+    /// Private key (PKCS8) of the certificate given using --tenant-cert
+    #[clap(long, env = "SERVUS_TENANT_KEY", requires = "tenant_cert")]
+    tenant_key: Option<String>,
+
+    /// Public IPs of the server, which a domain must resolve to before a certificate is ordered for it using ACME
+    #[clap(long("public-ip"), env = "SERVUS_PUBLIC_IPS", value_delimiter = ',')]
+    public_ips: Vec<IpAddr>,
 
-    #[clap(short('p'), long)]
+    /// Address to listen on, such as 0.0.0.0 or [::]:443, which can be given more than once
+    /// (defaults to 0.0.0.0, on the port given by --port)
+    #[clap(short('b'), long, env = "SERVUS_BIND", value_delimiter = ',')]
+    bind: Vec<String>,
+
+    /// Port to listen on (defaults to 443 when using SSL, 4884 otherwise)
+    #[clap(short('p'), long, env = "SERVUS_PORT")]
     port: Option<u32>,
+
+    /// When using SSL, port on which plain HTTP requests are redirected to HTTPS
+    #[clap(long, env = "SERVUS_HTTP_REDIRECT_PORT", default_value_t = 80)]
+    http_redirect_port: u32,
+
+    /// When using SSL, don't redirect plain HTTP requests to HTTPS
+    #[clap(long, env = "SERVUS_NO_HTTP_REDIRECT")]
+    no_http_redirect: bool,
+
+    /// Longest relay message accepted, in bytes
+    #[clap(long, env = "SERVUS_MAX_MESSAGE_LENGTH", default_value_t = 131072)]
+    max_message_length: usize,
+
+    /// Largest body of a request other than an upload (such as the JSON of the API), in bytes
+    #[clap(long, env = "SERVUS_MAX_BODY_SIZE", default_value_t = 1048576)]
+    max_body_size: usize,
+
+    /// How long clients have to send the body of a request other than an upload, in seconds
+    #[clap(long, env = "SERVUS_BODY_TIMEOUT", default_value_t = 30)]
+    body_timeout: u64,
+
+    /// Largest file (or content archive) that can be uploaded, in bytes
+    #[clap(long, env = "SERVUS_MAX_UPLOAD_SIZE", default_value_t = 1073741824)]
+    max_upload_size: usize,
+
+    /// How long clients have to send a file (or content archive) being uploaded, in seconds
+    #[clap(long, env = "SERVUS_UPLOAD_TIMEOUT", default_value_t = 3600)]
+    upload_timeout: u64,
+
+    /// Most relay subscriptions a connection can have open
+    #[clap(long, env = "SERVUS_MAX_SUBSCRIPTIONS", default_value_t = 20)]
+    max_subscriptions: usize,
+
+    /// Most filters a relay subscription can have
+    #[clap(long, env = "SERVUS_MAX_FILTERS", default_value_t = 10)]
+    max_filters: usize,
+
+    /// Language of the sites that don't specify one
+    #[clap(long, env = "SERVUS_DEFAULT_LANGUAGE", default_value = "en")]
+    default_language: String,
+
+    /// Charset of the sites that don't specify one
+    #[clap(long, env = "SERVUS_DEFAULT_CHARSET", default_value = "utf-8")]
+    default_charset: String,
+
+    /// Only load the content of sites when they are first requested, keeping at most this many loaded
+    /// (unloading the ones used least recently), rather than loading all of them on startup
+    #[clap(long, env = "SERVUS_MAX_LOADED_SITES")]
+    max_loaded_sites: Option<usize>,
+
+    /// Reload themes and sites when their files change
+    #[clap(long, env = "SERVUS_WATCH")]
+    watch: bool,
+
+    /// Write the PID to this file once ready to serve requests
+    #[clap(long, env = "SERVUS_PID_FILE")]
+    pid_file: Option<String>,
+
+    /// Config file (defaults to servus.toml in the data dir or the current directory, if any)
+    #[clap(long, env = "SERVUS_CONFIG")]
+    config: Option<String>,
+
+    /// Directory holding sites, themes, the ACME cache and the archives (defaults to the current directory)
+    #[clap(long, env = "SERVUS_DATA_DIR")]
+    data_dir: Option<String>,
+
+    /// Directory holding the sites (defaults to "sites" in the data dir)
+    #[clap(long, env = "SERVUS_SITES_DIR")]
+    sites_dir: Option<String>,
+
+    /// Directory holding the themes (defaults to "themes" in the data dir)
+    #[clap(long, env = "SERVUS_THEMES_DIR")]
+    themes_dir: Option<String>,
+
+    /// Directory holding the certificates obtained using ACME (defaults to "cache" in the data dir)
+    #[clap(long, env = "SERVUS_ACME_CACHE_DIR")]
+    acme_cache_dir: Option<String>,
+
+    /// Directory the archives of deleted sites are stored in (defaults to "archives" in the data dir)
+    #[clap(long, env = "SERVUS_ARCHIVES_DIR")]
+    archives_dir: Option<String>,
+
+    /// Honor the X-Forwarded-Host and X-Forwarded-Proto headers set by a reverse proxy
+    #[clap(long, env = "SERVUS_BEHIND_PROXY")]
+    behind_proxy: bool,
+
+    /// IPs of the reverse proxies whose X-Forwarded-* headers are trusted
+    #[clap(
+        long,
+        env = "SERVUS_TRUSTED_PROXIES",
+        value_delimiter = ',',
+        default_value = "127.0.0.1,::1"
+    )]
+    trusted_proxies: Vec<IpAddr>,
+
+    /// Public hosts of the server, which the URL signed for NIP-98 authorization can name
+    /// instead of the requested one, when the reverse proxy doesn't forward it
+    #[clap(long, env = "SERVUS_TRUSTED_HOSTS", value_delimiter = ',')]
+    trusted_hosts: Vec<String>,
+
+    /// Never ask anything on startup, even when there are no themes or sites (always the case without a terminal)
+    #[clap(long, env = "SERVUS_NO_PROMPT")]
+    no_prompt: bool,
+
+    /// Fetch the themes from the Servus themes repo on startup if there are none, without asking
+    #[clap(long, env = "SERVUS_FETCH_THEMES")]
+    fetch_themes: bool,
+
+    /// Create a site for this domain on startup, unless it exists already
+    #[clap(long, env = "SERVUS_CREATE_SITE")]
+    create_site: Option<String>,
+
+    /// Pubkey (hex or npub) of the admin of the site created using --create-site
+    #[clap(long, env = "SERVUS_ADMIN_PUBKEY", requires = "create_site")]
+    admin_pubkey: Option<String>,
+
+    /// Pubkeys (hex or npub) of the server's operators, who can list all the sites, create sites (for themselves or for others) and set quotas
+    #[clap(long, env = "SERVUS_OPERATOR_PUBKEY", value_delimiter = ',')]
+    operator_pubkey: Vec<String>,
+
+    /// Let any key create sites, rather than only the operators
+    #[clap(long, env = "SERVUS_ALLOW_OPEN_REGISTRATION")]
+    allow_open_registration: bool,
+
+    /// Only create new sites given an invite code, or once approved by an operator
+    #[clap(long, env = "SERVUS_REQUIRE_APPROVAL")]
+    require_approval: bool,
+
+    /// How many sites each key other than the operators' can have, when anyone can create (or ask for) sites
+    #[clap(long, env = "SERVUS_MAX_SITES_PER_PUBKEY")]
+    max_sites_per_pubkey: Option<usize>,
+
+    /// Kubo RPC API of an IPFS node (or pinning service) to pin uploaded files to, as in "http://127.0.0.1:5001"
+    #[clap(long, env = "SERVUS_IPFS_API")]
+    ipfs_api: Option<String>,
+
+    /// Bearer token for the API given using --ipfs-api
+    #[clap(
+        long,
+        env = "SERVUS_IPFS_API_TOKEN",
+        hide_env_values = true,
+        requires = "ipfs_api"
+    )]
+    ipfs_api_token: Option<String>,
+
+    /// IPFS gateway the files pinned to IPFS can be downloaded from
+    #[clap(long, env = "SERVUS_IPFS_GATEWAY", default_value = "https://ipfs.io")]
+    ipfs_gateway: String,
+
+    /// Whether to also log each request made to a site, in `traffic/<domain>.log` in the data dir
+    #[clap(long, env = "SERVUS_ACCESS_LOG", value_enum, default_value_t = traffic::AccessLog::Off)]
+    access_log: traffic::AccessLog,
+
+    /// How log messages are written
+    #[clap(long, env = "SERVUS_LOG_FORMAT", value_enum, default_value_t = logging::LogFormat::Pretty)]
+    log_format: logging::LogFormat,
+
+    /// Levels of the messages logged, by default and per module, as in "info,servus::watch=debug,tide=warn"
+    #[clap(long, env = "SERVUS_LOG", default_value = "info")]
+    log_level: String,
+}
+
+impl Cli {
+    /// The directories given on the command line (or in the environment), which take precedence over `servus.toml`.
+    fn get_dirs(&self) -> paths::ServusConfig {
+        paths::ServusConfig {
+            data_dir: self.data_dir.clone(),
+            sites_dir: self.sites_dir.clone(),
+            themes_dir: self.themes_dir.clone(),
+            acme_cache_dir: self.acme_cache_dir.clone(),
+            archives_dir: self.archives_dir.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Copy a site's config and content to a new domain
+    CloneSite { domain: String, new_domain: String },
+
+    /// Render a site to static files, which can be deployed to any static host
+    Build {
+        /// Domain of the site to build
+        #[clap(long)]
+        site: String,
+
+        /// Directory to write the files to
+        #[clap(long, default_value = "public")]
+        out: PathBuf,
+    },
+
+    /// Back up a site (its config, content, uploaded files, certificate and key) to a single `.tar.zst` file
+    Backup {
+        /// Domain of the site to back up
+        #[clap(long)]
+        site: String,
+
+        /// File to write the backup to (defaults to `<site>-<date>.tar.zst`)
+        #[clap(long)]
+        out: Option<PathBuf>,
+    },
+
+    /// Restore a site from a backup, once the whole backup is verified
+    Restore {
+        /// Backup made using `backup`
+        file: PathBuf,
+
+        /// Only verify the backup, without restoring anything
+        #[clap(long)]
+        verify: bool,
+
+        /// Replace the site if it already exists (it is archived first)
+        #[clap(long)]
+        force: bool,
+    },
+
+    /// Import the posts, pages and static files of a site made using Jekyll, Hugo or Zola
+    ImportSsg {
+        /// Generator the site was made using
+        #[clap(long, value_enum)]
+        format: import::SsgFormat,
+
+        /// Root directory of the site to import
+        #[clap(long)]
+        path: PathBuf,
+
+        /// Domain of the site to import into
+        #[clap(long)]
+        site: String,
+
+        /// Secret key of the site (as hex or as an nsec), to import posts and pages as events signed by it
+        #[clap(long, env = "SERVUS_IMPORT_SECRET_KEY", hide_env_values = true)]
+        sign_with: Option<String>,
+    },
+
+    /// Check the themes and sites for problems, without starting the server
+    Check,
+
+    /// Measure how fast a synthetic site is rendered, queried and uploaded to
+    Bench {
+        /// Number of posts of the site
+        #[clap(long, default_value_t = 1000)]
+        posts: usize,
+
+        /// How many times each page is rendered and each query is run
+        #[clap(long, default_value_t = 3)]
+        rounds: usize,
+
+        /// Number of files uploaded
+        #[clap(long, default_value_t = 20)]
+        uploads: usize,
+
+        /// Size of the files uploaded, in KiB
+        #[clap(long, default_value_t = 1024)]
+        upload_size: usize,
+
+        /// Theme of the site (defaults to the one of --site, or hyde)
+        #[clap(long)]
+        theme: Option<String>,
+
+        /// Existing site whose config (such as its theme and its settings) is used
+        #[clap(long)]
+        site: Option<String>,
+    },
+
+    /// Manage themes
+    Theme {
+        #[command(subcommand)]
+        command: ThemeCommand,
+    },
+}
+
+#[derive(Subcommand)]
+enum ThemeCommand {
+    /// Install a theme, given its git URL or its name in the Servus themes repo
+    Install { source: String },
+
+    /// List the installed themes and whether they can be used
+    List,
+
+    /// Update a theme (or all of them) to the latest version
+    Update { name: Option<String> },
 }
 
 #[derive(Clone)]
 struct State {
-    themes: Arc<RwLock<HashMap<String, Theme>>>,
-    sites: Arc<RwLock<HashMap<String, Site>>>,
+    themes: Arc<Shared<HashMap<String, Theme>>>,
+    sites: Arc<Shared<HashMap<String, Site>>>,
+    contact_email: Option<String>,
+    relay_limits: nostr::RelayLimits,
+    body_limits: limits::BodyLimits,
+    upload_limits: limits::BodyLimits,
+    default_language: String,
+    default_charset: String,
+    trusted_proxies: Vec<IpAddr>, // empty unless running behind a proxy
+    trusted_hosts: Arc<Vec<String>>,
+    acme: Option<Arc<tls::AcmeResolver>>, // only when using ACME
+    websockets: service::WebSockets,
+    listeners: Arc<Vec<listener::SiteListener>>,
+    max_loaded_sites: Option<usize>, // only when loading sites lazily
+    deletion_tokens: Arc<Mutex<HashMap<String, (String, Instant)>>>, // domain -> token confirming its deletion
+    operator_pubkeys: Arc<Vec<String>>,
+    allow_open_registration: bool,
+    require_approval: bool,
+    max_sites_per_pubkey: Option<usize>, // only for the keys of non-operators
+    tenant_domain: Option<String>,
+    default_site: Option<String>, // for unknown hosts
+    /// The tenant domain, if there is a wildcard certificate for its subdomains.
+    wildcard_domain: Option<String>,
+    traffic: traffic::Traffic,
+    ipfs: Option<ipfs::IpfsConfig>, // only when pinning uploaded files
+    notifiers: Arc<Vec<notifier::NotifierConfig>>,
+    cors: Arc<cors::CorsConfig>, // unless the site has its own
+    security_headers: Arc<security::SecurityHeadersConfig>, // unless the site has its own
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, ToSchema)]
 struct PostSiteRequestBody {
     domain: String,
+    /// The owner of the new site, if not the one creating it, which only the operator can do.
+    pubkey: Option<String>,
+    /// The code letting the site be created right away when sites need approval.
+    invite: Option<String>,
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize)]
+struct SubscribeRequestBody {
+    email: String,
+}
+
+#[derive(Deserialize, Serialize, ToSchema)]
 struct PutSiteConfigRequestBody {
     theme: String,
 }
@@ -86,6 +482,7 @@ static NIP96_CONTENT_TYPES: phf::Map<&'static str, &'static str> = phf_map! {
     "image/png" => "png",
     "image/jpeg" => "jpg",
     "image/gif" => "gif",
+    "image/svg+xml" => "svg",
     "audio/mpeg" => "mp3",
 };
 
@@ -94,56 +491,387 @@ static BLOSSOM_CONTENT_TYPES: phf::Set<&'static str> = phf_set! {
     "image/gif",
     "image/jpeg",
     "image/png",
+    "image/svg+xml",
     "image/webp",
 };
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
 struct FileMetadata {
     sha256: String,
     #[serde(rename = "type")]
     content_type: String,
     size: usize,
     url: String,
+    /// Once pinned to IPFS.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cid: Option<String>,
 }
 
-fn build_raw_response(content: Vec<u8>, mime: mime::Mime) -> Response {
+fn build_raw_response(content: impl Into<tide::Body>, mime: mime::Mime) -> Response {
     Response::builder(StatusCode::Ok)
         .content_type(mime)
-        .header("Access-Control-Allow-Origin", "*")
-        .body(&*content)
+        .body(content)
         .build()
 }
 
-fn get_resource(site: &Site, resource_path: &str) -> Resource {
-    let resources = site.resources.read().unwrap();
-    resources.get(resource_path).unwrap().clone()
+/// Like `build_raw_response`, but lets clients cache the asset forever
+/// when requested through a cache-busted URL (`get_url(..., cachebust=true)`) matching its content.
+fn build_asset_response(
+    request: &Request<State>,
+    site: &Site,
+    path: &str,
+    content: impl Into<tide::Body>,
+    mime: mime::Mime,
+) -> Response {
+    let hash = request
+        .url()
+        .query_pairs()
+        .find(|(k, _)| k == "h")
+        .map(|(_, v)| v.to_string());
+    let is_fingerprinted = hash.is_some_and(|h| Some(h) == site.assets.get_cachebust_hash(path));
+
+    let mut response = build_raw_response(content, mime);
+    if is_fingerprinted {
+        response.insert_header("Cache-Control", "public, max-age=31536000, immutable");
+    }
+
+    response
+}
+
+/// A file of the admin interface, which can be cached for good when requested with the current version,
+/// as the index does, since that changes whenever any of the files does.
+fn build_admin_asset_response(request: &Request<State>, name: &str) -> Response {
+    let content = admin::FILES
+        .iter()
+        .find(|(n, _)| *n == name && *n != "index.html")
+        .map(|(_, content)| *content);
+    let Some(content) = content else {
+        return Response::new(StatusCode::NotFound);
+    };
+    let guess = mime_guess::from_path(name).first_or_octet_stream();
+    let mime = mime::Mime::from_str(guess.essence_str()).unwrap();
+    let is_versioned = request
+        .url()
+        .query_pairs()
+        .any(|(k, v)| k == "h" && v == admin::VERSION);
+
+    let mut response = build_raw_response(content, mime);
+    if is_versioned {
+        response.insert_header("Cache-Control", "public, max-age=31536000, immutable");
+    } else {
+        response.insert_header("Cache-Control", "no-cache");
+    }
+
+    response
+}
+
+/// Whether the `If-None-Match` header lists `etag` (or is `*`), using the weak comparison, as for `GET` requests.
+fn is_etag_matched(if_none_match: &str, etag: &str) -> bool {
+    if_none_match
+        .split(',')
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
 }
 
-fn render_and_build_response(site: &Site, resource: Resource) -> Response {
-    Response::builder(StatusCode::Ok)
-        .content_type(mime::HTML)
-        .header("Access-Control-Allow-Origin", "*")
-        .body(&*resource.render(site))
-        .build()
+/// Sets the `ETag` of `response` to a hash of its `body`,
+/// answering with a `304 Not Modified` instead if the client already has that version.
+fn set_etag(request: &Request<State>, mut response: Response, body: &[u8]) -> Response {
+    let etag = format!("\"{}\"", &utils::sha256_hex(body)[..32]);
+    if request
+        .header("If-None-Match")
+        .is_some_and(|h| is_etag_matched(h.as_str(), &etag))
+    {
+        return Response::builder(StatusCode::NotModified)
+            .header("ETag", etag)
+            .build();
+    }
+    response.insert_header("ETag", etag);
+
+    response
+}
+
+/// Encodes `html` using `charset`, returning the encoding actually used,
+/// which is UTF-8 for unknown charsets and for those that cannot be used for output (like UTF-16).
+fn encode_html<'a>(
+    html: &'a str,
+    charset: &str,
+) -> (Cow<'a, [u8]>, &'static encoding_rs::Encoding) {
+    let encoding = encoding_rs::Encoding::for_label(charset.as_bytes()).unwrap_or_else(|| {
+        log::warn!("Unknown charset: {}. Using UTF-8.", charset);
+        encoding_rs::UTF_8
+    });
+
+    // NB: characters that cannot be represented in the charset are encoded as HTML entities
+    let (body, encoding, _) = encoding.encode(html);
+
+    (body, encoding)
+}
+
+/// The language and the charset the site's pages are rendered in: the site's own, or the server's defaults.
+fn get_language_and_charset<'a>(state: &'a State, site: &'a Site) -> (&'a str, &'a str) {
+    let language = site
+        .config
+        .default_language
+        .as_ref()
+        .unwrap_or(&state.default_language);
+    let charset = site
+        .config
+        .charset
+        .as_ref()
+        .unwrap_or(&state.default_charset);
+
+    (language, charset)
+}
+
+/// Renders the resource (see [`Resource::render`]) as a response, or a 404 if there is no such pager.
+/// Should rendering fail, such as when the theme's templates are broken, the failure is logged and a 500 sent.
+async fn render_and_build_response(
+    request: &Request<State>,
+    site: &Site,
+    resource: Resource,
+    pager: usize,
+) -> Response {
+    let (language, charset) = get_language_and_charset(request.state(), site);
+
+    // rendering reads the content files, unless it was already rendered
+    let noindex = resource.noindex;
+    let html = {
+        let (site, language) = (site.clone(), language.to_owned());
+        logging::spawn_blocking(move || resource.render(&site, &language, pager)).await
+    };
+    match html {
+        Ok(html) => build_html_response(request, &html, language, charset, noindex),
+        Err(e) => e.into(),
+    }
+}
+
+fn build_html_response(
+    request: &Request<State>,
+    html: &str,
+    language: &str,
+    charset: &str,
+    noindex: bool,
+) -> Response {
+    let (body, encoding) = encode_html(html, charset);
+
+    let mut response = Response::builder(StatusCode::Ok)
+        .content_type(
+            mime::Mime::from_str(&format!(
+                "text/html;charset={}",
+                encoding.name().to_lowercase()
+            ))
+            .unwrap(),
+        )
+        .header("Content-Language", language)
+        .body(&*body)
+        .build();
+    if noindex {
+        // works even if the theme doesn't include the robots meta tag
+        response.insert_header("X-Robots-Tag", "noindex");
+    }
+
+    set_etag(request, response, &body)
+}
+
+/// Renders the event with the given ID (hex, `note1...` or `nevent1...`) using the theme, fetching it from the upstream relays,
+/// or redirects to its page if it is part of the site's content.
+async fn render_external_event(request: &Request<State>, site: &Site, id: &str) -> Response {
+    let Some(id) = nostr::parse_event_id(id) else {
+        return Response::new(StatusCode::NotFound);
+    };
+    if let Some(resource) = site
+        .get_content()
+        .resources
+        .values()
+        .find(|r| matches!(&r.content_source, resource::ContentSource::Event(e) if *e == id))
+    {
+        return Response::builder(StatusCode::Found)
+            .header("Location", resource.get_permalink(&site.config))
+            .build();
+    }
+
+    let filter = nostr::Filter {
+        ids: Some(vec![id.to_owned()]),
+        authors: None,
+        kinds: Some(vec![nostr::EVENT_KIND_NOTE, nostr::EVENT_KIND_LONG_FORM]),
+        since: None,
+        until: None,
+        limit: Some(1),
+        extra: HashMap::new(),
+    };
+    let events = upstream::get_events(
+        &site.upstream_cache,
+        &site.config.upstream_relays,
+        &format!("e-{}", &id[..8]),
+        &[filter],
+    )
+    .await;
+    let Some(event) = events.into_iter().next() else {
+        return Response::new(StatusCode::NotFound);
+    };
+
+    let (language, charset) = get_language_and_charset(request.state(), site);
+    let html = {
+        let (site, language) = (site.clone(), language.to_owned());
+        logging::spawn_blocking(move || resource::render_external_event(&site, &event, &language))
+            .await
+    };
+
+    match html {
+        Ok(html) => build_html_response(request, &html, language, charset, true),
+        Err(e) => e.into(),
+    }
+}
+
+/// Renders the index of the site's notes (see [`resource::render_notes_index`]).
+async fn render_notes_index(request: &Request<State>, site: &Site) -> Response {
+    if let Some(redirect) = get_permalink_redirect(
+        request,
+        site,
+        site.config.make_permalink(resource::NOTES_INDEX_URL),
+    ) {
+        return redirect;
+    }
+
+    let (language, charset) = get_language_and_charset(request.state(), site);
+    let html = {
+        let (site, language) = (site.clone(), language.to_owned());
+        logging::spawn_blocking(move || resource::render_notes_index(&site, &language)).await
+    };
+
+    match html {
+        Ok(html) => build_html_response(request, &html, language, charset, false),
+        Err(e) => e.into(),
+    }
+}
+
+/// Renders the gallery of the site's uploaded images (see [`resource::render_gallery`]).
+async fn render_gallery(
+    request: &Request<State>,
+    site: &Site,
+    config: &gallery::GalleryConfig,
+) -> Response {
+    if let Some(redirect) = get_permalink_redirect(
+        request,
+        site,
+        site.config.make_permalink(gallery::GALLERY_URL),
+    ) {
+        return redirect;
+    }
+
+    let (language, charset) = get_language_and_charset(request.state(), site);
+    let html = {
+        let (site, config, language) = (site.clone(), config.clone(), language.to_owned());
+        logging::spawn_blocking(move || resource::render_gallery(&site, &config, &language)).await
+    };
+
+    match html {
+        Ok(html) => build_html_response(request, &html, language, charset, false),
+        Err(e) => e.into(),
+    }
+}
+
+/// A thumbnail of an image of the gallery, made the first time it is requested.
+async fn build_thumbnail_response(
+    request: &Request<State>,
+    site: &Site,
+    config: &gallery::GalleryConfig,
+    name: &str,
+) -> error::Result<Response> {
+    let thumbnail = {
+        let (site, config, name) = (site.clone(), config.clone(), name.to_owned());
+        logging::spawn_blocking(move || gallery::get_thumbnail(&site, &config, &name)).await
+    };
+    let Some(path) = thumbnail.context(|| format!("Cannot make a thumbnail of {}", name))? else {
+        return Ok(Response::new(StatusCode::NotFound));
+    };
+    let content = async_std::fs::read(&path)
+        .await
+        .context(|| "Cannot read a thumbnail")?;
+    let response = build_raw_response(content.clone(), guess_mime(name));
+
+    Ok(set_etag(request, response, &content))
+}
+
+/// Notifies the operator of a comment on (or a reaction to) the site's content, which the relay doesn't store.
+fn notify_interaction(state: &State, site: &Site, event: &nostr::Event) {
+    let author = nostr::pubkey_to_npub(&event.pubkey).unwrap_or_else(|| event.pubkey.to_owned());
+    let (name, description) = match event.kind {
+        nostr::EVENT_KIND_NOTE | nostr::EVENT_KIND_COMMENT => ("comment.received", "comment"),
+        nostr::EVENT_KIND_REACTION => ("reaction.received", "reaction"),
+        _ => return,
+    };
+    notifier::notify(
+        &state.notifiers,
+        name,
+        &site.domain,
+        &format!(
+            "New {} by {}: {}",
+            description,
+            author,
+            notifier::quote(&event.content)
+        ),
+    );
+}
+
+/// Notifies the operator of `event` being published to the site's relay.
+fn notify_published(state: &State, site: &Site, event: &nostr::Event) {
+    let text = match event.kind {
+        nostr::EVENT_KIND_LONG_FORM => {
+            format!("Published “{}”", event.get_tag("title").unwrap_or_default())
+        }
+        nostr::EVENT_KIND_NOTE => format!("New note: {}", notifier::quote(&event.content)),
+        kind => format!("New event of kind {}: {}", kind, event.id),
+    };
+    notifier::notify(&state.notifiers, "event.published", &site.domain, &text);
+}
+
+/// Notifies the operator of an upload to the site having failed, because of `reason`.
+fn notify_upload_failure(state: &State, site: &Site, reason: &str) {
+    notifier::notify(
+        &state.notifiers,
+        "upload.failed",
+        &site.domain,
+        &format!("Upload failed: {}", reason),
+    );
 }
 
 async fn handle_websocket(
     request: Request<State>,
     mut ws: WebSocketConnection,
 ) -> tide::Result<()> {
-    while let Some(Ok(Message::Text(message))) = async_std::stream::StreamExt::next(&mut ws).await {
+    let limits = request.state().relay_limits.clone();
+    let _guard = request.state().websockets.register(&ws);
+    let mut subscriptions: HashSet<String> = HashSet::new();
+    loop {
+        let message = match async_std::stream::StreamExt::next(&mut ws).await {
+            Some(Ok(Message::Text(message))) => message,
+            // longer than the relay's max_message_length, which the connection enforces
+            Some(Err(async_tungstenite::tungstenite::Error::Capacity(e))) => {
+                log::info!("Closing connection: {}.", e);
+                ws.send_json(&json!(["NOTICE", "error: message too long"]))
+                    .await?;
+                break;
+            }
+            _ => break,
+        };
         log::debug!("WS RECV: {}", message);
-        let nostr_message = nostr::Message::from_str(&message);
-        if nostr_message.is_err() {
+        let Ok(nostr_message) = nostr::Message::from_str(&message) else {
             log::warn!("Cannot parse: {}", message);
             continue;
-        }
-        match nostr_message.unwrap() {
+        };
+        match nostr_message {
             nostr::Message::Event { event } => {
                 {
                     if let Some(site) = get_site(&request) {
-                        if let Some(site_pubkey) = site.config.pubkey {
-                            if event.pubkey != site_pubkey {
+                        if let Some(site_pubkey) = &site.config.pubkey {
+                            if !auth::keys_equal(&event.pubkey, site_pubkey) {
+                                if !request.state().notifiers.is_empty()
+                                    && event.mentions(site_pubkey)
+                                    && event.validate_sig().is_ok()
+                                {
+                                    notify_interaction(request.state(), &site, &event);
+                                }
                                 log::info!("Ignoring event for unknown pubkey: {}.", event.pubkey);
                                 continue;
                             }
@@ -162,8 +890,37 @@ async fn handle_websocket(
                 }
 
                 if let Some(site) = get_site(&request) {
+                    let stored_version = {
+                        let (site, event) = (site.clone(), event.clone());
+                        logging::spawn_blocking(move || site.get_stored_version(&event)).await
+                    };
+                    let reply = match stored_version {
+                        Some(site::StoredVersion::Same) => {
+                            Some((true, "duplicate: already have this event"))
+                        }
+                        Some(site::StoredVersion::Newer) => {
+                            Some((false, "invalid: a newer version of this event is stored"))
+                        }
+                        None => None,
+                    };
+                    if let Some((accepted, message)) = reply {
+                        log::info!("Ignoring event: {} ({}).", event.id, message);
+                        ws.send_json(&json!(["OK", event.id, accepted, message]))
+                            .await?;
+                        continue;
+                    }
+
+                    auth::audit(
+                        &event.pubkey,
+                        auth::Role::Owner,
+                        &format!("EVENT {} (kind {})", event.id, event.kind),
+                        Some(&site.domain),
+                    );
                     if event.kind == nostr::EVENT_KIND_DELETE {
-                        let post_removed = site.remove_content(&event);
+                        let post_removed = {
+                            let (site, event) = (site.clone(), event.clone());
+                            logging::spawn_blocking(move || site.remove_content(&event)).await
+                        };
                         log::info!(
                             "Incoming DELETE event: {}. status: {}",
                             event.id,
@@ -175,58 +932,102 @@ async fn handle_websocket(
                             serde_json::Value::Bool(post_removed),
                             serde_json::Value::String("".to_string())
                         ]))
-                        .await
-                        .unwrap();
+                        .await?;
                     } else {
-                        site.add_content(&event);
+                        let is_over_quota = {
+                            let site = site.clone();
+                            logging::spawn_blocking(move || site.is_over_quota()).await
+                        };
+                        if is_over_quota {
+                            log::info!("Ignoring event over the storage quota: {}.", event.id);
+                            ws.send_json(&json!(["OK", event.id, false, QUOTA_EXCEEDED_MESSAGE]))
+                                .await?;
+                            continue;
+                        }
+                        {
+                            let (site, event) = (site.clone(), event.clone());
+                            logging::spawn_blocking(move || site.add_content(&event)).await;
+                        }
                         log::info!("Incoming event: {}.", event.id);
+                        notify_published(request.state(), &site, &event);
+                        if event.kind == nostr::EVENT_KIND_THEME_SETTINGS {
+                            // merge the new settings into the config of the site being served
+                            if let Some(settings) = site.get_theme_settings() {
+                                let mut site = site;
+                                site.config.apply_theme_settings(&settings);
+                                let render_cache = site.get_content().render_cache.clone();
+                                request
+                                    .state()
+                                    .sites
+                                    .update(|sites| sites.insert(site.domain.to_owned(), site));
+                                // pages rendered with the previous settings
+                                render_cache.clear();
+                            }
+                        }
                         ws.send_json(&json!(vec![
                             serde_json::Value::String("OK".to_string()),
                             serde_json::Value::String(event.id.to_string()),
                             serde_json::Value::Bool(true),
                             serde_json::Value::String("".to_string())
                         ]))
-                        .await
-                        .unwrap();
+                        .await?;
                     }
                 } else {
                     return Ok(());
                 }
             }
             nostr::Message::Req { sub_id, filters } => {
+                if filters.len() > limits.max_filters {
+                    ws.send_json(&json!(["CLOSED", &sub_id, "error: too many filters"]))
+                        .await?;
+                    continue;
+                }
+                if !subscriptions.contains(&sub_id)
+                    && subscriptions.len() >= limits.max_subscriptions
+                {
+                    ws.send_json(&json!(["CLOSED", &sub_id, "error: too many subscriptions"]))
+                        .await?;
+                    continue;
+                }
+                subscriptions.insert(sub_id.to_owned());
+
                 let mut events: Vec<nostr::Event> = vec![]; // Hashmap? (unique)
 
                 if let Some(site) = get_site(&request) {
-                    let site_pubkey = site.config.pubkey.unwrap();
+                    // a site without a key has no author to tell apart
+                    let site_pubkey = site.config.pubkey.clone().unwrap_or_default();
+                    let mut upstream_filters = vec![];
                     for filter in filters.iter() {
-                        for (k, _) in &filter.extra {
-                            log::warn!("Ignoring unknown filter: {}.", k);
+                        for k in filter.extra.keys() {
+                            if !k.starts_with('#') {
+                                log::warn!("Ignoring unknown filter: {}.", k);
+                            }
                         }
 
                         log::info!("Requested filter: {}", filter);
 
-                        if filter.matches_author(&site_pubkey) {
-                            for event_ref in site.events.read().unwrap().values() {
-                                if filter.matches_kind(&event_ref.kind)
-                                    && filter.matches_time(&event_ref.created_at)
-                                {
-                                    if let Some((front_matter, content)) = event_ref.read() {
-                                        if let Some(event) =
-                                            nostr::parse_event(&front_matter, &content)
-                                        {
-                                            if filter.matches_author(&event.pubkey) {
-                                                events.push(event);
-                                                if let Some(limit) = filter.limit {
-                                                    if events.len() >= limit {
-                                                        break;
-                                                    }
-                                                }
-                                            }
-                                        }
-                                    }
-                                }
-                            }
+                        let filter_events = site.query_events(filter);
+
+                        let only_site_author = filter
+                            .authors
+                            .as_ref()
+                            .is_some_and(|a| a.iter().all(|a| site_pubkey.starts_with(a)));
+                        if filter_events.is_empty() && !only_site_author {
+                            upstream_filters.push(filter.clone());
                         }
+                        events.extend(filter_events);
+                    }
+
+                    if !upstream_filters.is_empty() && !site.config.upstream_relays.is_empty() {
+                        let upstream_events = upstream::get_events(
+                            &site.upstream_cache,
+                            &site.config.upstream_relays,
+                            &sub_id,
+                            &upstream_filters,
+                        )
+                        .await;
+                        log::info!("Got {} events from upstream.", upstream_events.len());
+                        events.extend(upstream_events);
                     }
                 } else {
                     return Ok(());
@@ -238,12 +1039,10 @@ async fn handle_websocket(
                         serde_json::Value::String(sub_id.to_string()),
                         event.to_json(),
                     ]))
-                    .await
-                    .unwrap();
+                    .await?;
                 }
                 ws.send_json(&json!(vec!["EOSE", &sub_id.to_string()]))
-                    .await
-                    .unwrap();
+                    .await?;
                 log::info!(
                     "Sent {} events back for subscription {}.",
                     events.len(),
@@ -253,192 +1052,786 @@ async fn handle_websocket(
                 // For that, we probably need to introduce a dispatcher thread.
                 // See: https://stackoverflow.com/questions/35673702/chat-using-rust-websocket/35785414#35785414
             }
-            nostr::Message::Close { .. } => {
-                // Nothing to do here, since we don't actually store subscriptions!
+            nostr::Message::Close { sub_id } => {
+                // We don't actually store subscriptions, we only count them.
+                subscriptions.remove(&sub_id);
             }
         }
     }
     Ok(())
 }
 
-async fn handle_index(request: Request<State>) -> tide::Result<Response> {
-    if let Some(site) = get_site(&request) {
-        let resources = site.resources.read().unwrap();
-        match resources.get("/index") {
-            Some(..) => Ok(render_and_build_response(
-                &site,
-                get_resource(&site, "/index"),
-            )),
-            None => Ok(render_and_build_response(
-                &site,
-                Resource {
-                    kind: ResourceKind::Page,
-                    slug: "index".to_string(),
-                    title: Some("".to_string()),
-                    date: Utc::now().naive_utc(),
-                    content_source: ContentSource::String("Servus, world!".to_string()),
-                },
-            )),
+fn render_nip11_json(request: &Request<State>, site: &Site) -> String {
+    let (event_count, storage_bytes) = site.get_stats();
+    json!({
+        "name": site.config.title.clone().unwrap_or(site.domain.to_owned()),
+        "description": "",
+        "pubkey": site.config.pubkey,
+        "contact": request.state().contact_email,
+        "supported_nips": nostr::SUPPORTED_NIPS,
+        "software": "https://github.com/servuscms/servus",
+        "version": env!("CARGO_PKG_VERSION"),
+        "limitation": request.state().relay_limits,
+        "stats": {
+            "event_count": event_count,
+            "storage_bytes": storage_bytes,
+        },
+    })
+    .to_string()
+}
+
+/// Whether the `Accept` header lists `media_type` (which might come along with others,
+/// or have parameters such as a `q`, in which case it needs to be greater than 0).
+fn accepts_media_type(accept: &str, media_type: &str) -> bool {
+    accept.split(',').any(|entry| {
+        let mut parts = entry.split(';').map(|p| p.trim());
+        if !parts.next().unwrap().eq_ignore_ascii_case(media_type) {
+            return false;
         }
-    } else {
-        return Ok(Response::new(StatusCode::NotFound));
+        parts
+            .filter_map(|p| p.strip_prefix("q="))
+            .all(|q| q.parse::<f32>().is_ok_and(|q| q > 0.0))
+    })
+}
+
+/// The theme given by `?preview_theme=`, to preview the site with.
+fn get_preview_theme(request: &Request<State>) -> Option<String> {
+    request
+        .url()
+        .query_pairs()
+        .find(|(k, _)| k == "preview_theme")
+        .map(|(_, v)| v.to_string())
+}
+
+/// The site as it would be with the theme given by `?preview_theme=`, which only its owner gets to see,
+/// or the site itself when not previewing a theme.
+fn get_previewed_site(request: &Request<State>, site: Site) -> Result<Site, StatusCode> {
+    let Some(theme) = get_preview_theme(request) else {
+        return Ok(site);
+    };
+    if !is_authorized(request, Some(&site), auth::Access::Owner, &nostr_auth) {
+        return Err(StatusCode::Forbidden);
     }
+
+    site.with_theme(&theme, &request.state().themes.load())
+        .map_err(|e| {
+            log::info!("Cannot preview theme {}: {}", theme, e);
+            StatusCode::BadRequest
+        })
 }
 
-fn get_site(request: &Request<State>) -> Option<Site> {
-    let host = request.host().unwrap().to_string();
-    let sites = request.state().sites.read().unwrap();
+async fn handle_index(request: Request<State>) -> tide::Result<Response> {
+    if let Some(site) = get_site(&request) {
+        let site = match get_previewed_site(&request, site) {
+            Ok(site) => site,
+            Err(status) => return Ok(Response::new(status)),
+        };
+        if let Some(accept) = request.header(tide::http::headers::ACCEPT) {
+            if accepts_media_type(accept.as_str(), "application/nostr+json") {
+                return Ok(Response::builder(StatusCode::Ok)
+                    .content_type(mime::Mime::from_str("application/nostr+json").unwrap())
+                    .body(render_nip11_json(&request, &site))
+                    .build());
+            }
+        }
+        if let Some(redirect) = get_canonical_redirect(&request, &site) {
+            return Ok(redirect);
+        }
 
-    if !sites.contains_key(&host) {
-        if sites.len() == 1 {
-            return Some(sites.values().into_iter().next().unwrap().clone());
-        } else {
-            return None;
+        match site.get_content().resources.get("/index") {
+            Some(resource) => {
+                Ok(render_and_build_response(&request, &site, resource.clone(), 1).await)
+            }
+            None => {
+                Ok(render_and_build_response(&request, &site, Resource::default_index(), 1).await)
+            }
         }
     } else {
-        return sites.get(&host).cloned();
+        Ok(Response::new(StatusCode::NotFound))
     }
 }
 
-async fn handle_request(request: Request<State>) -> tide::Result<Response> {
-    let mut path = request.param("path").unwrap();
-    if path.ends_with('/') {
-        path = path.strip_suffix('/').unwrap();
+/// The value of a `X-Forwarded-*` header, as long as the request comes from a trusted proxy.
+fn get_forwarded_header(request: &Request<State>, name: &str) -> Option<String> {
+    let peer_addr = request.peer_addr()?.parse::<SocketAddr>().ok()?;
+    if !request.state().trusted_proxies.contains(&peer_addr.ip()) {
+        return None;
     }
 
-    if path == ".admin" {
-        let admin_index = admin::INDEX_HTML.replace(
-            "%%API_BASE_URL%%",
-            &format!("//{}", request.host().unwrap()),
-        );
-        return Ok(Response::builder(StatusCode::Ok)
-            .content_type(mime::HTML)
-            .body(admin_index)
-            .build());
+    // proxies append to the list, so the last value is the one set by the trusted proxy,
+    // while any previous ones could have been made up by the client
+    let value = request
+        .header(name)?
+        .last()
+        .as_str()
+        .rsplit(',')
+        .next()?
+        .trim();
+    Some(value.to_owned()).filter(|v| !v.is_empty())
+}
+
+/// The host the request was sent to.
+/// NB: unlike `request.host()`, this only honors forwarded headers coming from trusted proxies.
+fn get_host(request: &Request<State>) -> String {
+    get_forwarded_header(request, "X-Forwarded-Host")
+        .or_else(|| request.header("Host").map(|h| h.last().as_str().to_owned()))
+        .or_else(|| request.url().host_str().map(|h| h.to_owned()))
+        .unwrap_or_default()
+}
+
+/// Whether the request was made over TLS, to us or to a trusted proxy.
+fn is_tls(request: &Request<State>) -> bool {
+    match get_forwarded_header(request, "X-Forwarded-Proto") {
+        Some(scheme) => scheme == "https",
+        None => listener::is_tls(&request.state().listeners, request.local_addr()),
     }
+}
 
-    if path == ".well-known/nostr/nip96.json" {
-        let nip96_json = format!(
-            "{{ \"api_url\": \"https://{}/api/files\", \"download_url\": \"https://{}/\" }}",
-            request.host().unwrap(),
-            request.host().unwrap()
-        );
-        return Ok(Response::builder(StatusCode::Ok)
-            .content_type(mime::JSON)
-            .body(nip96_json)
-            .build());
+/// The scheme the site is served over, for the URLs we give out.
+fn get_scheme(request: &Request<State>) -> String {
+    get_forwarded_header(request, "X-Forwarded-Proto").unwrap_or("https".to_owned())
+}
+
+/// `host` (which may include a port) with `port` instead, omitting it if it is the default one for `scheme`.
+fn with_port(host: &str, scheme: &str, port: &str) -> String {
+    // NB: IPv6 literals contain colons too, but end with a bracket
+    let host = match host.rsplit_once(':') {
+        Some((h, p)) if !p.contains(']') => h,
+        _ => host,
+    };
+    match (scheme, port) {
+        ("http", "80") | ("https", "443") => host.to_owned(),
+        _ => format!("{}:{}", host, port),
     }
+}
 
-    let mut part: Option<String> = None;
-    if path.contains(".") {
-        let parts = path.split(".").collect::<Vec<_>>();
-        if parts.len() == 2 {
-            part = Some(parts[0].to_string());
-        }
-    } else {
-        part = Some(path.to_string());
+/// The URL of the request as sent by the client, which may differ from `request.url()` behind a proxy.
+/// Each of the forwarded headers (scheme, host and port) is honored independently of the others.
+fn get_request_url(request: &Request<State>) -> String {
+    let url = request.url();
+    let scheme =
+        get_forwarded_header(request, "X-Forwarded-Proto").unwrap_or(url.scheme().to_owned());
+    let mut host = get_forwarded_header(request, "X-Forwarded-Host")
+        .unwrap_or(url[Position::BeforeHost..Position::AfterPort].to_owned());
+    if let Some(port) = get_forwarded_header(request, "X-Forwarded-Port") {
+        host = with_port(&host, &scheme, &port);
     }
-    let mut sha256: Option<String> = None;
-    if let Some(part) = part {
-        if part.len() == 64 && part.chars().all(|c| char::is_ascii_alphanumeric(&c)) {
-            sha256 = Some(part.to_string());
+
+    format!("{}://{}{}", scheme, host, &url[Position::BeforePath..])
+}
+
+/// Adds the request ID, the domain and the pubkey of the site to what is logged while handling the request.
+fn log_context<'a>(
+    mut request: Request<State>,
+    next: Next<'a, State>,
+) -> Pin<Box<dyn Future<Output = tide::Result> + Send + 'a>> {
+    let site = get_site(&request);
+    let context = Arc::new(logging::LogContext {
+        request_id: logging::next_request_id(),
+        domain: site.as_ref().map(|s| s.domain.to_owned()),
+        pubkey: site.and_then(|s| s.config.pubkey),
+    });
+    // for the websocket handler, which runs on its own
+    request.set_ext(context.clone());
+
+    Box::pin(async move {
+        let request_id = context.request_id.to_owned();
+        let mut response = logging::with_context(context, next.run(request)).await;
+        response.insert_header("X-Request-Id", request_id);
+        Ok(response)
+    })
+}
+
+/// Loads the content of the site when loading sites lazily (see `--max-loaded-sites`), off the executor,
+/// unloading the sites used least recently to make room for it.
+fn load_site_content<'a>(
+    request: Request<State>,
+    next: Next<'a, State>,
+) -> Pin<Box<dyn Future<Output = tide::Result> + Send + 'a>> {
+    Box::pin(async move {
+        if let (Some(max_loaded_sites), Some(site)) =
+            (request.state().max_loaded_sites, get_site(&request))
+        {
+            site.touch();
+            if !site.is_loaded() {
+                {
+                    let site = site.clone();
+                    logging::spawn_blocking(move || site.get_content()).await;
+                }
+                site::unload_least_recently_used(&request.state().sites.load(), max_loaded_sites);
+            }
+        }
+
+        Ok(next.run(request).await)
+    })
+}
+
+/// Answers CORS preflight requests (all the `OPTIONS` requests) and lets the browser hand the responses
+/// to pages on other sites, as allowed by the site's `[cors]`, or the server's.
+fn handle_cors<'a>(
+    request: Request<State>,
+    next: Next<'a, State>,
+) -> Pin<Box<dyn Future<Output = tide::Result> + Send + 'a>> {
+    Box::pin(async move {
+        let site_cors = get_site(&request).and_then(|site| site.config.cors);
+        let cors = site_cors.as_ref().unwrap_or(&request.state().cors);
+        let header = |name| request.header(name).map(|h| h.as_str().to_owned());
+        let allowed_origin = cors.get_allowed_origin(header("Origin").as_deref());
+
+        let mut response = if request.method() == Method::Options {
+            let mut response = Response::new(StatusCode::NoContent);
+            if allowed_origin.is_some() {
+                let requested_headers = header("Access-Control-Request-Headers");
+                response.insert_header("Access-Control-Allow-Methods", cors.get_allowed_methods());
+                response.insert_header(
+                    "Access-Control-Allow-Headers",
+                    cors.get_allowed_headers(requested_headers.as_deref()),
+                );
+                response.insert_header("Access-Control-Max-Age", cors.max_age.to_string());
+            }
+            response
+        } else {
+            next.run(request).await
+        };
+        match allowed_origin.as_deref() {
+            Some("*") => {}
+            // the response depends on where the request came from, which caches need to know
+            _ => response.append_header("Vary", "Origin"),
+        }
+        if let Some(allowed_origin) = allowed_origin {
+            response.insert_header("Access-Control-Allow-Origin", allowed_origin);
+        }
+
+        Ok(response)
+    })
+}
+
+/// Whether the request uploads a file (or the content of a site), which can be larger, and take longer,
+/// than the body of any other request.
+fn is_upload(request: &Request<State>) -> bool {
+    let path = request.url().path();
+    match request.method() {
+        Method::Put => {
+            path == "/upload" || path.starts_with("/api/sites/") && path.ends_with("/content")
+        }
+        Method::Post => path == "/api/files",
+        _ => false,
+    }
+}
+
+/// Refuses the requests whose body is larger than the limits allow (see [`limits::BodyLimits`]),
+/// or takes too long to arrive, so that clients can't exhaust the memory, or the connections, of the server.
+fn limit_body<'a>(
+    mut request: Request<State>,
+    next: Next<'a, State>,
+) -> Pin<Box<dyn Future<Output = tide::Result> + Send + 'a>> {
+    Box::pin(async move {
+        let limits = match is_upload(&request) {
+            true => request.state().upload_limits,
+            false => request.state().body_limits,
+        };
+        if request.len().is_some_and(|length| length > limits.max_size) {
+            return Ok(Response::new(StatusCode::PayloadTooLarge));
+        }
+
+        let exceeded = Arc::new(Mutex::new(None));
+        let body = request.take_body();
+        let (length, mime) = (body.len(), body.mime().clone());
+        let body = limits::LimitedBody::new(body, limits, exceeded.clone());
+        let mut body = tide::Body::from_reader(async_std::io::BufReader::new(body), length);
+        body.set_mime(mime);
+        request.set_body(body);
+
+        let response = next.run(request).await;
+        let exceeded = *exceeded.lock().unwrap();
+        match exceeded {
+            Some(status) => {
+                log::info!("Request body refused: {}.", status.canonical_reason());
+                Ok(Response::new(status))
+            }
+            None => Ok(response),
+        }
+    })
+}
+
+/// Adds the security headers of the site (see [`security::SecurityHeadersConfig`]), or the server's, to the responses,
+/// unless the handler set its own.
+fn add_security_headers<'a>(
+    request: Request<State>,
+    next: Next<'a, State>,
+) -> Pin<Box<dyn Future<Output = tide::Result> + Send + 'a>> {
+    Box::pin(async move {
+        let config = get_site(&request)
+            .and_then(|site| site.config.security_headers)
+            .unwrap_or_else(|| (*request.state().security_headers).clone());
+        let is_tls = is_tls(&request);
+
+        let mut response = next.run(request).await;
+        let is_html = response
+            .content_type()
+            .is_some_and(|m| m.essence() == mime::HTML.essence());
+        for (name, value) in config.get_headers(is_html, is_tls) {
+            if response.header(name).is_none() {
+                response.insert_header(name, value);
+            }
+        }
+
+        Ok(response)
+    })
+}
+
+/// The address of the client, as given by a trusted proxy if any.
+fn get_client_ip(request: &Request<State>) -> Option<String> {
+    get_forwarded_header(request, "X-Forwarded-For").or_else(|| {
+        let peer_addr = request.peer_addr()?.parse::<SocketAddr>().ok()?;
+        Some(peer_addr.ip().to_string())
+    })
+}
+
+/// Counts the request and the bytes sent and received for the site it was made to,
+/// also logging it if asked to (see `--access-log`).
+fn count_traffic<'a>(
+    request: Request<State>,
+    next: Next<'a, State>,
+) -> Pin<Box<dyn Future<Output = tide::Result> + Send + 'a>> {
+    Box::pin(async move {
+        let domain = request
+            .ext::<Arc<logging::LogContext>>()
+            .and_then(|c| c.domain.clone());
+        let Some(domain) = domain else {
+            return Ok(next.run(request).await);
+        };
+        let traffic = request.state().traffic.clone();
+        let time = chrono::Utc::now();
+        let client_ip = get_client_ip(&request);
+        let method = request.method().to_string();
+        let path = request.url().path().to_owned();
+        let header = |name| request.header(name).map(|h| h.last().as_str().to_owned());
+        let (referer, user_agent) = (header("Referer"), header("User-Agent"));
+        let bytes_received = request.len().unwrap_or_default() as u64;
+
+        let response = next.run(request).await;
+        traffic.record(
+            &domain,
+            &traffic::Hit {
+                time,
+                client_ip,
+                method: &method,
+                path: &path,
+                status: response.status() as u16,
+                bytes_sent: response.len().unwrap_or_default() as u64,
+                bytes_received,
+                referer: referer.as_deref(),
+                user_agent: user_agent.as_deref(),
+            },
+        );
+
+        Ok(response)
+    })
+}
+
+fn get_site(request: &Request<State>) -> Option<Site> {
+    let host = get_host(request);
+    let sites = request.state().sites.load();
+    let listeners = &request.state().listeners;
+    let is_served =
+        |site: &&Site| listener::is_served(listeners, request.local_addr(), &site.domain);
+
+    if let Some(site) = site::find_site(&sites, &host) {
+        Some(site).filter(is_served).cloned()
+    } else if let Some(default_site) = &request.state().default_site {
+        sites.get(default_site).filter(is_served).cloned()
+    } else {
+        let mut served = sites.values().filter(is_served);
+        match (served.next(), served.next()) {
+            (Some(site), None) => Some(site.clone()),
+            _ => None,
+        }
+    }
+}
+
+/// A redirect to the same page on the site's canonical domain, if it has one and it was requested using another.
+fn get_canonical_redirect(request: &Request<State>, site: &Site) -> Option<Response> {
+    let canonical_domain = site.config.canonical_domain.as_ref()?;
+    if get_host(request) == *canonical_domain {
+        return None;
+    }
+    let mut location = tide::http::Url::parse(&site.config.base_url).ok()?;
+    location.set_path(request.url().path());
+    location.set_query(request.url().query());
+
+    Some(
+        Response::builder(StatusCode::MovedPermanently)
+            .header("Location", location.as_str())
+            .build(),
+    )
+}
+
+/// A redirect to `permalink` (of a resource, or of an index), if it was requested using another form of it,
+/// such as without its trailing slash (or with one it shouldn't have), or with the `index` of a section.
+fn get_permalink_redirect(
+    request: &Request<State>,
+    site: &Site,
+    permalink: String,
+) -> Option<Response> {
+    let path = permalink.strip_prefix(site.config.base_url.trim_end_matches('/'))?;
+    let requested_path =
+        percent_encoding::percent_decode_str(request.url().path()).decode_utf8_lossy();
+    if requested_path == path {
+        return None;
+    }
+    let location = match request.url().query() {
+        Some(query) => format!("{}?{}", permalink, query),
+        None => permalink,
+    };
+
+    Some(
+        Response::builder(StatusCode::MovedPermanently)
+            .header("Location", location)
+            .build(),
+    )
+}
+
+/// The type of a file going by its extension, or a byte stream if unknown.
+fn guess_mime(path: &str) -> mime::Mime {
+    let guess = mime_guess::from_path(path).first_or_octet_stream();
+    mime::Mime::from_str(guess.essence_str()).unwrap_or(mime::BYTE_STREAM)
+}
+
+/// The type of an uploaded file, as recorded in its metadata when it was uploaded.
+/// Should the metadata be missing or broken, the file is still served, as a byte stream.
+async fn read_file_mime(path: &str) -> mime::Mime {
+    let metadata_path = format!("{}.metadata.json", path);
+    let metadata = async_std::fs::read_to_string(&metadata_path)
+        .await
+        .context(|| "Cannot read the metadata of an uploaded file")
+        .and_then(|metadata| {
+            serde_json::from_str::<FileMetadata>(&metadata)
+                .context(|| format!("Cannot parse {}", metadata_path))
+        });
+    match metadata {
+        Ok(metadata) => mime::Mime::from_str(&metadata.content_type).unwrap_or_else(|_| {
+            log::warn!("Invalid content type in {}.", metadata_path);
+            mime::BYTE_STREAM
+        }),
+        Err(e) => {
+            log::warn!("{}", e);
+            mime::BYTE_STREAM
+        }
+    }
+}
+
+async fn handle_request(request: Request<State>) -> tide::Result<Response> {
+    let response = serve_request(&request).await.unwrap_or_else(Response::from);
+    let status = response.status();
+    if matches!(
+        status,
+        StatusCode::NotFound | StatusCode::InternalServerError
+    ) && response.is_empty() == Some(true)
+    {
+        return Ok(build_error_page_response(&request, status).await);
+    }
+
+    Ok(response)
+}
+
+/// A page telling visitors that their request failed with `status`, rendered using the site's theme if possible.
+async fn build_error_page_response(request: &Request<State>, status: StatusCode) -> Response {
+    let state = request.state();
+    let site = get_site(request);
+    let language = site
+        .as_ref()
+        .and_then(|site| site.config.default_language.clone())
+        .unwrap_or_else(|| state.default_language.clone());
+    let charset = site
+        .as_ref()
+        .and_then(|site| site.config.charset.clone())
+        .unwrap_or_else(|| state.default_charset.clone());
+
+    let html = match site {
+        Some(site) => {
+            let (path, language) = (request.url().path().to_owned(), language.clone());
+            logging::spawn_blocking(move || {
+                resource::render_error_page(&site, status.into(), &path, &language)
+            })
+            .await
         }
+        None => resource::render_default_error_page(status.into(), None),
+    };
+    let (body, encoding) = encode_html(&html, &charset);
+
+    Response::builder(status)
+        .content_type(
+            mime::Mime::from_str(&format!(
+                "text/html;charset={}",
+                encoding.name().to_lowercase()
+            ))
+            .unwrap(),
+        )
+        .header("Content-Language", language)
+        .header("Cache-Control", "no-cache")
+        .body(&*body)
+        .build()
+}
+
+/// Serves whatever is at the requested path of the site: a page, an asset, a static file or an uploaded file.
+async fn serve_request(request: &Request<State>) -> error::Result<Response> {
+    let path = request.param("path").unwrap_or_default();
+    let path = path.strip_suffix('/').unwrap_or(path);
+
+    if path == ".admin" {
+        let admin_index = admin::INDEX_HTML
+            .replace("%%API_BASE_URL%%", &format!("//{}", get_host(request)))
+            .replace("%%VERSION%%", admin::VERSION);
+        return Ok(Response::builder(StatusCode::Ok)
+            .content_type(mime::HTML)
+            .header("Cache-Control", "no-cache")
+            .header(
+                "Content-Security-Policy",
+                security::ADMIN_CONTENT_SECURITY_POLICY,
+            )
+            .body(admin_index)
+            .build());
+    }
+    if let Some(name) = path.strip_prefix(".admin/") {
+        return Ok(build_admin_asset_response(request, name));
     }
 
-    if sha256.is_some() && request.method() == Method::Options {
+    if path == ".well-known/nostr/nip96.json" {
+        let nip96_json = format!(
+            "{{ \"api_url\": \"{}://{}/api/files\", \"download_url\": \"{}://{}/\" }}",
+            get_scheme(request),
+            get_host(request),
+            get_scheme(request),
+            get_host(request)
+        );
         return Ok(Response::builder(StatusCode::Ok)
-            .header("Access-Control-Allow-Origin", "*")
-            .header("Access-Control-Allow-Headers", "Authorization,*")
-            .header("Access-Control-Allow-Methods", "GET,PUT,DELETE")
+            .content_type(mime::JSON)
+            .body(nip96_json)
             .build());
     }
 
-    if let Some(site) = get_site(&request) {
-        if let Some((mime, response)) = resource::render_standard_resource(path, &site) {
-            return Ok(Response::builder(StatusCode::Ok)
-                .content_type(mime)
-                .header("Access-Control-Allow-Origin", "*")
-                .body(response)
-                .build());
+    let mut part: Option<String> = None;
+    if path.contains(".") {
+        let parts = path.split(".").collect::<Vec<_>>();
+        if parts.len() == 2 {
+            part = Some(parts[0].to_string());
+        }
+    } else {
+        part = Some(path.to_string());
+    }
+    let mut sha256: Option<String> = None;
+    if let Some(part) = part {
+        if part.len() == 64 && part.chars().all(|c| char::is_ascii_alphanumeric(&c)) {
+            sha256 = Some(part.to_string());
+        }
+    }
+
+    if let Some(site) = get_site(request) {
+        // NIP-05 clients don't follow redirects
+        if !path.starts_with(".well-known/") {
+            if let Some(redirect) = get_canonical_redirect(request, &site) {
+                return Ok(redirect);
+            }
         }
+        let preview_theme = get_preview_theme(request);
+        let theme_name = preview_theme.as_ref().unwrap_or(&site.config.theme);
+        let theme_resource = request.state().themes.load().get(theme_name).and_then(|t| {
+            t.resources
+                .read()
+                .unwrap()
+                .get(&format!("/{}", path))
+                .cloned()
+        });
+        // the resources of a theme are public, but only the owner gets to preview their content with it
+        let site = if theme_resource.is_some() {
+            site
+        } else {
+            match get_previewed_site(request, site) {
+                Ok(site) => site,
+                Err(status) => return Ok(Response::new(status)),
+            }
+        };
 
-        let site_resources: Vec<String>;
+        // files of the site's own take precedence over the generated ones
+        if let Some(file) = path
+            .strip_prefix(".well-known/")
+            .and_then(|name| resource::get_well_known_file(&site, name))
         {
-            let resources = site.resources.read().unwrap();
-            site_resources = resources.keys().cloned().collect();
+            let content = async_std::fs::read(&file)
+                .await
+                .context(|| "Cannot read a well-known file")?;
+            let mime = resource::get_well_known_mime(path, &content);
+            return Ok(build_raw_response(content, mime));
+        }
+        if let Some((mime, content)) = resource::render_standard_resource(path, &site) {
+            let response = Response::builder(StatusCode::Ok)
+                .content_type(mime)
+                .body(content.as_str())
+                .build();
+            // feed readers poll the feed, but mostly get the same one back
+            return Ok(set_etag(request, response, content.as_bytes()));
+        }
+        if let Some(id) = path.strip_prefix("e/") {
+            if site.config.render_external_events && !site.config.upstream_relays.is_empty() {
+                return Ok(render_external_event(request, &site, id.trim_end_matches('/')).await);
+            }
         }
+        if path == "search" {
+            let query = request
+                .url()
+                .query_pairs()
+                .find(|(k, _)| k == "q")
+                .map(|(_, v)| v.to_string())
+                .unwrap_or_default();
+            if let Some(results) = resource::search_site(&site, &query) {
+                return Ok(build_raw_response(
+                    json!({ "query": query, "results": results }).to_string(),
+                    mime::JSON,
+                ));
+            }
+        }
+
+        let site_content = site.get_content();
 
-        let themes = request.state().themes.read().unwrap();
-        let theme = themes.get(&site.config.theme).unwrap();
+        // the other pagers of the index, when paginated
+        if let Some(pager) = path
+            .strip_prefix("page/")
+            .and_then(|p| p.parse::<usize>().ok())
+        {
+            if site.config.paginate_by.is_some() {
+                if pager <= 1 {
+                    return Ok(Response::builder(StatusCode::MovedPermanently)
+                        .header("Location", site.config.make_permalink("/"))
+                        .build());
+                }
+                let index = match site_content.resources.get("/index") {
+                    Some(resource) => resource.clone(),
+                    None => Resource::default_index(),
+                };
+                return Ok(render_and_build_response(request, &site, index, pager).await);
+            }
+        }
 
         let mut resource_path = format!("/{}", &path);
-        if site_resources.contains(&resource_path) {
-            return Ok(render_and_build_response(
-                &site,
-                get_resource(&site, &resource_path),
-            ));
+
+        // the gallery (unless a page is served there instead) and its thumbnails
+        if let Some(config) = &site.config.gallery {
+            if let Some(name) = resource_path.strip_prefix(gallery::THUMBNAILS_PATH) {
+                return build_thumbnail_response(request, &site, config, name).await;
+            }
+            if resource_path == gallery::GALLERY_URL
+                && !site_content.resources.contains_key(&resource_path)
+                && !site_content
+                    .resources
+                    .contains_key(&format!("{}/index", resource_path))
+            {
+                return Ok(render_gallery(request, &site, config).await);
+            }
+        }
+
+        // the index of the notes, unless a page is served there instead
+        if resource_path == resource::NOTES_INDEX_URL
+            && !site_content.resources.contains_key(&resource_path)
+            && !site_content
+                .resources
+                .contains_key(&format!("{}/index", resource_path))
+            && site_content
+                .resources
+                .values()
+                .any(|r| r.kind == ResourceKind::Note)
+        {
+            return Ok(render_notes_index(request, &site).await);
+        }
+
+        if let Some(resource) = site_content.resources.get(&resource_path) {
+            if let Some(redirect) =
+                get_permalink_redirect(request, &site, resource.get_permalink(&site.config))
+            {
+                return Ok(redirect);
+            }
+            Ok(render_and_build_response(request, &site, resource.clone(), 1).await)
         } else {
-            let theme_resources = theme.resources.read().unwrap();
-            if theme_resources.contains_key(&resource_path) {
-                let content = theme_resources.get(&resource_path).unwrap();
-                let guess = mime_guess::from_path(resource_path);
-                let mime = mime::Mime::from_str(guess.first().unwrap().essence_str()).unwrap();
-                return Ok(build_raw_response(content.as_bytes().to_vec(), mime));
+            if let Some(url) = site_content.redirects.get(&resource_path) {
+                let location = if url.contains("://") {
+                    url.to_owned()
+                } else if let Some(resource) = site_content.resources.get(url) {
+                    resource.get_permalink(&site.config)
+                } else {
+                    site.config.make_permalink(url)
+                };
+                return Ok(Response::builder(StatusCode::MovedPermanently)
+                    .header("Location", location)
+                    .build());
             }
-            resource_path = format!("{}/index", &resource_path);
-            if site_resources.contains(&resource_path) {
-                return Ok(render_and_build_response(
+            let sass_resource = site
+                .sass_resources
+                .read()
+                .unwrap()
+                .get(&resource_path)
+                .cloned();
+            if let Some(content) = sass_resource {
+                return Ok(build_asset_response(
+                    request,
                     &site,
-                    get_resource(&site, &resource_path),
+                    &resource_path,
+                    content.into_bytes(),
+                    guess_mime(&resource_path),
                 ));
-            } else {
-                resource_path = format!("{}/{}/{}", site::SITE_PATH, site.domain, path);
-                for part in resource_path.split('/').collect::<Vec<_>>() {
-                    let first_char = part.chars().next().unwrap();
-                    if first_char == '_' || (first_char == '.' && part.len() > 1) {
-                        return Ok(Response::builder(StatusCode::NotFound).build());
-                    }
+            }
+            if let Some(content) = theme_resource {
+                return Ok(build_asset_response(
+                    request,
+                    &site,
+                    &resource_path,
+                    content.into_bytes(),
+                    guess_mime(&resource_path),
+                ));
+            }
+            resource_path = format!("{}/index", &resource_path);
+            if let Some(resource) = site_content.resources.get(&resource_path) {
+                if let Some(redirect) =
+                    get_permalink_redirect(request, &site, resource.get_permalink(&site.config))
+                {
+                    return Ok(redirect);
                 }
-                if PathBuf::from(&resource_path).exists() {
-                    // look for a static file
-                    let raw_content = fs::read(&resource_path).unwrap();
-                    let guess = mime_guess::from_path(resource_path);
-                    let mime = mime::Mime::from_str(guess.first().unwrap().essence_str()).unwrap();
-                    return Ok(build_raw_response(raw_content, mime));
+                Ok(render_and_build_response(request, &site, resource.clone(), 1).await)
+            } else {
+                let site_root = Path::new(&paths::sites()).join(&site.domain);
+                if let Some(file) = resource::get_static_file(&site_root, path) {
+                    // look for a static file, which is streamed rather than read into memory
+                    let body = tide::Body::from_file(&file)
+                        .await
+                        .context(|| "Cannot serve a static file")?;
+                    let mime = guess_mime(path);
+                    Ok(build_asset_response(request, &site, path, body, mime))
                 } else {
                     // look for an uploaded file
                     if let Some(sha256) = sha256 {
                         resource_path = format!(
                             "{}/{}/_content/files/{}",
-                            site::SITE_PATH,
+                            paths::sites(),
                             site.domain,
                             sha256
                         );
                         if PathBuf::from(&resource_path).exists() {
-                            let raw_content = fs::read(&resource_path).unwrap();
-                            let metadata_file = File::open(&format!(
-                                "{}/{}/_content/files/{}.metadata.json",
-                                site::SITE_PATH,
-                                site.domain,
-                                sha256
-                            ))
-                            .unwrap();
-                            let metadata_reader = BufReader::new(metadata_file);
-                            let metadata: FileMetadata =
-                                serde_json::from_reader(metadata_reader).unwrap();
-                            let mime = mime::Mime::from_str(&metadata.content_type).unwrap();
-                            return Ok(build_raw_response(raw_content, mime));
+                            let mime = read_file_mime(&resource_path).await;
+                            // streamed, rather than read into memory
+                            let body = tide::Body::from_file(&resource_path)
+                                .await
+                                .context(|| "Cannot serve an uploaded file")?;
+                            Ok(build_raw_response(body, mime))
                         } else {
-                            return Ok(Response::builder(StatusCode::NotFound).build());
+                            Ok(Response::builder(StatusCode::NotFound).build())
                         }
                     } else {
-                        return Ok(Response::builder(StatusCode::NotFound).build());
+                        Ok(Response::builder(StatusCode::NotFound).build())
                     }
                 }
             }
         }
     } else {
-        return Ok(Response::new(StatusCode::NotFound));
+        Ok(Response::new(StatusCode::NotFound))
     }
 }
 
@@ -452,18 +1845,26 @@ fn get_nostr_auth_event(request: &Request<State>) -> Option<nostr::Event> {
         return None;
     }
 
-    Some(
-        serde_json::from_str(str::from_utf8(&STANDARD.decode(parts[1]).unwrap()).unwrap()).unwrap(),
-    )
-}
-
-fn get_pubkey(request: &Request<State>) -> Option<String> {
-    Some(request.param("pubkey").unwrap().to_string())
+    // a malformed header is no authorization, rather than an error
+    let event = STANDARD
+        .decode(parts[1])
+        .context(|| "Cannot decode authorization")
+        .and_then(|json| serde_json::from_slice(&json).context(|| "Cannot parse authorization"));
+    match event {
+        Ok(event) => Some(event),
+        Err(e) => {
+            log::debug!("{}", e);
+            None
+        }
+    }
 }
 
 fn nostr_auth(request: &Request<State>) -> Option<String> {
-    get_nostr_auth_event(request)?
-        .get_nip98_pubkey(request.url().as_str(), request.method().as_ref())
+    get_nostr_auth_event(request)?.get_nip98_pubkey(
+        &get_request_url(request),
+        request.method().as_ref(),
+        &request.state().trusted_hosts,
+    )
 }
 
 fn blossom_upload_auth(request: &Request<State>) -> Option<String> {
@@ -478,555 +1879,4018 @@ fn blossom_auth(request: &Request<State>, method: &str) -> Option<String> {
     get_nostr_auth_event(request)?.get_blossom_pubkey(method)
 }
 
+/// The domains of the site (its own and its aliases) needing a certificate obtained using ACME:
+/// the ones served over TLS, unless covered by the site's own certificate or by the wildcard certificate.
+fn get_acme_domains<'a>(
+    listeners: &[listener::SiteListener],
+    wildcard_domain: Option<&str>,
+    site: &'a Site,
+) -> Vec<&'a str> {
+    if !listener::is_served_over_tls(listeners, &site.domain) {
+        return vec![];
+    }
+    let mut domains = vec![];
+    // the site's own certificate is only served for its own domain
+    if site.config.ssl_cert.is_none() || site.config.ssl_key.is_none() {
+        domains.push(site.domain.as_str());
+    }
+    domains.extend(site.config.aliases.iter().map(|a| a.as_str()));
+    domains.retain(|d| !wildcard_domain.is_some_and(|w| site::is_subdomain_of(d, w)));
+
+    domains
+}
+
+/// Starts serving a newly created site, obtaining its certificate first if using ACME.
+fn add_site(state: &State, site: Site) {
+    if let Some(acme) = &state.acme {
+        for domain in get_acme_domains(&state.listeners, state.wildcard_domain.as_deref(), &site) {
+            acme.add_domain(domain);
+        }
+    }
+    state
+        .sites
+        .update(|sites| sites.insert(site.domain.clone(), site));
+}
+
+/// The domain of a site about to be created using the API, normalized,
+/// or why it can't be used. Only the operator can create sites for reserved domains.
+fn check_new_domain(request: &Request<State>, domain: &str) -> Result<String, &'static str> {
+    // just a name, given a subdomain of the tenant domain
+    let domain = match &request.state().tenant_domain {
+        Some(tenant_domain) if !domain.contains('.') => format!("{}.{}", domain, tenant_domain),
+        _ => domain.to_owned(),
+    };
+    let domain = match site::normalize_domain(&domain) {
+        Some(domain) if site::is_valid_domain(&domain) => domain,
+        _ => return Err("Invalid domain"),
+    };
+    if site::is_reserved_domain(&domain) && !is_operator(request) {
+        return Err("Reserved domain");
+    }
+
+    Ok(domain)
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/sites",
+    tag = "sites",
+    request_body(content = PostSiteRequestBody),
+    responses(
+        (status = 200, description = "The site was created", body = Object, example = json!({})),
+        (status = 202, description = "The site is waiting for the operator's approval", body = Object, example = json!({"pending": true})),
+        (status = 400, description = "Invalid domain, body or authorization"),
+        (status = 403, description = "Registration is closed, the key has too many sites, only operators can create sites for others, or the invite code is invalid"),
+        (status = 409, description = "There is a site at this domain already"),
+    ),
+    security(("nostr" = []))
+)]
 async fn handle_post_site(mut request: Request<State>) -> tide::Result<Response> {
-    let domain = request
-        .body_json::<PostSiteRequestBody>()
-        .await
-        .unwrap()
-        .domain;
+    let body = match request.body_json::<PostSiteRequestBody>().await {
+        Ok(body) => body,
+        Err(_) => return Ok(Response::builder(StatusCode::BadRequest).build()),
+    };
+    let domain = match check_new_domain(&request, &body.domain) {
+        Ok(domain) => domain,
+        Err(e) => return Ok(Response::builder(StatusCode::BadRequest).body(e).build()),
+    };
     let state = &request.state();
 
-    if state.sites.read().unwrap().contains_key(&domain) {
+    if state.sites.load().contains_key(&domain) {
         Ok(Response::builder(StatusCode::Conflict).build())
     } else {
-        let key = nostr_auth(&request);
-        if key.is_none() {
+        let Some(author) = nostr_auth(&request) else {
             return Ok(Response::builder(StatusCode::BadRequest).build());
+        };
+        let role = auth::check(
+            Some(&author),
+            None,
+            &state.operator_pubkeys,
+            auth::Access::Operator,
+        )
+        .unwrap_or(auth::Role::Owner);
+        let key = match body.pubkey {
+            Some(pubkey) if role != auth::Role::Operator => {
+                log::info!("Only the operator can create sites for others: {}.", pubkey);
+                return Ok(Response::builder(StatusCode::Forbidden).build());
+            }
+            Some(pubkey) => match nostr::parse_pubkey(&pubkey) {
+                Some(pubkey) => Some(pubkey),
+                None => return Ok(Response::builder(StatusCode::BadRequest).build()),
+            },
+            None => Some(author.clone()),
+        };
+        if role != auth::Role::Operator {
+            let pending =
+                match logging::spawn_blocking(|| registration::get().map(|r| r.pending)).await {
+                    Ok(pending) => pending,
+                    Err(e) => {
+                        log::error!("Failed to read the registrations: {}", e);
+                        return Ok(Response::builder(StatusCode::InternalServerError).build());
+                    }
+                };
+            if let Err(reason) = check_registration(state, &author, &pending) {
+                auth::audit_denied(
+                    Some(&author),
+                    &format!("{} {}", request.method(), request.url().path()),
+                    Some(&domain),
+                    reason,
+                );
+                return Ok(Response::builder(StatusCode::Forbidden)
+                    .body(reason)
+                    .build());
+            }
+        }
+
+        // an invite used to skip the approval, given back if the site fails to be created
+        let mut invite = None;
+        if state.require_approval && role != auth::Role::Operator {
+            let result = logging::spawn_blocking({
+                let (domain, pubkey, code) =
+                    (domain.clone(), key.clone().unwrap(), body.invite.clone());
+                move || {
+                    registration::update(|registrations| match &code {
+                        Some(code) => Ok(registrations.take_invite(code)),
+                        None => registrations.add_pending(&domain, &pubkey).map(|_| None),
+                    })
+                }
+            })
+            .await;
+            match result {
+                Ok(Some(taken)) => invite = Some(taken), // invited, so no need to wait
+                Ok(None) if body.invite.is_some() => {
+                    return Ok(Response::builder(StatusCode::Forbidden)
+                        .body("Invalid invite code")
+                        .build());
+                }
+                Ok(None) => {
+                    log::info!("Site {} is waiting for approval.", domain);
+                    return Ok(Response::builder(StatusCode::Accepted)
+                        .content_type(mime::JSON)
+                        .body(json!({"pending": true}).to_string())
+                        .build());
+                }
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                    return Ok(Response::builder(StatusCode::Conflict).build());
+                }
+                Err(e) => {
+                    log::error!("Failed to register site {}: {}", domain, e);
+                    return Ok(Response::builder(StatusCode::InternalServerError).build());
+                }
+            }
         }
 
-        let site = site::create_site(&domain, key);
+        let site = match logging::spawn_blocking({
+            let (domain, themes) = (domain.clone(), state.themes.load());
+            move || site::create_site(&domain, key, &themes)
+        })
+        .await
+        {
+            Ok(site) => site,
+            Err(e) => {
+                log::error!("Failed to create site {}: {}", domain, e);
+                if let Some(invite) = invite {
+                    let restored = logging::spawn_blocking(move || {
+                        registration::update(|registrations| {
+                            registrations.invites.push(invite);
+                            Ok(())
+                        })
+                    })
+                    .await;
+                    if let Err(e) = restored {
+                        log::error!("Failed to give back the invite: {}", e);
+                    }
+                }
+                return Ok(Response::builder(StatusCode::InternalServerError).build());
+            }
+        };
 
-        let sites = &mut state.sites.write().unwrap();
-        sites.insert(domain, site);
+        auth::audit(
+            &author,
+            role,
+            &format!("{} {}", request.method(), request.url().path()),
+            Some(&domain),
+        );
+        add_site(state, site);
 
         Ok(Response::builder(StatusCode::Ok)
             .content_type(mime::JSON)
-            .header("Access-Control-Allow-Origin", "*")
             .body("{}")
             .build())
     }
 }
 
-async fn handle_get_sites(request: Request<State>) -> tide::Result<Response> {
-    let key = nostr_auth(&request);
-    if key.is_none() {
-        return Ok(Response::builder(StatusCode::BadRequest).build());
+/// Whether `pubkey`, which is not an operator's, can create (or ask for) a site, or why not,
+/// given the sites waiting for approval.
+fn check_registration(
+    state: &State,
+    pubkey: &str,
+    pending: &[registration::PendingSite],
+) -> Result<(), &'static str> {
+    if !state.allow_open_registration && !state.require_approval {
+        return Err("registration is closed");
+    }
+    if let Some(max_sites) = state.max_sites_per_pubkey {
+        let sites = state
+            .sites
+            .load()
+            .values()
+            .filter(|site| {
+                site.config
+                    .pubkey
+                    .as_deref()
+                    .is_some_and(|site_pubkey| auth::keys_equal(site_pubkey, pubkey))
+            })
+            .count()
+            + pending
+                .iter()
+                .filter(|p| auth::keys_equal(&p.pubkey, pubkey))
+                .count();
+        if sites >= max_sites {
+            return Err("too many sites for this key");
+        }
     }
-    let key = key.unwrap();
-    let all_sites = &request.state().sites.read().unwrap();
-    let sites = all_sites
-        .iter()
-        .filter_map(|s| {
-            if s.1.config.pubkey.clone().unwrap() == key {
-                Some(HashMap::from([("domain", s.0)]))
-            } else {
-                None
-            }
-        })
-        .collect::<Vec<_>>();
 
-    Ok(Response::builder(StatusCode::Ok)
-        .content_type(mime::JSON)
-        .body(json!(sites).to_string())
-        .build())
+    Ok(())
 }
 
-async fn handle_get_site_config(request: Request<State>) -> tide::Result<Response> {
-    let site = {
-        if let Some(site) = get_site(&request) {
-            if !is_authorized(&request, &site, &nostr_auth) {
-                return Ok(Response::builder(StatusCode::Forbidden)
-                    .header("Access-Control-Allow-Origin", "*")
-                    .build());
-            }
-            site
-        } else {
-            return Ok(Response::builder(StatusCode::NotFound).build());
-        }
-    };
+const QUOTA_EXCEEDED_MESSAGE: &str = "blocked: storage quota exceeded";
+
+/// Whether the site takes more space than its quota allows, counting what is being uploaded.
+async fn is_over_quota(site: &Site) -> bool {
+    let site = site.clone();
+    logging::spawn_blocking(move || site.is_over_quota()).await
+}
 
-    let themes: Vec<String> = request
-        .state()
-        .themes
-        .read()
-        .unwrap()
-        .keys()
-        .cloned()
-        .collect();
+#[utoipa::path(
+    get,
+    path = "/api/sites/{domain}/quota",
+    tag = "operator",
+    params(
+        ("domain" = String, Path, description = "Domain of the site"),
+    ),
+    responses(
+        (status = 200, description = "The quota of the site", body = Quota),
+        (status = 403, description = "Not authorized by the site's key or the operator's key"),
+        (status = 404, description = "No such site"),
+    ),
+    security(("nostr" = []))
+)]
+async fn handle_get_site_quota(request: Request<State>) -> tide::Result<Response> {
+    let domain = request.param("domain").unwrap();
+    let site = match request.state().sites.load().get(domain) {
+        Some(site) => site.clone(),
+        None => return Ok(Response::builder(StatusCode::NotFound).build()),
+    };
+    if !is_authorized(
+        &request,
+        Some(&site),
+        auth::Access::OwnerOrOperator,
+        &nostr_auth,
+    ) {
+        return Ok(Response::builder(StatusCode::Forbidden).build());
+    }
 
     Ok(Response::builder(StatusCode::Ok)
         .content_type(mime::JSON)
-        .body(json!({"theme": site.config.theme, "available_themes": themes}).to_string())
+        .body(json!(site.config.quota).to_string())
         .build())
 }
 
-async fn handle_put_site_config(mut request: Request<State>) -> tide::Result<Response> {
-    let site = {
-        if let Some(site) = get_site(&request) {
-            if !is_authorized(&request, &site, &nostr_auth) {
-                return Ok(Response::builder(StatusCode::Forbidden)
-                    .header("Access-Control-Allow-Origin", "*")
-                    .build());
-            }
-            site
-        } else {
-            return Ok(Response::builder(StatusCode::NotFound).build());
-        }
+#[utoipa::path(
+    put,
+    path = "/api/sites/{domain}/quota",
+    tag = "operator",
+    params(
+        ("domain" = String, Path, description = "Domain of the site"),
+    ),
+    request_body(content = Quota),
+    responses(
+        (status = 200, description = "The new quota of the site", body = Quota),
+        (status = 400, description = "Invalid quota"),
+        (status = 403, description = "Not authorized by the operator's key"),
+        (status = 404, description = "No such site"),
+    ),
+    security(("nostr" = []))
+)]
+async fn handle_put_site_quota(mut request: Request<State>) -> tide::Result<Response> {
+    let domain = request.param("domain").unwrap().to_owned();
+    let site = match request.state().sites.load().get(&domain) {
+        Some(site) => site.clone(),
+        None => return Ok(Response::builder(StatusCode::NotFound).build()),
+    };
+    if !is_authorized(&request, Some(&site), auth::Access::Operator, &nostr_auth) {
+        return Ok(Response::builder(StatusCode::Forbidden).build());
+    }
+    let quota = match request.body_json::<site::Quota>().await {
+        Ok(quota) => quota,
+        Err(_) => return Ok(Response::builder(StatusCode::BadRequest).build()),
     };
-
-    // NB: we need to load config from the file rather than using the one already loaded,
-    // which is already merged with the theme's config!
-    let config_path = format!("{}/{}/_config.toml", site::SITE_PATH, site.domain);
-    let mut config = site::load_config(&config_path).unwrap();
-    config.theme = request
-        .body_json::<PutSiteConfigRequestBody>()
-        .await
-        .unwrap()
-        .theme;
-    site::save_config(&config_path, config);
-
-    let new_site = site::load_site(&site.domain);
 
     let state = request.state();
-    let sites = &mut state.sites.write().unwrap();
-    sites.remove(&site.domain);
-    sites.insert(site.domain, new_site);
+    let result = logging::spawn_blocking({
+        let (domain, themes) = (domain.clone(), state.themes.load());
+        move || site::set_quota(&domain, quota, &themes)
+    })
+    .await;
+    let site = match result {
+        Ok(site) => site,
+        Err(e) => {
+            log::error!("Failed to set the quota of {}: {}", domain, e);
+            return Ok(Response::builder(StatusCode::InternalServerError).build());
+        }
+    };
+    let quota = site.config.quota.clone();
+    add_site(state, site);
 
     Ok(Response::builder(StatusCode::Ok)
         .content_type(mime::JSON)
-        .body(json!({}).to_string())
+        .body(json!(quota).to_string())
         .build())
 }
 
-async fn handle_blossom_list_request(request: Request<State>) -> tide::Result<Response> {
-    let site_path = {
-        if let Some(site) = get_site(&request) {
-            if !is_authorized(&request, &site, &get_pubkey) {
-                return Ok(Response::builder(StatusCode::Forbidden)
-                    .header("Access-Control-Allow-Origin", "*")
-                    .build());
-            }
-            format!("{}/{}", site::SITE_PATH, site.domain)
-        } else {
-            return Ok(Response::builder(StatusCode::NotFound).build());
+/// A response with `value` as JSON or, if getting it failed, with an error.
+fn build_result_response<T: Serialize>(result: io::Result<T>) -> Response {
+    match result {
+        Ok(value) => Response::builder(StatusCode::Ok)
+            .content_type(mime::JSON)
+            .body(json!(value).to_string())
+            .build(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            Response::builder(StatusCode::NotFound).build()
         }
-    };
+        Err(e) if e.kind() == io::ErrorKind::InvalidInput => {
+            Response::builder(StatusCode::BadRequest)
+                .body(e.to_string())
+                .build()
+        }
+        Err(e) => {
+            log::error!("Request failed: {}", e);
+            Response::builder(StatusCode::InternalServerError).build()
+        }
+    }
+}
 
-    let paths = match fs::read_dir(format!("{}/_content/files", site_path)) {
-        Ok(paths) => paths.map(|r| r.unwrap()).collect(),
-        _ => vec![],
-    };
+fn build_operator_only_response() -> Response {
+    Response::builder(StatusCode::Forbidden).build()
+}
 
-    let mut list = vec![];
+#[utoipa::path(
+    get,
+    path = "/api/invites",
+    tag = "operator",
+    responses(
+        (status = 200, description = "The unused invite codes", body = [Invite]),
+        (status = 403, description = "Not authorized by the operator's key"),
+    ),
+    security(("nostr" = []))
+)]
+async fn handle_get_invites(request: Request<State>) -> tide::Result<Response> {
+    if !is_authorized(&request, None, auth::Access::Operator, &nostr_auth) {
+        return Ok(build_operator_only_response());
+    }
+    let result = logging::spawn_blocking(|| registration::get().map(|r| r.invites)).await;
 
-    for path in &paths {
-        if path.path().extension().is_none() {
-            let mut metadata_path = path.path();
-            metadata_path.set_extension("metadata.json");
-            let metadata_file = File::open(&metadata_path).unwrap();
-            let metadata_reader = BufReader::new(metadata_file);
-            let metadata: FileMetadata = serde_json::from_reader(metadata_reader).unwrap();
-            list.push(metadata);
-        }
+    Ok(build_result_response(result))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/invites",
+    tag = "operator",
+    responses(
+        (status = 200, description = "A new invite code", body = Invite),
+        (status = 403, description = "Not authorized by the operator's key"),
+    ),
+    security(("nostr" = []))
+)]
+async fn handle_post_invite(request: Request<State>) -> tide::Result<Response> {
+    if !is_authorized(&request, None, auth::Access::Operator, &nostr_auth) {
+        return Ok(build_operator_only_response());
     }
+    let result = logging::spawn_blocking(|| registration::update(|r| Ok(r.add_invite()))).await;
 
-    return Ok(Response::builder(StatusCode::Created)
-        .content_type(mime::JSON)
-        .header("Access-Control-Allow-Origin", "*")
-        .body(serde_json::to_string(&list).unwrap())
-        .build());
+    Ok(build_result_response(result))
 }
 
-fn is_authorized(
-    request: &Request<State>,
-    site: &Site,
-    get_pubkey: &dyn Fn(&Request<State>) -> Option<String>,
-) -> bool {
-    if let Some(pubkey) = get_pubkey(&request) {
-        if let Some(site_pubkey) = site.config.pubkey.to_owned() {
-            if site_pubkey != pubkey {
-                log::info!("Non-matching key.");
-                return false;
-            }
-        } else {
-            log::info!("The site has no pubkey.");
-            return false;
-        }
-    } else {
-        log::info!("Missing auth header.");
-        return false;
+#[utoipa::path(
+    delete,
+    path = "/api/invites/{code}",
+    tag = "operator",
+    params(
+        ("code" = String, Path, description = "The invite code"),
+    ),
+    responses(
+        (status = 200, description = "Done", body = Object, example = json!({})),
+        (status = 403, description = "Not authorized by the operator's key"),
+        (status = 404, description = "No such invite"),
+    ),
+    security(("nostr" = []))
+)]
+async fn handle_delete_invite(request: Request<State>) -> tide::Result<Response> {
+    if !is_authorized(&request, None, auth::Access::Operator, &nostr_auth) {
+        return Ok(build_operator_only_response());
     }
+    let code = request.param("code").unwrap().to_owned();
+    let result = logging::spawn_blocking(move || {
+        registration::update(|r| {
+            if r.remove_invite(&code) {
+                Ok(json!({}))
+            } else {
+                Err(io::Error::new(io::ErrorKind::NotFound, "No such invite"))
+            }
+        })
+    })
+    .await;
 
-    return true;
+    Ok(build_result_response(result))
 }
 
-fn write_file<C>(
-    site_path: &str,
-    host: &str,
-    hash: &str,
-    mime: &http_types::mime::Mime,
-    size: usize,
-    content: C,
-) -> FileMetadata
-where
-    C: AsRef<[u8]>,
-{
-    let metadata = FileMetadata {
-        sha256: hash.to_owned(),
-        content_type: mime.essence().to_owned(),
-        size,
-        url: format!("https://{}/{}", host, hash),
-    };
-
-    fs::create_dir_all(format!("{}/_content/files", site_path)).unwrap();
-    fs::write(format!("{}/_content/files/{}", site_path, hash), content).unwrap();
-    fs::write(
-        format!("{}/_content/files/{}.metadata.json", site_path, hash),
-        serde_json::to_string(&metadata).unwrap(),
-    )
-    .unwrap();
+#[utoipa::path(
+    get,
+    path = "/api/pending-sites",
+    tag = "operator",
+    responses(
+        (status = 200, description = "The sites waiting for approval", body = [PendingSite]),
+        (status = 403, description = "Not authorized by the operator's key"),
+    ),
+    security(("nostr" = []))
+)]
+async fn handle_get_pending_sites(request: Request<State>) -> tide::Result<Response> {
+    if !is_authorized(&request, None, auth::Access::Operator, &nostr_auth) {
+        return Ok(build_operator_only_response());
+    }
+    let result = logging::spawn_blocking(|| registration::get().map(|r| r.pending)).await;
 
-    metadata
+    Ok(build_result_response(result))
 }
 
-fn delete_file(site_path: &str, hash: &str) {
-    fs::remove_file(format!("{}/_content/files/{}", site_path, hash)).unwrap();
-    fs::remove_file(format!(
-        "{}/_content/files/{}.metadata.json",
-        site_path, hash
-    ))
-    .unwrap();
+/// Takes the site waiting for approval at `domain` out of the queue.
+async fn take_pending_site(domain: &str) -> io::Result<registration::PendingSite> {
+    let domain = domain.to_owned();
+    logging::spawn_blocking(move || {
+        registration::update(|r| {
+            r.take_pending(&domain).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, "No such site waiting for approval")
+            })
+        })
+    })
+    .await
 }
 
-async fn handle_nip96_upload_request(mut request: Request<State>) -> tide::Result<Response> {
-    if request.method() == Method::Options {
-        return Ok(Response::builder(StatusCode::Ok)
-            .header("Access-Control-Allow-Origin", "*")
-            .header("Access-Control-Allow-Headers", "Authorization")
-            .build());
+#[utoipa::path(
+    post,
+    path = "/api/pending-sites/{domain}",
+    tag = "operator",
+    params(
+        ("domain" = String, Path, description = "Domain of the site"),
+    ),
+    responses(
+        (status = 200, description = "The site was created", body = Object, example = json!({})),
+        (status = 403, description = "Not authorized by the operator's key"),
+        (status = 404, description = "No such site waiting for approval"),
+        (status = 409, description = "There is a site at this domain already"),
+    ),
+    security(("nostr" = []))
+)]
+async fn handle_approve_pending_site(request: Request<State>) -> tide::Result<Response> {
+    if !is_authorized(&request, None, auth::Access::Operator, &nostr_auth) {
+        return Ok(build_operator_only_response());
+    }
+    let domain = request.param("domain").unwrap();
+    let state = request.state();
+    if state.sites.load().contains_key(domain) {
+        return Ok(Response::builder(StatusCode::Conflict).build());
     }
+    let pending = match take_pending_site(domain).await {
+        Ok(pending) => pending,
+        Err(e) => return Ok(build_result_response::<()>(Err(e))),
+    };
 
-    let site_path = {
-        if let Some(site) = get_site(&request) {
-            if !is_authorized(&request, &site, &nostr_auth) {
-                return Ok(Response::builder(StatusCode::Forbidden)
-                    .header("Access-Control-Allow-Origin", "*")
-                    .build());
-            }
-            format!("{}/{}", site::SITE_PATH, site.domain)
-        } else {
-            return Ok(Response::builder(StatusCode::NotFound).build());
+    let site = match logging::spawn_blocking({
+        let (domain, themes) = (domain.to_owned(), state.themes.load());
+        move || site::create_site(&domain, Some(pending.pubkey), &themes)
+    })
+    .await
+    {
+        Ok(site) => site,
+        Err(e) => {
+            log::error!("Failed to create site {}: {}", domain, e);
+            return Ok(Response::builder(StatusCode::InternalServerError).build());
         }
     };
+    log::info!("Approved site {}.", domain);
+    add_site(state, site);
 
-    let content_type = request
-        .header(tide::http::headers::CONTENT_TYPE)
-        .unwrap()
-        .as_str();
-    let boundary_index = content_type.find("boundary=").unwrap();
-    let boundary: String = content_type
-        .chars()
-        .skip(boundary_index)
-        .skip("boundary=".len())
-        .collect();
-    let bytes = request.body_bytes().await?;
-    let stream = once(async move { Result::<Bytes, Infallible>::Ok(Bytes::from(bytes)) });
-    let mut multipart = Multipart::new(stream, boundary);
-    while let Some(field) = multipart.next_field().await.unwrap() {
-        if field.name().unwrap() == "file" {
-            let content = field.bytes().await.unwrap();
-            let hash = sha256::digest(&*content);
-            let mime = mime::Mime::sniff(&content);
-            if mime.is_err() || !NIP96_CONTENT_TYPES.contains_key(mime.as_ref().unwrap().essence())
-            {
-                return Ok(Response::builder(StatusCode::BadRequest)
-                    .content_type(mime::JSON)
-                    .header("Access-Control-Allow-Origin", "*")
-                    .body(json!({"status": "error", "message": "Unknown content type."}))
-                    .build());
-            }
+    Ok(build_result_response(Ok(json!({}))))
+}
 
-            let metadata = write_file(
-                &site_path,
-                request.host().unwrap(),
-                &hash,
-                &mime.unwrap(),
-                content.len(),
-                content,
-            );
+#[utoipa::path(
+    delete,
+    path = "/api/pending-sites/{domain}",
+    tag = "operator",
+    params(
+        ("domain" = String, Path, description = "Domain of the site"),
+    ),
+    responses(
+        (status = 200, description = "Done", body = Object, example = json!({})),
+        (status = 403, description = "Not authorized by the operator's key"),
+        (status = 404, description = "No such site waiting for approval"),
+    ),
+    security(("nostr" = []))
+)]
+async fn handle_reject_pending_site(request: Request<State>) -> tide::Result<Response> {
+    if !is_authorized(&request, None, auth::Access::Operator, &nostr_auth) {
+        return Ok(build_operator_only_response());
+    }
+    let domain = request.param("domain").unwrap();
+    let result = take_pending_site(domain).await.map(|_| json!({}));
+    if result.is_ok() {
+        log::info!("Rejected site {}.", domain);
+    }
 
-            return Ok(Response::builder(StatusCode::Created)
-               .content_type(mime::JSON)
-               .header("Access-Control-Allow-Origin", "*")
-               .body(json!({"status": "success", "nip94_event": {"tags": [["url", metadata.url], ["ox", hash]]}}).to_string())
-               .build());
+    Ok(build_result_response(result))
+}
+
+/// How long the token confirming the deletion of a site can be used for.
+const DELETION_TOKEN_TTL: Duration = Duration::from_secs(300);
+
+/// Deletes a site, once confirmed: the first request gets a token, to be passed as `?confirm=` to actually delete it.
+/// The site is archived first, unless `?archive=false`.
+#[utoipa::path(
+    delete,
+    path = "/api/sites/{domain}",
+    tag = "sites",
+    params(
+        ("domain" = String, Path, description = "Domain of the site"),
+        ("confirm" = Option<String>, Query, description = "Token returned by a first request, confirming the deletion"),
+        ("archive" = Option<bool>, Query, description = "Whether to archive the site first (by default, true)"),
+    ),
+    responses(
+        (status = 200, description = "The site was deleted", body = Object, example = json!({"archived": true})),
+        (status = 428, description = "The token to pass as `confirm` to actually delete the site", body = Object, example = json!({"confirm": "0123456789abcdef0123456789abcdef", "expires_in": 300})),
+        (status = 403, description = "Not authorized by the site's key"),
+        (status = 404, description = "No such site"),
+    ),
+    security(("nostr" = []))
+)]
+async fn handle_delete_site(request: Request<State>) -> tide::Result<Response> {
+    let domain = request.param("domain").unwrap().to_owned();
+    let state = request.state();
+    let site = match state.sites.load().get(&domain) {
+        Some(site) => site.clone(),
+        None => return Ok(Response::builder(StatusCode::NotFound).build()),
+    };
+    if !is_authorized(&request, Some(&site), auth::Access::Owner, &nostr_auth) {
+        return Ok(Response::builder(StatusCode::Forbidden).build());
+    }
+    let query = request.url().query_pairs().collect::<HashMap<_, _>>();
+    let archive = query.get("archive").is_none_or(|a| a != "false");
+
+    {
+        let mut tokens = state.deletion_tokens.lock().unwrap();
+        tokens.retain(|_, (_, issued_at)| issued_at.elapsed() < DELETION_TOKEN_TTL);
+        let is_confirmed = query
+            .get("confirm")
+            .is_some_and(|c| tokens.get(&domain).is_some_and(|(token, _)| token == c));
+        if !is_confirmed {
+            let token = utils::sha256_hex(
+                format!("{}:{}:{:?}", domain, process::id(), SystemTime::now()).as_bytes(),
+            )[..32]
+                .to_owned();
+            tokens.insert(domain.clone(), (token.clone(), Instant::now()));
+            return Ok(Response::builder(StatusCode::PreconditionRequired)
+                .content_type(mime::JSON)
+                .body(
+                    json!({"confirm": token, "expires_in": DELETION_TOKEN_TTL.as_secs()})
+                        .to_string(),
+                )
+                .build());
         }
+        tokens.remove(&domain);
     }
 
-    Ok(Response::builder(StatusCode::BadRequest)
+    let result = logging::spawn_blocking({
+        let domain = domain.clone();
+        move || site::delete_site(&domain, archive)
+    })
+    .await;
+    if let Err(e) = result {
+        log::error!("Failed to delete site {}: {}", domain, e);
+        return Ok(Response::builder(StatusCode::InternalServerError).build());
+    }
+    log::info!("Deleted site {}!", domain);
+    state.sites.update(|sites| sites.remove(&domain));
+
+    Ok(Response::builder(StatusCode::Ok)
         .content_type(mime::JSON)
-        .header("Access-Control-Allow-Origin", "*")
-        .body(json!({"status": "error", "message": "File not found."}))
+        .body(json!({"archived": archive}).to_string())
         .build())
 }
 
-async fn handle_nip96_delete_request(request: Request<State>) -> tide::Result<Response> {
-    let site_path = {
-        if let Some(site) = get_site(&request) {
-            if !is_authorized(&request, &site, &nostr_auth) {
-                return Ok(Response::builder(StatusCode::Forbidden).build());
-            }
-            format!("{}/{}", site::SITE_PATH, site.domain)
-        } else {
-            return Ok(Response::builder(StatusCode::NotFound).build());
-        }
+/// What `/api/sites/<domain>/stats` returns.
+#[derive(Serialize, ToSchema)]
+struct SiteStats {
+    #[serde(flatten)]
+    overview: SiteOverview,
+    traffic: TrafficSummary,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/sites/{domain}/stats",
+    tag = "sites",
+    params(
+        ("domain" = String, Path, description = "Domain of the site"),
+    ),
+    responses(
+        (status = 200, description = "An overview of the site and of its traffic", body = SiteStats),
+        (status = 403, description = "Not authorized by the site's key"),
+        (status = 404, description = "No such site"),
+    ),
+    security(("nostr" = []))
+)]
+async fn handle_get_site_stats(request: Request<State>) -> tide::Result<Response> {
+    let domain = request.param("domain").unwrap();
+    let site = match request.state().sites.load().get(domain) {
+        Some(site) => site.clone(),
+        None => return Ok(Response::builder(StatusCode::NotFound).build()),
     };
+    if !is_authorized(&request, Some(&site), auth::Access::Owner, &nostr_auth) {
+        return Ok(Response::builder(StatusCode::Forbidden).build());
+    }
 
-    delete_file(&site_path, request.param("sha256").unwrap());
+    let traffic = request.state().traffic.clone();
+    let (overview, traffic) = logging::spawn_blocking(move || {
+        let traffic = get_traffic_summary(&traffic, &site.domain);
+        (site.get_overview(), traffic)
+    })
+    .await;
 
-    return Ok(Response::builder(StatusCode::Ok)
+    Ok(Response::builder(StatusCode::Ok)
         .content_type(mime::JSON)
-        .body(json!({ "status": "success" }))
-        .build());
+        .body(json!(SiteStats { overview, traffic }).to_string())
+        .build())
 }
 
-async fn handle_blossom_upload_request(mut request: Request<State>) -> tide::Result<Response> {
-    if request.method() == Method::Options {
-        return Ok(Response::builder(StatusCode::Ok)
-            .header("Access-Control-Allow-Origin", "*")
-            .header("Access-Control-Allow-Headers", "Authorization,*")
-            .header("Access-Control-Allow-Methods", "GET,PUT,DELETE")
-            .build());
+/// The traffic of the site today and over the last 30 days, counting nothing if it cannot be read.
+fn get_traffic_summary(traffic: &traffic::Traffic, domain: &str) -> traffic::TrafficSummary {
+    let days = traffic.get(domain).unwrap_or_else(|e| {
+        log::warn!("Cannot read the traffic of {}: {}", domain, e);
+        vec![]
+    });
+
+    traffic::get_summary(&days, chrono::Utc::now().date_naive())
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/sites/{domain}/traffic",
+    tag = "sites",
+    params(
+        ("domain" = String, Path, description = "Domain of the site"),
+    ),
+    responses(
+        (status = 200, description = "The traffic of the site, one line per day", content_type = "text/csv", body = String),
+        (status = 403, description = "Not authorized by the site's key or the operator's key"),
+        (status = 404, description = "No such site"),
+    ),
+    security(("nostr" = []))
+)]
+async fn handle_get_site_traffic(request: Request<State>) -> tide::Result<Response> {
+    let domain = request.param("domain").unwrap();
+    let site = match request.state().sites.load().get(domain) {
+        Some(site) => site.clone(),
+        None => return Ok(Response::builder(StatusCode::NotFound).build()),
+    };
+    if !is_authorized(
+        &request,
+        Some(&site),
+        auth::Access::OwnerOrOperator,
+        &nostr_auth,
+    ) {
+        return Ok(Response::builder(StatusCode::Forbidden).build());
     }
 
-    let site_path = {
-        if let Some(site) = get_site(&request) {
-            if !is_authorized(&request, &site, &blossom_upload_auth) {
-                return Ok(Response::builder(StatusCode::Unauthorized)
-                    .header("Access-Control-Allow-Origin", "*")
-                    .build());
-            }
-            format!("{}/{}", site::SITE_PATH, site.domain)
-        } else {
-            return Ok(Response::builder(StatusCode::NotFound).build());
+    let traffic = request.state().traffic.clone();
+    let domain = site.domain.clone();
+    let days = logging::spawn_blocking(move || traffic.get(&domain)).await;
+
+    match days {
+        Ok(days) => Ok(Response::builder(StatusCode::Ok)
+            .content_type("text/csv; charset=utf-8")
+            .header(
+                "Content-Disposition",
+                format!("attachment; filename=\"{}-traffic.csv\"", site.domain),
+            )
+            .body(traffic::to_csv(&days))
+            .build()),
+        Err(e) => {
+            log::error!("Cannot read the traffic of {}: {}", site.domain, e);
+            Ok(Response::builder(StatusCode::InternalServerError).build())
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/sites/{domain}/content",
+    tag = "content",
+    params(
+        ("domain" = String, Path, description = "Domain of the site"),
+    ),
+    responses(
+        (status = 200, description = "The `_content` directory of the site, without the uploaded files", content_type = "application/gzip", body = Binary),
+        (status = 403, description = "Not authorized by the site's key"),
+        (status = 404, description = "No such site"),
+    ),
+    security(("nostr" = []))
+)]
+async fn handle_get_site_content(request: Request<State>) -> tide::Result<Response> {
+    let site = match get_owned_site(&request) {
+        Ok(site) => site,
+        Err(status) => return Ok(Response::builder(status).build()),
+    };
+
+    let domain = site.domain.clone();
+    match logging::spawn_blocking(move || site::export_content(&domain)).await {
+        Ok(archive) => Ok(Response::builder(StatusCode::Ok)
+            .content_type("application/gzip")
+            .header(
+                "Content-Disposition",
+                format!("attachment; filename=\"{}-content.tar.gz\"", site.domain),
+            )
+            .body(archive)
+            .build()),
+        Err(e) => {
+            log::error!("Failed to export the content of {}: {}", site.domain, e);
+            Ok(Response::builder(StatusCode::InternalServerError).build())
         }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/sites/{domain}/export",
+    tag = "sites",
+    params(
+        ("domain" = String, Path, description = "Domain of the site"),
+    ),
+    responses(
+        (status = 200, description = "The site as it is served", content_type = "application/zip", body = Binary),
+        (status = 403, description = "Not authorized by the site's key"),
+        (status = 404, description = "No such site"),
+    ),
+    security(("nostr" = []))
+)]
+async fn handle_get_site_export(request: Request<State>) -> tide::Result<Response> {
+    let site = match get_owned_site(&request) {
+        Ok(site) => site,
+        Err(status) => return Ok(Response::builder(status).build()),
+    };
+    let state = request.state();
+    let Some(theme) = state.themes.load().get(&site.config.theme).cloned() else {
+        return Ok(Response::builder(StatusCode::InternalServerError).build());
     };
 
-    let bytes = request.body_bytes().await?;
+    let domain = site.domain.clone();
+    let language = state.default_language.clone();
+    let charset = state.default_charset.clone();
+    match logging::spawn_blocking(move || build::export_site(&site, &theme, &language, &charset))
+        .await
+    {
+        Ok(archive) => Ok(Response::builder(StatusCode::Ok)
+            .content_type("application/zip")
+            .header(
+                "Content-Disposition",
+                format!("attachment; filename=\"{}.zip\"", domain),
+            )
+            .body(archive)
+            .build()),
+        Err(e) => {
+            log::error!("Failed to export {}: {}", domain, e);
+            Ok(Response::builder(StatusCode::InternalServerError).build())
+        }
+    }
+}
 
-    let hash = sha256::digest(&*bytes);
+#[utoipa::path(
+    put,
+    path = "/api/sites/{domain}/content",
+    tag = "content",
+    params(
+        ("domain" = String, Path, description = "Domain of the site"),
+    ),
+    request_body(content = Binary, content_type = "application/gzip"),
+    responses(
+        (status = 200, description = "The content was replaced", body = Object, example = json!({})),
+        (status = 400, description = "The archive cannot be extracted"),
+        (status = 403, description = "Not authorized by the site's key"),
+        (status = 404, description = "No such site"),
+        (status = 413, description = "The content would take the site over its storage quota"),
+    ),
+    security(("nostr" = []))
+)]
+async fn handle_put_site_content(mut request: Request<State>) -> tide::Result<Response> {
+    let site = match get_owned_site(&request) {
+        Ok(site) => site,
+        Err(status) => return Ok(Response::builder(status).build()),
+    };
 
-    let mime = mime::Mime::sniff(&bytes);
-    if mime.is_err() || !BLOSSOM_CONTENT_TYPES.contains(mime.as_ref().unwrap().essence()) {
-        return Ok(Response::builder(StatusCode::BadRequest)
-            .content_type(mime::JSON)
-            .header("Access-Control-Allow-Origin", "*")
-            .body(json!({"message": "Unknown content type."}))
-            .build());
+    // the archive is received to a file first, so that it is never held in memory
+    let archive_path = format!("{}/{}/_content.tar.gz.part", paths::sites(), site.domain);
+    let mut file = async_std::fs::File::create(&archive_path).await?;
+    let received = async_std::io::copy(request.take_body(), &mut file).await;
+    drop(file);
+    if let Err(e) = received {
+        let _ = async_std::fs::remove_file(&archive_path).await;
+        return Err(e.into());
     }
 
-    let metadata = write_file(
-        &site_path,
-        request.host().unwrap(),
-        &hash,
-        &mime.unwrap(),
-        bytes.len(),
-        bytes,
-    );
+    let result = logging::spawn_blocking({
+        let site = site.clone();
+        let archive_path = archive_path.clone();
+        move || {
+            let _loading = site.loading.lock().unwrap();
+            // the uploaded files stay, so only what is left of the quota can be used
+            let max_size = site
+                .config
+                .quota
+                .storage_bytes
+                .map(|storage_bytes| storage_bytes.saturating_sub(site.get_files_size()));
+            site::import_content(&site.domain, fs::File::open(&archive_path)?, max_size)
+        }
+    })
+    .await;
+    let _ = async_std::fs::remove_file(&archive_path).await;
+    match result {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::QuotaExceeded => {
+            return Ok(Response::builder(StatusCode::PayloadTooLarge)
+                .content_type(mime::JSON)
+                .body(json!({ "message": QUOTA_EXCEEDED_MESSAGE }))
+                .build())
+        }
+        Err(e) if e.kind() == io::ErrorKind::InvalidInput => {
+            return Ok(Response::builder(StatusCode::BadRequest)
+                .body(e.to_string())
+                .build())
+        }
+        Err(e) => {
+            log::error!("Failed to import the content of {}: {}", site.domain, e);
+            return Ok(Response::builder(StatusCode::InternalServerError).build());
+        }
+    }
+    log::info!("Imported the content of {}.", site.domain);
+    site.record_change("Import _content".to_owned());
 
-    return Ok(Response::builder(StatusCode::Created)
+    let state = request.state();
+    let (domain, themes) = (site.domain.clone(), state.themes.load());
+    match logging::spawn_blocking(move || site::load_site(&domain, &themes)).await {
+        Ok(site) => {
+            state
+                .sites
+                .update(|sites| sites.insert(site.domain.clone(), site));
+        }
+        Err(e) => {
+            log::error!("Failed to reload site {}: {}", site.domain, e);
+            return Ok(Response::builder(StatusCode::InternalServerError).build());
+        }
+    }
+
+    Ok(Response::builder(StatusCode::Ok)
         .content_type(mime::JSON)
-        .header("Access-Control-Allow-Origin", "*")
-        .body(serde_json::to_string(&metadata).unwrap())
-        .build());
+        .body("{}")
+        .build())
 }
 
-async fn handle_blossom_delete_request(request: Request<State>) -> tide::Result<Response> {
-    let site_path = {
-        if let Some(site) = get_site(&request) {
-            if !is_authorized(&request, &site, &blossom_delete_auth) {
-                return Ok(Response::builder(StatusCode::Unauthorized)
-                    .header("Access-Control-Allow-Origin", "*")
-                    .build());
-            }
-            format!("{}/{}", site::SITE_PATH, site.domain)
-        } else {
-            return Ok(Response::builder(StatusCode::NotFound).build());
-        }
+#[utoipa::path(
+    post,
+    path = "/api/sites/{domain}/git/push",
+    tag = "sites",
+    params(
+        ("domain" = String, Path, description = "Domain of the site"),
+    ),
+    responses(
+        (status = 200, description = "Done", body = Object, example = json!({})),
+        (status = 400, description = "The site has no git remote"),
+        (status = 403, description = "Not authorized by the site's key"),
+        (status = 404, description = "No such site"),
+        (status = 502, description = "The push failed"),
+    ),
+    security(("nostr" = []))
+)]
+async fn handle_git_push(request: Request<State>) -> tide::Result<Response> {
+    let site = match get_owned_site(&request) {
+        Ok(site) => site,
+        Err(status) => return Ok(Response::builder(status).build()),
     };
+    let config = site.config.git.clone().unwrap_or_default();
+    if let Err(e) = config.get_remote() {
+        return Ok(Response::builder(StatusCode::BadRequest).body(e).build());
+    }
 
-    delete_file(&site_path, request.param("sha256").unwrap());
+    let domain = site.domain.clone();
+    if let Err(e) = logging::spawn_blocking(move || git::push(&domain, &config)).await {
+        log::warn!("Failed to push {}: {}", site.domain, e);
+        return Ok(Response::builder(StatusCode::BadGateway).body(e).build());
+    }
 
-    return Ok(Response::builder(StatusCode::Ok)
+    Ok(Response::builder(StatusCode::Ok)
         .content_type(mime::JSON)
-        .header("Access-Control-Allow-Origin", "*")
-        .body(json!({}))
-        .build());
+        .body("{}")
+        .build())
 }
 
-#[async_std::main]
-async fn main() -> Result<(), std::io::Error> {
-    let args = Cli::parse();
+#[utoipa::path(
+    post,
+    path = "/api/sites/{domain}/git/pull",
+    tag = "sites",
+    params(
+        ("domain" = String, Path, description = "Domain of the site"),
+    ),
+    responses(
+        (status = 200, description = "Whether anything changed", body = Object, example = json!({"updated": true})),
+        (status = 400, description = "The site has no git remote"),
+        (status = 403, description = "Not authorized by the site's key"),
+        (status = 404, description = "No such site"),
+        (status = 502, description = "The pull failed"),
+    ),
+    security(("nostr" = []))
+)]
+async fn handle_git_pull(request: Request<State>) -> tide::Result<Response> {
+    let site = match get_owned_site(&request) {
+        Ok(site) => site,
+        Err(status) => return Ok(Response::builder(status).build()),
+    };
+    let config = site.config.git.clone().unwrap_or_default();
+    if let Err(e) = config.get_remote() {
+        return Ok(Response::builder(StatusCode::BadRequest).body(e).build());
+    }
 
-    femme::with_level(log::LevelFilter::Info);
+    let domain = site.domain.clone();
+    let updated = logging::spawn_blocking(move || {
+        let previous_config = site::read_config_table(&domain).map_err(|e| e.to_string())?;
+        let updated = git::pull(&domain, &config)?;
+        if updated {
+            site::restore_protected_config(&domain, &previous_config).map_err(|e| e.to_string())?;
+        }
+        Ok::<_, String>(updated)
+    });
+    let updated = match updated.await {
+        Ok(updated) => updated,
+        Err(e) => {
+            log::warn!("Failed to pull {}: {}", site.domain, e);
+            return Ok(Response::builder(StatusCode::BadGateway).body(e).build());
+        }
+    };
+    if updated {
+        let state = request.state();
+        let (domain, themes) = (site.domain.clone(), state.themes.load());
+        match logging::spawn_blocking(move || site::load_site(&domain, &themes)).await {
+            Ok(site) => {
+                state
+                    .sites
+                    .update(|sites| sites.insert(site.domain.clone(), site));
+            }
+            Err(e) => {
+                log::error!("Failed to reload site {}: {}", site.domain, e);
+                return Ok(Response::builder(StatusCode::InternalServerError).build());
+            }
+        }
+    }
 
-    let mut themes = theme::load_themes();
+    Ok(Response::builder(StatusCode::Ok)
+        .content_type(mime::JSON)
+        .body(json!({ "updated": updated }))
+        .build())
+}
 
-    if themes.len() == 0 {
-        log::error!("No themes found!");
+#[utoipa::path(
+    post,
+    path = "/api/sites/{domain}/reload",
+    tag = "sites",
+    params(
+        ("domain" = String, Path, description = "Domain of the site"),
+    ),
+    responses(
+        (status = 200, description = "The site was read again from its files", body = Object, example = json!({})),
+        (status = 403, description = "Not authorized by the site's key"),
+        (status = 404, description = "No such site"),
+        (status = 500, description = "The site could not be read, and keeps its previous version"),
+    ),
+    security(("nostr" = []))
+)]
+async fn handle_reload_site(request: Request<State>) -> tide::Result<Response> {
+    let site = match get_owned_site(&request) {
+        Ok(site) => site,
+        Err(status) => return Ok(Response::builder(status).build()),
+    };
 
-        let stdin = io::stdin();
-        let mut response = String::new();
-        while response != "n" && response != "y" {
-            print!("Fetch themes from {}? [y/n]? ", THEMES_REPO);
-            io::stdout().flush().unwrap();
-            response = stdin.lock().lines().next().unwrap().unwrap().to_lowercase();
-        }
+    let state = request.state();
+    let (themes, sites) = (state.themes.clone(), state.sites.clone());
+    let reloaded =
+        logging::spawn_blocking(move || watch::reload_site(&site.domain, &themes, &sites)).await;
+    if !reloaded {
+        return Ok(Response::builder(StatusCode::InternalServerError).build());
+    }
 
-        if response == "y" {
-            let url = format!("{}.git", THEMES_REPO);
-            match Repository::clone(&url, "./themes") {
-                Ok(repo) => {
-                    for mut submodule in repo.submodules().unwrap() {
-                        log::info!(
-                            "Cloning theme: {}...",
-                            submodule.path().as_os_str().to_str().unwrap()
-                        );
-                        if let Err(e) = submodule.update(true, None) {
-                            log::warn!(
-                                "Failed to clone theme {}: {}",
-                                submodule.path().as_os_str().to_str().unwrap(),
-                                e
-                            );
-                        };
-                    }
-                }
-                Err(e) => panic!("Failed to clone themes repo: {}", e),
-            };
-        } else {
-            return Ok(());
-        }
+    Ok(Response::builder(StatusCode::Ok)
+        .content_type(mime::JSON)
+        .body("{}")
+        .build())
+}
 
-        themes = theme::load_themes();
+/// Where the token verifying a domain is published, as a TXT record.
+fn get_verification_record(domain: &str) -> String {
+    format!("_servus-challenge.{}", domain)
+}
 
-        if themes.len() == 0 {
-            panic!("No themes!");
-        }
+/// The site owned by the author of the request, or the response to send if there is no such site.
+fn get_owned_site(request: &Request<State>) -> Result<Site, StatusCode> {
+    let domain = request.param("domain").unwrap();
+    let site = match request.state().sites.load().get(domain) {
+        Some(site) => site.clone(),
+        None => return Err(StatusCode::NotFound),
+    };
+    if !is_authorized(request, Some(&site), auth::Access::Owner, &nostr_auth) {
+        return Err(StatusCode::Forbidden);
     }
 
-    let sites;
-
-    let existing_sites = site::load_sites();
+    Ok(site)
+}
 
-    if existing_sites.len() == 0 {
-        let stdin = io::stdin();
-        let mut response = String::new();
-        while response != "n" && response != "y" {
-            print!("No sites found. Create a default site [y/n]? ");
-            io::stdout().flush().unwrap();
-            response = stdin.lock().lines().next().unwrap().unwrap().to_lowercase();
+#[utoipa::path(
+    get,
+    path = "/api/sites/{domain}/domains",
+    tag = "domains",
+    params(
+        ("domain" = String, Path, description = "Domain of the site"),
+    ),
+    responses(
+        (status = 200, description = "The domains of the site, and the ones being verified", body = Object, example = json!({"aliases": ["www.example.com"], "canonical_domain": null, "verifications": [{"domain": "example.org", "record": "_servus-challenge.example.org", "token": "0123456789abcdef"}]})),
+        (status = 403, description = "Not authorized by the site's key"),
+        (status = 404, description = "No such site"),
+    ),
+    security(("nostr" = []))
+)]
+async fn handle_get_site_domains(request: Request<State>) -> tide::Result<Response> {
+    let site = match get_owned_site(&request) {
+        Ok(site) => site,
+        Err(status) => return Ok(Response::builder(status).build()),
+    };
+    let result = logging::spawn_blocking({
+        let domain = site.domain.clone();
+        move || {
+            registration::get().map(|r| {
+                r.verifications
+                    .into_iter()
+                    .filter(|v| v.site == domain)
+                    .map(|v| {
+                        json!({
+                            "domain": v.domain,
+                            "record": get_verification_record(&v.domain),
+                            "token": v.token,
+                        })
+                    })
+                    .collect::<Vec<_>>()
+            })
         }
+    })
+    .await;
 
-        if response == "y" {
-            print!("Domain: ");
-            io::stdout().flush().unwrap();
-            let domain = stdin.lock().lines().next().unwrap().unwrap().to_lowercase();
-            print!("Admin pubkey: ");
-            io::stdout().flush().unwrap();
-            let admin_pubkey = stdin.lock().lines().next().unwrap().unwrap().to_lowercase();
-            let site = site::create_site(&domain, Some(admin_pubkey));
+    Ok(build_result_response(result.map(|verifications| {
+        json!({
+            "aliases": site.config.aliases,
+            "canonical_domain": site.config.canonical_domain,
+            "verifications": verifications,
+        })
+    })))
+}
 
-            sites = [(domain, site)].iter().cloned().collect();
-        } else {
-            sites = HashMap::new();
+#[utoipa::path(
+    post,
+    path = "/api/sites/{domain}/domains",
+    tag = "domains",
+    params(
+        ("domain" = String, Path, description = "Domain of the site"),
+    ),
+    request_body(content = PostSiteRequestBody, description = "The domain to attach, as `domain`"),
+    responses(
+        (status = 200, description = "The TXT record to publish the token in", body = Object, example = json!({"domain": "example.org", "record": "_servus-challenge.example.org", "token": "0123456789abcdef"})),
+        (status = 400, description = "Invalid domain"),
+        (status = 403, description = "Not authorized by the site's key"),
+        (status = 404, description = "No such site"),
+        (status = 409, description = "The domain is used by a site already"),
+    ),
+    security(("nostr" = []))
+)]
+async fn handle_post_site_domain(mut request: Request<State>) -> tide::Result<Response> {
+    let site = match get_owned_site(&request) {
+        Ok(site) => site,
+        Err(status) => return Ok(Response::builder(status).build()),
+    };
+    let alias = match request.body_json::<PostSiteRequestBody>().await {
+        Ok(body) => body.domain,
+        Err(_) => return Ok(Response::builder(StatusCode::BadRequest).build()),
+    };
+    let alias = match site::normalize_domain(&alias) {
+        Some(alias) if site::is_valid_domain(&alias) && !site::is_reserved_domain(&alias) => alias,
+        _ => {
+            return Ok(Response::builder(StatusCode::BadRequest)
+                .body("Invalid domain")
+                .build())
         }
-    } else {
-        sites = existing_sites;
+    };
+    if site::find_site(&request.state().sites.load(), &alias).is_some() {
+        return Ok(Response::builder(StatusCode::Conflict).build());
     }
 
-    let site_count = sites.len();
+    let result = logging::spawn_blocking(move || {
+        registration::update(|r| Ok(r.add_verification(&alias, &site.domain)))
+    })
+    .await;
 
-    let mut app = tide::with_state(State {
-        themes: Arc::new(RwLock::new(themes)),
-        sites: Arc::new(RwLock::new(sites)),
-    });
+    Ok(build_result_response(result.map(|v| {
+        json!({
+            "domain": v.domain,
+            "record": get_verification_record(&v.domain),
+            "token": v.token,
+        })
+    })))
+}
 
-    app.with(log::LogMiddleware::new());
-    app.at("/")
-        .with(WebSocket::new(handle_websocket))
-        .get(handle_index);
-    app.at("*path").options(handle_request).get(handle_request);
+#[derive(Deserialize, ToSchema)]
+struct VerifySiteDomainRequestBody {
+    /// Whether to redirect the pages of the site to the new domain.
+    #[serde(default)]
+    redirect: bool,
+}
 
-    // API
-    app.at("/api/sites")
-        .post(handle_post_site)
-        .get(handle_get_sites);
+#[utoipa::path(
+    post,
+    path = "/api/sites/{domain}/domains/{alias}/verify",
+    tag = "domains",
+    params(
+        ("domain" = String, Path, description = "Domain of the site"),
+        ("alias" = String, Path, description = "Domain being verified"),
+    ),
+    request_body(content = Option<VerifySiteDomainRequestBody>),
+    responses(
+        (status = 200, description = "The domain was added to the site", body = Object, example = json!({"aliases": ["example.org"]})),
+        (status = 403, description = "Not authorized by the site's key"),
+        (status = 404, description = "No such site or domain being verified"),
+        (status = 409, description = "The domain is used by another site already"),
+        (status = 412, description = "No TXT record containing the token yet"),
+    ),
+    security(("nostr" = []))
+)]
+async fn handle_verify_site_domain(mut request: Request<State>) -> tide::Result<Response> {
+    let site = match get_owned_site(&request) {
+        Ok(site) => site,
+        Err(status) => return Ok(Response::builder(status).build()),
+    };
+    let alias = request.param("alias").unwrap().to_owned();
+    let redirect = request
+        .body_json::<VerifySiteDomainRequestBody>()
+        .await
+        .is_ok_and(|body| body.redirect);
+    let verification = logging::spawn_blocking({
+        let (alias, domain) = (alias.clone(), site.domain.clone());
+        move || registration::get().map(|r| r.get_verification(&alias, &domain).cloned())
+    })
+    .await;
+    let verification = match verification {
+        Ok(Some(verification)) => verification,
+        Ok(None) => return Ok(Response::builder(StatusCode::NotFound).build()),
+        Err(e) => return Ok(build_result_response::<()>(Err(e))),
+    };
 
-    // Site API
-    app.at("/api/config")
-        .get(handle_get_site_config)
-        .put(handle_put_site_config);
+    let records = dns::lookup_txt(&get_verification_record(&alias))
+        .await
+        .unwrap_or_else(|e| {
+            log::warn!("Cannot look up the TXT record of {}: {}", alias, e);
+            vec![]
+        });
+    if !records.contains(&verification.token) {
+        return Ok(Response::builder(StatusCode::PreconditionFailed)
+            .body(format!(
+                "No TXT record {} containing the token",
+                get_verification_record(&alias)
+            ))
+            .build());
+    }
 
-    // Blossom API
-    app.at("/upload")
-        .options(handle_blossom_upload_request)
-        .put(handle_blossom_upload_request);
-    app.at("/list/:pubkey").get(handle_blossom_list_request);
-    app.at("/:sha256").delete(handle_blossom_delete_request);
+    let state = request.state();
+    if site::find_site(&state.sites.load(), &alias).is_some_and(|s| s.domain != site.domain) {
+        return Ok(Response::builder(StatusCode::Conflict).build());
+    }
+    let result = logging::spawn_blocking({
+        let (alias, domain, themes) = (alias.clone(), site.domain.clone(), state.themes.load());
+        move || {
+            let site = site::add_alias(&domain, &alias, redirect, &themes)?;
+            // whoever else tried to verify the domain can stop trying
+            registration::update(|r| {
+                r.verifications.retain(|v| v.domain != alias);
+                Ok(())
+            })?;
+            Ok(site)
+        }
+    })
+    .await;
+    let site = match result {
+        Ok(site) => site,
+        Err(e) => return Ok(build_result_response::<()>(Err(e))),
+    };
+    log::info!("Verified domain {} for site {}.", alias, site.domain);
+    let aliases = site.config.aliases.clone();
+    add_site(state, site);
 
-    // NIP-96 API
-    app.at("/api/files")
-        .options(handle_nip96_upload_request)
-        .post(handle_nip96_upload_request);
-    app.at("/api/files/:sha256")
-        .delete(handle_nip96_delete_request);
+    Ok(build_result_response(Ok(json!({ "aliases": aliases }))))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/sites/{domain}/domains/{alias}",
+    tag = "domains",
+    params(
+        ("domain" = String, Path, description = "Domain of the site"),
+        ("alias" = String, Path, description = "Domain to detach, or to stop verifying"),
+    ),
+    responses(
+        (status = 200, description = "Done", body = Object, example = json!({})),
+        (status = 403, description = "Not authorized by the site's key"),
+        (status = 404, description = "No such site or domain"),
+    ),
+    security(("nostr" = []))
+)]
+async fn handle_delete_site_domain(request: Request<State>) -> tide::Result<Response> {
+    let site = match get_owned_site(&request) {
+        Ok(site) => site,
+        Err(status) => return Ok(Response::builder(status).build()),
+    };
+    let alias = request.param("alias").unwrap().to_owned();
+
+    let state = request.state();
+    if !site.config.aliases.contains(&alias) {
+        // not verified yet, so just no longer verifying it
+        let result = logging::spawn_blocking(move || {
+            registration::update(|r| {
+                if r.remove_verification(&alias, &site.domain) {
+                    Ok(json!({}))
+                } else {
+                    Err(io::Error::new(io::ErrorKind::NotFound, "No such domain"))
+                }
+            })
+        })
+        .await;
+        return Ok(build_result_response(result));
+    }
+
+    let result = logging::spawn_blocking({
+        let (domain, themes) = (site.domain.clone(), state.themes.load());
+        move || site::remove_alias(&domain, &alias, &themes)
+    })
+    .await;
+    match result {
+        Ok(site) => {
+            add_site(state, site);
+            Ok(build_result_response(Ok(json!({}))))
+        }
+        Err(e) => Ok(build_result_response::<()>(Err(e))),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/sites/{domain}/clone",
+    tag = "sites",
+    params(
+        ("domain" = String, Path, description = "Domain of the site"),
+    ),
+    request_body(content = PostSiteRequestBody, description = "The new domain, as `domain`"),
+    responses(
+        (status = 200, description = "Done", body = Object, example = json!({})),
+        (status = 400, description = "Invalid domain"),
+        (status = 403, description = "Not authorized by the site's key"),
+        (status = 404, description = "No such site"),
+        (status = 409, description = "There is a site at the new domain already"),
+    ),
+    security(("nostr" = []))
+)]
+async fn handle_clone_site(mut request: Request<State>) -> tide::Result<Response> {
+    let domain = request.param("domain").unwrap().to_owned();
+    let new_domain = match request.body_json::<PostSiteRequestBody>().await {
+        Ok(body) => body.domain,
+        Err(_) => return Ok(Response::builder(StatusCode::BadRequest).build()),
+    };
+    let state = &request.state();
+
+    let site = match state.sites.load().get(&domain) {
+        Some(site) => site.clone(),
+        None => return Ok(Response::builder(StatusCode::NotFound).build()),
+    };
+    if !is_authorized(&request, Some(&site), auth::Access::Owner, &nostr_auth) {
+        return Ok(Response::builder(StatusCode::Forbidden).build());
+    }
+    let new_domain = match check_new_domain(&request, &new_domain) {
+        Ok(new_domain) => new_domain,
+        Err(e) => return Ok(Response::builder(StatusCode::BadRequest).body(e).build()),
+    };
+    if state.sites.load().contains_key(&new_domain) {
+        return Ok(Response::builder(StatusCode::Conflict).build());
+    }
+
+    let cloned = logging::spawn_blocking({
+        let (domain, new_domain) = (domain.clone(), new_domain.clone());
+        move || site::clone_site(&domain, &new_domain)
+    })
+    .await;
+    if let Err(e) = cloned {
+        log::warn!("Failed to clone site {}: {}", domain, e);
+        let status = match e.kind() {
+            io::ErrorKind::AlreadyExists => StatusCode::Conflict,
+            io::ErrorKind::InvalidInput => StatusCode::BadRequest,
+            _ => StatusCode::InternalServerError,
+        };
+        return Ok(Response::builder(status).build());
+    }
+    let new_site = match logging::spawn_blocking({
+        let (new_domain, themes) = (new_domain.clone(), state.themes.load());
+        move || site::load_site(&new_domain, &themes)
+    })
+    .await
+    {
+        Ok(site) => site,
+        Err(e) => {
+            log::error!("Failed to load cloned site {}: {}", new_domain, e);
+            return Ok(Response::builder(StatusCode::InternalServerError).build());
+        }
+    };
+
+    add_site(state, new_site);
+
+    Ok(Response::builder(StatusCode::Ok)
+        .content_type(mime::JSON)
+        .body("{}")
+        .build())
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/sites/{domain}/config",
+    tag = "sites",
+    params(
+        ("domain" = String, Path, description = "Domain of the site"),
+    ),
+    responses(
+        (status = 200, description = "The `_config.toml` of the site, and the themes it can use", body = Object, example = json!({"config": {"title": "My site", "theme": "hyde"}, "available_themes": ["hyde"]})),
+        (status = 403, description = "Not authorized by the site's key"),
+        (status = 404, description = "No such site"),
+    ),
+    security(("nostr" = []))
+)]
+async fn handle_get_site_settings(request: Request<State>) -> tide::Result<Response> {
+    let domain = request.param("domain").unwrap();
+    let site = match request.state().sites.load().get(domain) {
+        Some(site) => site.clone(),
+        None => return Ok(Response::builder(StatusCode::NotFound).build()),
+    };
+    if !is_authorized(&request, Some(&site), auth::Access::Owner, &nostr_auth) {
+        return Ok(Response::builder(StatusCode::Forbidden).build());
+    }
+
+    let domain = site.domain.clone();
+    let config = logging::spawn_blocking(move || site::read_config_table(&domain)).await?;
+    let mut themes: Vec<String> = request.state().themes.load().keys().cloned().collect();
+    themes.sort();
+
+    Ok(Response::builder(StatusCode::Ok)
+        .content_type(mime::JSON)
+        .body(json!({"config": config, "available_themes": themes}).to_string())
+        .build())
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/sites/{domain}/config",
+    tag = "sites",
+    params(
+        ("domain" = String, Path, description = "Domain of the site"),
+    ),
+    request_body(content = Object, description = "The keys to change, or to remove when `null`"),
+    responses(
+        (status = 200, description = "Done", body = Object, example = json!({})),
+        (status = 400, description = "Invalid config"),
+        (status = 403, description = "Not authorized by the site's key"),
+        (status = 404, description = "No such site"),
+    ),
+    security(("nostr" = []))
+)]
+async fn handle_put_site_settings(mut request: Request<State>) -> tide::Result<Response> {
+    let changes = match request
+        .body_json::<serde_json::Map<String, serde_json::Value>>()
+        .await
+    {
+        Ok(changes) => changes,
+        Err(_) => return Ok(Response::builder(StatusCode::BadRequest).build()),
+    };
+    let domain = request.param("domain").unwrap().to_owned();
+    let state = request.state();
+    let site = match state.sites.load().get(&domain) {
+        Some(site) => site.clone(),
+        None => return Ok(Response::builder(StatusCode::NotFound).build()),
+    };
+    if !is_authorized(&request, Some(&site), auth::Access::Owner, &nostr_auth) {
+        return Ok(Response::builder(StatusCode::Forbidden).build());
+    }
+
+    let themes = state.themes.load();
+    let new_site =
+        match logging::spawn_blocking(move || site::update_config(&domain, changes, &themes)).await
+        {
+            Ok(site) => site,
+            Err(e) if e.kind() == io::ErrorKind::InvalidInput => {
+                return Ok(Response::builder(StatusCode::BadRequest)
+                    .body(e.to_string())
+                    .build());
+            }
+            Err(e) => {
+                log::error!("Failed to update the config of site {}: {}", site.domain, e);
+                return Ok(Response::builder(StatusCode::InternalServerError).build());
+            }
+        };
+
+    state
+        .sites
+        .update(|sites| sites.insert(site.domain, new_site));
+
+    Ok(Response::builder(StatusCode::Ok)
+        .content_type(mime::JSON)
+        .body("{}")
+        .build())
+}
+
+async fn handle_get_openapi(_request: Request<State>) -> tide::Result<Response> {
+    Ok(build_raw_response(openapi::get_json(), mime::JSON))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/themes",
+    tag = "sites",
+    responses(
+        (status = 200, description = "The installed themes", body = [Object], example = json!([{"name": "hyde"}])),
+    )
+)]
+async fn handle_get_themes(request: Request<State>) -> tide::Result<Response> {
+    let mut themes: Vec<String> = request.state().themes.load().keys().cloned().collect();
+    themes.sort();
+    let themes = themes
+        .iter()
+        .map(|name| json!({ "name": name }))
+        .collect::<Vec<_>>();
+
+    Ok(Response::builder(StatusCode::Ok)
+        .content_type(mime::JSON)
+        .body(json!(themes).to_string())
+        .build())
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/sites/{domain}/public/{kind}",
+    tag = "content",
+    params(
+        ("domain" = String, Path, description = "Domain of the site"),
+        ("kind" = String, Path, description = "`posts`, `pages` or `notes`"),
+    ),
+    responses(
+        (status = 200, description = "The resources, newest first", body = [PublicResource]),
+        (status = 404, description = "No such site or kind"),
+    )
+)]
+async fn handle_get_public_resources(request: Request<State>) -> tide::Result<Response> {
+    let domain = request.param("domain").unwrap();
+    let kind = match request.param("kind").unwrap() {
+        "posts" => ResourceKind::Post,
+        "pages" => ResourceKind::Page,
+        "notes" => ResourceKind::Note,
+        _ => return Ok(Response::builder(StatusCode::NotFound).build()),
+    };
+    let site = match request.state().sites.load().get(domain) {
+        Some(site) => site.clone(),
+        None => return Ok(Response::builder(StatusCode::NotFound).build()),
+    };
+
+    let site_content = site.get_content();
+    let mut resources = site_content
+        .resources
+        .values()
+        .filter(|r| r.kind == kind && !r.noindex)
+        .collect::<Vec<_>>();
+    resources.sort_by_key(|r| std::cmp::Reverse(r.date));
+    let public_resources = resources
+        .iter()
+        .map(|r| r.to_public(&site, &site_content))
+        .collect::<Vec<_>>();
+
+    Ok(Response::builder(StatusCode::Ok)
+        .content_type(mime::JSON)
+        .body(json!(public_resources).to_string())
+        .build())
+}
+
+/// The site being requested, as long as it can be followed using ActivityPub.
+fn get_federated_site(request: &Request<State>) -> Option<Site> {
+    get_site(request).filter(|site| site.config.activitypub.is_some())
+}
+
+fn build_activity_response(content_type: &str, document: serde_json::Value) -> Response {
+    Response::builder(StatusCode::Ok)
+        .content_type(content_type)
+        .body(document.to_string())
+        .build()
+}
+
+async fn handle_webfinger(request: Request<State>) -> tide::Result<Response> {
+    let resource = request
+        .url()
+        .query_pairs()
+        .find(|(k, _)| k == "resource")
+        .map(|(_, v)| v.into_owned())
+        .unwrap_or_default();
+    let document = get_federated_site(&request)
+        .and_then(|site| activitypub::render_webfinger(&site, &resource));
+
+    Ok(match document {
+        Some(document) => build_activity_response("application/jrd+json", document),
+        None => Response::new(StatusCode::NotFound),
+    })
+}
+
+async fn handle_activitypub_actor(request: Request<State>) -> tide::Result<Response> {
+    let site = match get_federated_site(&request) {
+        Some(site) => site,
+        None => return Ok(Response::new(StatusCode::NotFound)),
+    };
+    // the key is generated the first time the actor is requested, which takes a while
+    match logging::spawn_blocking(move || activitypub::render_actor(&site)).await {
+        Ok(actor) => Ok(build_activity_response(activitypub::ACTIVITY_JSON, actor)),
+        Err(e) => {
+            log::error!("Cannot render ActivityPub actor: {}", e);
+            Ok(Response::new(StatusCode::InternalServerError))
+        }
+    }
+}
+
+async fn handle_activitypub_outbox(request: Request<State>) -> tide::Result<Response> {
+    let site = match get_federated_site(&request) {
+        Some(site) => site,
+        None => return Ok(Response::new(StatusCode::NotFound)),
+    };
+    let outbox = logging::spawn_blocking(move || activitypub::render_outbox(&site)).await;
+
+    Ok(build_activity_response(activitypub::ACTIVITY_JSON, outbox))
+}
+
+async fn handle_activitypub_followers(request: Request<State>) -> tide::Result<Response> {
+    let site = match get_federated_site(&request) {
+        Some(site) => site,
+        None => return Ok(Response::new(StatusCode::NotFound)),
+    };
+    let followers = logging::spawn_blocking(move || activitypub::render_followers(&site)).await;
+
+    Ok(build_activity_response(
+        activitypub::ACTIVITY_JSON,
+        followers,
+    ))
+}
+
+async fn handle_activitypub_inbox(mut request: Request<State>) -> tide::Result<Response> {
+    let site = match get_federated_site(&request) {
+        Some(site) => site,
+        None => return Ok(Response::new(StatusCode::NotFound)),
+    };
+    let body = request.body_bytes().await?;
+    let request_target = format!(
+        "{} {}",
+        request.method().to_string().to_lowercase(),
+        &request.url()[Position::BeforePath..Position::AfterQuery]
+    );
+    let headers = request
+        .iter()
+        .map(|(name, values)| (name.as_str().to_lowercase(), values.last().to_string()))
+        .collect::<HashMap<_, _>>();
+
+    let status = match activitypub::receive(&site, &request_target, &headers, &body).await {
+        Ok(()) => StatusCode::Accepted,
+        Err(e) => {
+            log::info!("Rejecting activity sent to {}: {}", site.domain, e);
+            match e.kind() {
+                io::ErrorKind::PermissionDenied => StatusCode::Unauthorized,
+                io::ErrorKind::InvalidData => StatusCode::BadRequest,
+                _ => StatusCode::InternalServerError,
+            }
+        }
+    };
+
+    Ok(Response::new(status))
+}
+
+async fn handle_lnurlp(request: Request<State>) -> tide::Result<Response> {
+    let site = match get_site(&request) {
+        Some(site) => site,
+        None => return Ok(Response::new(StatusCode::NotFound)),
+    };
+    let name = request.param("name").unwrap();
+
+    let (status, body) = match lightning::get_pay_request(&site, name).await {
+        Ok(pay_request) => (StatusCode::Ok, pay_request),
+        Err(e) => {
+            let status = match e.kind() {
+                io::ErrorKind::NotFound => StatusCode::NotFound,
+                _ => {
+                    log::warn!(
+                        "Cannot get lightning address {} of {}: {}",
+                        name,
+                        site.domain,
+                        e
+                    );
+                    StatusCode::BadGateway
+                }
+            };
+            (status, json!({"status": "ERROR", "reason": e.to_string()}))
+        }
+    };
+
+    Ok(Response::builder(status)
+        .content_type(mime::JSON)
+        .body(body.to_string())
+        .build())
+}
+
+fn build_newsletter_response(status: StatusCode, message: &str) -> Response {
+    Response::builder(status)
+        .content_type(mime::PLAIN)
+        .body(message)
+        .build()
+}
+
+/// The site the request is for, if visitors can subscribe to it, along with the `token` given in the query.
+fn get_newsletter_site(request: &Request<State>) -> Option<(Site, String)> {
+    let site = get_site(request).filter(|site| site.config.newsletter.is_some())?;
+    let token = request
+        .url()
+        .query_pairs()
+        .find(|(k, _)| k == "token")
+        .map(|(_, v)| v.into_owned())
+        .unwrap_or_default();
+    Some((site, token))
+}
+
+async fn handle_newsletter_subscribe(mut request: Request<State>) -> tide::Result<Response> {
+    let site = match get_newsletter_site(&request) {
+        Some((site, _)) => site,
+        None => return Ok(Response::new(StatusCode::NotFound)),
+    };
+    // from a form, or from a script
+    let is_json = request
+        .content_type()
+        .is_some_and(|m| m.essence() == mime::JSON.essence());
+    let body: SubscribeRequestBody = if is_json {
+        request.body_json().await?
+    } else {
+        request.body_form().await?
+    };
+
+    Ok(match newsletter::subscribe(&site, &body.email).await {
+        Ok(()) => build_newsletter_response(
+            StatusCode::Accepted,
+            "Almost there! Please follow the link sent to your inbox to confirm your subscription.",
+        ),
+        Err(e) if e.kind() == io::ErrorKind::InvalidInput => {
+            build_newsletter_response(StatusCode::BadRequest, &e.to_string())
+        }
+        Err(e) => {
+            log::warn!(
+                "Cannot subscribe to the newsletter of {}: {}",
+                site.domain,
+                e
+            );
+            build_newsletter_response(
+                StatusCode::BadGateway,
+                "Cannot send the confirmation email. Please try again later.",
+            )
+        }
+    })
+}
+
+async fn handle_newsletter_confirm(request: Request<State>) -> tide::Result<Response> {
+    let (site, token) = match get_newsletter_site(&request) {
+        Some(site_and_token) => site_and_token,
+        None => return Ok(Response::new(StatusCode::NotFound)),
+    };
+
+    Ok(if newsletter::confirm(&site, &token).await? {
+        let title = site.config.title.as_deref().unwrap_or(&site.domain);
+        build_newsletter_response(
+            StatusCode::Ok,
+            &format!("You are now subscribed to {}.", title),
+        )
+    } else {
+        build_newsletter_response(StatusCode::NotFound, "Unknown or expired link.")
+    })
+}
+
+async fn handle_newsletter_unsubscribe(request: Request<State>) -> tide::Result<Response> {
+    let (site, token) = match get_newsletter_site(&request) {
+        Some(site_and_token) => site_and_token,
+        None => return Ok(Response::new(StatusCode::NotFound)),
+    };
+
+    Ok(if newsletter::unsubscribe(&site, &token).await? {
+        build_newsletter_response(StatusCode::Ok, "You are now unsubscribed.")
+    } else {
+        build_newsletter_response(StatusCode::NotFound, "Unknown link.")
+    })
+}
+
+/// The kind of content managed through `/api/sites/:domain/:kind/:slug`.
+fn get_content_kind(request: &Request<State>) -> Option<ResourceKind> {
+    match request.param("kind").unwrap() {
+        "posts" => Some(ResourceKind::Post),
+        "pages" => Some(ResourceKind::Page),
+        _ => None,
+    }
+}
+
+/// The response to a failed attempt at saving or deleting content.
+fn build_content_error_response(domain: &str, slug: &str, e: io::Error) -> Response {
+    let status = match e.kind() {
+        io::ErrorKind::NotFound => StatusCode::NotFound,
+        io::ErrorKind::InvalidInput => StatusCode::BadRequest,
+        io::ErrorKind::AlreadyExists => StatusCode::Conflict,
+        _ => {
+            log::error!("Failed to update {} on site {}: {}", slug, domain, e);
+            StatusCode::InternalServerError
+        }
+    };
+
+    Response::builder(status).body(e.to_string()).build()
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/sites/{domain}/{kind}/{slug}",
+    tag = "content",
+    params(
+        ("domain" = String, Path, description = "Domain of the site"),
+        ("kind" = String, Path, description = "`posts` or `pages`"),
+        ("slug" = String, Path, description = "Slug of the post or page"),
+    ),
+    responses(
+        (status = 200, description = "The file of the post or page: YAML front matter followed by markdown", content_type = "text/markdown", body = String),
+        (status = 403, description = "Not authorized by the site's key"),
+        (status = 404, description = "No such site, kind or post"),
+    ),
+    security(("nostr" = []))
+)]
+async fn handle_get_content(request: Request<State>) -> tide::Result<Response> {
+    let domain = request.param("domain").unwrap();
+    let slug = request.param("slug").unwrap();
+    let Some(kind) = get_content_kind(&request) else {
+        return Ok(Response::builder(StatusCode::NotFound).build());
+    };
+    let site = match request.state().sites.load().get(domain) {
+        Some(site) => site.clone(),
+        None => return Ok(Response::builder(StatusCode::NotFound).build()),
+    };
+    if !is_authorized(&request, Some(&site), auth::Access::Owner, &nostr_auth) {
+        return Ok(Response::builder(StatusCode::Forbidden).build());
+    }
+
+    let Some((filename, _)) = site.get_source_file(kind, slug) else {
+        return Ok(Response::builder(StatusCode::NotFound).build());
+    };
+    let text = async_std::fs::read_to_string(&filename).await?;
+
+    Ok(Response::builder(StatusCode::Ok)
+        .content_type("text/markdown; charset=utf-8")
+        .body(text)
+        .build())
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/sites/{domain}/{kind}/{slug}",
+    tag = "content",
+    params(
+        ("domain" = String, Path, description = "Domain of the site"),
+        ("kind" = String, Path, description = "`posts` or `pages`"),
+        ("slug" = String, Path, description = "Slug of the post or page"),
+    ),
+    request_body(content = String, content_type = "text/markdown"),
+    responses(
+        (status = 200, description = "Done", body = Object, example = json!({})),
+        (status = 400, description = "Invalid file"),
+        (status = 403, description = "Not authorized by the site's key"),
+        (status = 404, description = "No such site or kind"),
+        (status = 409, description = "The post was published as a Nostr event"),
+    ),
+    security(("nostr" = []))
+)]
+async fn handle_put_content(mut request: Request<State>) -> tide::Result<Response> {
+    let text = match request.body_string().await {
+        Ok(text) => text,
+        Err(_) => return Ok(Response::builder(StatusCode::BadRequest).build()),
+    };
+    let domain = request.param("domain").unwrap().to_owned();
+    let slug = request.param("slug").unwrap().to_owned();
+    let Some(kind) = get_content_kind(&request) else {
+        return Ok(Response::builder(StatusCode::NotFound).build());
+    };
+    let site = match request.state().sites.load().get(&domain) {
+        Some(site) => site.clone(),
+        None => return Ok(Response::builder(StatusCode::NotFound).build()),
+    };
+    if !is_authorized(&request, Some(&site), auth::Access::Owner, &nostr_auth) {
+        return Ok(Response::builder(StatusCode::Forbidden).build());
+    }
+
+    let result = logging::spawn_blocking({
+        let slug = slug.clone();
+        move || site.save_file(kind, &slug, &text)
+    })
+    .await;
+    if let Err(e) = result {
+        return Ok(build_content_error_response(&domain, &slug, e));
+    }
+
+    Ok(Response::builder(StatusCode::Ok)
+        .content_type(mime::JSON)
+        .body("{}")
+        .build())
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/sites/{domain}/{kind}/{slug}",
+    tag = "content",
+    params(
+        ("domain" = String, Path, description = "Domain of the site"),
+        ("kind" = String, Path, description = "`posts` or `pages`"),
+        ("slug" = String, Path, description = "Slug of the post or page"),
+    ),
+    responses(
+        (status = 200, description = "Done", body = Object, example = json!({})),
+        (status = 403, description = "Not authorized by the site's key"),
+        (status = 404, description = "No such site, kind or post"),
+        (status = 409, description = "The post was published as a Nostr event"),
+    ),
+    security(("nostr" = []))
+)]
+async fn handle_delete_content(request: Request<State>) -> tide::Result<Response> {
+    let domain = request.param("domain").unwrap().to_owned();
+    let slug = request.param("slug").unwrap().to_owned();
+    let Some(kind) = get_content_kind(&request) else {
+        return Ok(Response::builder(StatusCode::NotFound).build());
+    };
+    let site = match request.state().sites.load().get(&domain) {
+        Some(site) => site.clone(),
+        None => return Ok(Response::builder(StatusCode::NotFound).build()),
+    };
+    if !is_authorized(&request, Some(&site), auth::Access::Owner, &nostr_auth) {
+        return Ok(Response::builder(StatusCode::Forbidden).build());
+    }
+
+    let result = logging::spawn_blocking({
+        let slug = slug.clone();
+        move || site.delete_file(kind, &slug)
+    })
+    .await;
+    if let Err(e) = result {
+        return Ok(build_content_error_response(&domain, &slug, e));
+    }
+
+    Ok(Response::builder(StatusCode::Ok)
+        .content_type(mime::JSON)
+        .body("{}")
+        .build())
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/sites",
+    tag = "sites",
+    responses(
+        (status = 200, description = "The sites of the key making the request or, for the operator, all the sites along with their owner and traffic", body = [Object], example = json!([{"domain": "example.com"}])),
+        (status = 400, description = "Missing or invalid authorization"),
+    ),
+    security(("nostr" = []))
+)]
+async fn handle_get_sites(request: Request<State>) -> tide::Result<Response> {
+    let key = nostr_auth(&request);
+    if key.is_none() {
+        return Ok(Response::builder(StatusCode::BadRequest).build());
+    }
+    let key = key.unwrap();
+    // the operator gets all the sites, along with who owns them
+    let is_operator = is_operator(&request);
+    let all_sites = &request.state().sites.load();
+    let mut sites = all_sites
+        .iter()
+        .filter_map(|s| {
+            if is_operator {
+                Some(json!({"domain": s.0, "pubkey": s.1.config.pubkey}))
+            } else if s
+                .1
+                .config
+                .pubkey
+                .as_deref()
+                .is_some_and(|p| auth::keys_equal(p, &key))
+            {
+                Some(json!({ "domain": s.0 }))
+            } else {
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+    sites.sort_by(|a, b| a["domain"].as_str().cmp(&b["domain"].as_str()));
+    // ... and what they served, to tell which ones use the bandwidth
+    if is_operator {
+        let traffic = request.state().traffic.clone();
+        sites = logging::spawn_blocking(move || {
+            for site in &mut sites {
+                let domain = site["domain"].as_str().unwrap_or_default().to_owned();
+                site["traffic"] = json!(get_traffic_summary(&traffic, &domain));
+            }
+            sites
+        })
+        .await;
+    }
+
+    Ok(Response::builder(StatusCode::Ok)
+        .content_type(mime::JSON)
+        .body(json!(sites).to_string())
+        .build())
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/config",
+    tag = "sites",
+    responses(
+        (status = 200, description = "The theme of the site being requested, and the available themes", body = Object, example = json!({"theme": "hyde", "available_themes": ["hyde"]})),
+        (status = 403, description = "Not authorized by the site's key"),
+        (status = 404, description = "No such site"),
+    ),
+    security(("nostr" = []))
+)]
+async fn handle_get_site_config(request: Request<State>) -> tide::Result<Response> {
+    let site = {
+        if let Some(site) = get_site(&request) {
+            if !is_authorized(&request, Some(&site), auth::Access::Owner, &nostr_auth) {
+                return Ok(Response::builder(StatusCode::Forbidden).build());
+            }
+            site
+        } else {
+            return Ok(Response::builder(StatusCode::NotFound).build());
+        }
+    };
+
+    let themes: Vec<String> = request.state().themes.load().keys().cloned().collect();
+
+    Ok(Response::builder(StatusCode::Ok)
+        .content_type(mime::JSON)
+        .body(json!({"theme": site.config.theme, "available_themes": themes}).to_string())
+        .build())
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/config",
+    tag = "sites",
+    request_body(content = PutSiteConfigRequestBody),
+    responses(
+        (status = 200, description = "Done", body = Object, example = json!({})),
+        (status = 400, description = "Invalid request body, or unknown theme"),
+        (status = 403, description = "Not authorized by the site's key"),
+        (status = 404, description = "No such site"),
+    ),
+    security(("nostr" = []))
+)]
+async fn handle_put_site_config(mut request: Request<State>) -> tide::Result<Response> {
+    let site = {
+        if let Some(site) = get_site(&request) {
+            if !is_authorized(&request, Some(&site), auth::Access::Owner, &nostr_auth) {
+                return Ok(Response::builder(StatusCode::Forbidden).build());
+            }
+            site
+        } else {
+            return Ok(Response::builder(StatusCode::NotFound).build());
+        }
+    };
+
+    let body = match request.body_json::<PutSiteConfigRequestBody>().await {
+        Ok(body) => body,
+        Err(_) => return Ok(Response::builder(StatusCode::BadRequest).build()),
+    };
+
+    // the same as changing the theme using `/api/sites/:domain/config`
+    let state = request.state();
+    let (domain, themes) = (site.domain.to_owned(), state.themes.load());
+    let changes = serde_json::Map::from_iter([("theme".to_owned(), json!(body.theme))]);
+    let new_site =
+        match logging::spawn_blocking(move || site::update_config(&domain, changes, &themes)).await
+        {
+            Ok(site) => site,
+            Err(e) if e.kind() == io::ErrorKind::InvalidInput => {
+                return Ok(Response::builder(StatusCode::BadRequest)
+                    .body(e.to_string())
+                    .build());
+            }
+            Err(e) => {
+                // the site keeps running with its previous theme
+                log::error!("Failed to update the config of site {}: {}", site.domain, e);
+                return Ok(Response::builder(StatusCode::InternalServerError).build());
+            }
+        };
+
+    state
+        .sites
+        .update(|sites| sites.insert(site.domain, new_site));
+
+    Ok(Response::builder(StatusCode::Ok)
+        .content_type(mime::JSON)
+        .body(json!({}).to_string())
+        .build())
+}
+
+#[utoipa::path(
+    get,
+    path = "/list/{pubkey}",
+    tag = "blossom",
+    params(
+        ("pubkey" = String, Path, description = "Key of the site, in hex"),
+    ),
+    responses(
+        (status = 201, description = "The descriptors of the uploaded files", body = [FileMetadata]),
+        (status = 403, description = "Not the site's key"),
+        (status = 404, description = "No such site"),
+    )
+)]
+async fn handle_blossom_list_request(request: Request<State>) -> tide::Result<Response> {
+    let site_path = {
+        if let Some(site) = get_site(&request) {
+            // anyone can list the files, as long as they ask for those of the site's key
+            let pubkey = request.param("pubkey").unwrap_or_default();
+            if !site
+                .config
+                .pubkey
+                .as_deref()
+                .is_some_and(|site_pubkey| auth::keys_equal(pubkey, site_pubkey))
+            {
+                return Ok(Response::builder(StatusCode::Forbidden).build());
+            }
+            format!("{}/{}", paths::sites(), site.domain)
+        } else {
+            return Ok(Response::builder(StatusCode::NotFound).build());
+        }
+    };
+
+    let mut list = vec![];
+
+    if let Ok(mut entries) = async_std::fs::read_dir(format!("{}/_content/files", site_path)).await
+    {
+        while let Some(entry) = async_std::stream::StreamExt::next(&mut entries).await {
+            let mut metadata_path = entry?.path();
+            if metadata_path.extension().is_none() {
+                metadata_path.set_extension("metadata.json");
+                let metadata = async_std::fs::read_to_string(&metadata_path).await?;
+                let metadata: FileMetadata = serde_json::from_str(&metadata)?;
+                list.push(metadata);
+            }
+        }
+    }
+
+    Ok(Response::builder(StatusCode::Created)
+        .content_type(mime::JSON)
+        .body(serde_json::to_string(&list).unwrap())
+        .build())
+}
+
+/// Whether the request is made by the server's operator, who can manage all the sites.
+fn is_operator(request: &Request<State>) -> bool {
+    auth::check(
+        nostr_auth(request).as_deref(),
+        None,
+        &request.state().operator_pubkeys,
+        auth::Access::Operator,
+    )
+    .is_ok()
+}
+
+/// Whether the request, made by the key `get_pubkey` finds, is allowed `access` to `site`
+/// (or to the server, for what only the operator can do), recording the outcome in the audit log.
+fn is_authorized(
+    request: &Request<State>,
+    site: Option<&Site>,
+    access: auth::Access,
+    get_pubkey: &dyn Fn(&Request<State>) -> Option<String>,
+) -> bool {
+    let pubkey = get_pubkey(request);
+    let action = format!("{} {}", request.method(), request.url().path());
+    let domain = site.map(|site| site.domain.as_str());
+    match auth::check(
+        pubkey.as_deref(),
+        site.and_then(|site| site.config.pubkey.as_deref()),
+        &request.state().operator_pubkeys,
+        access,
+    ) {
+        Ok(role) => {
+            auth::audit(&pubkey.unwrap_or_default(), role, &action, domain);
+            true
+        }
+        Err(reason) => {
+            auth::audit_denied(pubkey.as_deref(), &action, domain, reason);
+            false
+        }
+    }
+}
+
+/// How much of the beginning of an upload is kept to guess its content type.
+const SNIFF_LENGTH: usize = 8192;
+
+static NEXT_UPLOAD_ID: AtomicU64 = AtomicU64::new(1);
+
+/// A file being uploaded, written to a temporary file and hashed as it is received,
+/// so that large files are never held in memory. The temporary file is removed unless the upload is saved.
+struct Upload {
+    dir: String,
+    path: Option<String>,
+    file: async_std::fs::File,
+    hasher: Sha256,
+    head: Vec<u8>,
+    size: usize,
+}
+
+impl Upload {
+    async fn new(site_path: &str) -> io::Result<Self> {
+        let dir = format!("{}/_content/files", site_path);
+        async_std::fs::create_dir_all(&dir).await?;
+        // with an extension, so that it is not mistaken for an uploaded file
+        let path = format!(
+            "{}/upload-{}-{}.part",
+            dir,
+            process::id(),
+            NEXT_UPLOAD_ID.fetch_add(1, Ordering::Relaxed)
+        );
+        let file = async_std::fs::File::create(&path).await?;
+
+        Ok(Self {
+            dir,
+            path: Some(path),
+            file,
+            hasher: Sha256::new(),
+            head: vec![],
+            size: 0,
+        })
+    }
+
+    async fn write(&mut self, chunk: &[u8]) -> io::Result<()> {
+        self.hasher.update(chunk);
+        let head_length = chunk.len().min(SNIFF_LENGTH - self.head.len());
+        self.head.extend_from_slice(&chunk[..head_length]);
+        self.size += chunk.len();
+        self.file.write_all(chunk).await
+    }
+
+    /// Writes everything that can be read from `body`.
+    async fn write_all(&mut self, mut body: impl async_std::io::Read + Unpin) -> io::Result<()> {
+        let mut buffer = vec![0; 64 * 1024];
+        loop {
+            match body.read(&mut buffer).await? {
+                0 => return Ok(()),
+                length => self.write(&buffer[..length]).await?,
+            }
+        }
+    }
+
+    /// The content type of the file, going by its beginning. SVG images are only recognized if `allow_svg`.
+    fn sniff(&self, allow_svg: bool) -> Option<mime::Mime> {
+        if allow_svg && sanitize::is_svg(&self.head) {
+            return Some(mime::SVG);
+        }
+        mime::Mime::sniff(&self.head).ok()
+    }
+
+    /// Strips the metadata of an image, replacing the file, or checks that an SVG image can't run scripts.
+    async fn sanitize(&mut self, mime: &mime::Mime) -> error::Result<()> {
+        if *mime != mime::SVG && !sanitize::STRIPPED_TYPES.contains(&mime.essence()) {
+            return Ok(());
+        }
+        let path = self.path.clone().unwrap();
+        self.file
+            .sync_all()
+            .await
+            .context(|| format!("Cannot write {}", path))?;
+        let content = async_std::fs::read(&path)
+            .await
+            .context(|| format!("Cannot read {}", path))?;
+        if *mime == mime::SVG {
+            return sanitize::check_svg(&content)
+                .map_err(|e| error::Error::BadRequest(format!("{}.", e)));
+        }
+        let stripped = sanitize::strip_metadata(mime.essence(), &content)
+            .map_err(|e| error::Error::BadRequest(format!("{}.", e)))?;
+        if let Some(stripped) = stripped {
+            self.file = async_std::fs::File::create(&path)
+                .await
+                .context(|| format!("Cannot write {}", path))?;
+            self.file
+                .write_all(&stripped)
+                .await
+                .context(|| format!("Cannot write {}", path))?;
+            self.hasher = Sha256::new();
+            self.hasher.update(&stripped);
+            self.size = stripped.len();
+        }
+
+        Ok(())
+    }
+
+    /// Moves the file, once it is safely on disk, to where it is served from, along with its metadata.
+    async fn save(mut self, base_url: &str, mime: &mime::Mime) -> io::Result<FileMetadata> {
+        self.file.sync_all().await?;
+        let hash = utils::to_hex(&self.hasher.finalize_reset());
+        let metadata = FileMetadata {
+            sha256: hash.clone(),
+            content_type: mime.essence().to_owned(),
+            size: self.size,
+            url: format!("{}/{}", base_url, hash),
+            cid: None,
+        };
+
+        let metadata_path = format!("{}/{}.metadata.json", self.dir, hash);
+        let mut metadata_file = async_std::fs::File::create(&metadata_path).await?;
+        metadata_file
+            .write_all(serde_json::to_string(&metadata).unwrap().as_bytes())
+            .await?;
+        metadata_file.sync_all().await?;
+        let path = self.path.take().unwrap();
+        if let Err(e) = async_std::fs::rename(&path, format!("{}/{}", self.dir, hash)).await {
+            let _ = fs::remove_file(path);
+            return Err(e);
+        }
+        // makes the rename itself durable
+        async_std::fs::File::open(&self.dir)
+            .await?
+            .sync_all()
+            .await?;
+
+        Ok(metadata)
+    }
+}
+
+impl Drop for Upload {
+    fn drop(&mut self) {
+        if let Some(path) = self.path.take() {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// The request's body, as a stream of chunks, rather than all of it at once.
+fn stream_body(body: tide::Body) -> impl futures_util::Stream<Item = io::Result<Bytes>> {
+    futures_util::stream::unfold(body, |mut body| async move {
+        let mut buffer = vec![0; 64 * 1024];
+        match body.read(&mut buffer).await {
+            Ok(0) => None,
+            Ok(length) => {
+                buffer.truncate(length);
+                Some((Ok(Bytes::from(buffer)), body))
+            }
+            Err(e) => Some((Err(e), body)),
+        }
+    })
+}
+
+/// Pins an uploaded file to IPFS, if the server is configured to, saving its CID along with its metadata.
+/// Failing to pin it doesn't fail the upload.
+async fn pin_file(state: &State, site_path: &str, metadata: &mut FileMetadata) {
+    let Some(ipfs) = &state.ipfs else {
+        return;
+    };
+    let path = format!("{}/_content/files/{}", site_path, metadata.sha256);
+    match ipfs::pin(ipfs, &path).await {
+        Ok(cid) => {
+            log::info!("Pinned {} to IPFS as {}.", path, cid);
+            metadata.cid = Some(cid);
+            let metadata_json = serde_json::to_string(&metadata).unwrap();
+            if let Err(e) =
+                async_std::fs::write(format!("{}.metadata.json", path), metadata_json).await
+            {
+                log::warn!("Cannot save the CID of {}: {}", path, e);
+            }
+        }
+        Err(e) => log::warn!("Cannot pin {} to IPFS: {}", path, e),
+    }
+}
+
+/// Deletes an uploaded file, failing with a 404 if there is no such file,
+/// and unpins it from IPFS in the background if it was pinned.
+async fn delete_file(state: &State, site_path: &str, hash: &str) -> tide::Result<()> {
+    let not_found = |e: io::Error| {
+        let status = match e.kind() {
+            io::ErrorKind::NotFound => StatusCode::NotFound,
+            _ => StatusCode::InternalServerError,
+        };
+        tide::Error::new(status, e)
+    };
+    let metadata_path = format!("{}/_content/files/{}.metadata.json", site_path, hash);
+    let cid = async_std::fs::read_to_string(&metadata_path)
+        .await
+        .ok()
+        .and_then(|metadata| serde_json::from_str::<FileMetadata>(&metadata).ok())
+        .and_then(|metadata| metadata.cid);
+    async_std::fs::remove_file(format!("{}/_content/files/{}", site_path, hash))
+        .await
+        .map_err(not_found)?;
+    async_std::fs::remove_file(metadata_path)
+        .await
+        .map_err(not_found)?;
+
+    if let (Some(ipfs), Some(cid)) = (state.ipfs.clone(), cid) {
+        async_std::task::spawn(async move {
+            if let Err(e) = ipfs::unpin(&ipfs, &cid).await {
+                log::warn!("Cannot unpin {} from IPFS: {}", cid, e);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/files",
+    tag = "nip96",
+    request_body(content = Binary, content_type = "multipart/form-data", description = "The file, as `file`"),
+    responses(
+        (status = 201, description = "The NIP-94 tags of the uploaded file", body = Object, example = json!({"status": "success", "nip94_event": {"tags": [["url", "https://example.com/0123abcd.png"], ["ox", "0123abcd"]]}})),
+        (status = 400, description = "No file, or unknown content type"),
+        (status = 403, description = "Not authorized by the site's key"),
+        (status = 413, description = "The file would take the site over its storage quota"),
+    ),
+    security(("nostr" = []))
+)]
+async fn handle_nip96_upload_request(mut request: Request<State>) -> tide::Result<Response> {
+    let site = {
+        if let Some(site) = get_site(&request) {
+            if !is_authorized(&request, Some(&site), auth::Access::Owner, &nostr_auth) {
+                return Ok(Response::builder(StatusCode::Forbidden).build());
+            }
+            site
+        } else {
+            return Ok(Response::builder(StatusCode::NotFound).build());
+        }
+    };
+    let site_path = format!("{}/{}", paths::sites(), site.domain);
+
+    let content_type = request
+        .header(tide::http::headers::CONTENT_TYPE)
+        .map(|h| h.as_str())
+        .unwrap_or_default();
+    let Ok(boundary) = multer::parse_boundary(content_type) else {
+        return Ok(error::Error::BadRequest("Expected multipart/form-data.".to_string()).into());
+    };
+    let mut multipart = Multipart::new(stream_body(request.take_body()), boundary);
+    loop {
+        let mut field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(e) => return Ok(error::Error::BadRequest(e.to_string()).into()),
+        };
+        if field.name() == Some("file") {
+            let mut upload = Upload::new(&site_path).await?;
+            while let Some(chunk) = field.chunk().await? {
+                upload.write(&chunk).await?;
+            }
+            if is_over_quota(&site).await {
+                notify_upload_failure(request.state(), &site, QUOTA_EXCEEDED_MESSAGE);
+                return Ok(Response::builder(StatusCode::PayloadTooLarge)
+                    .content_type(mime::JSON)
+                    .body(json!({"status": "error", "message": QUOTA_EXCEEDED_MESSAGE}))
+                    .build());
+            }
+            let mime = upload
+                .sniff(site.config.sanitize_uploads)
+                .filter(|m| NIP96_CONTENT_TYPES.contains_key(m.essence()));
+            let Some(mime) = mime else {
+                notify_upload_failure(request.state(), &site, "unknown content type");
+                return Ok(Response::builder(StatusCode::BadRequest)
+                    .content_type(mime::JSON)
+                    .body(json!({"status": "error", "message": "Unknown content type."}))
+                    .build());
+            };
+            if site.config.sanitize_uploads {
+                if let Err(e) = upload.sanitize(&mime).await {
+                    notify_upload_failure(request.state(), &site, &e.to_string());
+                    return Ok(match e {
+                        error::Error::BadRequest(message) => {
+                            Response::builder(StatusCode::BadRequest)
+                                .content_type(mime::JSON)
+                                .body(json!({"status": "error", "message": message}))
+                                .build()
+                        }
+                        e => e.into(),
+                    });
+                }
+            }
+
+            let mut metadata = match upload
+                .save(
+                    &format!("{}://{}", get_scheme(&request), get_host(&request)),
+                    &mime,
+                )
+                .await
+            {
+                Ok(metadata) => metadata,
+                Err(e) => {
+                    notify_upload_failure(request.state(), &site, &e.to_string());
+                    return Err(e.into());
+                }
+            };
+            pin_file(request.state(), &site_path, &mut metadata).await;
+            site.record_change(format!("Upload _content/files/{}", metadata.sha256));
+            webhooks::notify(&site, "blob.uploaded", json!(metadata));
+
+            let mut tags = vec![json!(["url", metadata.url]), json!(["ox", metadata.sha256])];
+            if let (Some(ipfs), Some(cid)) = (&request.state().ipfs, &metadata.cid) {
+                tags.push(json!(["cid", cid]));
+                tags.push(json!(["fallback", ipfs.get_gateway_url(cid)]));
+            }
+
+            return Ok(Response::builder(StatusCode::Created)
+                .content_type(mime::JSON)
+                .body(json!({"status": "success", "nip94_event": {"tags": tags}}).to_string())
+                .build());
+        }
+    }
+
+    Ok(Response::builder(StatusCode::BadRequest)
+        .content_type(mime::JSON)
+        .body(json!({"status": "error", "message": "File not found."}))
+        .build())
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/files/{sha256}",
+    tag = "nip96",
+    params(
+        ("sha256" = String, Path, description = "Hash of the file"),
+    ),
+    responses(
+        (status = 200, description = "The file was deleted", body = Object, example = json!({"status": "success"})),
+        (status = 403, description = "Not authorized by the site's key"),
+    ),
+    security(("nostr" = []))
+)]
+async fn handle_nip96_delete_request(request: Request<State>) -> tide::Result<Response> {
+    let site = {
+        if let Some(site) = get_site(&request) {
+            if !is_authorized(&request, Some(&site), auth::Access::Owner, &nostr_auth) {
+                return Ok(Response::builder(StatusCode::Forbidden).build());
+            }
+            site
+        } else {
+            return Ok(Response::builder(StatusCode::NotFound).build());
+        }
+    };
+    let site_path = format!("{}/{}", paths::sites(), site.domain);
+
+    let hash = request.param("sha256").unwrap();
+    delete_file(request.state(), &site_path, hash).await?;
+    site.record_change(format!("Delete _content/files/{}", hash));
+
+    Ok(Response::builder(StatusCode::Ok)
+        .content_type(mime::JSON)
+        .body(json!({ "status": "success" }))
+        .build())
+}
+
+#[utoipa::path(
+    put,
+    path = "/upload",
+    tag = "blossom",
+    request_body(content = Binary, content_type = "application/octet-stream"),
+    responses(
+        (status = 201, description = "The descriptor of the uploaded file", body = FileMetadata),
+        (status = 400, description = "Unknown content type"),
+        (status = 401, description = "Not authorized by the site's key"),
+        (status = 413, description = "The file would take the site over its storage quota"),
+    ),
+    security(("nostr" = []))
+)]
+async fn handle_blossom_upload_request(mut request: Request<State>) -> tide::Result<Response> {
+    let site = {
+        if let Some(site) = get_site(&request) {
+            if !is_authorized(
+                &request,
+                Some(&site),
+                auth::Access::Owner,
+                &blossom_upload_auth,
+            ) {
+                return Ok(Response::builder(StatusCode::Unauthorized).build());
+            }
+            site
+        } else {
+            return Ok(Response::builder(StatusCode::NotFound).build());
+        }
+    };
+    let site_path = format!("{}/{}", paths::sites(), site.domain);
+
+    let mut upload = Upload::new(&site_path).await?;
+    upload.write_all(request.take_body()).await?;
+    if is_over_quota(&site).await {
+        notify_upload_failure(request.state(), &site, QUOTA_EXCEEDED_MESSAGE);
+        return Ok(Response::builder(StatusCode::PayloadTooLarge)
+            .content_type(mime::JSON)
+            .body(json!({ "message": QUOTA_EXCEEDED_MESSAGE }))
+            .build());
+    }
+
+    let mime = upload
+        .sniff(site.config.sanitize_uploads)
+        .filter(|m| BLOSSOM_CONTENT_TYPES.contains(m.essence()));
+    let Some(mime) = mime else {
+        notify_upload_failure(request.state(), &site, "unknown content type");
+        return Ok(Response::builder(StatusCode::BadRequest)
+            .content_type(mime::JSON)
+            .body(json!({"message": "Unknown content type."}))
+            .build());
+    };
+    if site.config.sanitize_uploads {
+        if let Err(e) = upload.sanitize(&mime).await {
+            notify_upload_failure(request.state(), &site, &e.to_string());
+            return Ok(match e {
+                error::Error::BadRequest(message) => Response::builder(StatusCode::BadRequest)
+                    .content_type(mime::JSON)
+                    .body(json!({ "message": message }))
+                    .build(),
+                e => e.into(),
+            });
+        }
+    }
+
+    let mut metadata = match upload
+        .save(
+            &format!("{}://{}", get_scheme(&request), get_host(&request)),
+            &mime,
+        )
+        .await
+    {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            notify_upload_failure(request.state(), &site, &e.to_string());
+            return Err(e.into());
+        }
+    };
+    pin_file(request.state(), &site_path, &mut metadata).await;
+    site.record_change(format!("Upload _content/files/{}", metadata.sha256));
+    webhooks::notify(&site, "blob.uploaded", json!(metadata));
+
+    Ok(Response::builder(StatusCode::Created)
+        .content_type(mime::JSON)
+        .body(serde_json::to_string(&metadata).unwrap())
+        .build())
+}
+
+#[utoipa::path(
+    delete,
+    path = "/{sha256}",
+    tag = "blossom",
+    params(
+        ("sha256" = String, Path, description = "Hash of the file"),
+    ),
+    responses(
+        (status = 200, description = "The file was deleted", body = Object, example = json!({})),
+        (status = 401, description = "Not authorized by the site's key"),
+    ),
+    security(("nostr" = []))
+)]
+async fn handle_blossom_delete_request(request: Request<State>) -> tide::Result<Response> {
+    let site = {
+        if let Some(site) = get_site(&request) {
+            if !is_authorized(
+                &request,
+                Some(&site),
+                auth::Access::Owner,
+                &blossom_delete_auth,
+            ) {
+                return Ok(Response::builder(StatusCode::Unauthorized).build());
+            }
+            site
+        } else {
+            return Ok(Response::builder(StatusCode::NotFound).build());
+        }
+    };
+    let site_path = format!("{}/{}", paths::sites(), site.domain);
+
+    let hash = request.param("sha256").unwrap();
+    delete_file(request.state(), &site_path, hash).await?;
+    site.record_change(format!("Delete _content/files/{}", hash));
+
+    Ok(Response::builder(StatusCode::Ok)
+        .content_type(mime::JSON)
+        .body(json!({}))
+        .build())
+}
+
+fn print_check(name: &str, errors: &[String], warnings: &[String], failure: &str) {
+    if errors.is_empty() {
+        println!("{}: OK", name);
+    } else {
+        println!("{}: {}", name, failure);
+    }
+    for error in errors {
+        println!("  error: {}", error);
+    }
+    for warning in warnings {
+        println!("  warning: {}", warning);
+    }
+}
+
+fn print_theme_check(name: &str, check: &theme::ThemeCheck) {
+    print_check(name, &check.errors, &check.warnings, "cannot be used!");
+}
+
+/// Checks all the themes and sites, printing the problems found. Returns the number of errors.
+fn run_check() -> usize {
+    let list_dirs = |path: &str| -> Vec<String> {
+        let mut names: Vec<String> = match fs::read_dir(path) {
+            Ok(paths) => paths
+                .filter_map(|p| p.ok())
+                .filter(|p| p.path().is_dir())
+                .map(|p| p.file_name().to_string_lossy().to_string())
+                .collect(),
+            _ => vec![],
+        };
+        names.sort();
+        names
+    };
+
+    let mut error_count = 0;
+    let mut warning_count = 0;
+
+    println!("Themes:");
+    for name in list_dirs(paths::themes()) {
+        let check = theme::check_theme(&PathBuf::from(paths::themes()).join(&name));
+        print_theme_check(&name, &check);
+        error_count += check.errors.len();
+        warning_count += check.warnings.len();
+    }
+
+    let themes = theme::load_themes();
+    println!("Sites:");
+    for domain in list_dirs(paths::sites()) {
+        let check = site::check_site(&domain, &themes);
+        print_check(&domain, &check.errors, &check.warnings, "cannot be loaded!");
+        error_count += check.errors.len();
+        warning_count += check.warnings.len();
+    }
+
+    println!("{} error(s), {} warning(s).", error_count, warning_count);
+
+    error_count
+}
+
+fn run_theme_command(command: ThemeCommand) {
+    let mut theme_names = match fs::read_dir(paths::themes()) {
+        Ok(paths) => paths
+            .filter_map(|p| p.ok())
+            .filter(|p| p.path().is_dir())
+            .map(|p| p.file_name().to_string_lossy().to_string())
+            .collect(),
+        _ => vec![],
+    };
+    theme_names.sort();
+
+    match command {
+        ThemeCommand::Install { source } => match theme::install_theme(&source) {
+            Ok((name, check)) => {
+                println!("Installed theme: {}.", name);
+                print_theme_check(&name, &check);
+            }
+            Err(e) => println!("{}", e),
+        },
+        ThemeCommand::List => {
+            for name in theme_names {
+                let path = PathBuf::from(paths::themes()).join(&name);
+                print_theme_check(&name, &theme::check_theme(&path));
+            }
+        }
+        ThemeCommand::Update { name } => {
+            for name in name.map_or(theme_names, |n| vec![n]) {
+                match theme::update_theme(&name) {
+                    Ok(true) => println!("{}: updated", name),
+                    Ok(false) => println!("{}: up to date", name),
+                    Err(e) => println!("{}: {}", name, e),
+                }
+            }
+        }
+    }
+}
+
+const ACME_CHALLENGE_PATH: &str = "/.well-known/acme-challenge/";
+
+/// Redirects to the same URL over HTTPS, on the port given as state.
+async fn handle_http_redirect(request: Request<u32>) -> tide::Result<Response> {
+    let url = request.url();
+    if url.path().starts_with(ACME_CHALLENGE_PATH) {
+        // not ours to answer (we use TLS-ALPN challenges), but never redirect them
+        return Ok(Response::new(StatusCode::NotFound));
+    }
+
+    let host = match request.header("Host") {
+        Some(host) => host.last().as_str().to_owned(),
+        None => return Ok(Response::new(StatusCode::BadRequest)),
+    };
+    // strip the port, taking care of IPv6 addresses
+    let host = match host.rsplit_once(':') {
+        Some((h, p)) if p.chars().all(|c| c.is_ascii_digit()) && !h.ends_with(':') => h.to_owned(),
+        _ => host,
+    };
+    let port = match *request.state() {
+        443 => "".to_string(),
+        port => format!(":{}", port),
+    };
+
+    Ok(Response::builder(StatusCode::MovedPermanently)
+        .header(
+            "Location",
+            format!("https://{}{}{}", host, port, &url[Position::BeforePath..]),
+        )
+        .build())
+}
+
+/// Listens for plain HTTP requests in the background on each of the `hosts`, redirecting them to HTTPS.
+fn redirect_http(hosts: &[&str], http_port: u32, https_port: u32) {
+    let mut listener = ConcurrentListener::new();
+    for host in hosts {
+        let addr = listener::format_addr(host, http_port);
+        let bound = (*host, http_port as u16)
+            .to_socket_addrs()
+            .and_then(|addrs| addrs.map(listener::bind).collect::<io::Result<Vec<_>>>());
+        match bound {
+            Ok(tcp_listeners) => {
+                for tcp_listener in tcp_listeners {
+                    let _ = listener.add(tcp_listener);
+                }
+            }
+            Err(e) => log::warn!("Cannot redirect HTTP to HTTPS on {}: {}", addr, e),
+        }
+    }
+
+    let mut app = tide::with_state(https_port);
+    app.at("/").all(handle_http_redirect);
+    app.at("*").all(handle_http_redirect);
+    async_std::task::spawn(async move {
+        if let Err(e) = app.listen(listener).await {
+            log::warn!("Cannot redirect HTTP to HTTPS: {}", e);
+        }
+    });
+}
 
-    let addr = args.bind.unwrap_or("0.0.0.0".to_owned());
-
-    if args.ssl_cert.is_some() && args.ssl_key.is_some() {
-        let port = args.port.unwrap_or(443);
-        let bind_to = format!("{addr}:{port}");
-        let mut listener = tide_rustls::TlsListener::build().addrs(bind_to);
-        listener = listener
-            .cert(args.ssl_cert.unwrap())
-            .key(args.ssl_key.unwrap());
-        app.listen(listener).await?;
-    } else if args.ssl_acme || args.ssl_acme_production {
-        if args.contact_email.is_none() {
-            panic!("Use -e to provide a contact email!");
-        }
-        let domains: Vec<String> = app
-            .state()
-            .sites
-            .read()
-            .unwrap()
-            .keys()
-            .map(|x| x.to_string())
-            .collect();
-        let cache = DirCache::new("./cache");
-        let acme_config = AcmeConfig::new(domains)
-            .cache(cache)
-            .directory_lets_encrypt(args.ssl_acme_production)
-            .contact_push(format!("mailto:{}", args.contact_email.unwrap()));
-        let port = args.port.unwrap_or(443);
-        let bind_to = format!("{addr}:{port}");
-        let mut listener = tide_rustls::TlsListener::build().addrs(bind_to);
-        listener = listener.acme(acme_config);
-        if !args.ssl_acme_production {
-            println!("NB: Using Let's Encrypt STAGING environment! Great for testing, but browsers will complain about the certificate.");
+/// Binds the listener, then notifies readiness before accepting connections.
+/// When asked to terminate, stops accepting connections and lets the requests being handled complete.
+async fn listen<L: ToListener<State>>(
+    mut app: tide::Server<State>,
+    listener: L,
+    pid_file: Option<&str>,
+) -> io::Result<()> {
+    let in_flight = service::InFlightRequests::default();
+    app.with(in_flight.clone());
+    let websockets = app.state().websockets.clone();
+    let traffic = app.state().traffic.clone();
+    let shutdown = service::handle_shutdown(pid_file.map(|p| p.to_owned()))?;
+
+    let mut listener = app.bind(listener).await?;
+    for info in listener.info() {
+        log::info!("Server listening on {}", info);
+    }
+    service::notify_ready(pid_file)?;
+
+    let accept = Box::pin(listener.accept());
+    if let future::Either::Left((result, _)) = future::select(accept, Box::pin(shutdown)).await {
+        return result;
+    }
+
+    // the accept loop is gone, and dropping the listener closes the socket
+    drop(listener);
+    let deadline = Instant::now() + service::SHUTDOWN_TIMEOUT;
+    log::info!(
+        "Waiting for {} request(s) to complete and {} websocket(s) to close...",
+        in_flight.count(),
+        websockets.count()
+    );
+    websockets.close_all("server is shutting down").await;
+    if !in_flight
+        .drain(deadline.saturating_duration_since(Instant::now()))
+        .await
+    {
+        log::warn!(
+            "Exiting with {} request(s) still in flight!",
+            in_flight.count()
+        );
+    }
+    if !websockets
+        .drain(deadline.saturating_duration_since(Instant::now()))
+        .await
+    {
+        log::warn!(
+            "Exiting with {} websocket(s) still open!",
+            websockets.count()
+        );
+    }
+    logging::spawn_blocking(move || traffic.save()).await;
+
+    Ok(())
+}
+
+#[async_std::main]
+async fn main() -> Result<(), std::io::Error> {
+    let args = Cli::parse();
+
+    logging::init(args.log_format, &args.log_level)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    // command line (or environment) first, then servus.toml
+    let mut config = paths::load_config(args.config.as_deref(), args.data_dir.as_deref())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let listener_configs = std::mem::take(&mut config.listeners);
+    let notifiers = std::mem::take(&mut config.notifiers);
+    let cors = std::mem::take(&mut config.cors);
+    let security_headers = std::mem::take(&mut config.security_headers);
+    let operator_pubkeys: Vec<String> = if args.operator_pubkey.is_empty() {
+        let operator_pubkeys = std::mem::take(&mut config.operator_pubkeys);
+        config
+            .operator_pubkey
+            .take()
+            .into_iter()
+            .chain(operator_pubkeys)
+            .collect()
+    } else {
+        args.operator_pubkey.clone()
+    };
+    let operator_pubkeys = operator_pubkeys
+        .iter()
+        .map(|operator_pubkey| {
+            nostr::parse_pubkey(operator_pubkey).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Invalid operator pubkey: {}", operator_pubkey),
+                )
+            })
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    let allow_open_registration = args.allow_open_registration || config.allow_open_registration;
+    let require_approval = args.require_approval || config.require_approval;
+    let max_sites_per_pubkey = args.max_sites_per_pubkey.or(config.max_sites_per_pubkey);
+    let ipfs = match &args.ipfs_api {
+        Some(api_url) => {
+            // the API's paths being relative to it
+            let api_url = format!("{}/", api_url.trim_end_matches('/'));
+            Some(ipfs::IpfsConfig {
+                api_url: http_types::Url::parse(&api_url).map_err(|e| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("Invalid IPFS API URL: {}", e),
+                    )
+                })?,
+                api_token: args.ipfs_api_token.clone(),
+                gateway_url: args.ipfs_gateway.clone(),
+            })
+        }
+        None => None,
+    };
+    let tenant_domain = match args.tenant_domain.clone().or(config.tenant_domain.take()) {
+        Some(tenant_domain) => match site::normalize_domain(&tenant_domain) {
+            Some(tenant_domain) if site::is_valid_domain(&tenant_domain) => Some(tenant_domain),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Invalid tenant domain: {}", tenant_domain),
+                ))
+            }
+        },
+        None => None,
+    };
+    if args.tenant_cert.is_some() && tenant_domain.is_none() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "A tenant certificate requires a tenant domain!",
+        ));
+    }
+    let wildcard_domain = tenant_domain.clone().filter(|_| args.tenant_cert.is_some());
+    let default_site = match args.default_site.clone().or(config.default_site.take()) {
+        Some(default_site) => match site::normalize_domain(&default_site) {
+            Some(default_site) if site::is_valid_domain(&default_site) => Some(default_site),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Invalid default site: {}", default_site),
+                ))
+            }
+        },
+        None => None,
+    };
+    if require_approval && operator_pubkeys.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Approving sites requires an operator pubkey!",
+        ));
+    }
+    if !allow_open_registration && !require_approval && operator_pubkeys.is_empty() {
+        log::info!("Without an operator pubkey, sites can't be created using the API (see --allow-open-registration).");
+    }
+    let mut dirs = paths::Paths::resolve(args.get_dirs(), config);
+    let sites_dir = dirs.sites.clone();
+    if matches!(args.command, Some(Command::Bench { .. })) {
+        // the synthetic site is not created among the actual sites
+        dirs.sites = bench::get_sites_dir();
+    }
+    paths::init(dirs).map_err(|e| io::Error::new(io::ErrorKind::AlreadyExists, e))?;
+
+    if let Some(command) = args.command {
+        match command {
+            Command::CloneSite { domain, new_domain } => {
+                site::clone_site(&domain, &new_domain)?;
+                println!("Cloned site {} to {}.", domain, new_domain);
+            }
+            Command::Build { site, out } => {
+                let themes = theme::load_themes();
+                let site = site::load_site(&site, &themes)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                let theme = themes.get(&site.config.theme).unwrap();
+                let count = build::build_site(
+                    &site,
+                    theme,
+                    &out,
+                    &args.default_language,
+                    &args.default_charset,
+                )
+                .map_err(io::Error::other)?;
+                println!(
+                    "Built site {} to {} ({} files).",
+                    site.domain,
+                    out.display(),
+                    count
+                );
+            }
+            Command::Backup { site, out } => {
+                let out = out.unwrap_or_else(|| {
+                    PathBuf::from(format!(
+                        "{}-{}.tar.zst",
+                        site,
+                        chrono::Utc::now().format("%Y%m%d%H%M%S")
+                    ))
+                });
+                let manifest = backup::backup_site(&site, &out)?;
+                println!(
+                    "Backed up site {} to {} ({} files, {} bytes).",
+                    site,
+                    out.display(),
+                    manifest.files.len(),
+                    manifest.get_size()
+                );
+            }
+            Command::Restore {
+                file,
+                verify,
+                force,
+            } => {
+                if verify {
+                    let manifest = backup::verify_backup(&file)?;
+                    println!(
+                        "Backup of site {} is valid ({} files, {} bytes).",
+                        manifest.domain,
+                        manifest.files.len(),
+                        manifest.get_size()
+                    );
+                } else {
+                    let (manifest, archive_path) = backup::restore_backup(&file, force)?;
+                    if let Some(archive_path) = archive_path {
+                        println!("Archived the previous site to {}.", archive_path);
+                    }
+                    println!(
+                        "Restored site {} ({} files).",
+                        manifest.domain,
+                        manifest.files.len()
+                    );
+                }
+            }
+            Command::ImportSsg {
+                format,
+                path,
+                site,
+                sign_with,
+            } => {
+                let keypair = match sign_with {
+                    Some(secret_key) => {
+                        Some(nostr::parse_secret_key(&secret_key).ok_or_else(|| {
+                            io::Error::new(io::ErrorKind::InvalidInput, "Invalid secret key")
+                        })?)
+                    }
+                    None => None,
+                };
+                let themes = theme::load_themes();
+                let site = site::load_site(&site, &themes)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                let summary = import::import_ssg(format, &path, &site, keypair.as_ref())
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+                for (path, reason) in &summary.skipped {
+                    println!("Skipped {}: {}.", path, reason);
+                }
+                println!(
+                    "Imported {} posts, {} pages and {} static files into {}.",
+                    summary.posts, summary.pages, summary.static_files, site.domain
+                );
+            }
+            Command::Check => {
+                if run_check() > 0 {
+                    process::exit(1);
+                }
+            }
+            Command::Bench {
+                posts,
+                rounds,
+                uploads,
+                upload_size,
+                theme,
+                site,
+            } => {
+                let options = bench::BenchOptions {
+                    posts,
+                    rounds,
+                    uploads,
+                    upload_size: upload_size * 1024,
+                    theme,
+                    config_path: site.map(|s| format!("{}/{}/_config.toml", sites_dir, s)),
+                };
+                let themes = theme::load_themes();
+                bench::run(&options, &themes, &args.default_language)
+                    .await
+                    .map_err(io::Error::other)?;
+            }
+            Command::Theme { command } => run_theme_command(command),
+        }
+        return Ok(());
+    }
+
+    let prompt = !args.no_prompt && io::stdin().is_terminal();
+    let stdin = io::stdin();
+    let ask = |question: &str| -> String {
+        print!("{}", question);
+        io::stdout().flush().unwrap();
+        stdin.lock().lines().next().unwrap().unwrap()
+    };
+
+    let mut themes = theme::load_themes();
+
+    if themes.is_empty() {
+        log::error!("No themes found!");
+
+        let fetch = if args.fetch_themes {
+            true
+        } else if prompt {
+            let mut response = String::new();
+            while response != "n" && response != "y" {
+                response = ask(&format!(
+                    "Fetch themes from {}? [y/n]? ",
+                    theme::THEMES_REPO
+                ))
+                .to_lowercase();
+            }
+            response == "y"
+        } else {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "Pass --fetch-themes to fetch the themes from {} or install some using `servus theme install`.",
+                    theme::THEMES_REPO
+                ),
+            ));
+        };
+        if !fetch {
+            return Ok(());
+        }
+
+        theme::fetch_themes().map_err(io::Error::other)?;
+
+        themes = theme::load_themes();
+
+        if themes.is_empty() {
+            panic!("No themes!");
+        }
+    }
+
+    site::set_lazy_loading(args.max_loaded_sites.is_some());
+    let mut sites = site::load_sites(&themes);
+
+    let create_site = args
+        .create_site
+        .map(|domain| site::normalize_domain(&domain).unwrap_or(domain));
+    let new_site = match create_site {
+        Some(domain) if !sites.contains_key(&domain) => Some((domain, args.admin_pubkey.clone())),
+        Some(_) => None,
+        None if sites.is_empty() && prompt => {
+            let mut response = String::new();
+            while response != "n" && response != "y" {
+                response = ask("No sites found. Create a default site [y/n]? ").to_lowercase();
+            }
+            if response == "y" {
+                let domain = ask("Domain: ").to_lowercase();
+                let admin_pubkey = ask("Admin pubkey: ");
+                Some((domain, Some(admin_pubkey).filter(|p| !p.is_empty())))
+            } else {
+                None
+            }
+        }
+        None => {
+            if sites.is_empty() {
+                log::warn!("No sites found. Starting without any! Sites can be created using the API or --create-site.");
+            }
+            None
         }
-        app.listen(listener).await?;
+    };
+
+    if let Some((domain, admin_pubkey)) = new_site {
+        let domain = match site::normalize_domain(&domain) {
+            Some(domain) if site::is_valid_domain(&domain) => domain,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Invalid domain: {}", domain),
+                ))
+            }
+        };
+        let admin_pubkey = match admin_pubkey {
+            Some(admin_pubkey) => Some(nostr::parse_pubkey(&admin_pubkey).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Invalid pubkey: {}", admin_pubkey),
+                )
+            })?),
+            None => None,
+        };
+        let site = site::create_site(&domain, admin_pubkey, &themes)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        log::info!("Created site: {}!", domain);
+
+        sites.insert(domain, site);
+    }
+
+    let site_count = sites.len();
+    if let Some(default_site) = default_site.as_ref().filter(|d| !sites.contains_key(*d)) {
+        log::warn!(
+            "No site {} yet: unknown hosts get a 404 until it is created.",
+            default_site
+        );
+    }
+
+    let sites = Arc::new(Shared::new(sites));
+
+    let tls_enabled = (args.ssl_cert.is_some() && args.ssl_key.is_some())
+        || args.ssl_acme
+        || args.ssl_acme_production;
+    let mut binds = args
+        .bind
+        .iter()
+        .map(|b| listener::parse_bind(b))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    if binds.is_empty() {
+        binds.push(("0.0.0.0".to_owned(), None));
+    }
+    let listeners = if listener_configs.is_empty() {
+        let default_port = args.port.unwrap_or(if tls_enabled { 443 } else { 4884 });
+        binds
+            .into_iter()
+            .map(|(bind, port)| listener::SiteListener {
+                bind,
+                port: port.unwrap_or(default_port),
+                tls: tls_enabled,
+                sites: vec![],
+            })
+            .collect()
     } else {
-        let port = args.port.unwrap_or(4884);
-        let bind_to = format!("{addr}:{port}");
-        println!("####################################");
-        if site_count == 1 {
-            println!("*** Your site: http://localhost:{port}/ ***");
+        // listeners have their own ports, so only the addresses passed using --bind apply
+        let mut default_binds: Vec<String> = vec![];
+        for (bind, _) in binds {
+            if !default_binds.contains(&bind) {
+                default_binds.push(bind);
+            }
+        }
+        let mut listeners = vec![];
+        for config in listener_configs {
+            listeners.extend(
+                listener::SiteListener::resolve(config, &default_binds, tls_enabled)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?,
+            );
         }
-        println!("*** The admin interface: http://localhost:{port}/.admin/ ***");
-        println!("####################################");
-        app.listen(bind_to).await?;
+        listeners
     };
+    let listeners = Arc::new(listeners);
+
+    // NB: sites with their own certificates (`ssl_cert` and `ssl_key`) don't affect how we listen,
+    // they just get served their own certificates rather than the ones of the other sites
+    let acme = if args.ssl_cert.is_none() && (args.ssl_acme || args.ssl_acme_production) {
+        let contact_email = match &args.contact_email {
+            Some(contact_email) => contact_email,
+            None => panic!("Use -e to provide a contact email!"),
+        };
+        let directory_url = if args.ssl_acme_production {
+            LETS_ENCRYPT_PRODUCTION_DIRECTORY
+        } else {
+            LETS_ENCRYPT_STAGING_DIRECTORY
+        };
+        let acme = tls::AcmeResolver::new(
+            directory_url,
+            contact_email,
+            sites.clone(),
+            args.public_ips.clone(),
+        );
+        for site in sites.load().values() {
+            for domain in get_acme_domains(&listeners, wildcard_domain.as_deref(), site) {
+                acme.add_domain(domain);
+            }
+        }
+        Some(Arc::new(acme))
+    } else {
+        None
+    };
+
+    let mut resolver = tls::ReloadingCertResolver::new(sites.clone(), acme.clone());
+    if let (Some(ssl_cert), Some(ssl_key)) = (&args.ssl_cert, &args.ssl_key) {
+        resolver.set_default(ssl_cert, ssl_key)?;
+    }
+    if let (Some(wildcard_domain), Some(tenant_cert), Some(tenant_key)) =
+        (&wildcard_domain, &args.tenant_cert, &args.tenant_key)
+    {
+        resolver.set_wildcard(wildcard_domain, tenant_cert, tenant_key)?;
+    }
+    if resolver.is_enabled() {
+        resolver.load_site_certs();
+    } else if sites.load().values().any(|s| s.config.ssl_cert.is_some()) {
+        log::warn!("Not using TLS, so the certificates of the sites are ignored. Pass --ssl-cert and --ssl-key or --ssl-acme to use them.");
+    }
+
+    let mut app = tide::with_state(State {
+        themes: Arc::new(Shared::new(themes)),
+        sites,
+        contact_email: args.contact_email.clone(),
+        relay_limits: nostr::RelayLimits {
+            max_message_length: args.max_message_length,
+            max_subscriptions: args.max_subscriptions,
+            max_filters: args.max_filters,
+            auth_required: false,
+            payment_required: false,
+        },
+        body_limits: limits::BodyLimits {
+            max_size: args.max_body_size,
+            timeout: Duration::from_secs(args.body_timeout),
+        },
+        upload_limits: limits::BodyLimits {
+            max_size: args.max_upload_size,
+            timeout: Duration::from_secs(args.upload_timeout),
+        },
+        default_language: args.default_language.clone(),
+        default_charset: args.default_charset.clone(),
+        trusted_proxies: if args.behind_proxy {
+            args.trusted_proxies.clone()
+        } else {
+            vec![]
+        },
+        trusted_hosts: Arc::new(args.trusted_hosts.clone()),
+        acme: acme.clone(),
+        websockets: service::WebSockets::default(),
+        listeners: listeners.clone(),
+        max_loaded_sites: args.max_loaded_sites,
+        deletion_tokens: Arc::default(),
+        operator_pubkeys: Arc::new(operator_pubkeys),
+        allow_open_registration,
+        require_approval,
+        max_sites_per_pubkey,
+        tenant_domain,
+        default_site,
+        wildcard_domain: wildcard_domain.clone(),
+        traffic: traffic::Traffic::new(args.access_log),
+        ipfs,
+        notifiers: Arc::new(notifiers),
+        cors: Arc::new(cors),
+        security_headers: Arc::new(security_headers),
+    });
+    app.state().traffic.save_periodically();
+
+    if args.watch {
+        watch::watch(app.state().themes.clone(), app.state().sites.clone());
+    }
+
+    {
+        let themes = app.state().themes.clone();
+        let sites = app.state().sites.clone();
+        let acme = acme.clone();
+        let listeners = listeners.clone();
+        service::handle_reload(move || {
+            watch::reload_all(&themes, &sites);
+            if let Some(acme) = &acme {
+                for site in sites.load().values() {
+                    for domain in get_acme_domains(&listeners, wildcard_domain.as_deref(), site) {
+                        acme.add_domain(domain);
+                    }
+                }
+            }
+        })?;
+    }
+
+    app.with(log_context);
+    app.with(count_traffic);
+    app.with(log::LogMiddleware::new());
+    app.with(limit_body);
+    app.with(handle_cors);
+    app.with(add_security_headers);
+    app.with(load_site_content);
+    app.at("/")
+        .with(limits::WebSocket::new(
+            args.max_message_length,
+            |request: Request<State>, ws| {
+                let context = request
+                    .ext::<Arc<logging::LogContext>>()
+                    .cloned()
+                    .unwrap_or_default();
+                logging::with_context(context, handle_websocket(request, ws))
+            },
+        ))
+        .get(handle_index);
+    app.at("*path").get(handle_request);
+    app.at("/.well-known/webfinger").get(handle_webfinger);
+    app.at("/.well-known/lnurlp/:name").get(handle_lnurlp);
+    app.at(newsletter::SUBSCRIBE_PATH)
+        .post(handle_newsletter_subscribe);
+    app.at(newsletter::CONFIRM_PATH)
+        .get(handle_newsletter_confirm);
+    // one-click unsubscribing (see RFC 8058) POSTs to the same URL
+    app.at(newsletter::UNSUBSCRIBE_PATH)
+        .get(handle_newsletter_unsubscribe)
+        .post(handle_newsletter_unsubscribe);
+    app.at(activitypub::ACTOR_PATH)
+        .get(handle_activitypub_actor);
+    app.at(activitypub::OUTBOX_PATH)
+        .get(handle_activitypub_outbox);
+    app.at(activitypub::INBOX_PATH)
+        .post(handle_activitypub_inbox);
+    app.at(activitypub::FOLLOWERS_PATH)
+        .get(handle_activitypub_followers);
+
+    // API
+    app.at("/api/sites")
+        .post(handle_post_site)
+        .get(handle_get_sites);
+    app.at("/api/sites/:domain").delete(handle_delete_site);
+    app.at("/api/sites/:domain/clone").post(handle_clone_site);
+    app.at("/api/sites/:domain/domains")
+        .get(handle_get_site_domains)
+        .post(handle_post_site_domain);
+    app.at("/api/sites/:domain/domains/:alias")
+        .delete(handle_delete_site_domain);
+    app.at("/api/sites/:domain/domains/:alias/verify")
+        .post(handle_verify_site_domain);
+    app.at("/api/sites/:domain/stats")
+        .get(handle_get_site_stats);
+    app.at("/api/sites/:domain/traffic")
+        .get(handle_get_site_traffic);
+    app.at("/api/sites/:domain/content")
+        .get(handle_get_site_content)
+        .put(handle_put_site_content);
+    app.at("/api/sites/:domain/export")
+        .get(handle_get_site_export);
+    app.at("/api/sites/:domain/git/push").post(handle_git_push);
+    app.at("/api/sites/:domain/git/pull").post(handle_git_pull);
+    app.at("/api/sites/:domain/reload").post(handle_reload_site);
+    app.at("/api/sites/:domain/quota")
+        .get(handle_get_site_quota)
+        .put(handle_put_site_quota);
+    app.at("/api/sites/:domain/config")
+        .get(handle_get_site_settings)
+        .put(handle_put_site_settings);
+    app.at("/api/sites/:domain/public/:kind")
+        .get(handle_get_public_resources);
+    app.at("/api/sites/:domain/:kind/:slug")
+        .get(handle_get_content)
+        .put(handle_put_content)
+        .delete(handle_delete_content);
+    app.at("/api/invites")
+        .get(handle_get_invites)
+        .post(handle_post_invite);
+    app.at("/api/invites/:code").delete(handle_delete_invite);
+    app.at("/api/pending-sites").get(handle_get_pending_sites);
+    app.at("/api/pending-sites/:domain")
+        .post(handle_approve_pending_site)
+        .delete(handle_reject_pending_site);
+    app.at("/api/themes").get(handle_get_themes);
+    app.at(openapi::OPENAPI_PATH).get(handle_get_openapi);
+
+    // Site API
+    app.at("/api/config")
+        .get(handle_get_site_config)
+        .put(handle_put_site_config);
+
+    // Blossom API
+    app.at("/upload").put(handle_blossom_upload_request);
+    app.at("/list/:pubkey").get(handle_blossom_list_request);
+    app.at("/:sha256").delete(handle_blossom_delete_request);
+
+    // NIP-96 API
+    app.at("/api/files").post(handle_nip96_upload_request);
+    app.at("/api/files/:sha256")
+        .delete(handle_nip96_delete_request);
+
+    let mut listener = ConcurrentListener::new();
+    let resolver = Arc::new(resolver);
+    if resolver.is_enabled() {
+        resolver.clone().watch();
+        if acme.is_some() && !args.ssl_acme_production {
+            println!("NB: Using Let's Encrypt STAGING environment! Great for testing, but browsers will complain about the certificate.");
+        }
+    }
+    for site_listener in listeners.iter() {
+        let tcp_listeners = site_listener.bind().map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Cannot listen on {}: {}", site_listener.addr(), e),
+            )
+        })?;
+        for tcp_listener in tcp_listeners {
+            if site_listener.tls {
+                let mut tls_listener = tide_rustls::TlsListener::build().tcp(tcp_listener);
+                if acme.is_some() {
+                    tls_listener = tls_listener
+                        .tls_acceptor(Arc::new(tls::AcmeAcceptor::new(resolver.clone())));
+                } else {
+                    tls_listener = tls_listener.config(tls::make_server_config(resolver.clone()));
+                }
+                listener.add(tls_listener)?;
+            } else {
+                listener.add(tcp_listener)?;
+            }
+        }
+    }
+    // there is only one port for plain HTTP, redirecting to the port of the first TLS listener,
+    // on all the addresses TLS listeners listen on
+    if let Some(first) = listeners.iter().find(|l| l.tls) {
+        if !args.no_http_redirect {
+            let mut hosts: Vec<&str> = vec![];
+            for site_listener in listeners.iter().filter(|l| l.tls) {
+                if !hosts.contains(&site_listener.bind.as_str()) {
+                    hosts.push(&site_listener.bind);
+                }
+            }
+            redirect_http(&hosts, args.http_redirect_port, first.port);
+        }
+    }
+
+    if let Some(site_listener) = listeners.first() {
+        if listeners
+            .iter()
+            .all(|l| !l.tls && l.port == site_listener.port)
+        {
+            let port = site_listener.port;
+            println!("####################################");
+            if site_count == 1 {
+                println!("*** Your site: http://localhost:{port}/ ***");
+            }
+            println!("*** The admin interface: http://localhost:{port}/.admin/ ***");
+            println!("####################################");
+        }
+    }
+    listen(app, listener, args.pid_file.as_deref()).await?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+    use std::sync::RwLock;
+
+    fn test_site(domain: &str) -> Site {
+        Site {
+            domain: domain.to_owned(),
+            config: toml::from_str(&format!(
+                "base_url = \"https://{}\"\ntheme = \"hyde\"",
+                domain
+            ))
+            .unwrap(),
+            content: Arc::new(Shared::default()),
+            upstream_cache: Arc::new(upstream::UpstreamCache::default()),
+            sass_resources: Arc::new(RwLock::new(HashMap::new())),
+            assets: template::Assets::new(String::new(), vec![]),
+            tera: Arc::default(),
+            loading: Arc::default(),
+            last_used: Arc::default(),
+        }
+    }
+
+    fn test_state(acme: Option<Arc<tls::AcmeResolver>>) -> State {
+        State {
+            themes: Arc::new(Shared::default()),
+            sites: Arc::new(Shared::default()),
+            contact_email: None,
+            relay_limits: nostr::RelayLimits {
+                max_message_length: 0,
+                max_subscriptions: 0,
+                max_filters: 0,
+                auth_required: false,
+                payment_required: false,
+            },
+            body_limits: limits::BodyLimits {
+                max_size: 1024,
+                timeout: Duration::from_secs(1),
+            },
+            upload_limits: limits::BodyLimits {
+                max_size: 1024,
+                timeout: Duration::from_secs(1),
+            },
+            default_language: "en".to_owned(),
+            default_charset: "utf-8".to_owned(),
+            trusted_proxies: vec![],
+            trusted_hosts: Arc::default(),
+            listeners: Arc::new(vec![listener::SiteListener {
+                bind: "0.0.0.0".to_owned(),
+                port: 443,
+                tls: acme.is_some(),
+                sites: vec![],
+            }]),
+            acme,
+            websockets: service::WebSockets::default(),
+            max_loaded_sites: None,
+            deletion_tokens: Arc::default(),
+            operator_pubkeys: Arc::default(),
+            allow_open_registration: false,
+            require_approval: false,
+            max_sites_per_pubkey: None,
+            traffic: traffic::Traffic::default(),
+            tenant_domain: None,
+            default_site: None,
+            wildcard_domain: None,
+            ipfs: None,
+            notifiers: Arc::new(vec![]),
+            cors: Arc::default(),
+            security_headers: Arc::default(),
+        }
+    }
+
+    /// Responds with what we make of the request, behind a trusted proxy at 10.0.0.1.
+    async fn forwarded_request(peer_addr: &str, headers: &[(&str, &str)]) -> String {
+        let mut state = test_state(None);
+        state.trusted_proxies = vec!["10.0.0.1".parse().unwrap()];
+        let mut app = tide::with_state(state);
+        app.at("/*path").get(|request: Request<State>| async move {
+            Ok(format!(
+                "{} {}",
+                get_host(&request),
+                get_request_url(&request)
+            ))
+        });
+
+        let mut request = tide::http::Request::new(
+            tide::http::Method::Get,
+            "http://internal:8080/some/path?q=1",
+        );
+        request.set_peer_addr(Some(peer_addr));
+        request.insert_header("Host", "internal:8080");
+        for (name, value) in headers {
+            request.append_header(*name, *value);
+        }
+        let mut response: tide::http::Response = app.respond(request).await.unwrap();
+        response.body_string().await.unwrap()
+    }
+
+    #[async_std::test]
+    async fn test_forwarded_headers() {
+        let proxy = "10.0.0.1:1234";
+        assert_eq!(
+            forwarded_request(proxy, &[]).await,
+            "internal:8080 http://internal:8080/some/path?q=1"
+        );
+        assert_eq!(
+            forwarded_request(
+                proxy,
+                &[
+                    ("X-Forwarded-Proto", "https"),
+                    ("X-Forwarded-Host", "example.com")
+                ]
+            )
+            .await,
+            "example.com https://example.com/some/path?q=1"
+        );
+        // each header on its own
+        assert_eq!(
+            forwarded_request(proxy, &[("X-Forwarded-Host", "example.com")]).await,
+            "example.com http://example.com/some/path?q=1"
+        );
+        assert_eq!(
+            forwarded_request(proxy, &[("X-Forwarded-Proto", "https")]).await,
+            "internal:8080 https://internal:8080/some/path?q=1"
+        );
+        assert_eq!(
+            forwarded_request(
+                proxy,
+                &[
+                    ("X-Forwarded-Proto", "https"),
+                    ("X-Forwarded-Host", "example.com"),
+                    ("X-Forwarded-Port", "8443")
+                ]
+            )
+            .await,
+            "example.com https://example.com:8443/some/path?q=1"
+        );
+        assert_eq!(
+            forwarded_request(
+                proxy,
+                &[("X-Forwarded-Proto", "https"), ("X-Forwarded-Port", "443")]
+            )
+            .await,
+            "internal:8080 https://internal/some/path?q=1"
+        );
+        // the last value is the one set by our proxy, the first one could be made up by the client
+        assert_eq!(
+            forwarded_request(proxy, &[("X-Forwarded-Host", "evil.com, example.com")]).await,
+            "example.com http://example.com/some/path?q=1"
+        );
+        // untrusted peers cannot forward anything
+        assert_eq!(
+            forwarded_request(
+                "10.0.0.2:1234",
+                &[
+                    ("X-Forwarded-Proto", "https"),
+                    ("X-Forwarded-Host", "example.com")
+                ]
+            )
+            .await,
+            "internal:8080 http://internal:8080/some/path?q=1"
+        );
+    }
+
+    async fn http_redirect(
+        https_port: u32,
+        host: &str,
+        path: &str,
+    ) -> (StatusCode, Option<String>) {
+        let mut app = tide::with_state(https_port);
+        app.at("/").all(handle_http_redirect);
+        app.at("*").all(handle_http_redirect);
+        let mut request = tide::http::Request::new(
+            tide::http::Method::Get,
+            format!("http://localhost{}", path).as_str(),
+        );
+        request.insert_header("Host", host);
+        let response: tide::http::Response = app.respond(request).await.unwrap();
+        (
+            response.status(),
+            response.header("Location").map(|l| l.last().to_string()),
+        )
+    }
+
+    #[async_std::test]
+    async fn test_handle_http_redirect() {
+        let location = |l: &str| (StatusCode::MovedPermanently, Some(l.to_string()));
+        assert_eq!(
+            http_redirect(443, "example.com", "/a/b?c=d").await,
+            location("https://example.com/a/b?c=d")
+        );
+        assert_eq!(
+            http_redirect(443, "example.com:80", "/").await,
+            location("https://example.com/")
+        );
+        // a non-default HTTPS port is kept
+        assert_eq!(
+            http_redirect(8443, "example.com:8080", "/a").await,
+            location("https://example.com:8443/a")
+        );
+        // IPv6 literals, with and without a port
+        assert_eq!(
+            http_redirect(443, "[::1]:80", "/").await,
+            location("https://[::1]/")
+        );
+        assert_eq!(
+            http_redirect(8443, "[2001:db8::1]", "/a").await,
+            location("https://[2001:db8::1]:8443/a")
+        );
+        assert_eq!(
+            http_redirect(443, "example.com", "/.well-known/acme-challenge/token").await,
+            (StatusCode::NotFound, None)
+        );
+    }
+
+    #[test]
+    fn test_with_port() {
+        assert_eq!(
+            with_port("example.com", "https", "8443"),
+            "example.com:8443"
+        );
+        assert_eq!(
+            with_port("example.com:80", "http", "8080"),
+            "example.com:8080"
+        );
+        assert_eq!(with_port("example.com:8080", "https", "443"), "example.com");
+        assert_eq!(with_port("[::1]", "http", "8080"), "[::1]:8080");
+        assert_eq!(with_port("[::1]:80", "http", "80"), "[::1]");
+    }
+
+    #[async_std::test]
+    async fn test_add_site_registers_acme_domain() {
+        let sites = Arc::new(Shared::default());
+        // nothing listens there, so no certificate actually gets ordered
+        let acme = Arc::new(tls::AcmeResolver::new(
+            "http://127.0.0.1:9/directory",
+            "admin@example.com",
+            sites.clone(),
+            vec![],
+        ));
+        let mut state = test_state(Some(acme.clone()));
+        state.sites = sites;
+
+        add_site(&state, test_site("new.example.com"));
+
+        assert!(state.sites.load().contains_key("new.example.com"));
+        assert!(acme.has_domain("new.example.com"));
+        assert!(!acme.has_domain("other.example.com"));
+
+        // subdomains of the tenant domain are served the wildcard certificate instead
+        state.wildcard_domain = Some("myservus.net".to_owned());
+        add_site(&state, test_site("blog.myservus.net"));
+        assert!(state.sites.load().contains_key("blog.myservus.net"));
+        assert!(!acme.has_domain("blog.myservus.net"));
+
+        // sites only served over plain HTTP don't need certificates
+        state.listeners = Arc::new(vec![
+            listener::SiteListener {
+                bind: "0.0.0.0".to_owned(),
+                port: 443,
+                tls: true,
+                sites: vec!["new.example.com".to_owned()],
+            },
+            listener::SiteListener {
+                bind: "10.0.0.5".to_owned(),
+                port: 8080,
+                tls: false,
+                sites: vec![],
+            },
+        ]);
+        add_site(&state, test_site("staging.example.com"));
+        assert!(state.sites.load().contains_key("staging.example.com"));
+        assert!(!acme.has_domain("staging.example.com"));
+    }
+
+    #[test]
+    fn test_dirs_command_line_over_config_file() {
+        let args = Cli::parse_from(["servus", "--sites-dir", "/cli/sites"]);
+        let config = paths::ServusConfig {
+            sites_dir: Some("/file/sites".to_owned()),
+            themes_dir: Some("/file/themes".to_owned()),
+            ..Default::default()
+        };
+        let dirs = paths::Paths::resolve(args.get_dirs(), config);
+
+        assert_eq!(dirs.sites, "/cli/sites");
+        assert_eq!(dirs.themes, "/file/themes");
+    }
+
+    #[test]
+    fn test_options_environment() {
+        // the environment is only read by clap, so the variables are checked rather than set,
+        // which would affect the tests running alongside
+        let command = Cli::command();
+        let get_env = |id: &str| {
+            command
+                .get_arguments()
+                .find(|a| a.get_id() == id)
+                .and_then(|a| a.get_env())
+                .and_then(|e| e.to_str())
+        };
+        for (id, env) in [
+            ("sites_dir", "SERVUS_SITES_DIR"),
+            ("themes_dir", "SERVUS_THEMES_DIR"),
+            ("port", "SERVUS_PORT"),
+            ("ssl_acme", "SERVUS_SSL_ACME"),
+            ("no_http_redirect", "SERVUS_NO_HTTP_REDIRECT"),
+            ("trusted_proxies", "SERVUS_TRUSTED_PROXIES"),
+            ("bind", "SERVUS_BIND"),
+        ] {
+            assert_eq!(get_env(id), Some(env));
+        }
+
+        // lists are comma-separated, as they are in the environment
+        let args = Cli::parse_from([
+            "servus",
+            "--port",
+            "8443",
+            "--ssl-acme",
+            "--trusted-proxies",
+            "10.0.0.1,10.0.0.2",
+            "--bind",
+            "0.0.0.0,[::]:443",
+        ]);
+        assert_eq!(args.port, Some(8443));
+        assert!(args.ssl_acme);
+        assert!(!args.no_http_redirect);
+        assert_eq!(
+            args.trusted_proxies,
+            vec![
+                "10.0.0.1".parse::<IpAddr>().unwrap(),
+                "10.0.0.2".parse().unwrap()
+            ]
+        );
+        assert_eq!(args.bind, vec!["0.0.0.0", "[::]:443"]);
+
+        let args = Cli::parse_from(["servus", "-b", "127.0.0.1", "--bind", "[::1]:8080"]);
+        assert_eq!(args.bind, vec!["127.0.0.1", "[::1]:8080"]);
+    }
+
+    #[test]
+    fn test_encode_html() {
+        let (body, encoding) = encode_html("<p>café €</p>", "iso-8859-1");
+        assert_eq!(encoding.name(), "windows-1252");
+        assert_eq!(&*body, b"<p>caf\xe9 \x80</p>");
+
+        // UTF-16 cannot be used for output, so UTF-8 is actually emitted
+        let (body, encoding) = encode_html("<p>café</p>", "utf-16le");
+        assert_eq!(encoding, encoding_rs::UTF_8);
+        assert_eq!(&*body, "<p>café</p>".as_bytes());
+
+        // so is it for the "replacement" encoding and for unknown charsets
+        assert_eq!(encode_html("", "iso-2022-kr").1, encoding_rs::UTF_8);
+        assert_eq!(encode_html("", "no-such-charset").1, encoding_rs::UTF_8);
+
+        // characters missing from the charset become HTML entities
+        let (body, _) = encode_html("€", "iso-8859-2");
+        assert_eq!(&*body, b"&#8364;");
+    }
+
+    #[test]
+    fn test_accepts_media_type() {
+        let nostr_json = "application/nostr+json";
+        assert!(accepts_media_type("application/nostr+json", nostr_json));
+        assert!(accepts_media_type(
+            "text/html, application/nostr+json;q=0.9",
+            nostr_json
+        ));
+        assert!(accepts_media_type("Application/Nostr+JSON", nostr_json));
+        assert!(!accepts_media_type(
+            "application/nostr+json;q=0",
+            nostr_json
+        ));
+        assert!(!accepts_media_type(
+            "text/html,application/json",
+            nostr_json
+        ));
+        assert!(!accepts_media_type("*/*", nostr_json));
+    }
+
+    #[test]
+    fn test_is_etag_matched() {
+        let etag = "\"0123abcd\"";
+        assert!(is_etag_matched("\"0123abcd\"", etag));
+        assert!(is_etag_matched("W/\"0123abcd\"", etag));
+        assert!(is_etag_matched("\"ffff\", \"0123abcd\"", etag));
+        assert!(is_etag_matched("*", etag));
+        assert!(!is_etag_matched("\"ffff\"", etag));
+        assert!(!is_etag_matched("0123abcd", etag));
+    }
+
+    #[test]
+    fn test_add_site_without_acme() {
+        let state = test_state(None);
+
+        add_site(&state, test_site("new.example.com"));
+
+        assert!(state.sites.load().contains_key("new.example.com"));
+    }
+
+    #[async_std::test]
+    async fn test_get_site() {
+        let request_site = |state: State, host: &str| {
+            let mut app = tide::with_state(state);
+            app.at("/").get(|request: Request<State>| async move {
+                Ok(get_site(&request).map(|s| s.domain).unwrap_or_default())
+            });
+            let mut request = tide::http::Request::new(Method::Get, "http://localhost/");
+            request.insert_header("Host", host);
+            async move {
+                let mut response: tide::http::Response = app.respond(request).await.unwrap();
+                response.body_string().await.unwrap()
+            }
+        };
+
+        // a single site is served for any host
+        let mut state = test_state(None);
+        add_site(&state, test_site("a.example.com"));
+        assert_eq!(
+            request_site(state.clone(), "10.0.0.1").await,
+            "a.example.com"
+        );
+
+        // ... but with more, only given a default site
+        add_site(&state, test_site("b.example.com"));
+        assert_eq!(request_site(state.clone(), "10.0.0.1").await, "");
+        state.default_site = Some("b.example.com".to_owned());
+        assert_eq!(
+            request_site(state.clone(), "10.0.0.1").await,
+            "b.example.com"
+        );
+        assert_eq!(request_site(state, "a.example.com").await, "a.example.com");
+    }
+
+    #[test]
+    fn test_check_registration() {
+        let mut state = test_state(None);
+        assert_eq!(
+            check_registration(&state, "aa", &[]),
+            Err("registration is closed")
+        );
+
+        state.allow_open_registration = true;
+        state.max_sites_per_pubkey = Some(1);
+        assert_eq!(check_registration(&state, "aa", &[]), Ok(()));
+        let mut site = test_site("a.example.com");
+        site.config.pubkey = Some("aa".to_owned());
+        add_site(&state, site);
+        assert_eq!(
+            check_registration(&state, "aa", &[]),
+            Err("too many sites for this key")
+        );
+        assert_eq!(check_registration(&state, "bb", &[]), Ok(()));
+
+        // sites waiting for approval count as well
+        let pending = [registration::PendingSite {
+            domain: "b.example.com".to_owned(),
+            pubkey: "bb".to_owned(),
+            requested_at: 0,
+        }];
+        assert_eq!(
+            check_registration(&state, "bb", &pending),
+            Err("too many sites for this key")
+        );
+    }
+
+    #[async_std::test]
+    async fn test_handle_cors() {
+        let mut state = test_state(None);
+        state.cors =
+            Arc::new(toml::from_str("allowed_origins = [\"https://example.org\"]").unwrap());
+        let mut app = tide::with_state(state);
+        app.with(handle_cors);
+        app.at("/api/sites").get(|_| async { Ok("[]") });
+
+        let respond = |method, origin: &str| {
+            let mut request = tide::http::Request::new(method, "http://localhost/api/sites");
+            request.insert_header("Origin", origin);
+            request.insert_header("Access-Control-Request-Headers", "authorization");
+            app.respond::<_, tide::http::Response>(request)
+        };
+        let header = |response: &tide::http::Response, name| {
+            response.header(name).map(|h| h.last().to_string())
+        };
+
+        let preflight = respond(Method::Options, "https://example.org")
+            .await
+            .unwrap();
+        assert_eq!(preflight.status(), StatusCode::NoContent);
+        assert_eq!(
+            header(&preflight, "Access-Control-Allow-Origin").as_deref(),
+            Some("https://example.org")
+        );
+        assert_eq!(
+            header(&preflight, "Access-Control-Allow-Headers").as_deref(),
+            Some("authorization")
+        );
+        assert!(header(&preflight, "Access-Control-Allow-Methods")
+            .unwrap()
+            .contains("PUT"));
+
+        let response = respond(Method::Get, "https://example.org").await.unwrap();
+        assert_eq!(response.status(), StatusCode::Ok);
+        assert_eq!(header(&response, "Vary").as_deref(), Some("Origin"));
+
+        // other origins get the response, but the browser doesn't hand it to them
+        let preflight = respond(Method::Options, "https://evil.com").await.unwrap();
+        assert!(header(&preflight, "Access-Control-Allow-Methods").is_none());
+        let response = respond(Method::Get, "https://evil.com").await.unwrap();
+        assert!(header(&response, "Access-Control-Allow-Origin").is_none());
+    }
+}
@@ -0,0 +1,356 @@
+use async_std::task;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fs, io, sync::Mutex};
+use tide::log;
+
+use crate::{
+    logging, paths,
+    resource::{ContentSource, PublicResource, ResourceKind},
+    site::{self, Site},
+    smtp,
+};
+
+pub const SUBSCRIBE_PATH: &str = "/newsletter/subscribe";
+pub const CONFIRM_PATH: &str = "/newsletter/confirm";
+pub const UNSUBSCRIBE_PATH: &str = "/newsletter/unsubscribe";
+
+const SUBSCRIBERS_FILENAME: &str = "subscribers.json";
+
+/// How long a subscription can be confirmed for, after which it is forgotten, in seconds.
+const CONFIRMATION_TTL: i64 = 7 * 24 * 60 * 60;
+
+/// How long before the confirmation of a subscription can be sent again, in seconds.
+const RESEND_DELAY: i64 = 24 * 60 * 60;
+
+/// The `[newsletter]` table of a site's `_config.toml`, having new posts sent to the subscribers by email.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct NewsletterConfig {
+    /// The SMTP server emails are sent through, such as `smtps://<user>:<password>@smtp.example.com` (see [`smtp::Client::connect`]).
+    pub smtp_url: Option<String>,
+    /// The sender of the emails, such as `My blog <newsletter@example.com>`.
+    pub from: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct Subscriber {
+    /// Confirms the subscription, then cancels it.
+    token: String,
+    confirmed: bool,
+    created_at: i64,
+}
+
+/// Held while the subscribers are updated.
+static LOCK: Mutex<()> = Mutex::new(());
+
+fn invalid_input(error: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, error)
+}
+
+fn get_dir(domain: &str) -> String {
+    format!("{}/{}/_newsletter", paths::sites(), domain)
+}
+
+/// The URL of one of the newsletter's endpoints, with the subscriber's `token`.
+fn get_url(site: &Site, path: &str, token: &str) -> String {
+    format!(
+        "{}{}?token={}",
+        site.config.base_url.trim_end_matches('/'),
+        path,
+        token
+    )
+}
+
+/// Whether `email` looks like an address that can safely be put in the headers of an email.
+pub fn is_valid_email(email: &str) -> bool {
+    match email.split_once('@') {
+        Some((user, domain)) => {
+            email.len() <= 254
+                && !user.is_empty()
+                && user
+                    .chars()
+                    .all(|c| c.is_ascii_graphic() && !"<>()[]\\,;:@\"".contains(c))
+                && domain.contains('.')
+                && site::is_valid_domain(domain)
+        }
+        None => false,
+    }
+}
+
+/// The subscribers of the site, by email address.
+fn read_subscribers(domain: &str) -> BTreeMap<String, Subscriber> {
+    fs::read_to_string(format!("{}/{}", get_dir(domain), SUBSCRIBERS_FILENAME))
+        .ok()
+        .and_then(|json| serde_json::from_str(&json).ok())
+        .unwrap_or_default()
+}
+
+/// Applies `f` to the subscribers, once the subscriptions that were never confirmed are forgotten.
+fn update_subscribers<T>(
+    domain: &str,
+    f: impl FnOnce(&mut BTreeMap<String, Subscriber>) -> T,
+) -> io::Result<T> {
+    let _lock = LOCK.lock().unwrap();
+    let mut subscribers = read_subscribers(domain);
+    let now = Utc::now().timestamp();
+    subscribers.retain(|_, s| s.confirmed || now - s.created_at < CONFIRMATION_TTL);
+    let result = f(&mut subscribers);
+    fs::create_dir_all(get_dir(domain))?;
+    let path = format!("{}/{}", get_dir(domain), SUBSCRIBERS_FILENAME);
+    fs::write(
+        format!("{}.part", path),
+        serde_json::to_string_pretty(&subscribers)?,
+    )?;
+    fs::rename(format!("{}.part", path), path)?;
+
+    Ok(result)
+}
+
+fn get_config(site: &Site) -> io::Result<(&NewsletterConfig, &str)> {
+    let config = site
+        .config
+        .newsletter
+        .as_ref()
+        .ok_or_else(|| invalid_input("No newsletter"))?;
+    let smtp_url = config
+        .smtp_url
+        .as_deref()
+        .ok_or_else(|| io::Error::other("No SMTP server configured"))?;
+    Ok((config, smtp_url))
+}
+
+/// Subscribes `email`, pending confirmation using the link sent to it.
+/// Nothing is sent if it is subscribed already, or was sent a confirmation recently.
+pub async fn subscribe(site: &Site, email: &str) -> io::Result<()> {
+    let email = email.trim().to_lowercase();
+    if !is_valid_email(&email) {
+        return Err(invalid_input("Invalid email address"));
+    }
+    let (config, smtp_url) = get_config(site)?;
+
+    let token = {
+        let (domain, email) = (site.domain.to_owned(), email.to_owned());
+        logging::spawn_blocking(move || {
+            update_subscribers(&domain, |subscribers| {
+                let now = Utc::now().timestamp();
+                if let Some(s) = subscribers.get(&email) {
+                    if s.confirmed || now - s.created_at < RESEND_DELAY {
+                        return None;
+                    }
+                }
+                let token = format!("{:032x}", rand::random::<u128>());
+                subscribers.insert(
+                    email,
+                    Subscriber {
+                        token: token.to_owned(),
+                        confirmed: false,
+                        created_at: now,
+                    },
+                );
+                Some(token)
+            })
+        })
+        .await?
+    };
+    let Some(token) = token else {
+        return Ok(());
+    };
+
+    let title = site.config.title.as_deref().unwrap_or(&site.domain);
+    let message = smtp::Message {
+        from: config.from.to_owned(),
+        to: email,
+        subject: format!("Confirm your subscription to {}", title),
+        headers: vec![],
+        text: format!(
+            "Please confirm your subscription to {} by following this link:\n\n{}\n\nIf you didn't subscribe, just ignore this email.\n",
+            title,
+            get_url(site, CONFIRM_PATH, &token)
+        ),
+        html: None,
+    };
+    let sent = async {
+        let mut client = smtp::Client::connect(smtp_url, &site.domain).await?;
+        client.send(&message).await?;
+        client.quit().await;
+        io::Result::Ok(())
+    }
+    .await;
+    if sent.is_err() {
+        // so that subscribing can be tried again right away
+        let domain = site.domain.to_owned();
+        logging::spawn_blocking(move || {
+            update_subscribers(&domain, |subscribers| {
+                subscribers.retain(|_, s| s.token != token);
+            })
+        })
+        .await?;
+    }
+
+    sent
+}
+
+/// Confirms the subscription with the given token, returning whether there is one.
+pub async fn confirm(site: &Site, token: &str) -> io::Result<bool> {
+    let (domain, token) = (site.domain.to_owned(), token.to_owned());
+    logging::spawn_blocking(move || {
+        update_subscribers(&domain, |subscribers| {
+            match subscribers.values_mut().find(|s| s.token == token) {
+                Some(subscriber) => {
+                    subscriber.confirmed = true;
+                    true
+                }
+                None => false,
+            }
+        })
+    })
+    .await
+}
+
+/// Cancels the subscription with the given token, returning whether there was one.
+pub async fn unsubscribe(site: &Site, token: &str) -> io::Result<bool> {
+    let (domain, token) = (site.domain.to_owned(), token.to_owned());
+    logging::spawn_blocking(move || {
+        update_subscribers(&domain, |subscribers| {
+            let count = subscribers.len();
+            subscribers.retain(|_, s| s.token != token);
+            subscribers.len() != count
+        })
+    })
+    .await
+}
+
+/// The email sending `post` to the subscriber with address `email` and token `token`.
+fn get_post_message(
+    site: &Site,
+    config: &NewsletterConfig,
+    post: &PublicResource,
+    email: &str,
+    token: &str,
+) -> smtp::Message {
+    let unsubscribe_url = get_url(site, UNSUBSCRIBE_PATH, token);
+    let mut text = format!("{}\n\n", post.title);
+    if let Some(summary) = &post.summary {
+        text.push_str(&format!("{}\n\n", summary));
+    }
+    text.push_str(&format!(
+        "Read it at: {}\n\n--\nUnsubscribe: {}\n",
+        post.url, unsubscribe_url
+    ));
+    let html = format!(
+        "<h1><a href=\"{}\">{}</a></h1>\n{}\n<hr>\n<p><a href=\"{}\">Unsubscribe</a></p>\n",
+        post.url,
+        tera::escape_html(&post.title),
+        post.html,
+        unsubscribe_url
+    );
+
+    smtp::Message {
+        from: config.from.to_owned(),
+        to: email.to_owned(),
+        subject: post.title.to_owned(),
+        headers: vec![
+            (
+                "List-Unsubscribe".to_string(),
+                format!("<{}>", unsubscribe_url),
+            ),
+            (
+                "List-Unsubscribe-Post".to_string(),
+                "List-Unsubscribe=One-Click".to_string(),
+            ),
+        ],
+        text,
+        html: Some(html),
+    }
+}
+
+/// Sends the post read from the event with ID `event_id` to the confirmed subscribers, in the background.
+pub fn send_post_in_background(site: &Site, event_id: &str) {
+    let (site, event_id) = (site.clone(), event_id.to_owned());
+    task::spawn(async move {
+        let Ok((config, smtp_url)) = get_config(&site) else {
+            return;
+        };
+        let (post, subscribers) = {
+            let site = site.clone();
+            logging::spawn_blocking(move || {
+                let site_content = site.get_content();
+                let post = site_content.resources.values().find(|r| {
+                    r.kind == ResourceKind::Post
+                        && !r.noindex
+                        && matches!(&r.content_source, ContentSource::Event(id) if id == &event_id)
+                })?;
+                let subscribers = read_subscribers(&site.domain)
+                    .into_iter()
+                    .filter(|(_, s)| s.confirmed)
+                    .collect::<Vec<_>>();
+                Some((post.to_public(&site, &site_content), subscribers))
+            })
+            .await
+        }
+        .unzip();
+        let (Some(post), Some(subscribers)) = (post, subscribers) else {
+            return;
+        };
+        if subscribers.is_empty() {
+            return;
+        }
+
+        let mut client = match smtp::Client::connect(smtp_url, &site.domain).await {
+            Ok(client) => client,
+            Err(e) => {
+                log::warn!("Cannot send the newsletter of {}: {}", site.domain, e);
+                return;
+            }
+        };
+        let mut sent = 0;
+        for (email, subscriber) in &subscribers {
+            let message = get_post_message(&site, config, &post, email, &subscriber.token);
+            match client.send(&message).await {
+                Ok(()) => sent += 1,
+                Err(e) => {
+                    log::warn!(
+                        "Cannot send the newsletter of {} to {}: {}",
+                        site.domain,
+                        email,
+                        e
+                    );
+                    if e.kind() != io::ErrorKind::Other {
+                        break; // the connection is lost
+                    }
+                }
+            }
+        }
+        client.quit().await;
+        log::info!(
+            "Sent {} to {} of {} subscribers of {}.",
+            post.url,
+            sent,
+            subscribers.len(),
+            site.domain
+        );
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_valid_email() {
+        assert!(is_valid_email("reader@example.com"));
+        assert!(is_valid_email("first.last+news@mail.example.org"));
+        for email in [
+            "reader",
+            "@example.com",
+            "reader@",
+            "reader@localhost",
+            "reader@example.com\r\nBcc: x@example.com",
+            "<reader>@example.com",
+            "rea der@example.com",
+            "reader@exa mple.com",
+        ] {
+            assert!(!is_valid_email(email), "{}", email);
+        }
+    }
+}
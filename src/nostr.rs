@@ -1,7 +1,7 @@
 use bitcoin_hashes::sha256;
 use chrono::{DateTime, NaiveDateTime, TimeDelta, TimeZone, Utc};
 use lazy_static::lazy_static;
-use secp256k1::{schnorr, Secp256k1, VerifyOnly, XOnlyPublicKey};
+use secp256k1::{schnorr, KeyPair, Secp256k1, VerifyOnly, XOnlyPublicKey};
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value as JsonValue};
 use serde_yaml::Value as YamlValue;
@@ -15,7 +15,7 @@ use std::{
     str::FromStr,
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
-use tide::log;
+use tide::{http::Url, log};
 
 pub struct InvalidEventError;
 
@@ -32,11 +32,26 @@ pub struct Event {
 
 pub const EVENT_KIND_NOTE: u64 = 1;
 pub const EVENT_KIND_DELETE: u64 = 5;
+pub const EVENT_KIND_REACTION: u64 = 7;
+pub const EVENT_KIND_COMMENT: u64 = 1111;
 pub const EVENT_KIND_BLOSSOM: u64 = 24242;
 pub const EVENT_KIND_AUTH: u64 = 27235;
 pub const EVENT_KIND_LONG_FORM: u64 = 30023;
 pub const EVENT_KIND_LONG_FORM_DRAFT: u64 = 30024;
 pub const EVENT_KIND_CUSTOM_DATA: u64 = 30078;
+pub const EVENT_KIND_THEME_SETTINGS: u64 = 30079;
+
+pub const SUPPORTED_NIPS: &[u64] = &[1, 9, 11, 23, 96, 98];
+
+/// Policy values enforced by the relay, advertised in the NIP-11 `limitation` object.
+#[derive(Clone, Debug, Serialize)]
+pub struct RelayLimits {
+    pub max_message_length: usize,
+    pub max_subscriptions: usize,
+    pub max_filters: usize,
+    pub auth_required: bool,
+    pub payment_required: bool,
+}
 
 lazy_static! {
     pub static ref SECP: Secp256k1<VerifyOnly> = Secp256k1::verification_only();
@@ -44,7 +59,7 @@ lazy_static! {
 
 impl Event {
     pub fn is_parameterized_replaceable(&self) -> bool {
-        return 30000 <= self.kind && self.kind < 40000;
+        30000 <= self.kind && self.kind < 40000
     }
 
     pub fn is_long_form(&self) -> bool {
@@ -65,18 +80,45 @@ impl Event {
         Utc.timestamp_opt(self.created_at, 0).unwrap()
     }
 
+    /// The value of each tag, by name (the last one, for a tag given more than once), ignoring tags without a value.
     pub fn get_tags_hash(&self) -> HashMap<String, String> {
         let mut tags: HashMap<String, String> = HashMap::new();
         for t in &self.tags {
-            tags.insert(t[0].to_owned(), t[1].to_owned());
+            if let [name, value, ..] = t.as_slice() {
+                tags.insert(name.to_owned(), value.to_owned());
+            }
         }
         tags
     }
 
+    /// The tags named `tag` that have a value, whole: the name, the value, and any others,
+    /// such as the relay hint of `e` and `p` tags.
+    pub fn get_tags(&self, tag: &str) -> Vec<&[String]> {
+        self.tags
+            .iter()
+            .filter(|t| t.len() >= 2 && t[0] == tag)
+            .map(|t| t.as_slice())
+            .collect()
+    }
+
     pub fn get_tag(&self, tag: &str) -> Option<String> {
         self.get_tags_hash().get(tag).cloned()
     }
 
+    pub fn get_tag_values(&self, tag: &str) -> Vec<String> {
+        self.get_tags(tag)
+            .into_iter()
+            .map(|t| t[1].to_owned())
+            .collect()
+    }
+
+    /// Whether the event is addressed to `pubkey`, as when replying to (or reacting to) its content.
+    pub fn mentions(&self, pubkey: &str) -> bool {
+        ["p", "P"]
+            .iter()
+            .any(|t| self.get_tag_values(t).iter().any(|p| p == pubkey))
+    }
+
     pub fn get_d_tag(&self) -> Option<String> {
         self.get_tag("d")
     }
@@ -94,7 +136,7 @@ impl Event {
             return None;
         }
 
-        let ts = self.get_tag("published_at")?.parse::<i64>().unwrap();
+        let ts = self.get_tag("published_at")?.parse::<i64>().ok()?;
 
         DateTime::from_timestamp(ts, 0).map(|d| d.naive_utc())
     }
@@ -129,7 +171,25 @@ impl Event {
         }
     }
 
-    pub fn get_nip98_pubkey(&self, url: &str, method: &str) -> Option<String> {
+    /// Sets the event's pubkey and id, then signs it, using `keypair`.
+    pub fn sign(&mut self, keypair: &KeyPair) {
+        self.pubkey = keypair.x_only_public_key().0.to_string();
+        let hash = sha256::Hash::hash(self.to_canonical().as_bytes());
+        self.id = format!("{:x}", hash);
+        let msg = secp256k1::Message::from_slice(hash.as_ref()).unwrap();
+        self.sig = Secp256k1::signing_only()
+            .sign_schnorr_no_aux_rand(&msg, keypair)
+            .to_string();
+    }
+
+    /// The pubkey of a valid NIP-98 event authorizing a `method` request to `url`
+    /// (see [`nip98_url_matches`] for `trusted_hosts`).
+    pub fn get_nip98_pubkey(
+        &self,
+        url: &str,
+        method: &str,
+        trusted_hosts: &[String],
+    ) -> Option<String> {
         if self.validate_sig().is_err() {
             log::info!("NIP-98: Invalid signature.");
             return None;
@@ -142,7 +202,7 @@ impl Event {
 
         let now = chrono::offset::Utc::now();
         let five_mins = TimeDelta::minutes(5);
-        let created_at = DateTime::from_timestamp(self.created_at as i64, 0).unwrap();
+        let created_at = DateTime::from_timestamp(self.created_at, 0).unwrap();
         if created_at < now && now - created_at > five_mins {
             log::info!("NIP-98: Event too old.");
             return None;
@@ -153,12 +213,29 @@ impl Event {
         }
 
         let tags = self.get_tags_hash();
-        if tags.get("u")? != url {
-            log::info!("NIP-98: Invalid 'u' tag: {} vs. {}.", tags.get("u")?, url);
+        let Some(u) = tags.get("u") else {
+            log::info!("NIP-98: Missing 'u' tag.");
+            return None;
+        };
+        if let Err(reason) = nip98_url_matches(u, url, trusted_hosts) {
+            log::info!(
+                "NIP-98: The 'u' tag ({}) doesn't match the request ({}): {}.",
+                u,
+                url,
+                reason
+            );
             return None;
         }
-        if tags.get("method")? != method {
-            log::info!("NIP-98: Invalid method.");
+        let Some(signed_method) = tags.get("method") else {
+            log::info!("NIP-98: Missing 'method' tag.");
+            return None;
+        };
+        if !signed_method.eq_ignore_ascii_case(method) {
+            log::info!(
+                "NIP-98: The 'method' tag ({}) doesn't match the request ({}).",
+                signed_method,
+                method
+            );
             return None;
         }
 
@@ -219,32 +296,52 @@ impl Event {
         serde_json::to_string(&c).unwrap()
     }
 
+    /// Writes the event to `filename`, as YAML front matter followed by the content.
+    /// The file is first written next to it, then renamed, so that it is either complete or not there at all,
+    /// even if we stop in the middle.
     pub fn write(&self, filename: &str) -> std::io::Result<()> {
         let path = Path::new(&filename);
-        fs::create_dir_all(path.ancestors().nth(1).unwrap()).unwrap();
-        let mut file;
-        file = File::create(path).unwrap();
-
-        writeln!(file, "---")?;
-        writeln!(file, "id: {}", self.id)?;
-        writeln!(file, "pubkey: {}", self.pubkey)?;
-        writeln!(file, "created_at: {}", self.created_at)?;
-        writeln!(file, "kind: {}", self.kind)?;
-        writeln!(file, "tags:")?;
-        for tag in self.tags.clone() {
-            for (i, t) in tag.iter().enumerate() {
-                if i == 0 {
-                    writeln!(file, "- - {}", t)?;
-                } else {
-                    writeln!(file, "  - \"{}\"", t)?;
-                }
-            }
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
         }
-        writeln!(file, "sig: {}", self.sig)?;
-        writeln!(file, "---")?;
-        write!(file, "{}", self.content)?;
 
-        Ok(())
+        let part_path = format!("{}.part", filename);
+        let front_matter = self.to_front_matter().map_err(std::io::Error::other)?;
+        let result = File::create(&part_path)
+            .and_then(|mut file| {
+                file.write_all(front_matter.as_bytes())?;
+                file.sync_all()
+            })
+            .and_then(|_| fs::rename(&part_path, path));
+        if result.is_err() {
+            let _ = fs::remove_file(&part_path);
+        }
+
+        result
+    }
+
+    /// The event as YAML front matter, followed by the content.
+    fn to_front_matter(&self) -> Result<String, serde_yaml::Error> {
+        #[derive(Serialize)]
+        struct FrontMatter<'a> {
+            id: &'a str,
+            pubkey: &'a str,
+            created_at: i64,
+            kind: u64,
+            tags: &'a [Vec<String>],
+            sig: &'a str,
+        }
+
+        let front_matter = serde_yaml::to_string(&FrontMatter {
+            id: &self.id,
+            pubkey: &self.pubkey,
+            created_at: self.created_at,
+            kind: self.kind,
+            tags: &self.tags,
+            sig: &self.sig,
+        })?;
+
+        Ok(format!("---\n{}---\n{}", front_matter, self.content))
     }
 }
 
@@ -253,8 +350,14 @@ fn get_metadata_tags(metadata: &HashMap<String, YamlValue>) -> Option<Vec<Vec<St
     if let Some(seq) = metadata.get("tags")?.as_sequence() {
         for tag in seq {
             let mut tag_vec: Vec<String> = vec![];
-            for t in tag.as_sequence().unwrap() {
-                tag_vec.push(t.as_str().unwrap().to_owned());
+            for t in tag.as_sequence()? {
+                // written unquoted by older versions, so possibly read as a number or a boolean
+                tag_vec.push(match t {
+                    YamlValue::String(t) => t.to_owned(),
+                    YamlValue::Number(t) => t.to_string(),
+                    YamlValue::Bool(t) => t.to_string(),
+                    _ => return None,
+                });
             }
             tags.push(tag_vec);
         }
@@ -263,6 +366,94 @@ fn get_metadata_tags(metadata: &HashMap<String, YamlValue>) -> Option<Vec<Vec<St
     Some(tags)
 }
 
+pub fn pubkey_to_npub(pubkey: &str) -> Option<String> {
+    let pubkey = XOnlyPublicKey::from_str(pubkey).ok()?;
+    let hrp = bech32::Hrp::parse("npub").unwrap();
+
+    bech32::encode::<bech32::Bech32>(hrp, &pubkey.serialize()).ok()
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// The `note1...` (see NIP-19) of the event with the (hex) ID `id`.
+pub fn event_id_to_note(id: &str) -> Option<String> {
+    let id = decode_hex(id).filter(|id| id.len() == 32)?;
+    let hrp = bech32::Hrp::parse("note").unwrap();
+
+    bech32::encode::<bech32::Bech32>(hrp, &id).ok()
+}
+
+/// The hex ID of the event given either as hex, as a `note1...` or as an `nevent1...` (see NIP-19).
+/// The relays and author an `nevent` may point to are not used.
+pub fn parse_event_id(id: &str) -> Option<String> {
+    let id = id.trim().to_lowercase();
+    let id = if id.starts_with("note1") || id.starts_with("nevent1") {
+        let (hrp, data) = bech32::decode(&id).ok()?;
+        match hrp.as_str() {
+            "note" => data,
+            "nevent" => {
+                // TLV entries, the ID being the one of type 0
+                let mut rest = data.as_slice();
+                loop {
+                    let (&t, &l) = (rest.first()?, rest.get(1)?);
+                    let value = rest.get(2..2 + l as usize)?;
+                    if t == 0 {
+                        break value.to_vec();
+                    }
+                    rest = &rest[2 + l as usize..];
+                }
+            }
+            _ => return None,
+        }
+    } else {
+        decode_hex(&id)?
+    };
+    if id.len() != 32 {
+        return None;
+    }
+
+    Some(id.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// The hex pubkey given either as hex or as an npub.
+pub fn parse_pubkey(pubkey: &str) -> Option<String> {
+    let pubkey = pubkey.trim().to_lowercase();
+    let pubkey = if pubkey.starts_with("npub1") {
+        let (hrp, data) = bech32::decode(&pubkey).ok()?;
+        if hrp.as_str() != "npub" {
+            return None;
+        }
+        XOnlyPublicKey::from_slice(&data).ok()?
+    } else {
+        XOnlyPublicKey::from_str(&pubkey).ok()?
+    };
+
+    Some(pubkey.to_string())
+}
+
+/// The key pair of a secret key given either as hex or as an nsec.
+pub fn parse_secret_key(secret_key: &str) -> Option<KeyPair> {
+    let secret_key = secret_key.trim().to_lowercase();
+    let secp = Secp256k1::signing_only();
+    if secret_key.starts_with("nsec1") {
+        let (hrp, data) = bech32::decode(&secret_key).ok()?;
+        if hrp.as_str() != "nsec" {
+            return None;
+        }
+        KeyPair::from_seckey_slice(&secp, &data).ok()
+    } else {
+        KeyPair::from_seckey_str(&secp, &secret_key).ok()
+    }
+}
+
 pub fn parse_event(front_matter: &HashMap<String, YamlValue>, content: &str) -> Option<Event> {
     Some(Event {
         id: front_matter.get("id")?.as_str()?.to_owned(),
@@ -277,10 +468,17 @@ pub fn parse_event(front_matter: &HashMap<String, YamlValue>, content: &str) ->
 
 #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub struct Filter {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ids: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub authors: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub kinds: Option<Vec<u64>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub since: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub until: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub limit: Option<usize>,
 
     #[serde(flatten)]
@@ -288,6 +486,14 @@ pub struct Filter {
 }
 
 impl Filter {
+    pub fn matches_id(&self, id: &str) -> bool {
+        if let Some(ids) = &self.ids {
+            ids.iter().any(|i| id.starts_with(i))
+        } else {
+            true
+        }
+    }
+
     pub fn matches_author(&self, author: &str) -> bool {
         if let Some(authors) = &self.authors {
             authors
@@ -301,7 +507,7 @@ impl Filter {
 
     pub fn matches_kind(&self, kind: &u64) -> bool {
         if let Some(kinds) = &self.kinds {
-            kinds.contains(&kind)
+            kinds.contains(kind)
         } else {
             true
         }
@@ -321,6 +527,35 @@ impl Filter {
 
         matches_since && matches_until
     }
+
+    /// Tag filters (`#e`, `#p`, ...) end up in `extra`.
+    pub fn get_tag_filters(&self) -> Vec<(&str, Vec<&str>)> {
+        self.extra
+            .iter()
+            .filter_map(|(k, v)| {
+                let tag = k.strip_prefix('#')?;
+                let values = v.as_array()?.iter().filter_map(|v| v.as_str()).collect();
+                Some((tag, values))
+            })
+            .collect()
+    }
+
+    pub fn matches_tags(&self, event: &Event) -> bool {
+        self.get_tag_filters().iter().all(|(tag, values)| {
+            event
+                .tags
+                .iter()
+                .any(|t| t.len() >= 2 && t[0] == *tag && values.contains(&t[1].as_str()))
+        })
+    }
+
+    pub fn matches_event(&self, event: &Event) -> bool {
+        self.matches_id(&event.id)
+            && self.matches_author(&event.pubkey)
+            && self.matches_kind(&event.kind)
+            && self.matches_time(&event.created_at)
+            && self.matches_tags(event)
+    }
 }
 
 impl fmt::Display for Filter {
@@ -345,10 +580,11 @@ impl fmt::Display for Filter {
 }
 
 #[derive(PartialEq, Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "UPPERCASE")]
 pub enum MessageType {
-    EVENT,
-    REQ,
-    CLOSE,
+    Event,
+    Req,
+    Close,
 }
 
 #[derive(PartialEq, Clone, Debug, Deserialize, Serialize)]
@@ -377,13 +613,13 @@ pub enum Message {
 
 impl Message {
     pub fn from_str(s: &str) -> Result<Message, &'static str> {
-        let mut data: VecDeque<ProtocolData> = serde_json::from_str(&s).unwrap();
+        let mut data: VecDeque<ProtocolData> = serde_json::from_str(s).unwrap();
         match data.pop_front().unwrap() {
             ProtocolData::Type(msg_type) => {
                 if let Some(msg) = match msg_type {
-                    MessageType::EVENT => Message::from_event(data),
-                    MessageType::REQ => Message::from_req(data),
-                    MessageType::CLOSE => Message::from_close(data),
+                    MessageType::Event => Message::from_event(data),
+                    MessageType::Req => Message::from_req(data),
+                    MessageType::Close => Message::from_close(data),
                 } {
                     Ok(msg)
                 } else {
@@ -409,18 +645,15 @@ impl Message {
             None
         }?;
 
-        let filters: Vec<Filter> = data
-            .into_iter()
-            .fold(Some(vec![]), |acc, entry| match acc {
-                None => None,
-                Some(mut acc) => match entry {
+        let filters: Vec<Filter> =
+            data.into_iter()
+                .try_fold(vec![], |mut acc, entry| match entry {
                     ProtocolData::Filter(filter) => {
                         acc.push(filter);
                         Some(acc)
                     }
                     _ => None,
-                },
-            })?;
+                })?;
 
         Some(Message::Req { sub_id, filters })
     }
@@ -434,6 +667,53 @@ impl Message {
     }
 }
 
+/// Whether `u`, the URL signed in a NIP-98 event, is that of the request, `url`, or why not.
+/// Schemes and hosts are compared case-insensitively, default ports are the same as none,
+/// trailing slashes are ignored and so is the order of the query parameters, which can be left out of `u` altogether.
+/// A `u` naming one of `trusted_hosts` (with or without a port) is taken as naming the request's host, whatever its scheme and port:
+/// these are the public names of the server, used when the proxy in front of it doesn't forward them.
+pub fn nip98_url_matches(u: &str, url: &str, trusted_hosts: &[String]) -> Result<(), String> {
+    let u = Url::parse(u).map_err(|e| format!("cannot parse the 'u' tag: {}", e))?;
+    let url = Url::parse(url).map_err(|e| format!("cannot parse the request URL: {}", e))?;
+
+    let host_with_port = match u.port() {
+        Some(port) => format!("{}:{}", u.host_str().unwrap_or_default(), port),
+        None => u.host_str().unwrap_or_default().to_owned(),
+    };
+    let is_trusted_host = trusted_hosts.iter().any(|h| {
+        h.eq_ignore_ascii_case(&host_with_port)
+            || h.eq_ignore_ascii_case(u.host_str().unwrap_or_default())
+    });
+    if !is_trusted_host {
+        if u.scheme() != url.scheme() {
+            return Err("different scheme".to_owned());
+        }
+        if u.host_str() != url.host_str() {
+            return Err("different host".to_owned());
+        }
+        if u.port_or_known_default() != url.port_or_known_default() {
+            return Err("different port".to_owned());
+        }
+    }
+
+    if u.path().trim_end_matches('/') != url.path().trim_end_matches('/') {
+        return Err("different path".to_owned());
+    }
+
+    if u.query().is_some() {
+        let sorted_query = |url: &Url| {
+            let mut pairs = url.query_pairs().into_owned().collect::<Vec<_>>();
+            pairs.sort();
+            pairs
+        };
+        if sorted_query(&u) != sorted_query(&url) {
+            return Err("different query".to_owned());
+        }
+    }
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -468,10 +748,222 @@ mod tests {
         assert!(no_event.is_none());
     }
 
+    #[test]
+    fn test_write() {
+        let dir = std::env::temp_dir().join(format!("servus-write-{}", std::process::id()));
+        let filename = dir.join("notes/event.md").display().to_string();
+        let event = Event {
+            id: "0ff0c8f57ddea79cb9f12c574b5056b712d584b9fe55118149ea4b343d3f89a7".to_owned(),
+            pubkey: "f982dbf2a0a4a484c98c5cbb8b83a1ecaf6589cb2652e19381158b5646fe23d6".to_owned(),
+            created_at: 1710006173,
+            kind: 1,
+            tags: vec![
+                vec!["t".to_owned(), "servus".to_owned()],
+                vec!["e".to_owned(), "abc".to_owned(), "wss://relay.example.com".to_owned()],
+            ],
+            content: "qwerty\n---\n".to_owned(),
+            sig: "39944d4aa9bdba0b6739d6ee126ae84cdbacb90e9b4412ff44bf91c1948525c07ef022c5941921c25154d08b2a43bd3c8f4e5181b905eaaef18957d89d01f598".to_owned(),
+        };
+        event.write(&filename).unwrap();
+
+        let mut reader = std::io::BufReader::new(File::open(&filename).unwrap());
+        let (front_matter, content) = crate::content::read(&mut reader).unwrap();
+        let read_event = parse_event(&front_matter, &content).unwrap();
+        assert_eq!(read_event.tags, event.tags);
+        assert_eq!(read_event.content, "qwerty\n---");
+        assert!(!Path::new(&format!("{}.part", filename)).exists());
+
+        // whatever the tags contain
+        let adversarial_values = [
+            "say \"hi\"",
+            "it's",
+            "a: b",
+            "# not a comment",
+            "\n---\nid: evil\n---\n",
+            "---",
+            "  leading and trailing spaces  ",
+            "true",
+            "1",
+            "~",
+            "null",
+            "",
+            "[1, 2]",
+            "{a: b}",
+            "tab\there\\backslash",
+            "émoji 🎉",
+        ];
+        let event = Event {
+            tags: adversarial_values
+                .iter()
+                .map(|v| vec![v.to_string(), v.to_string()])
+                .chain([vec!["single".to_owned()]])
+                .collect(),
+            ..event
+        };
+        event.write(&filename).unwrap();
+        let mut reader = std::io::BufReader::new(File::open(&filename).unwrap());
+        let (front_matter, content) = crate::content::read(&mut reader).unwrap();
+        let read_event = parse_event(&front_matter, &content).unwrap();
+        assert_eq!(read_event.id, event.id);
+        assert_eq!(read_event.tags, event.tags);
+        assert_eq!(read_event.content, "qwerty\n---");
+
+        // tags written unquoted, as they used to be
+        let front_matter =
+            "id: x\npubkey: y\ncreated_at: 1\nkind: 1\ntags:\n- - 1\n  - true\nsig: z\n";
+        let event = parse_event(&serde_yaml::from_str(front_matter).unwrap(), "").unwrap();
+        assert_eq!(event.tags, vec![vec!["1".to_owned(), "true".to_owned()]]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_tags() {
+        let event = Event {
+            id: "".to_owned(),
+            pubkey: "".to_owned(),
+            created_at: 0,
+            kind: EVENT_KIND_LONG_FORM,
+            tags: vec![
+                vec![],
+                vec!["d".to_owned()],
+                vec!["published_at".to_owned(), "soon".to_owned()],
+                vec!["e".to_owned(), "abc".to_owned()],
+                vec![
+                    "e".to_owned(),
+                    "def".to_owned(),
+                    "wss://relay.example.com".to_owned(),
+                    "reply".to_owned(),
+                ],
+            ],
+            content: "".to_owned(),
+            sig: "".to_owned(),
+        };
+
+        assert_eq!(event.get_d_tag(), None);
+        assert_eq!(event.get_tag("e").as_deref(), Some("def"));
+        assert_eq!(event.get_tag_values("e"), ["abc", "def"]);
+        let e_tags = event.get_tags("e");
+        assert_eq!(e_tags.len(), 2);
+        assert_eq!(
+            e_tags[1].get(2).map(|r| r.as_str()),
+            Some("wss://relay.example.com")
+        );
+        assert!(event.get_tags("d").is_empty());
+        assert_eq!(event.get_long_form_published_at(), None);
+    }
+
+    #[test]
+    fn test_pubkey_to_npub() {
+        assert_eq!(
+            pubkey_to_npub("3bf0c63fcb93463407af97a5e5ee64fa883d107ef9e558472c4eb9aaaefa459d")
+                .unwrap(),
+            "npub180cvv07tjdrrgpa0j7j7tmnyl2yr6yr7l8j4s3evf6u64th6gkwsyjh6w6"
+        );
+        assert!(pubkey_to_npub("qwerty").is_none());
+    }
+
+    #[test]
+    fn test_parse_event_id() {
+        let id = "b9f5441e45ca39179320e0031cfb18e34078673dcc3d3e3a3b3a981760aa5696";
+        let note = "note1h865g8j9egu30yequqp3e7ccudq8seeaes7nuw3m82vpwc9226tqtudlvp";
+        assert_eq!(parse_event_id(id).unwrap(), id);
+        assert_eq!(parse_event_id(note).unwrap(), id);
+        assert_eq!(event_id_to_note(id).unwrap(), note);
+        // with a relay before the ID, then the author
+        assert_eq!(
+            parse_event_id("nevent1qythwumn8ghj7un9d3shjtn90psk6urvv5hxxmmdqqstna2yrezu5wghjvswqqculvvwxsrcvu7uc0f78gan4xqhvz49d9szyqalp33lewf5vdq847t6te0wvnags0gs0mu72kz8938tn24wlfze6tcjm4h")
+                .unwrap(),
+            id
+        );
+        assert!(parse_event_id(&id[2..]).is_none());
+        assert!(
+            parse_event_id("note1h865g8j9egu30yequqp3e7ccudq8seeaes7nuw3m82vpwc9226tqtudlvq")
+                .is_none()
+        );
+        assert!(
+            parse_event_id("npub180cvv07tjdrrgpa0j7j7tmnyl2yr6yr7l8j4s3evf6u64th6gkwsyjh6w6")
+                .is_none()
+        );
+        assert!(parse_event_id("qwerty").is_none());
+    }
+
+    #[test]
+    fn test_parse_pubkey() {
+        let pubkey = "3bf0c63fcb93463407af97a5e5ee64fa883d107ef9e558472c4eb9aaaefa459d";
+        assert_eq!(parse_pubkey(pubkey).unwrap(), pubkey);
+        assert_eq!(parse_pubkey(&pubkey.to_uppercase()).unwrap(), pubkey);
+        assert_eq!(
+            parse_pubkey("npub180cvv07tjdrrgpa0j7j7tmnyl2yr6yr7l8j4s3evf6u64th6gkwsyjh6w6")
+                .unwrap(),
+            pubkey
+        );
+        assert!(
+            parse_pubkey("npub180cvv07tjdrrgpa0j7j7tmnyl2yr6yr7l8j4s3evf6u64th6gkwsyjh6w7")
+                .is_none()
+        );
+        assert!(parse_pubkey("qwerty").is_none());
+    }
+
+    #[test]
+    fn test_sign() {
+        let keypair = parse_secret_key(&format!("{:064x}", 1)).unwrap();
+        let mut event = Event {
+            id: String::new(),
+            pubkey: String::new(),
+            created_at: 1710006173,
+            kind: EVENT_KIND_LONG_FORM,
+            tags: vec![vec!["d".to_owned(), "hello".to_owned()]],
+            content: "Hello!".to_owned(),
+            sig: String::new(),
+        };
+        event.sign(&keypair);
+        assert_eq!(
+            event.pubkey,
+            "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798"
+        );
+        assert!(event.validate_sig().is_ok());
+
+        assert!(parse_secret_key("qwerty").is_none());
+        assert!(parse_secret_key(&"0".repeat(64)).is_none());
+    }
+
+    #[test]
+    fn test_nip98_url_matches() {
+        let url = "https://example.com/api/sites?limit=10&offset=20";
+        for u in [
+            url,
+            "HTTPS://Example.com:443/api/sites/?offset=20&limit=10",
+            "https://example.com/api/sites",
+        ] {
+            assert_eq!(nip98_url_matches(u, url, &[]), Ok(()), "{}", u);
+        }
+        for u in [
+            "http://example.com/api/sites",
+            "https://example.org/api/sites",
+            "https://example.com:8443/api/sites",
+            "https://example.com/api/sites/example.com",
+            "https://example.com/api/sites?limit=100",
+            "/api/sites",
+        ] {
+            assert!(nip98_url_matches(u, url, &[]).is_err(), "{}", u);
+        }
+
+        // behind a proxy that doesn't forward the host
+        let url = "http://127.0.0.1:4884/api/sites";
+        let trusted_hosts = ["example.com".to_owned()];
+        assert!(nip98_url_matches("https://example.com/api/sites", url, &[]).is_err());
+        assert_eq!(
+            nip98_url_matches("https://example.com/api/sites", url, &trusted_hosts),
+            Ok(())
+        );
+        assert!(nip98_url_matches("https://example.com/api/config", url, &trusted_hosts).is_err());
+    }
+
     #[test]
     fn test_parse_req() {
         let s = "[\"REQ\",\"subid\",{\"authors\":[\"a\"],\"kinds\":[0],\"limit\":1},{\"authors\":[\"b\"],\"kinds\":[3],\"limit\":2}]";
-        let message = Message::from_str(&s).unwrap();
+        let message = Message::from_str(s).unwrap();
 
         if let Message::Req { sub_id, filters } = message {
             assert_eq!(sub_id, "subid");
@@ -505,7 +997,7 @@ mod tests {
             assert!(!filters[1].kinds.as_ref().unwrap().contains(&0));
             assert_eq!(filters[1].limit, Some(2));
         } else {
-            assert!(false);
+            panic!("expected a REQ message");
         }
     }
 }
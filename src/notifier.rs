@@ -0,0 +1,225 @@
+use async_std::{future, task};
+use http_client::{h1::H1Client, HttpClient};
+use http_types::{Method, Request as HttpRequest, Url};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use serde::Deserialize;
+use serde_json::json;
+use std::{sync::Mutex, time::Duration};
+use tide::log;
+
+use crate::webhooks;
+
+/// How long to wait for the chat's server.
+const NOTIFIER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How many notifications are sent each minute at most, so that a flood of comments doesn't flood the chat too.
+const MAX_PER_MINUTE: usize = 20;
+
+/// How many characters of a comment (or post) are quoted in its notification.
+const MAX_QUOTE_LENGTH: usize = 280;
+
+/// One of the `[[notifiers]]` of `servus.toml`: a chat the operator is notified in of what happens on the sites.
+#[derive(Clone, Debug, Deserialize)]
+pub struct NotifierConfig {
+    #[serde(flatten)]
+    pub target: Target,
+    /// Events to be notified of, such as `comment.received` or `upload.*`. By default, all of them.
+    #[serde(default)]
+    pub events: Vec<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Target {
+    /// A Matrix room, which the user whose access token is used must have joined.
+    Matrix {
+        /// Such as `https://matrix.example.com`.
+        homeserver: String,
+        /// Such as `!abcdefghijklmnop:example.com`.
+        room_id: String,
+        access_token: String,
+    },
+    /// A Telegram chat, which the bot must be a member of.
+    Telegram {
+        bot_token: String,
+        /// Such as `-1001234567890` or `@mychannel`.
+        chat_id: String,
+        /// For a self-hosted Bot API server.
+        #[serde(default = "default_telegram_api_url")]
+        api_url: String,
+    },
+}
+
+fn default_telegram_api_url() -> String {
+    "https://api.telegram.org".to_string()
+}
+
+/// The minute notifications were last sent in, and how many.
+static SENT: Mutex<(i64, usize)> = Mutex::new((0, 0));
+
+/// Whether another notification can be sent this minute, counting it if so.
+fn take_slot() -> bool {
+    let minute = chrono::Utc::now().timestamp() / 60;
+    let mut sent = SENT.lock().unwrap();
+    if sent.0 != minute {
+        *sent = (minute, 0);
+    }
+    if sent.1 >= MAX_PER_MINUTE {
+        return false;
+    }
+    sent.1 += 1;
+    true
+}
+
+/// `text`, cut to `MAX_QUOTE_LENGTH` characters.
+pub fn quote(text: &str) -> String {
+    let text = text.trim();
+    match text.char_indices().nth(MAX_QUOTE_LENGTH) {
+        Some((end, _)) => format!("{}…", &text[..end]),
+        None => text.to_owned(),
+    }
+}
+
+fn build_request(target: &Target, text: &str) -> Result<HttpRequest, String> {
+    let (method, url, body, token) = match target {
+        Target::Matrix {
+            homeserver,
+            room_id,
+            access_token,
+        } => (
+            Method::Put,
+            format!(
+                "{}/_matrix/client/v3/rooms/{}/send/m.room.message/servus-{:032x}",
+                homeserver.trim_end_matches('/'),
+                utf8_percent_encode(room_id, NON_ALPHANUMERIC),
+                rand::random::<u128>()
+            ),
+            json!({"msgtype": "m.notice", "body": text}),
+            Some(access_token),
+        ),
+        Target::Telegram {
+            bot_token,
+            chat_id,
+            api_url,
+        } => (
+            Method::Post,
+            format!(
+                "{}/bot{}/sendMessage",
+                api_url.trim_end_matches('/'),
+                bot_token
+            ),
+            json!({"chat_id": chat_id, "text": text, "disable_web_page_preview": true}),
+            None,
+        ),
+    };
+    let url = Url::parse(&url).map_err(|e| e.to_string())?;
+    if !["https", "http"].contains(&url.scheme()) {
+        return Err("Unsupported URL".to_string());
+    }
+    let mut request = HttpRequest::new(method, url);
+    request.insert_header("Content-Type", "application/json");
+    if let Some(token) = token {
+        request.insert_header("Authorization", format!("Bearer {}", token));
+    }
+    request.set_body(body.to_string());
+
+    Ok(request)
+}
+
+async fn send(target: &Target, text: &str) -> Result<(), String> {
+    let request = build_request(target, text)?;
+    let response = future::timeout(NOTIFIER_TIMEOUT, H1Client::new().send(request))
+        .await
+        .map_err(|_| "Timed out".to_string())?
+        .map_err(|e| e.to_string())?;
+    if !response.status().is_success() {
+        return Err(response.status().to_string());
+    }
+
+    Ok(())
+}
+
+/// Posts `text` about `event` (such as `comment.received`) on `domain` to each of the notifiers wanting it, in the background.
+pub fn notify(notifiers: &[NotifierConfig], event: &str, domain: &str, text: &str) {
+    let targets = notifiers
+        .iter()
+        .filter(|n| webhooks::matches(&n.events, event))
+        .map(|n| n.target.clone())
+        .collect::<Vec<_>>();
+    if targets.is_empty() {
+        return;
+    }
+    if !take_slot() {
+        log::warn!(
+            "Too many notifications, not sending {} on {}.",
+            event,
+            domain
+        );
+        return;
+    }
+
+    let (event, text) = (event.to_owned(), format!("[{}] {}", domain, text));
+    task::spawn(async move {
+        for target in targets {
+            if let Err(e) = send(&target, &text).await {
+                let kind = match target {
+                    Target::Matrix { .. } => "Matrix",
+                    Target::Telegram { .. } => "Telegram",
+                };
+                log::warn!("Cannot send {} to {}: {}", event, kind, e);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config() {
+        #[derive(Deserialize)]
+        struct Config {
+            notifiers: Vec<NotifierConfig>,
+        }
+        let config: Config = toml::from_str(
+            r#"
+            [[notifiers]]
+            type = "matrix"
+            homeserver = "https://matrix.example.com/"
+            room_id = "!room:example.com"
+            access_token = "secret"
+            events = ["comment.*"]
+
+            [[notifiers]]
+            type = "telegram"
+            bot_token = "123:abc"
+            chat_id = "-100123"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.notifiers.len(), 2);
+        assert_eq!(config.notifiers[0].events, ["comment.*"]);
+        assert!(config.notifiers[1].events.is_empty());
+
+        let request = build_request(&config.notifiers[0].target, "Hello").unwrap();
+        assert_eq!(request.method(), Method::Put);
+        assert!(request.url().as_str().starts_with(
+            "https://matrix.example.com/_matrix/client/v3/rooms/%21room%3Aexample%2Ecom/send/m.room.message/"
+        ));
+        assert_eq!(request["Authorization"], "Bearer secret");
+
+        let request = build_request(&config.notifiers[1].target, "Hello").unwrap();
+        assert_eq!(
+            request.url().as_str(),
+            "https://api.telegram.org/bot123:abc/sendMessage"
+        );
+    }
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote(" Hello! "), "Hello!");
+        let long = "é".repeat(MAX_QUOTE_LENGTH + 1);
+        assert_eq!(quote(&long), format!("{}…", "é".repeat(MAX_QUOTE_LENGTH)));
+    }
+}
@@ -0,0 +1,165 @@
+use utoipa::{
+    openapi::{
+        security::{ApiKey, ApiKeyValue, SecurityScheme},
+        KnownFormat, ObjectBuilder, RefOr, Schema, SchemaFormat, SchemaType,
+    },
+    Modify, OpenApi, ToSchema,
+};
+
+use crate::{registration, resource, site, traffic};
+
+pub const OPENAPI_PATH: &str = "/api/openapi.json";
+
+/// The REST API, the Blossom API and the NIP-96 API, as described by the handlers themselves.
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "Servus",
+        license(name = "GPL-3.0", url = "https://www.gnu.org/licenses/gpl-3.0.html"),
+        description = "The HTTP API of a Servus server. Most endpoints require NIP-98 authorization, by the key of the site or by the operator's key."
+    ),
+    paths(
+        crate::handle_get_sites,
+        crate::handle_post_site,
+        crate::handle_delete_site,
+        crate::handle_clone_site,
+        crate::handle_get_site_stats,
+        crate::handle_get_site_traffic,
+        crate::handle_get_site_export,
+        crate::handle_git_push,
+        crate::handle_git_pull,
+        crate::handle_reload_site,
+        crate::handle_get_site_settings,
+        crate::handle_put_site_settings,
+        crate::handle_get_themes,
+        crate::handle_get_site_config,
+        crate::handle_put_site_config,
+        crate::handle_get_site_domains,
+        crate::handle_post_site_domain,
+        crate::handle_verify_site_domain,
+        crate::handle_delete_site_domain,
+        crate::handle_get_site_content,
+        crate::handle_put_site_content,
+        crate::handle_get_public_resources,
+        crate::handle_get_content,
+        crate::handle_put_content,
+        crate::handle_delete_content,
+        crate::handle_get_site_quota,
+        crate::handle_put_site_quota,
+        crate::handle_get_invites,
+        crate::handle_post_invite,
+        crate::handle_delete_invite,
+        crate::handle_get_pending_sites,
+        crate::handle_approve_pending_site,
+        crate::handle_reject_pending_site,
+        crate::handle_blossom_upload_request,
+        crate::handle_blossom_list_request,
+        crate::handle_blossom_delete_request,
+        crate::handle_nip96_upload_request,
+        crate::handle_nip96_delete_request,
+    ),
+    components(schemas(
+        crate::PostSiteRequestBody,
+        crate::PutSiteConfigRequestBody,
+        crate::VerifySiteDomainRequestBody,
+        crate::FileMetadata,
+        crate::SiteStats,
+        site::SiteOverview,
+        site::Quota,
+        resource::RenderCacheStats,
+        resource::PublicResource,
+        traffic::TrafficSummary,
+        traffic::Counters,
+        registration::Invite,
+        registration::PendingSite,
+        Binary,
+    )),
+    modifiers(&NostrAuth),
+    tags(
+        (name = "sites", description = "Managing sites"),
+        (name = "domains", description = "Attaching other domains to a site"),
+        (name = "content", description = "Reading and editing the content of a site"),
+        (name = "operator", description = "Reserved to the server's operator (except reading a site's quota)"),
+        (name = "blossom", description = "Blossom file storage, on the site's own domain"),
+        (name = "nip96", description = "NIP-96 file storage, on the site's own domain"),
+    )
+)]
+pub struct ApiDoc;
+
+/// A file, sent or returned as is.
+pub struct Binary;
+
+impl<'s> ToSchema<'s> for Binary {
+    fn schema() -> (&'s str, RefOr<Schema>) {
+        (
+            "Binary",
+            ObjectBuilder::new()
+                .schema_type(SchemaType::String)
+                .format(Some(SchemaFormat::KnownFormat(KnownFormat::Binary)))
+                .into(),
+        )
+    }
+}
+
+/// The `nostr` security scheme the handlers require.
+struct NostrAuth;
+
+impl Modify for NostrAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        openapi
+            .components
+            .get_or_insert_with(Default::default)
+            .add_security_scheme(
+                "nostr",
+                SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::with_description(
+                    "Authorization",
+                    "`Nostr <event>`, the event being a NIP-98 event (kind 27235) signed by the required key, as base64. For the Blossom API, a kind 24242 event instead.",
+                ))),
+            );
+    }
+}
+
+/// The OpenAPI document served at [`OPENAPI_PATH`].
+pub fn get_json() -> String {
+    ApiDoc::openapi().to_pretty_json().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_json() {
+        let document: serde_json::Value = serde_json::from_str(&get_json()).unwrap();
+        assert_eq!(document["info"]["title"], "Servus");
+        assert!(document["paths"]["/api/sites/{domain}/stats"]["get"].is_object());
+        assert_eq!(
+            document["paths"]["/api/sites/{domain}/public/{kind}"]["get"]["security"],
+            serde_json::Value::Null
+        );
+        assert_eq!(
+            document["paths"]["/api/sites"]["post"]["security"][0]["nostr"],
+            serde_json::json!([])
+        );
+        assert!(document["components"]["schemas"]["FileMetadata"].is_object());
+        assert_eq!(
+            document["components"]["securitySchemes"]["nostr"]["name"],
+            "Authorization"
+        );
+    }
+
+    #[test]
+    fn test_refs() {
+        // schemas are referred to by the name of their type as written in the handlers
+        let json = get_json();
+        let document: serde_json::Value = serde_json::from_str(&json).unwrap();
+        for (i, _) in json.match_indices("#/components/schemas/") {
+            let name = json[i + 21..].split('"').next().unwrap();
+            assert!(
+                document["components"]["schemas"][name].is_object(),
+                "{}",
+                name
+            );
+        }
+    }
+}
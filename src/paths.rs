@@ -0,0 +1,219 @@
+use serde::Deserialize;
+use std::{fs, path::Path, sync::OnceLock};
+
+use crate::{
+    cors::CorsConfig, listener::ListenerConfig, notifier::NotifierConfig,
+    security::SecurityHeadersConfig,
+};
+
+const CONFIG_FILENAME: &str = "servus.toml";
+
+/// Settings read from `servus.toml`, which can be used instead of passing them on the command line.
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServusConfig {
+    pub data_dir: Option<String>,
+    pub sites_dir: Option<String>,
+    pub themes_dir: Option<String>,
+    pub acme_cache_dir: Option<String>,
+    pub archives_dir: Option<String>,
+    /// Pubkey of the server's operator, who can manage all the sites.
+    pub operator_pubkey: Option<String>,
+    /// Pubkeys of more operators, if there are several.
+    #[serde(default)]
+    pub operator_pubkeys: Vec<String>,
+    /// Whether any key can create sites, rather than only the operators.
+    #[serde(default)]
+    pub allow_open_registration: bool,
+    /// Whether new sites need an invite code or an operator's approval.
+    #[serde(default)]
+    pub require_approval: bool,
+    /// How many sites each key other than the operators' can have.
+    pub max_sites_per_pubkey: Option<usize>,
+    /// Domain under which sites can be created using just a name.
+    pub tenant_domain: Option<String>,
+    /// Site served for hosts that are not the domain (or an alias) of any site.
+    pub default_site: Option<String>,
+    /// Listeners to use instead of the one given by `--bind` and `--port`.
+    #[serde(default)]
+    pub listeners: Vec<ListenerConfig>,
+    /// Chats to notify of what happens on the sites, such as comments arriving or uploads failing.
+    #[serde(default)]
+    pub notifiers: Vec<NotifierConfig>,
+    /// Which other sites can use the APIs of the sites from the browser, unless a site has its own `[cors]`.
+    #[serde(default)]
+    pub cors: CorsConfig,
+    /// The security headers of the pages of the sites, unless a site has its own `[security_headers]`.
+    #[serde(default)]
+    pub security_headers: SecurityHeadersConfig,
+}
+
+/// Loads `config_path` or, if not given, `servus.toml` from the data dir or the current directory, if any.
+pub fn load_config(
+    config_path: Option<&str>,
+    data_dir: Option<&str>,
+) -> Result<ServusConfig, String> {
+    let config_path = match config_path {
+        Some(config_path) => config_path.to_owned(),
+        None => {
+            let candidates = [
+                data_dir.map(|d| format!("{}/{}", d, CONFIG_FILENAME)),
+                Some(CONFIG_FILENAME.to_owned()),
+            ];
+            match candidates
+                .into_iter()
+                .flatten()
+                .find(|p| Path::new(p).exists())
+            {
+                Some(config_path) => config_path,
+                None => return Ok(ServusConfig::default()),
+            }
+        }
+    };
+
+    let content = fs::read_to_string(&config_path)
+        .map_err(|e| format!("Cannot read {}: {}", config_path, e))?;
+    toml::from_str(&content).map_err(|e| format!("Invalid {}: {}", config_path, e))
+}
+
+/// Where sites, themes, the certificates obtained using ACME and the archives of deleted sites are stored,
+/// along with the server's own data (such as the sites waiting for approval).
+pub struct Paths {
+    pub data: String,
+    pub sites: String,
+    pub themes: String,
+    pub acme_cache: String,
+    pub archives: String,
+}
+
+impl Paths {
+    /// The default layout, with everything under `data_dir`.
+    pub fn new(data_dir: &str) -> Self {
+        let data_dir = data_dir.trim_end_matches('/');
+        Self {
+            data: data_dir.to_owned(),
+            sites: format!("{}/sites", data_dir),
+            themes: format!("{}/themes", data_dir),
+            acme_cache: format!("{}/cache", data_dir),
+            archives: format!("{}/archives", data_dir),
+        }
+    }
+
+    /// The layout given by `settings` (from the command line or the environment),
+    /// falling back to `config` (from `servus.toml`), then to the default one.
+    pub fn resolve(settings: ServusConfig, config: ServusConfig) -> Self {
+        let data_dir = settings.data_dir.or(config.data_dir);
+        let mut paths = Self::new(data_dir.as_deref().unwrap_or("."));
+        if let Some(sites_dir) = settings.sites_dir.or(config.sites_dir) {
+            paths.sites = sites_dir;
+        }
+        if let Some(themes_dir) = settings.themes_dir.or(config.themes_dir) {
+            paths.themes = themes_dir;
+        }
+        if let Some(acme_cache_dir) = settings.acme_cache_dir.or(config.acme_cache_dir) {
+            paths.acme_cache = acme_cache_dir;
+        }
+        if let Some(archives_dir) = settings.archives_dir.or(config.archives_dir) {
+            paths.archives = archives_dir;
+        }
+
+        paths
+    }
+}
+
+static PATHS: OnceLock<Paths> = OnceLock::new();
+
+/// Sets the paths, once, at startup. Until then, everything is relative to the current directory.
+pub fn init(paths: Paths) -> Result<(), String> {
+    PATHS
+        .set(paths)
+        .map_err(|_| "Paths already set!".to_string())
+}
+
+fn get() -> &'static Paths {
+    PATHS.get_or_init(|| Paths::new("."))
+}
+
+pub fn data() -> &'static str {
+    &get().data
+}
+
+pub fn sites() -> &'static str {
+    &get().sites
+}
+
+pub fn themes() -> &'static str {
+    &get().themes
+}
+
+pub fn acme_cache() -> &'static str {
+    &get().acme_cache
+}
+
+pub fn archives() -> &'static str {
+    &get().archives
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(data_dir: Option<&str>, sites_dir: Option<&str>) -> ServusConfig {
+        ServusConfig {
+            data_dir: data_dir.map(|d| d.to_owned()),
+            sites_dir: sites_dir.map(|d| d.to_owned()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_resolve() {
+        let paths = Paths::resolve(ServusConfig::default(), ServusConfig::default());
+        assert_eq!(paths.data, ".");
+        assert_eq!(paths.sites, "./sites");
+        assert_eq!(paths.acme_cache, "./cache");
+        assert_eq!(paths.archives, "./archives");
+
+        // a directory set in the config file is kept even if the data dir is given on the command line...
+        let paths = Paths::resolve(
+            settings(Some("/cli"), None),
+            settings(Some("/file"), Some("/file/sites")),
+        );
+        assert_eq!(paths.sites, "/file/sites");
+        assert_eq!(paths.themes, "/cli/themes");
+
+        // ... but the command line (or environment) wins for the same setting
+        let paths = Paths::resolve(
+            settings(None, Some("/cli/sites")),
+            settings(Some("/file/"), Some("/file/sites")),
+        );
+        assert_eq!(paths.data, "/file");
+        assert_eq!(paths.sites, "/cli/sites");
+        assert_eq!(paths.themes, "/file/themes");
+        assert_eq!(paths.acme_cache, "/file/cache");
+    }
+
+    #[test]
+    fn test_load_config() {
+        let dir = std::env::temp_dir().join(format!("servus-paths-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let data_dir = dir.display().to_string();
+
+        // no config file is fine...
+        assert!(load_config(None, Some(&data_dir))
+            .unwrap()
+            .sites_dir
+            .is_none());
+
+        fs::write(dir.join(CONFIG_FILENAME), "sites_dir = \"/file/sites\"\n").unwrap();
+        let config = load_config(None, Some(&data_dir)).unwrap();
+        assert_eq!(config.sites_dir.as_deref(), Some("/file/sites"));
+
+        // ... but an explicitly given one must exist, and be valid
+        assert!(load_config(Some("/no/such/servus.toml"), None).is_err());
+        fs::write(dir.join(CONFIG_FILENAME), "no_such_setting = 1\n").unwrap();
+        assert!(load_config(None, Some(&data_dir)).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
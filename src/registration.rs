@@ -0,0 +1,199 @@
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::{fs, io, process, sync::Mutex, time::SystemTime};
+use utoipa::ToSchema;
+
+use crate::{paths, utils};
+
+/// A code given out by the operator, letting whoever has it create a site without waiting for approval.
+/// Each code can only be used once.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize, ToSchema)]
+pub struct Invite {
+    pub code: String,
+    pub created_at: i64,
+}
+
+/// A site someone asked for, which is only created once the operator approves it.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize, ToSchema)]
+pub struct PendingSite {
+    pub domain: String,
+    pub pubkey: String,
+    pub requested_at: i64,
+}
+
+/// A domain the owner of a site wants to serve it at, once they proved they control its DNS
+/// by publishing `token` in a TXT record.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct DomainVerification {
+    pub domain: String,
+    pub site: String,
+    pub token: String,
+    pub requested_at: i64,
+}
+
+/// The invites, the pending sites and the domains waiting to be verified,
+/// kept in `registrations.toml` in the data dir.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Registrations {
+    #[serde(default)]
+    pub invites: Vec<Invite>,
+    #[serde(default)]
+    pub pending: Vec<PendingSite>,
+    #[serde(default)]
+    pub verifications: Vec<DomainVerification>,
+}
+
+impl Registrations {
+    pub fn add_invite(&mut self) -> Invite {
+        let invite = Invite {
+            code: get_random_code(self.invites.len())[..16].to_owned(),
+            created_at: Utc::now().timestamp(),
+        };
+        self.invites.push(invite.clone());
+
+        invite
+    }
+
+    /// Removes the invite with `code`, returning whether there was one.
+    pub fn remove_invite(&mut self, code: &str) -> bool {
+        self.take_invite(code).is_some()
+    }
+
+    /// Removes the invite with `code`, returning it so that it can be given back if it ends up not being used.
+    pub fn take_invite(&mut self, code: &str) -> Option<Invite> {
+        let index = self.invites.iter().position(|i| i.code == code)?;
+
+        Some(self.invites.remove(index))
+    }
+
+    pub fn add_pending(&mut self, domain: &str, pubkey: &str) -> io::Result<()> {
+        if self.pending.iter().any(|p| p.domain == domain) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} is already waiting for approval", domain),
+            ));
+        }
+        self.pending.push(PendingSite {
+            domain: domain.to_owned(),
+            pubkey: pubkey.to_owned(),
+            requested_at: Utc::now().timestamp(),
+        });
+
+        Ok(())
+    }
+
+    pub fn take_pending(&mut self, domain: &str) -> Option<PendingSite> {
+        let index = self.pending.iter().position(|p| p.domain == domain)?;
+
+        Some(self.pending.remove(index))
+    }
+
+    /// Starts verifying `domain` for `site`, replacing any previous verification of the domain for the same site.
+    /// Other sites can try to verify the same domain, but only the one whose token is published gets it.
+    pub fn add_verification(&mut self, domain: &str, site: &str) -> DomainVerification {
+        self.remove_verification(domain, site);
+        let verification = DomainVerification {
+            domain: domain.to_owned(),
+            site: site.to_owned(),
+            token: get_random_code(self.verifications.len())[..32].to_owned(),
+            requested_at: Utc::now().timestamp(),
+        };
+        self.verifications.push(verification.clone());
+
+        verification
+    }
+
+    pub fn get_verification(&self, domain: &str, site: &str) -> Option<&DomainVerification> {
+        self.verifications
+            .iter()
+            .find(|v| v.domain == domain && v.site == site)
+    }
+
+    /// Removes the verification of `domain` for `site`, returning whether there was one.
+    pub fn remove_verification(&mut self, domain: &str, site: &str) -> bool {
+        let count = self.verifications.len();
+        self.verifications
+            .retain(|v| v.domain != domain || v.site != site);
+
+        self.verifications.len() != count
+    }
+}
+
+/// A code no one can guess, as a hex string.
+fn get_random_code(salt: usize) -> String {
+    utils::sha256_hex(format!("{}:{}:{:?}", salt, process::id(), SystemTime::now()).as_bytes())
+}
+
+/// Held while the registrations are read and written back, so that concurrent changes don't get lost.
+static LOCK: Mutex<()> = Mutex::new(());
+
+fn get_path() -> String {
+    format!("{}/registrations.toml", paths::data())
+}
+
+fn load() -> io::Result<Registrations> {
+    match fs::read_to_string(get_path()) {
+        Ok(content) => toml::from_str(&content)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Registrations::default()),
+        Err(e) => Err(e),
+    }
+}
+
+pub fn get() -> io::Result<Registrations> {
+    let _lock = LOCK.lock().unwrap();
+    load()
+}
+
+/// Applies `update` to the registrations, which are saved unless it fails.
+pub fn update<T>(update: impl FnOnce(&mut Registrations) -> io::Result<T>) -> io::Result<T> {
+    let _lock = LOCK.lock().unwrap();
+    let mut registrations = load()?;
+    let result = update(&mut registrations)?;
+    fs::write(get_path(), toml::to_string(&registrations).unwrap())?;
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registrations() {
+        let mut registrations = Registrations::default();
+        let invite = registrations.add_invite();
+        assert_eq!(invite.code.len(), 16);
+        assert_ne!(registrations.add_invite().code, invite.code);
+        assert!(registrations.remove_invite(&invite.code));
+        assert!(!registrations.remove_invite(&invite.code));
+        assert_eq!(registrations.invites.len(), 1);
+        let other = registrations.invites[0].clone();
+        assert_eq!(registrations.take_invite(&other.code), Some(other));
+        assert!(registrations.invites.is_empty());
+
+        registrations.add_pending("example.com", "abc").unwrap();
+        assert!(registrations.add_pending("example.com", "def").is_err());
+        assert!(registrations.take_pending("other.com").is_none());
+        assert_eq!(
+            registrations.take_pending("example.com").unwrap().pubkey,
+            "abc"
+        );
+        assert!(registrations.pending.is_empty());
+
+        let verification = registrations.add_verification("example.com", "alice.myservus.net");
+        assert_eq!(verification.token.len(), 32);
+        let other = registrations.add_verification("example.com", "mallory.myservus.net");
+        assert_ne!(other.token, verification.token);
+        let verification = registrations.add_verification("example.com", "alice.myservus.net");
+        assert_eq!(registrations.verifications.len(), 2);
+        assert_eq!(
+            registrations.get_verification("example.com", "alice.myservus.net"),
+            Some(&verification)
+        );
+        assert!(registrations.remove_verification("example.com", "mallory.myservus.net"));
+        assert!(!registrations.remove_verification("example.com", "mallory.myservus.net"));
+        assert!(registrations.remove_verification("example.com", "alice.myservus.net"));
+        assert!(registrations.verifications.is_empty());
+    }
+}
@@ -1,14 +1,39 @@
-use chrono::NaiveDateTime;
+use chrono::{DateTime, Datelike, FixedOffset, NaiveDateTime, TimeZone, Utc};
 use http_types::mime;
+use percent_encoding::percent_decode_str;
 use serde::Serialize;
-use std::{collections::HashMap, env, fs::File, io::BufReader, path::PathBuf, str};
+use serde_json::json;
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    fs::File,
+    io::BufReader,
+    path::{Component, Path, PathBuf},
+    str::{self, FromStr},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use utoipa::ToSchema;
 
 use crate::{
-    content, nostr,
-    site::{ServusMetadata, Site},
+    content,
+    error::{self, Error},
+    gallery::{self, GalleryConfig},
+    nostr, paths, search,
+    site::{
+        normalize_path, FeedConfig, PermalinkConfig, PermalinkHistory, ServusMetadata, Site,
+        SiteConfig, SiteContent,
+    },
+    template,
 };
 
-#[derive(Clone, Copy, PartialEq, Serialize)]
+/// Where files served as `/.well-known/<name>` are, relative to the site's directory.
+pub const WELL_KNOWN_DIR: &str = "_content/well-known";
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
 pub enum ResourceKind {
     Post,
     Page,
@@ -29,23 +54,73 @@ struct Page {
     url: String,
     slug: String,
     path: Option<String>,
+    relative_path: Option<String>,
     description: Option<String>,
     summary: Option<String>,
     content: String,
-    date: NaiveDateTime,
+    date: NaiveDateTime,               // in UTC
+    local_date: DateTime<FixedOffset>, // in the site's timezone
+    formatted_date: String,            // in the site's timezone and date format
     translations: Vec<PathBuf>,
     lang: Option<String>,
-    reading_time: Option<String>,
+    word_count: Option<usize>,
+    reading_time: Option<usize>,
+    image: Option<String>,
+    author: Option<String>,
+    taxonomies: HashMap<String, Vec<String>>,
+    earlier: Option<Box<Page>>,
+    later: Option<Box<Page>>,
+    related: Vec<Page>,
+    backlinks: Vec<Backlink>,
+    toc: Vec<Heading>,
+    noindex: bool,
+    extra: HashMap<String, serde_yaml::Value>, // the front matter of hand-written content, as is
+}
+
+/// An entry of the table of contents of a page.
+#[derive(Clone, Serialize)]
+struct Heading {
+    level: u32,
+    id: String,
+    permalink: String,
+    title: String,
+    children: Vec<Heading>,
+}
+
+/// A resource linking to the current page.
+#[derive(Clone, Serialize)]
+struct Backlink {
+    permalink: String,
+    title: Option<String>,
+}
+
+/// A resource, as exposed by the public JSON API.
+#[derive(Serialize, ToSchema)]
+pub struct PublicResource {
+    pub title: String,
+    pub slug: String,
+    pub url: String,
+    pub summary: Option<String>,
+    pub date: NaiveDateTime,
+    pub html: String,
 }
 
 impl Page {
-    fn from_resource(resource: &Resource, site: &Site) -> Self {
-        let (front_matter, content) = resource.read(site).unwrap();
+    fn from_resource(resource: &Resource, site: &Site, site_content: &SiteContent) -> Self {
+        let (front_matter, content) = resource.read(site_content).unwrap();
         let title;
         let summary;
+        let image;
+        let author;
+        let tags;
+        let extra;
         if let Some(event) = nostr::parse_event(&front_matter, &content) {
             title = event.get_tag("title").unwrap_or("".to_string()).to_owned();
             summary = event.get_long_form_summary();
+            image = event.get_tag("image");
+            author = nostr::pubkey_to_npub(&event.pubkey);
+            tags = event.get_tag_values("t");
+            extra = HashMap::new();
         } else {
             title = front_matter
                 .get("title")
@@ -54,37 +129,341 @@ impl Page {
                 .unwrap()
                 .to_owned();
             summary = None;
+            image = front_matter
+                .get("image")
+                .and_then(|i| i.as_str())
+                .map(|i| i.to_owned());
+            author = site
+                .config
+                .pubkey
+                .as_ref()
+                .and_then(|p| nostr::pubkey_to_npub(p));
+            tags = get_front_matter_tags(&front_matter);
+            extra = front_matter;
         }
+        let url = resource
+            .get_resource_url(&site.config.permalinks, site.config.get_timezone())
+            .unwrap();
+        let permalink = resource.get_permalink(&site.config);
+        let (html, toc) = md_to_html_with_toc(&content, &permalink);
+        let word_count = md_word_count(&content);
+        let image = image
+            .or_else(|| md_first_image(&content))
+            .map(|i| make_absolute_url(&site.config.base_url, &i));
         Self {
             title,
-            permalink: site
-                .config
-                .make_permalink(&resource.get_resource_url().unwrap()),
-            url: resource.get_resource_url().unwrap(),
+            permalink,
+            url,
             slug: resource.slug.to_owned(),
-            path: None,        // TODO
+            path: None, // TODO
+            relative_path: resource.get_relative_path(site, site_content),
             description: None, // TODO
             summary,
-            content: md_to_html(&content),
+            content: html,
             date: resource.date,
+            local_date: site.config.to_local_date(&resource.date).fixed_offset(),
+            formatted_date: format_local_date(&site.config, &resource.date),
             translations: vec![], // TODO
             lang: None,           // TODO
-            reading_time: None,   // TODO
+            word_count: Some(word_count),
+            reading_time: Some(word_count.div_ceil(WORDS_PER_MINUTE)),
+            image,
+            author,
+            taxonomies: HashMap::from([("tags".to_string(), tags)]),
+            earlier: None,
+            later: None,
+            related: vec![],
+            backlinks: vec![],
+            toc,
+            noindex: resource.noindex,
+            extra,
         }
     }
+
+    fn get_tags(&self) -> &[String] {
+        self.taxonomies.get("tags").map_or(&[], |t| t)
+    }
+}
+
+fn get_front_matter_tags(front_matter: &HashMap<String, serde_yaml::Value>) -> Vec<String> {
+    let tags = front_matter.get("tags").or_else(|| {
+        front_matter
+            .get("taxonomies")
+            .and_then(|t| t.as_mapping())
+            .and_then(|t| t.get("tags"))
+    });
+    tags.and_then(|t| t.as_sequence())
+        .map(|t| {
+            t.iter()
+                .filter_map(|t| t.as_str())
+                .map(|t| t.to_owned())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+const MAX_RELATED_PAGES: usize = 5;
+
+/// Pages sharing at least one tag with `page`, the ones with most tags in common first.
+/// `posts_by_tag` has the indices of the posts having each tag, newest first, and `get_post` gets them.
+fn get_related_pages<'a>(
+    page: &Page,
+    posts_by_tag: &HashMap<String, Vec<usize>>,
+    get_post: impl Fn(usize) -> &'a Page,
+) -> Vec<Page> {
+    let mut shared = HashMap::<usize, usize>::new();
+    let tags = page.get_tags().iter().collect::<HashSet<_>>();
+    for tag in tags {
+        for i in posts_by_tag.get(tag).into_iter().flatten() {
+            if get_post(*i).url != page.url {
+                *shared.entry(*i).or_default() += 1;
+            }
+        }
+    }
+    let mut related = shared.into_iter().collect::<Vec<_>>();
+    // pages with the same number of shared tags stay sorted by date
+    related.sort_by_key(|(i, shared)| (std::cmp::Reverse(*shared), *i));
+    related
+        .into_iter()
+        .take(MAX_RELATED_PAGES)
+        .map(|(i, _)| get_post(i).clone())
+        .collect()
+}
+
+/// Resources linking to `url` (or, for long-form content, referencing the event by its `a` coordinate),
+/// newest first.
+fn get_backlinks(
+    url: &str,
+    coordinate: Option<String>,
+    site: &Site,
+    site_content: &SiteContent,
+) -> Vec<Backlink> {
+    let mut backlinks = site_content
+        .links
+        .iter()
+        .filter(|(source, targets)| {
+            *source != url
+                && targets
+                    .iter()
+                    .any(|t| t == url || Some(t) == coordinate.as_ref())
+        })
+        .filter_map(|(source, _)| site_content.resources.get(source).map(|r| (source, r)))
+        .collect::<Vec<_>>();
+    backlinks.sort_by_key(|(_, r)| std::cmp::Reverse(r.date));
+    backlinks
+        .into_iter()
+        .map(|(source, r)| Backlink {
+            permalink: site.config.make_permalink(source),
+            title: r.title.clone(),
+        })
+        .collect()
 }
 
 #[derive(Clone, Serialize)]
 struct Section {
+    relative_path: String,
+    permalink: String,
     pages: Vec<Page>,
     title: Option<String>,
     content: Option<String>,
     description: Option<String>,
 }
 
+impl Section {
+    fn new(site: &Site, relative_path: &str, pages: Vec<Page>) -> Self {
+        let url = relative_path.trim_end_matches("_index.md");
+        Self {
+            relative_path: relative_path.to_owned(),
+            permalink: site.config.make_permalink(url),
+            pages,
+            title: None,       // TODO
+            content: None,     // TODO
+            description: None, // TODO
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct TaxonomyKind {
+    name: String,
+}
+
+#[derive(Serialize)]
+struct TaxonomyTerm {
+    name: String,
+    slug: String,
+    path: String,
+    permalink: String,
+    pages: Vec<Page>,
+    page_count: usize,
+}
+
+#[derive(Serialize)]
+struct Taxonomy {
+    kind: TaxonomyKind,
+    permalink: String,
+    items: Vec<TaxonomyTerm>,
+}
+
+fn get_tags_taxonomy(site: &Site, pages: &[&Page]) -> Taxonomy {
+    let mut terms: Vec<&String> = pages.iter().flat_map(|p| p.get_tags()).collect();
+    terms.sort();
+    terms.dedup();
+    let items = terms
+        .into_iter()
+        .map(|term| {
+            let pages = pages
+                .iter()
+                .filter(|p| p.get_tags().contains(term))
+                .map(|p| (*p).clone())
+                .collect::<Vec<_>>();
+            let path = format!("/tags/{}/", slugify(term));
+            TaxonomyTerm {
+                name: term.to_owned(),
+                slug: slugify(term),
+                permalink: site.config.make_permalink(&path),
+                path,
+                page_count: pages.len(),
+                pages,
+            }
+        })
+        .collect();
+    Taxonomy {
+        kind: TaxonomyKind {
+            name: "tags".to_string(),
+        },
+        permalink: site.config.make_permalink("/tags/"),
+        items,
+    }
+}
+
+/// Registers the functions that give templates access to the whole content of the site,
+/// such as `get_page` or `load_data`, for the snapshot being rendered.
+fn register_content_functions(
+    tera: &mut tera::Tera,
+    site: &Site,
+    site_content: &SiteContent,
+    pages: &[(ResourceKind, Page)],
+) {
+    let mut pages_by_path = HashMap::new();
+    for (_, page) in pages {
+        let value = tera::to_value(page).unwrap();
+        if let Some(relative_path) = &page.relative_path {
+            pages_by_path.insert(relative_path.to_owned(), value.clone());
+        }
+        pages_by_path.insert(page.url.to_owned(), value);
+    }
+    tera.register_function("get_page", template::GetPage::new(pages_by_path));
+
+    let get_pages = |kind: Option<ResourceKind>| {
+        pages
+            .iter()
+            .filter(|(k, _)| kind.is_none() || kind == Some(*k))
+            .map(|(_, p)| p.clone())
+            .collect::<Vec<_>>()
+    };
+    let sections = [
+        ("_index.md", None),
+        ("posts/_index.md", Some(ResourceKind::Post)),
+        ("pages/_index.md", Some(ResourceKind::Page)),
+    ]
+    .into_iter()
+    .map(|(path, kind)| {
+        let section = Section::new(site, path, get_pages(kind));
+        (path.to_owned(), tera::to_value(section).unwrap())
+    })
+    .collect();
+    tera.register_function("get_section", template::GetSection::new(sections));
+
+    let pages = pages.iter().map(|(_, p)| p).collect::<Vec<_>>();
+    let taxonomies = HashMap::from([(
+        "tags".to_string(),
+        tera::to_value(get_tags_taxonomy(site, &pages)).unwrap(),
+    )]);
+    tera.register_function("get_taxonomy", template::GetTaxonomy::new(taxonomies));
+
+    let data = site_content
+        .data
+        .iter()
+        .map(|(k, v)| (k.to_owned(), tera::to_value(v).unwrap()))
+        .collect();
+    tera.register_function(
+        "load_data",
+        template::LoadData::new(format!("{}/{}", paths::sites(), site.domain), data),
+    );
+}
+
+/// Posts of a month, as listed in the archive.
+#[derive(Serialize)]
+struct ArchiveMonth {
+    month: u32,
+    pages: Vec<Page>,
+}
+
+/// Posts of a year, grouped by month, as listed in the archive.
+#[derive(Serialize)]
+struct ArchiveYear {
+    year: i32,
+    months: Vec<ArchiveMonth>,
+}
+
+/// `date` (in UTC) in the site's timezone and date format.
+fn format_local_date(config: &SiteConfig, date: &NaiveDateTime) -> String {
+    config
+        .to_local_date(date)
+        .format(config.get_date_format())
+        .to_string()
+}
+
+/// Posts grouped by year and month (where the site is), newest first, given the posts sorted by date (newest first).
+fn get_archive(posts: &[&Page]) -> Vec<ArchiveYear> {
+    let mut archive: Vec<ArchiveYear> = vec![];
+    for post in posts {
+        let (year, month) = (post.local_date.year(), post.local_date.month());
+        if archive.last().map(|y| y.year) != Some(year) {
+            archive.push(ArchiveYear {
+                year,
+                months: vec![],
+            });
+        }
+        let months = &mut archive.last_mut().unwrap().months;
+        if months.last().map(|m| m.month) != Some(month) {
+            months.push(ArchiveMonth {
+                month,
+                pages: vec![],
+            });
+        }
+        months.last_mut().unwrap().pages.push((*post).clone());
+    }
+
+    archive
+}
+
+/// The pages listed on one of the pagers of the index, as in Zola.
 #[derive(Clone, Serialize)]
 struct Paginator {
     pages: Vec<Page>,
+    paginate_by: usize,
+    base_url: String,
+    number_pagers: usize,
+    first: String,
+    last: String,
+    previous: Option<String>,
+    next: Option<String>,
+    current_index: usize,
+    total_pages: usize,
+}
+
+/// Where the index of the notes is served, unless a page is.
+pub const NOTES_INDEX_URL: &str = "/notes";
+
+/// The URL of a pager of the index: `/` for the first one, then `/page/2/` and so on.
+pub fn get_pager_url(pager: usize) -> String {
+    if pager <= 1 {
+        "/".to_string()
+    } else {
+        format!("/page/{}/", pager)
+    }
 }
 
 #[derive(Clone, Serialize)]
@@ -95,11 +474,29 @@ pub struct Resource {
     pub title: Option<String>,
     pub date: NaiveDateTime,
 
+    /// Kept out of search engines (the sitemap and using a robots meta tag) and feeds.
+    pub noindex: bool,
+
     pub content_source: ContentSource,
 }
 
 impl Resource {
-    fn read(&self, site: &Site) -> Option<(HashMap<String, serde_yaml::Value>, String)> {
+    /// The index shown until the site has one.
+    pub fn default_index() -> Self {
+        Resource {
+            kind: ResourceKind::Page,
+            slug: "index".to_string(),
+            title: Some("".to_string()),
+            date: Utc::now().naive_utc(),
+            noindex: false,
+            content_source: ContentSource::String("Servus, world!".to_string()),
+        }
+    }
+
+    fn read(
+        &self,
+        site_content: &SiteContent,
+    ) -> Option<(HashMap<String, serde_yaml::Value>, String)> {
         let filename = match self.content_source.clone() {
             ContentSource::String(s) => {
                 return Some((
@@ -112,88 +509,682 @@ impl Resource {
             }
             ContentSource::File(f) => f,
             ContentSource::Event(e_id) => {
-                let events = site.events.read().unwrap();
-                let event_ref = events.get(&e_id).unwrap();
+                let event_ref = site_content.events.get(&e_id).unwrap();
                 event_ref.filename.to_owned()
             }
         };
-        let file = File::open(filename).unwrap();
+        let file = File::open(&filename).unwrap();
         let mut reader = BufReader::new(file);
 
         content::read(&mut reader)
+            .map_err(|e| log::warn!("Cannot read {}: {}", filename, e))
+            .ok()
     }
 
-    pub fn get_resource_url(&self) -> Option<String> {
-        // TODO: extract all URL patterns from config!
+    /// Paths of the site linked from this resource's content,
+    /// plus the coordinates of events it references using `a` tags.
+    pub fn get_links(&self, config: &SiteConfig, site_content: &SiteContent) -> Vec<String> {
+        let (front_matter, content) = match self.read(site_content) {
+            Some(r) => r,
+            None => return vec![],
+        };
+        let event = nostr::parse_event(&front_matter, &content);
+
+        get_content_links(config, &content, event.as_ref())
+    }
+
+    fn get_event_coordinate(&self, site: &Site, site_content: &SiteContent) -> Option<String> {
+        if let ContentSource::Event(event_id) = &self.content_source {
+            let event_ref = site_content.events.get(event_id)?;
+            Some(format!(
+                "{}:{}:{}",
+                event_ref.kind,
+                site.config.pubkey.as_ref()?,
+                event_ref.d_tag.as_ref()?
+            ))
+        } else {
+            None
+        }
+    }
+
+    pub fn to_public(&self, site: &Site, site_content: &SiteContent) -> PublicResource {
+        let page = Page::from_resource(self, site, site_content);
+        PublicResource {
+            title: page.title,
+            slug: page.slug,
+            url: page.permalink,
+            summary: page.summary,
+            date: page.date,
+            html: sanitize_html(&page.content),
+        }
+    }
+
+    /// Path of the file holding the resource, relative to `_content`.
+    fn get_relative_path(&self, site: &Site, site_content: &SiteContent) -> Option<String> {
+        let filename = match &self.content_source {
+            ContentSource::File(f) => f,
+            ContentSource::Event(e_id) => &site_content.events.get(e_id)?.filename,
+            ContentSource::String(_) => return None,
+        };
+        let root = PathBuf::from(format!("{}/{}/_content", paths::sites(), site.domain));
+        Some(
+            PathBuf::from(filename)
+                .strip_prefix(&root)
+                .ok()?
+                .display()
+                .to_string(),
+        )
+    }
+
+    pub fn get_default_url(&self) -> String {
         match self.kind {
-            ResourceKind::Post => Some(format!("/posts/{}", &self.slug)),
-            ResourceKind::Page => Some(format!("/{}", &self.clone().slug)),
-            ResourceKind::Note => Some(format!("/notes/{}", &self.clone().slug)),
+            ResourceKind::Post => format!("/posts/{}", &self.slug),
+            ResourceKind::Page => format!("/{}", &self.slug),
+            ResourceKind::Note => format!("/notes/{}", &self.slug),
         }
     }
 
-    pub fn render(&self, site: &Site) -> Vec<u8> {
-        let page = Page::from_resource(&self, &site);
+    fn get_permalink_pattern<'a>(&self, permalinks: &'a PermalinkConfig) -> Option<&'a String> {
+        match self.kind {
+            ResourceKind::Post => permalinks.post.as_ref(),
+            ResourceKind::Page => permalinks.page.as_ref(),
+            ResourceKind::Note => permalinks.note.as_ref(),
+        }
+    }
 
-        let mut tera = site.tera.write().unwrap();
-        let mut extra_context = tera::Context::new();
+    /// `{year}`, `{month}` and `{day}` are those of the date in `timezone` (UTC if None).
+    fn expand_permalink_pattern(&self, pattern: &str, timezone: Option<chrono_tz::Tz>) -> String {
+        let kind = match self.kind {
+            ResourceKind::Post => "posts",
+            ResourceKind::Page => "pages",
+            ResourceKind::Note => "notes",
+        };
+        let date = timezone
+            .unwrap_or(chrono_tz::UTC)
+            .from_utc_datetime(&self.date)
+            .date_naive();
+        let url = pattern
+            .replace("{year}", &date.format("%Y").to_string())
+            .replace("{month}", &date.format("%m").to_string())
+            .replace("{day}", &date.format("%d").to_string())
+            .replace("{kind}", kind)
+            .replace("{slug}", &self.slug)
+            .replace("{d_tag}", &self.slug);
 
-        // TODO: need real multilang support,
-        // but for now, we just set this so that Zola themes don't complain
-        extra_context.insert("lang", "en");
+        normalize_path(&url)
+    }
+
+    pub fn get_resource_url(
+        &self,
+        permalinks: &PermalinkConfig,
+        timezone: Option<chrono_tz::Tz>,
+    ) -> Option<String> {
+        match self.get_permalink_pattern(permalinks) {
+            // the index page is always served at the root
+            Some(pattern) if self.slug != "index" => {
+                Some(self.expand_permalink_pattern(pattern, timezone))
+            }
+            _ => Some(self.get_default_url()),
+        }
+    }
+
+    /// The URLs the resource had under the default and any previous permalink patterns,
+    /// as well as under the current pattern with dates in UTC, as they were before the site had a timezone.
+    pub fn get_old_urls(
+        &self,
+        history: &PermalinkHistory,
+        permalinks: &PermalinkConfig,
+        timezone: Option<chrono_tz::Tz>,
+    ) -> Vec<String> {
+        let mut urls = vec![self.get_default_url()];
+        if self.slug != "index" {
+            let patterns = match self.kind {
+                ResourceKind::Post => &history.post,
+                ResourceKind::Page => &history.page,
+                ResourceKind::Note => &history.note,
+            };
+            for pattern in patterns {
+                urls.push(self.expand_permalink_pattern(pattern, timezone));
+                urls.push(self.expand_permalink_pattern(pattern, None));
+            }
+            if let Some(pattern) = self.get_permalink_pattern(permalinks) {
+                urls.push(self.expand_permalink_pattern(pattern, None));
+            }
+            urls.dedup();
+        }
+
+        urls
+    }
+
+    /// The absolute URL of the resource. URLs given by patterns such as `/{slug}.html`
+    /// point to files rather than directories, so they don't get a trailing slash,
+    /// and the indexes of sections are at the URL of the section itself.
+    pub fn get_permalink(&self, config: &SiteConfig) -> String {
+        let url = self
+            .get_resource_url(&config.permalinks, config.get_timezone())
+            .unwrap();
+        let permalink = match url.strip_suffix("/index") {
+            Some("") => config.make_permalink("/"),
+            Some(section) => config.make_permalink(section),
+            None => config.make_permalink(&url),
+        };
+        match self.get_permalink_pattern(&config.permalinks) {
+            Some(pattern)
+                if self.slug != "index"
+                    && !pattern.ends_with('/')
+                    && Path::new(pattern).extension().is_some() =>
+            {
+                permalink.trim_end_matches('/').to_owned()
+            }
+            _ => permalink,
+        }
+    }
+
+    /// Renders the resource, or gets it from the render cache of the site's current content.
+    /// The index can also be rendered with the pages of another `pager` than the first one,
+    /// as long as there is such a pager ([`Error::NotFound`] otherwise).
+    pub fn render(&self, site: &Site, lang: &str, pager: usize) -> error::Result<String> {
+        let site_content = site.get_content();
+        let url = self
+            .get_resource_url(&site.config.permalinks, site.config.get_timezone())
+            .unwrap();
+        if pager > 1 {
+            let data = get_template_data(site, &site_content);
+            if self.slug != "index" || pager > data.get_pager_count(site) {
+                return Err(Error::NotFound);
+            }
+        }
+
+        let cache_key = match pager {
+            1 => url.to_owned(),
+            _ => get_pager_url(pager),
+        };
+        render_cached(site, &site_content, &cache_key, lang, || {
+            self.render_uncached(site, &site_content, &url, lang, pager)
+        })
+    }
+
+    fn render_uncached(
+        &self,
+        site: &Site,
+        site_content: &SiteContent,
+        url: &str,
+        lang: &str,
+        pager: usize,
+    ) -> error::Result<String> {
+        let data = get_template_data(site, site_content);
+
+        let (mut page, post_index) = match data.positions.get(url) {
+            Some(&(i, post_index)) => (data.pages[i].1.clone(), post_index),
+            None => (Page::from_resource(self, site, site_content), None),
+        };
+
+        let mut extra_context = data.context.clone();
+
+        // TODO: need real multilang support
+        extra_context.insert("lang", lang);
 
         extra_context.insert("current_url", &page.permalink);
         extra_context.insert("current_path", &page.url);
 
-        extra_context.insert("config", &site.config);
-        extra_context.insert("data", &site.data);
+        if let Some(i) = post_index {
+            page.later = i
+                .checked_sub(1)
+                .and_then(|i| data.get_post(i))
+                .map(|p| Box::new(p.clone()));
+            page.earlier = data.get_post(i + 1).map(|p| Box::new(p.clone()));
+        }
+        page.related = get_related_pages(&page, &data.posts_by_tag, |i| data.get_post(i).unwrap());
+        page.backlinks = get_backlinks(
+            &page.url,
+            self.get_event_coordinate(site, site_content),
+            site,
+            site_content,
+        );
         extra_context.insert("page", &page);
+        extra_context.insert("paginator", &data.get_paginator(site, pager));
 
-        let resources = site.resources.read().unwrap();
-        let mut resources_list = resources.values().collect::<Vec<&Resource>>();
-        resources_list.sort_by(|a, b| b.date.cmp(&a.date));
-        let pages_list = resources_list
-            .into_iter()
-            .filter(|r| r.kind == ResourceKind::Post || r.kind == ResourceKind::Page)
-            .map(|r| Page::from_resource(r, site))
-            .collect::<Vec<Page>>();
+        let template = if self.slug == "index" {
+            "index.html"
+        } else {
+            "page.html"
+        };
+        render_template(template, &data.tera, page.content, extra_context)
+    }
+}
 
-        // NB: some themes expect to iterate over section.pages, others look for paginator.pages.
-        // We are currently passing both in all cases, so all themes will find the pages.
-        extra_context.insert(
-            "section",
-            &Section {
-                pages: pages_list.clone(),
-                title: None,       // TODO
-                content: None,     // TODO
-                description: None, // TODO
-            },
-        );
-        // TODO: paginator.pages should be paginated, but it is not.
+/// Gets the page at `cache_key` from the render cache of the site's current content, or renders and caches it.
+fn render_cached(
+    site: &Site,
+    site_content: &SiteContent,
+    cache_key: &str,
+    lang: &str,
+    render: impl FnOnce() -> error::Result<String>,
+) -> error::Result<String> {
+    let ttl = site.config.render_cache_ttl.map(Duration::from_secs);
+    if ttl == Some(Duration::ZERO) {
+        return render();
+    }
+
+    if let Some(html) = site_content.render_cache.get(cache_key, lang, ttl) {
+        return Ok(html);
+    }
+    let html = render()?;
+    site_content
+        .render_cache
+        .insert(cache_key, lang, html.clone());
+
+    Ok(html)
+}
+
+/// Renders the index of the notes, newest first, using the site's `notes.html`,
+/// or its `index.html` (with the notes as `section.pages` and `paginator.pages`) if it has none.
+pub fn render_notes_index(site: &Site, lang: &str) -> error::Result<String> {
+    let site_content = site.get_content();
+    render_cached(site, &site_content, NOTES_INDEX_URL, lang, || {
+        let data = get_template_data(site, &site_content);
+        let permalink = site.config.make_permalink(NOTES_INDEX_URL);
+
+        let mut extra_context = data.context.clone();
+        extra_context.insert("lang", lang);
+        extra_context.insert("current_url", &permalink);
+        extra_context.insert("current_path", NOTES_INDEX_URL);
+        let section = Section::new(site, "notes/_index.md", data.notes.clone());
+        extra_context.insert("section", &section);
         extra_context.insert(
             "paginator",
             &Paginator {
-                pages: pages_list.clone(),
+                pages: data.notes.clone(),
+                paginate_by: data.notes.len().max(1),
+                base_url: permalink.to_owned(),
+                number_pagers: 1,
+                first: permalink.to_owned(),
+                last: permalink.to_owned(),
+                previous: None,
+                next: None,
+                current_index: 1,
+                total_pages: data.notes.len(),
             },
         );
 
-        let template = if self.slug == "index" {
+        let template = if data.tera.get_template_names().any(|t| t == "notes.html") {
+            "notes.html"
+        } else {
             "index.html"
+        };
+        render_template(template, &data.tera, String::new(), extra_context)
+    })
+}
+
+/// Renders the gallery of the images uploaded to the site (see [`gallery`]) using the site's `gallery.html`,
+/// or its `page.html` if it has none, the images being laid out by the built-in `servus/gallery.html` as the page's content.
+pub fn render_gallery(site: &Site, config: &GalleryConfig, lang: &str) -> error::Result<String> {
+    let site_content = site.get_content();
+    // uploading files doesn't change the content, but it does change the gallery
+    let cache_key = format!("{}@{}", gallery::GALLERY_URL, gallery::get_version(site));
+    render_cached(site, &site_content, &cache_key, lang, || {
+        let data = get_template_data(site, &site_content);
+        let resource = Resource {
+            kind: ResourceKind::Page,
+            slug: "gallery".to_string(),
+            title: Some(config.get_title().to_owned()),
+            date: Utc::now().naive_utc(),
+            noindex: false,
+            content_source: ContentSource::String(String::new()),
+        };
+        let mut page = Page::from_resource(&resource, site, &site_content);
+        page.title = config.get_title().to_owned();
+        page.url = gallery::GALLERY_URL.to_owned();
+        page.permalink = site.config.make_permalink(gallery::GALLERY_URL);
+
+        let mut extra_context = data.context.clone();
+        extra_context.insert("lang", lang);
+        extra_context.insert("current_url", &page.permalink);
+        extra_context.insert("current_path", &page.url);
+        extra_context.insert(
+            "gallery",
+            &json!({
+                "images": gallery::get_images(site),
+                "columns": config.get_columns(),
+            }),
+        );
+        extra_context.insert("paginator", &data.get_paginator(site, 1));
+
+        if data.tera.get_template_names().any(|t| t == "gallery.html") {
+            extra_context.insert("page", &page);
+            return render_template("gallery.html", &data.tera, String::new(), extra_context);
+        }
+        page.content = render_template(
+            template::GALLERY_TEMPLATE_NAME,
+            &data.tera,
+            String::new(),
+            extra_context.clone(),
+        )?;
+        extra_context.insert("page", &page);
+        render_template("page.html", &data.tera, page.content.clone(), extra_context)
+    })
+}
+
+/// What templates get to know about the content of the whole site, which is the same for every page,
+/// along with the site's templates, having the functions giving access to that content registered.
+/// Built once per content snapshot, as it requires rendering all the posts and pages,
+/// so that rendering a page doesn't depend on the number of pages.
+pub struct TemplateData {
+    pages: Vec<(ResourceKind, Page)>, // posts and pages, newest first
+    posts: Vec<usize>,                // indices of the posts in `pages`
+    positions: HashMap<String, (usize, Option<usize>)>, // URL -> index in `pages` and in `posts`
+    posts_by_tag: HashMap<String, Vec<usize>>, // tag -> indices in `posts`, newest first
+    notes: Vec<Page>,                 // newest first
+    context: tera::Context,           // what is passed to the templates of all the pages
+    tera: tera::Tera,
+    search_index: Vec<search::SearchDocument>, // empty unless `build_search_index` is set
+}
+
+impl TemplateData {
+    fn new(site: &Site, site_content: &SiteContent) -> Self {
+        let mut resources_list = site_content
+            .resources
+            .values()
+            .filter(|r| r.kind == ResourceKind::Post || r.kind == ResourceKind::Page)
+            .collect::<Vec<&Resource>>();
+        resources_list.sort_by_key(|r| std::cmp::Reverse(r.date));
+        let pages = resources_list
+            .iter()
+            .map(|r| (r.kind, Page::from_resource(r, site, site_content)))
+            .collect::<Vec<_>>();
+
+        let mut search_index = vec![];
+        if site.config.build_search_index {
+            for (resource, (_, page)) in resources_list.iter().zip(&pages) {
+                if page.noindex {
+                    continue;
+                }
+                if let Some((_, content)) = resource.read(site_content) {
+                    search_index.push(search::SearchDocument {
+                        title: page.title.to_owned(),
+                        url: page.permalink.to_owned(),
+                        date: page.date,
+                        summary: page.summary.to_owned(),
+                        body: search::md_to_text(&content),
+                    });
+                }
+            }
+        }
+
+        let posts = (0..pages.len())
+            .filter(|i| pages[*i].0 == ResourceKind::Post)
+            .collect::<Vec<_>>();
+        let mut positions = pages
+            .iter()
+            .enumerate()
+            .map(|(i, (_, p))| (p.url.to_owned(), (i, None)))
+            .collect::<HashMap<_, _>>();
+        let mut posts_by_tag = HashMap::<String, Vec<usize>>::new();
+        for (post_index, i) in posts.iter().enumerate() {
+            let post = &pages[*i].1;
+            positions.insert(post.url.to_owned(), (*i, Some(post_index)));
+            for tag in post.get_tags().iter().collect::<HashSet<_>>() {
+                posts_by_tag
+                    .entry(tag.to_owned())
+                    .or_default()
+                    .push(post_index);
+            }
+        }
+
+        let mut context = tera::Context::new();
+        context.insert("config", &site.config.for_templates());
+        context.insert("data", &site_content.data);
+
+        let posts_list = posts.iter().map(|i| &pages[*i].1).collect::<Vec<&Page>>();
+        context.insert("archive", &get_archive(&posts_list));
+
+        let mut notes_list = site_content
+            .resources
+            .values()
+            .filter(|r| r.kind == ResourceKind::Note)
+            .collect::<Vec<&Resource>>();
+        notes_list.sort_by_key(|r| std::cmp::Reverse(r.date));
+        let notes = notes_list
+            .iter()
+            .map(|r| Page::from_resource(r, site, site_content))
+            .collect::<Vec<_>>();
+        context.insert("notes", &notes);
+
+        // NB: some themes expect to iterate over section.pages, others look for paginator.pages.
+        // We are passing both in all cases, so all themes will find the pages,
+        // but only the paginator is paginated, when using `paginate_by`.
+        let pages_list = pages.iter().map(|(_, p)| p.clone()).collect::<Vec<Page>>();
+        context.insert("section", &Section::new(site, "_index.md", pages_list));
+
+        let mut tera = tera::Tera::clone(&site.tera);
+        template::register_site_functions(&mut tera, &site.config, &site.assets);
+        register_content_functions(&mut tera, site, site_content, &pages);
+
+        Self {
+            pages,
+            posts,
+            positions,
+            posts_by_tag,
+            notes,
+            context,
+            tera,
+            search_index,
+        }
+    }
+
+    fn get_paginate_by(&self, site: &Site) -> usize {
+        site.config.paginate_by.unwrap_or(self.pages.len()).max(1)
+    }
+
+    /// The number of pagers the pages are split into, at least one even without pages.
+    fn get_pager_count(&self, site: &Site) -> usize {
+        self.pages.len().div_ceil(self.get_paginate_by(site)).max(1)
+    }
+
+    fn get_paginator(&self, site: &Site, pager: usize) -> Paginator {
+        let paginate_by = self.get_paginate_by(site);
+        let number_pagers = self.get_pager_count(site);
+        let permalink = |pager| site.config.make_permalink(&get_pager_url(pager));
+        Paginator {
+            pages: self
+                .pages
+                .iter()
+                .skip((pager - 1) * paginate_by)
+                .take(paginate_by)
+                .map(|(_, p)| p.clone())
+                .collect(),
+            paginate_by,
+            base_url: site.config.make_permalink("/page/"),
+            number_pagers,
+            first: permalink(1),
+            last: permalink(number_pagers),
+            previous: (pager > 1).then(|| permalink(pager - 1)),
+            next: (pager < number_pagers).then(|| permalink(pager + 1)),
+            current_index: pager,
+            total_pages: self.pages.len(),
+        }
+    }
+
+    /// The post at `index`, newest first.
+    fn get_post(&self, index: usize) -> Option<&Page> {
+        self.posts.get(index).map(|i| &self.pages[*i].1)
+    }
+}
+
+/// The rendered pages of a content snapshot, by URL and language.
+/// Like the template data, it goes away as soon as the content changes.
+#[derive(Default)]
+pub struct RenderCache {
+    pages: Mutex<HashMap<(String, String), (Instant, String)>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+/// How much the render cache holds and how often it was used, since the content last changed.
+#[derive(Debug, PartialEq, Serialize, ToSchema)]
+pub struct RenderCacheStats {
+    pub pages: usize,
+    pub size: usize, // in bytes
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl RenderCache {
+    /// The page rendered at `url` in `lang`, unless rendered more than `ttl` ago.
+    fn get(&self, url: &str, lang: &str, ttl: Option<Duration>) -> Option<String> {
+        let pages = self.pages.lock().unwrap();
+        let html = pages
+            .get(&(url.to_owned(), lang.to_owned()))
+            .filter(|(rendered_at, _)| ttl.is_none_or(|ttl| rendered_at.elapsed() < ttl))
+            .map(|(_, html)| html.to_owned());
+        let counter = if html.is_some() {
+            &self.hits
         } else {
-            "page.html"
+            &self.misses
         };
-        render_template(&template, &mut tera, page.content, extra_context)
-            .as_bytes()
-            .to_vec()
+        counter.fetch_add(1, Ordering::Relaxed);
+
+        html
+    }
+
+    fn insert(&self, url: &str, lang: &str, html: String) {
+        self.pages
+            .lock()
+            .unwrap()
+            .insert((url.to_owned(), lang.to_owned()), (Instant::now(), html));
+    }
+
+    pub fn clear(&self) {
+        self.pages.lock().unwrap().clear();
+    }
+
+    pub fn get_stats(&self) -> RenderCacheStats {
+        let pages = self.pages.lock().unwrap();
+        RenderCacheStats {
+            pages: pages.len(),
+            size: pages.values().map(|(_, html)| html.len()).sum(),
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
     }
 }
 
+/// The template data of `site_content`, built on first use.
+fn get_template_data(site: &Site, site_content: &SiteContent) -> Arc<TemplateData> {
+    site_content
+        .template_data
+        .get_or_init(|| Arc::new(TemplateData::new(site, site_content)))
+        .clone()
+}
+
+/// Renders an event that is not part of the site's content (such as a reply to one of its posts)
+/// using the site's `page.html`, as if it were a page at `/e/<note1...>`, kept out of search engines.
+/// Its content being someone else's, the HTML is sanitized.
+pub fn render_external_event(
+    site: &Site,
+    event: &nostr::Event,
+    lang: &str,
+) -> error::Result<String> {
+    let site_content = site.get_content();
+    let data = get_template_data(site, &site_content);
+
+    let url = format!(
+        "/e/{}",
+        nostr::event_id_to_note(&event.id).unwrap_or(event.id.to_owned())
+    );
+    let permalink = site.config.make_permalink(&url);
+    let (html, toc) = md_to_html_with_toc(&event.content, &permalink);
+    let word_count = md_word_count(&event.content);
+    let page = Page {
+        title: event.get_tag("title").unwrap_or_default(),
+        permalink,
+        url,
+        slug: event.id.to_owned(),
+        path: None,
+        relative_path: None,
+        description: None,
+        summary: event.get_long_form_summary(),
+        content: sanitize_html(&html),
+        date: event.get_date(),
+        local_date: site.config.to_local_date(&event.get_date()).fixed_offset(),
+        formatted_date: format_local_date(&site.config, &event.get_date()),
+        translations: vec![],
+        lang: None,
+        word_count: Some(word_count),
+        reading_time: Some(word_count.div_ceil(WORDS_PER_MINUTE)),
+        image: event
+            .get_tag("image")
+            .or_else(|| md_first_image(&event.content)),
+        author: nostr::pubkey_to_npub(&event.pubkey),
+        taxonomies: HashMap::from([("tags".to_string(), event.get_tag_values("t"))]),
+        earlier: None,
+        later: None,
+        related: vec![],
+        backlinks: vec![],
+        toc,
+        noindex: true,
+        extra: HashMap::new(),
+    };
+
+    let mut extra_context = data.context.clone();
+    extra_context.insert("lang", lang);
+    extra_context.insert("current_url", &page.permalink);
+    extra_context.insert("current_path", &page.url);
+    extra_context.insert("page", &page);
+    extra_context.insert("paginator", &data.get_paginator(site, 1));
+
+    render_template("page.html", &data.tera, page.content.clone(), extra_context)
+}
+
+/// The page telling visitors that the request for `path` failed with `status` (404 or 500):
+/// the theme's `404.html` (or `500.html`), given the same context as the other pages along with the `status`,
+/// or, if the theme has none (or it cannot be rendered), a minimal one of our own.
+pub fn render_error_page(site: &Site, status: u16, path: &str, lang: &str) -> String {
+    let site_content = site.get_content();
+    let data = get_template_data(site, &site_content);
+    let template = format!("{}.html", status);
+    if data.tera.get_template_names().any(|t| t == template) {
+        let mut extra_context = data.context.clone();
+        extra_context.insert("lang", lang);
+        extra_context.insert("status", &status);
+        extra_context.insert("current_url", &site.config.make_permalink(path));
+        extra_context.insert("current_path", path);
+        match render_template(&template, &data.tera, String::new(), extra_context) {
+            Ok(html) => return html,
+            Err(e) => log::warn!("{}", e),
+        }
+    }
+
+    render_default_error_page(status, Some(&site.config.base_url))
+}
+
+/// A page telling visitors the request failed with `status`, for sites whose theme has none,
+/// linking to `home_url`, if any.
+pub fn render_default_error_page(status: u16, home_url: Option<&str>) -> String {
+    let title = match status {
+        404 => "Page not found",
+        _ => "Something went wrong",
+    };
+    let home_link = home_url
+        .map(|url| {
+            format!(
+                "<p><a href=\"{}\">Go to the home page</a></p>",
+                tera::escape_html(url)
+            )
+        })
+        .unwrap_or_default();
+    format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><meta name=\"viewport\" content=\"width=device-width, initial-scale=1\"><title>{1}</title></head><body><h1>{0} {1}</h1>{2}</body></html>\n",
+        status, title, home_link
+    )
+}
+
 fn render_template(
     template: &str,
-    tera: &mut tera::Tera,
+    tera: &tera::Tera,
     content: String,
     extra_context: tera::Context,
-) -> String {
+) -> error::Result<String> {
     let mut context = tera::Context::new();
     context.insert(
         "servus",
@@ -204,39 +1195,90 @@ fn render_template(
     context.insert("content", &content);
     context.extend(extra_context);
 
-    tera.render(template, &context).unwrap()
+    // such as when the theme lacks the template, or the template uses something that's missing
+    tera.render(template, &context).map_err(|e| {
+        Error::Internal(format!(
+            "Cannot render {}: {}",
+            template,
+            template::format_error(e)
+        ))
+    })
 }
 
-fn render_robots_txt(site_url: &str) -> (mime::Mime, String) {
-    let content = format!("User-agent: *\nSitemap: {}/sitemap.xml", site_url);
+fn render_robots_txt(site_url: &str, site: &Site) -> (mime::Mime, String) {
+    let mut content = format!("User-agent: *\nSitemap: {}/sitemap.xml", site_url);
+    if let Some(rules) = &site.config.robots_txt {
+        content.push_str(&format!("\n\n{}", rules.trim_end()));
+    }
     (mime::PLAIN, content)
 }
 
+/// The site's relay, as given to NIP-05 clients.
+fn get_relay_url(base_url: &str) -> Option<String> {
+    let (scheme, rest) = base_url.split_once("://")?;
+    let host = rest.split('/').next()?;
+    let scheme = if scheme == "https" { "wss" } else { "ws" };
+    Some(format!("{}://{}", scheme, host))
+}
+
+/// The NIP-05 names of the site: `_` for the site's owner, plus those in its `nip05` table,
+/// along with the relay each of them can be found at (the site's own).
+fn get_nostr_json(config: &SiteConfig) -> serde_json::Value {
+    let mut names = serde_json::Map::new();
+    for (name, pubkey) in &config.nip05 {
+        match nostr::parse_pubkey(pubkey) {
+            Some(pubkey) => {
+                names.insert(name.to_lowercase(), json!(pubkey));
+            }
+            None => log::warn!("Invalid pubkey for NIP-05 name {}: {}", name, pubkey),
+        }
+    }
+    if let Some(pubkey) = &config.pubkey {
+        names.insert("_".to_string(), json!(pubkey));
+    }
+
+    let mut relays = serde_json::Map::new();
+    if let Some(relay_url) = get_relay_url(&config.base_url) {
+        for pubkey in names.values() {
+            relays.insert(pubkey.as_str().unwrap().to_owned(), json!([relay_url]));
+        }
+    }
+
+    json!({"names": names, "relays": relays})
+}
+
 fn render_nostr_json(site: &Site) -> (mime::Mime, String) {
-    let content = format!(
-        "{{ \"names\": {{ \"_\": \"{}\" }} }}",
-        site.config.pubkey.clone().unwrap_or("".to_string())
-    );
-    (mime::JSON, content)
+    (mime::JSON, get_nostr_json(&site.config).to_string())
 }
 
-fn render_sitemap_xml(site_url: &str, site: &Site) -> (mime::Mime, String) {
+fn render_sitemap_xml(site: &Site) -> (mime::Mime, String) {
     let mut response: String = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n".to_owned();
-    let resources = site.resources.read().unwrap();
+    let site_content = site.get_content();
     response.push_str("<urlset xmlns:xsi=\"http://www.w3.org/2001/XMLSchema-instance\" xsi:schemaLocation=\"http://www.sitemaps.org/schemas/sitemap/0.9 http://www.sitemaps.org/schemas/sitemap/0.9/sitemap.xsd\" xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">\n");
-    for url in resources.keys() {
-        let mut url = url.trim_end_matches("/index").to_owned();
-        if url == site_url && !url.ends_with('/') {
-            url.push('/');
+    for resource in site_content.resources.values() {
+        if resource.noindex {
+            continue;
         }
-        response.push_str(&format!("    <url><loc>{}</loc></url>\n", url));
+        response.push_str(&format!(
+            "    <url><loc>{}</loc><lastmod>{}</lastmod></url>\n",
+            resource.get_permalink(&site.config),
+            site.config.to_local_date(&resource.date).format("%Y-%m-%d")
+        ));
     }
     response.push_str("</urlset>");
 
     (mime::XML, response)
 }
 
-fn render_atom_xml(site_url: &str, site: &Site) -> (mime::Mime, String) {
+/// Renders the feed at `path` (`atom.xml` or `notes/atom.xml`), listing the resources of the kinds `feed` includes,
+/// newest first. Notes, having no title, get the beginning of their text as one.
+fn render_atom_xml(
+    site_url: &str,
+    site: &Site,
+    path: &str,
+    feed: &FeedConfig,
+    defaults: &[ResourceKind],
+) -> (mime::Mime, String) {
     let mut response: String = "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n".to_owned();
     response.push_str("<feed xmlns=\"http://www.w3.org/2005/Atom\">\n");
     response.push_str(&format!(
@@ -244,14 +1286,25 @@ fn render_atom_xml(site_url: &str, site: &Site) -> (mime::Mime, String) {
         &site.config.title.clone().unwrap_or("".to_string())
     ));
     response.push_str(&format!(
-        "<link href=\"{}/atom.xml\" rel=\"self\"/>\n",
-        site_url
+        "<link href=\"{}/{}\" rel=\"self\"/>\n",
+        site_url, path
     ));
     response.push_str(&format!("<link href=\"{}/\"/>\n", site_url));
     response.push_str(&format!("<id>{}</id>\n", site_url));
-    let resources = site.resources.read().unwrap();
-    for (url, resource) in &*resources {
-        if let Some((_, content)) = resource.read(site) {
+    let site_content = site.get_content();
+    let mut resources = site_content
+        .resources
+        .values()
+        .filter(|r| !r.noindex && feed.includes(r.kind, defaults))
+        .collect::<Vec<_>>();
+    resources.sort_by_key(|r| std::cmp::Reverse(r.date));
+    for resource in resources {
+        if let Some((_, content)) = resource.read(&site_content) {
+            let title = match (&resource.title, resource.kind) {
+                (Some(title), _) if !title.is_empty() => title.to_owned(),
+                (_, ResourceKind::Note) => get_note_title(&content),
+                _ => "".to_string(),
+            };
             response.push_str(
                 &format!(
                     "<entry>
@@ -262,9 +1315,9 @@ fn render_atom_xml(site_url: &str, site: &Site) -> (mime::Mime, String) {
 <content type=\"xhtml\"><div xmlns=\"http://www.w3.org/1999/xhtml\">{}</div></content>
 </entry>
 ",
-                    resource.title.clone().unwrap_or("".to_string()),
-                    &url,
-                    &resource.date,
+                    tera::escape_html(&title),
+                    resource.get_permalink(&site.config),
+                    site.config.to_local_date(&resource.date).to_rfc3339(),
                     site_url,
                     resource.slug.clone(),
                     &md_to_html(&content).to_owned()
@@ -278,19 +1331,758 @@ fn render_atom_xml(site_url: &str, site: &Site) -> (mime::Mime, String) {
     (mime::XML, response)
 }
 
+/// Where WebFinger (served for sites that can be followed using ActivityPub) is, for clients looking it up using host-meta.
+fn render_host_meta(site: &Site) -> Option<(mime::Mime, String)> {
+    site.config.activitypub.as_ref()?;
+    let content = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>
+<XRD xmlns=\"http://docs.oasis-open.org/ns/xri/xrd-1.0\">
+  <Link rel=\"lrdd\" template=\"{}/.well-known/webfinger?resource={{uri}}\"/>
+</XRD>
+",
+        site.config.base_url.trim_end_matches('/')
+    );
+    Some((
+        mime::Mime::from_str("application/xrd+xml").unwrap(),
+        content,
+    ))
+}
+
+/// The posts and pages of the site, for themes to search client-side.
+fn render_search_index_json(site: &Site) -> Option<(mime::Mime, String)> {
+    if !site.config.build_search_index {
+        return None;
+    }
+    let data = get_template_data(site, &site.get_content());
+    Some((mime::JSON, json!(data.search_index).to_string()))
+}
+
+/// The posts and pages of the site best matching `query`, unless it has no search index.
+pub fn search_site(site: &Site, query: &str) -> Option<serde_json::Value> {
+    if !site.config.build_search_index {
+        return None;
+    }
+    let data = get_template_data(site, &site.get_content());
+    Some(json!(search::search(&data.search_index, query)))
+}
+
 pub fn render_standard_resource(resource_name: &str, site: &Site) -> Option<(mime::Mime, String)> {
     match resource_name {
-        "robots.txt" => Some(render_robots_txt(&site.config.base_url)),
+        "robots.txt" => Some(render_robots_txt(&site.config.base_url, site)),
         ".well-known/nostr.json" => Some(render_nostr_json(site)),
-        "sitemap.xml" => Some(render_sitemap_xml(&site.config.base_url, site)),
-        "atom.xml" => Some(render_atom_xml(&site.config.base_url, site)),
+        ".well-known/security.txt" => site
+            .config
+            .security_txt
+            .as_ref()
+            .map(|content| (mime::PLAIN, format!("{}\n", content.trim_end()))),
+        ".well-known/host-meta" => render_host_meta(site),
+        "sitemap.xml" => Some(render_sitemap_xml(site)),
+        "atom.xml" => Some(render_atom_xml(
+            &site.config.base_url,
+            site,
+            resource_name,
+            &site.config.feeds.atom,
+            &[ResourceKind::Post, ResourceKind::Page],
+        )),
+        "notes/atom.xml" => Some(render_atom_xml(
+            &site.config.base_url,
+            site,
+            resource_name,
+            &site.config.feeds.notes,
+            &[ResourceKind::Note],
+        )),
+        "search_index.json" => render_search_index_json(site),
+        _ => None,
+    }
+}
+
+/// The file in the site's `_content/well-known` served as `/.well-known/<name>`, if there is one.
+pub fn get_well_known_file(site: &Site, name: &str) -> Option<PathBuf> {
+    let relative_path = Path::new(name);
+    if !relative_path
+        .components()
+        .all(|c| matches!(c, Component::Normal(_)))
+    {
+        return None;
+    }
+    let path = Path::new(&paths::sites())
+        .join(&site.domain)
+        .join(WELL_KNOWN_DIR)
+        .join(relative_path);
+
+    Some(path).filter(|p| p.is_file())
+}
+
+/// The static file at `path` (as requested, so percent-encoded) in `root`, the site's directory, if there is one.
+/// Hidden files and Servus' own (such as `_config.toml` or `_content`) are not served, nor is anything that turns out
+/// to be outside of `root` once `..` and symbolic links are resolved.
+pub fn get_static_file(root: &Path, path: &str) -> Option<PathBuf> {
+    let path = percent_decode_str(path).decode_utf8().ok()?;
+    let relative_path = Path::new(path.as_ref());
+    if !relative_path.components().all(|c| match c {
+        Component::Normal(name) => !name.to_string_lossy().starts_with(['_', '.']),
+        _ => false,
+    }) {
+        return None;
+    }
+    let root = root.canonicalize().ok()?;
+    let file = root.join(relative_path).canonicalize().ok()?;
+
+    Some(file).filter(|f| f.starts_with(&root) && f.is_file())
+}
+
+/// The type of a file served from `_content/well-known`, most of which have no extension.
+pub fn get_well_known_mime(name: &str, content: &[u8]) -> mime::Mime {
+    if let Some(guess) = mime_guess::from_path(name).first() {
+        return mime::Mime::from_str(guess.essence_str()).unwrap();
+    }
+    if name == "host-meta" {
+        mime::Mime::from_str("application/xrd+xml").unwrap()
+    } else if content.trim_ascii_start().starts_with(b"{") {
+        mime::JSON
+    } else {
+        mime::PLAIN
+    }
+}
+
+fn md_first_image(md_content: &str) -> Option<String> {
+    pulldown_cmark::Parser::new(md_content).find_map(|e| match e {
+        pulldown_cmark::Event::Start(pulldown_cmark::Tag::Image { dest_url, .. }) => {
+            Some(dest_url.to_string())
+        }
         _ => None,
+    })
+}
+
+// https://github.com/getzola/zola/blob/master/components/utils/src/site.rs
+const WORDS_PER_MINUTE: usize = 200;
+
+fn md_word_count(md_content: &str) -> usize {
+    pulldown_cmark::Parser::new(md_content)
+        .map(|e| match e {
+            pulldown_cmark::Event::Text(t) | pulldown_cmark::Event::Code(t) => {
+                t.split_whitespace().count()
+            }
+            _ => 0,
+        })
+        .sum()
+}
+
+fn md_links(md_content: &str) -> Vec<String> {
+    pulldown_cmark::Parser::new(md_content)
+        .filter_map(|e| match e {
+            pulldown_cmark::Event::Start(pulldown_cmark::Tag::Link { dest_url, .. }) => {
+                Some(dest_url.to_string())
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// The normalized path of `url` if it points to the site itself.
+fn get_internal_path(base_url: &str, url: &str) -> Option<String> {
+    let path = match url.strip_prefix(base_url.trim_end_matches('/')) {
+        Some(path) if path.is_empty() || path.starts_with('/') => path,
+        _ if url.starts_with('/') && !url.starts_with("//") => url,
+        _ => return None,
+    };
+    let path = path.split(['#', '?']).next().unwrap();
+
+    Some(normalize_path(path))
+}
+
+/// Internal paths linked from `content`, along with the coordinates of the events referenced by `event`.
+pub fn get_content_links(
+    config: &SiteConfig,
+    content: &str,
+    event: Option<&nostr::Event>,
+) -> Vec<String> {
+    let mut links = md_links(content)
+        .into_iter()
+        .filter_map(|l| get_internal_path(&config.base_url, &l))
+        .collect::<Vec<_>>();
+    if let Some(event) = event {
+        links.extend(event.get_tag_values("a"));
+    }
+
+    links
+}
+
+fn make_absolute_url(base_url: &str, url: &str) -> String {
+    if url.starts_with('/') {
+        format!("{}{}", base_url.trim_end_matches('/'), url)
+    } else {
+        url.to_owned()
     }
 }
 
-fn md_to_html(md_content: &str) -> String {
-    let parser = pulldown_cmark::Parser::new(md_content);
+const NOTE_TITLE_LENGTH: usize = 80; // in characters
+
+/// A title for a note, which has none: the beginning of its text, cut at a word boundary.
+fn get_note_title(md_content: &str) -> String {
+    let text = search::md_to_text(md_content);
+    if text.chars().count() <= NOTE_TITLE_LENGTH {
+        return text;
+    }
+    let mut title = String::new();
+    for word in text.split(' ') {
+        if title.chars().count() + word.chars().count() + 1 > NOTE_TITLE_LENGTH {
+            break;
+        }
+        if !title.is_empty() {
+            title.push(' ');
+        }
+        title.push_str(word);
+    }
+    if title.is_empty() {
+        title = text.chars().take(NOTE_TITLE_LENGTH).collect();
+    }
+    title.push('…');
+
+    title
+}
+
+pub fn md_to_html(md_content: &str) -> String {
+    md_to_html_with_toc(md_content, "").0
+}
+
+/// Renders markdown, giving headings without an explicit id a stable one derived from their text,
+/// and returns the (nested) table of contents along with the HTML.
+fn md_to_html_with_toc(md_content: &str, permalink: &str) -> (String, Vec<Heading>) {
+    let mut events = pulldown_cmark::Parser::new_ext(
+        md_content,
+        pulldown_cmark::Options::ENABLE_HEADING_ATTRIBUTES,
+    )
+    .collect::<Vec<_>>();
+
+    let mut headings = vec![];
+    let mut used_ids = HashSet::new();
+    for i in 0..events.len() {
+        if let pulldown_cmark::Event::Start(pulldown_cmark::Tag::Heading { level, id, .. }) =
+            &events[i]
+        {
+            let level = *level as u32;
+            let title = events[i + 1..]
+                .iter()
+                .take_while(|e| {
+                    !matches!(
+                        e,
+                        pulldown_cmark::Event::End(pulldown_cmark::TagEnd::Heading(_))
+                    )
+                })
+                .filter_map(|e| match e {
+                    pulldown_cmark::Event::Text(t) | pulldown_cmark::Event::Code(t) => {
+                        Some(t.to_string())
+                    }
+                    _ => None,
+                })
+                .collect::<String>();
+            let id = match id {
+                Some(id) => id.to_string(),
+                None => {
+                    let slug = slugify(&title);
+                    let mut id = slug.clone();
+                    let mut n = 1;
+                    while used_ids.contains(&id) {
+                        id = format!("{}-{}", slug, n);
+                        n += 1;
+                    }
+                    id
+                }
+            };
+            used_ids.insert(id.clone());
+            if let pulldown_cmark::Event::Start(pulldown_cmark::Tag::Heading {
+                id: tag_id, ..
+            }) = &mut events[i]
+            {
+                *tag_id = Some(id.clone().into());
+            }
+            headings.push(Heading {
+                level,
+                permalink: format!("{}#{}", permalink, id),
+                id,
+                title,
+                children: vec![],
+            });
+        }
+    }
+
     let mut html_output = String::new();
-    pulldown_cmark::html::push_html(&mut html_output, parser);
-    html_output
+    pulldown_cmark::html::push_html(&mut html_output, events.into_iter());
+
+    (html_output, make_toc(headings))
+}
+
+fn make_toc(headings: Vec<Heading>) -> Vec<Heading> {
+    let mut toc: Vec<Heading> = vec![];
+    for heading in headings {
+        let mut siblings = &mut toc;
+        while siblings.last().is_some_and(|h| h.level < heading.level) {
+            siblings = &mut siblings.last_mut().unwrap().children;
+        }
+        siblings.push(heading);
+    }
+    toc
+}
+
+/// Makes a slug the same way Tera's `slugify` filter does (transliterating to ASCII),
+/// so templates can link to the headings.
+pub fn slugify(text: &str) -> String {
+    let slug = slug::slugify(text);
+    if slug.is_empty() {
+        "section".to_string()
+    } else {
+        slug
+    }
+}
+
+/// Strips scripts, event handlers and anything else unsafe from HTML rendered from Markdown,
+/// which can contain raw HTML, before handing it out to other sites.
+fn sanitize_html(html: &str) -> String {
+    ammonia::clean(html)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_nostr_json() {
+        let pubkey = "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+        let config: SiteConfig = toml::from_str(&format!(
+            "base_url = \"https://example.com/\"\ntheme = \"hyde\"\npubkey = \"{}\"\n[nip05]\nAlice = \"{}\"\nbob = \"not a pubkey\"",
+            pubkey,
+            nostr::pubkey_to_npub(pubkey).unwrap()
+        ))
+        .unwrap();
+        let nostr_json = get_nostr_json(&config);
+        assert_eq!(nostr_json["names"], json!({"_": pubkey, "alice": pubkey}));
+        assert_eq!(nostr_json["relays"][pubkey], json!(["wss://example.com"]));
+        assert_eq!(
+            get_relay_url("http://localhost:4884"),
+            Some("ws://localhost:4884".to_string())
+        );
+    }
+
+    #[test]
+    fn test_get_well_known_mime() {
+        assert_eq!(
+            get_well_known_mime("security.txt", b"Contact: x").essence(),
+            "text/plain"
+        );
+        assert_eq!(
+            get_well_known_mime("matrix/server", b" {\"m.server\": \"x\"}"),
+            mime::JSON
+        );
+        assert_eq!(
+            get_well_known_mime("host-meta", b"<XRD/>").essence(),
+            "application/xrd+xml"
+        );
+        assert_eq!(get_well_known_mime("keybase", b"hello"), mime::PLAIN);
+    }
+
+    #[test]
+    fn test_md_first_image() {
+        assert_eq!(
+            md_first_image("Some text\n\n![first](/images/a.png)\n\n![second](b.png)"),
+            Some("/images/a.png".to_string())
+        );
+        assert_eq!(
+            md_first_image("[a link](https://example.com/a.png), no image"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_get_note_title() {
+        assert_eq!(get_note_title("Just *a* short note"), "Just a short note");
+        let long = format!("Hello, {} world", "wonderful ".repeat(10));
+        let title = get_note_title(&long);
+        assert!(title.chars().count() <= NOTE_TITLE_LENGTH + 1);
+        assert!(title.starts_with("Hello, wonderful wonderful"));
+        assert!(title.ends_with("wonderful…"));
+        assert_eq!(get_note_title(&"x".repeat(100)).chars().count(), 81);
+    }
+
+    #[test]
+    fn test_make_absolute_url() {
+        assert_eq!(
+            make_absolute_url("https://example.com/", "/images/a.png"),
+            "https://example.com/images/a.png"
+        );
+        assert_eq!(
+            make_absolute_url("https://example.com", "/images/a.png"),
+            "https://example.com/images/a.png"
+        );
+        assert_eq!(
+            make_absolute_url("https://example.com", "https://cdn.example.com/a.png"),
+            "https://cdn.example.com/a.png"
+        );
+    }
+
+    #[test]
+    fn test_sanitize_html() {
+        let html = md_to_html("Hello <script>alert(1)</script> <b onclick=\"x()\">world</b>\n\n<script>\nalert(2)\n</script>\n");
+        assert!(html.contains("<script>"));
+        let html = sanitize_html(&html);
+        assert!(!html.contains("script"));
+        assert!(!html.contains("alert"));
+        assert!(!html.contains("onclick"));
+        assert!(html.contains("<b>world</b>"));
+    }
+
+    #[test]
+    fn test_slugify() {
+        assert_eq!(slugify("Hello, World!"), "hello-world");
+        assert_eq!(slugify("  Über  café -- 2024 "), "uber-cafe-2024");
+        assert_eq!(slugify("Ελληνικά"), "ellenika");
+        assert_eq!(slugify("`code` & more"), "code-more");
+        assert_eq!(slugify("?!"), "section");
+    }
+
+    #[test]
+    fn test_md_to_html_with_toc() {
+        let md = "# Intro\n\n## Details\n\n### Deeper\n\n## Details\n\n# Custom {#my-id}\n\n## `code` here\n";
+        let (html, toc) = md_to_html_with_toc(md, "https://example.com/post/");
+
+        assert!(html.contains("<h1 id=\"intro\">Intro</h1>"));
+        assert!(html.contains("<h2 id=\"details\">Details</h2>"));
+        // duplicate headings get unique ids
+        assert!(html.contains("<h2 id=\"details-1\">Details</h2>"));
+        assert!(html.contains("<h1 id=\"my-id\">Custom</h1>"));
+
+        assert_eq!(toc.len(), 2);
+        assert_eq!(toc[0].id, "intro");
+        assert_eq!(toc[0].permalink, "https://example.com/post/#intro");
+        assert_eq!(
+            toc[0].children.iter().map(|h| &h.id).collect::<Vec<_>>(),
+            vec!["details", "details-1"]
+        );
+        assert_eq!(toc[0].children[0].children.len(), 1);
+        assert_eq!(toc[0].children[0].children[0].id, "deeper");
+        assert_eq!(toc[0].children[0].children[0].level, 3);
+        assert_eq!(toc[1].id, "my-id");
+        assert_eq!(toc[1].title, "Custom");
+        assert_eq!(toc[1].children[0].id, "code-here");
+        assert_eq!(toc[1].children[0].title, "code here");
+    }
+
+    #[test]
+    fn test_make_toc_skipped_levels() {
+        let heading = |level, id: &str| Heading {
+            level,
+            id: id.to_string(),
+            permalink: "".to_string(),
+            title: "".to_string(),
+            children: vec![],
+        };
+        // a deeper heading nests under the previous one, even when levels are skipped,
+        // and a shallower heading goes back up to the closest ancestor with a lower level
+        let toc = make_toc(vec![
+            heading(2, "a"),
+            heading(4, "b"),
+            heading(3, "c"),
+            heading(1, "d"),
+        ]);
+        assert_eq!(toc.len(), 2);
+        assert_eq!(toc[0].id, "a");
+        assert_eq!(
+            toc[0].children.iter().map(|h| &h.id).collect::<Vec<_>>(),
+            vec!["b", "c"]
+        );
+        assert_eq!(toc[1].id, "d");
+    }
+
+    fn test_page(url: &str, date: &str) -> Page {
+        Page {
+            title: url.to_string(),
+            permalink: format!("https://example.com{}/", url),
+            url: url.to_string(),
+            slug: url.trim_start_matches('/').to_string(),
+            path: None,
+            relative_path: None,
+            description: None,
+            summary: None,
+            content: "".to_string(),
+            date: NaiveDateTime::parse_from_str(&format!("{} 12:00", date), "%Y-%m-%d %H:%M")
+                .unwrap(),
+            local_date: NaiveDateTime::parse_from_str(&format!("{} 12:00", date), "%Y-%m-%d %H:%M")
+                .unwrap()
+                .and_utc()
+                .fixed_offset(),
+            formatted_date: date.to_string(),
+            translations: vec![],
+            lang: None,
+            word_count: None,
+            reading_time: None,
+            image: None,
+            author: None,
+            taxonomies: HashMap::new(),
+            earlier: None,
+            later: None,
+            related: vec![],
+            backlinks: vec![],
+            toc: vec![],
+            noindex: false,
+            extra: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_get_archive() {
+        let posts = [
+            test_page("/e", "2024-03-20"),
+            test_page("/d", "2024-03-01"),
+            test_page("/c", "2024-01-31"),
+            test_page("/b", "2023-12-31"),
+            test_page("/a", "2022-12-01"),
+        ];
+        let archive = get_archive(&posts.iter().collect::<Vec<_>>());
+
+        let summary = archive
+            .iter()
+            .map(|y| {
+                (
+                    y.year,
+                    y.months
+                        .iter()
+                        .map(|m| (m.month, m.pages.iter().map(|p| p.url.as_str()).collect()))
+                        .collect::<Vec<(u32, Vec<&str>)>>(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec![
+                (2024, vec![(3, vec!["/e", "/d"]), (1, vec!["/c"])]),
+                (2023, vec![(12, vec!["/b"])]),
+                (2022, vec![(12, vec!["/a"])]),
+            ]
+        );
+        assert!(get_archive(&[]).is_empty());
+    }
+
+    fn test_resource(kind: ResourceKind, slug: &str) -> Resource {
+        Resource {
+            kind,
+            slug: slug.to_string(),
+            title: None,
+            date: chrono::NaiveDate::from_ymd_opt(2024, 3, 7)
+                .unwrap()
+                .and_hms_opt(12, 0, 0)
+                .unwrap(),
+            noindex: false,
+            content_source: ContentSource::String("".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_get_resource_url() {
+        let permalinks = PermalinkConfig {
+            post: Some("/blog/{year}/{month}/{day}/{slug}/".to_string()),
+            page: Some("/{slug}.html".to_string()),
+            note: Some("/{kind}/{d_tag}".to_string()),
+        };
+        let post = test_resource(ResourceKind::Post, "hello");
+        assert_eq!(
+            post.get_resource_url(&permalinks, None),
+            Some("/blog/2024/03/07/hello".to_string())
+        );
+        assert_eq!(
+            post.get_resource_url(&PermalinkConfig::default(), None),
+            Some("/posts/hello".to_string())
+        );
+        assert_eq!(
+            test_resource(ResourceKind::Page, "about").get_resource_url(&permalinks, None),
+            Some("/about.html".to_string())
+        );
+        assert_eq!(
+            test_resource(ResourceKind::Note, "abc").get_resource_url(&permalinks, None),
+            Some("/notes/abc".to_string())
+        );
+        // the index page ignores patterns
+        assert_eq!(
+            test_resource(ResourceKind::Page, "index").get_resource_url(&permalinks, None),
+            Some("/index".to_string())
+        );
+        // noon in UTC is already the next day in Kiribati
+        let timezone = Some(chrono_tz::Pacific::Kiritimati);
+        assert_eq!(
+            post.get_resource_url(&permalinks, timezone),
+            Some("/blog/2024/03/08/hello".to_string())
+        );
+        assert!(post
+            .get_old_urls(&PermalinkHistory::default(), &permalinks, timezone)
+            .contains(&"/blog/2024/03/07/hello".to_string()));
+    }
+
+    #[test]
+    fn test_get_old_urls() {
+        let history = PermalinkHistory {
+            post: vec!["/{year}/{slug}".to_string(), "/p/{slug}".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(
+            test_resource(ResourceKind::Post, "hello").get_old_urls(
+                &history,
+                &PermalinkConfig::default(),
+                None
+            ),
+            vec!["/posts/hello", "/2024/hello", "/p/hello"]
+        );
+        assert_eq!(
+            test_resource(ResourceKind::Page, "about").get_old_urls(
+                &history,
+                &PermalinkConfig::default(),
+                None
+            ),
+            vec!["/about"]
+        );
+    }
+
+    #[test]
+    fn test_get_permalink() {
+        let mut config: SiteConfig =
+            toml::from_str("base_url = \"https://example.com\"\ntheme = \"hyde\"").unwrap();
+        // dots in slugs don't affect the trailing slash...
+        let post = test_resource(ResourceKind::Post, "v1.2");
+        assert_eq!(
+            post.get_permalink(&config),
+            "https://example.com/posts/v1.2/"
+        );
+        // ... unless the pattern itself points to a file
+        config.permalinks.post = Some("/{slug}.html".to_string());
+        assert_eq!(post.get_permalink(&config), "https://example.com/v1.2.html");
+        config.permalinks.post = Some("/{year}/{slug}/".to_string());
+        assert_eq!(
+            post.get_permalink(&config),
+            "https://example.com/2024/v1.2/"
+        );
+
+        // indexes are at the URL of their section
+        let index = test_resource(ResourceKind::Page, "index");
+        assert_eq!(index.get_permalink(&config), "https://example.com/");
+        let docs = test_resource(ResourceKind::Page, "docs/index");
+        assert_eq!(docs.get_permalink(&config), "https://example.com/docs/");
+
+        config.trailing_slash = false;
+        assert_eq!(post.get_permalink(&config), "https://example.com/2024/v1.2");
+        assert_eq!(docs.get_permalink(&config), "https://example.com/docs");
+        assert_eq!(index.get_permalink(&config), "https://example.com/");
+    }
+
+    #[test]
+    fn test_render_cache() {
+        let cache = RenderCache::default();
+        assert!(cache.get("/about", "en", None).is_none());
+
+        cache.insert("/about", "en", "<p>About</p>".to_string());
+        assert_eq!(cache.get("/about", "en", None).unwrap(), "<p>About</p>");
+        assert!(cache.get("/about", "de", None).is_none());
+        assert!(cache
+            .get("/about", "en", Some(Duration::from_secs(60)))
+            .is_some());
+        assert!(cache.get("/about", "en", Some(Duration::ZERO)).is_none());
+
+        assert_eq!(
+            cache.get_stats(),
+            RenderCacheStats {
+                pages: 1,
+                size: 12,
+                hits: 2,
+                misses: 3
+            }
+        );
+
+        cache.clear();
+        assert!(cache.get("/about", "en", None).is_none());
+    }
+
+    #[test]
+    fn test_get_related_pages() {
+        let mut posts = [
+            test_page("/c", "2024-03-01"),
+            test_page("/b", "2024-02-01"),
+            test_page("/a", "2024-01-01"),
+            test_page("/d", "2023-12-01"),
+        ];
+        let tags = [
+            vec!["rust"],
+            vec!["rust", "nostr"],
+            vec!["nostr"],
+            vec!["rust", "nostr"],
+        ];
+        let mut posts_by_tag = HashMap::<String, Vec<usize>>::new();
+        for (i, (post, tags)) in posts.iter_mut().zip(tags).enumerate() {
+            let tags = tags.iter().map(|t| t.to_string()).collect::<Vec<_>>();
+            for tag in &tags {
+                posts_by_tag.entry(tag.to_owned()).or_default().push(i);
+            }
+            post.taxonomies.insert("tags".to_string(), tags);
+        }
+
+        let related = |i: usize| {
+            get_related_pages(&posts[i], &posts_by_tag, |i| &posts[i])
+                .into_iter()
+                .map(|p| p.url)
+                .collect::<Vec<_>>()
+        };
+        // most shared tags first, then newest first
+        assert_eq!(related(1), vec!["/d", "/c", "/a"]);
+        assert_eq!(related(0), vec!["/b", "/d"]);
+        assert_eq!(related(2), vec!["/b", "/d"]);
+    }
+
+    #[test]
+    fn test_render_default_error_page() {
+        let html = render_default_error_page(404, Some("https://example.com/?a=1&b=\"2\""));
+        assert!(html.contains("<title>Page not found</title>"));
+        assert!(html.contains("a=1&amp;b=&quot;2&quot;\">Go to the home page"));
+
+        let html = render_default_error_page(500, None);
+        assert!(html.contains("500 Something went wrong"));
+        assert!(!html.contains("href"));
+    }
+
+    #[test]
+    fn test_get_static_file() {
+        let dir = env::temp_dir().join(format!("servus-static-{}", std::process::id()));
+        let root = dir.join("example.com");
+        std::fs::create_dir_all(root.join("assets")).unwrap();
+        std::fs::create_dir_all(root.join("_content")).unwrap();
+        std::fs::write(root.join("assets/my file.txt"), "hello").unwrap();
+        std::fs::write(root.join("_config.toml"), "").unwrap();
+        std::fs::write(dir.join("secret.txt"), "secret").unwrap();
+
+        let file = get_static_file(&root, "assets/my%20file.txt").unwrap();
+        assert_eq!(std::fs::read_to_string(file).unwrap(), "hello");
+        assert!(get_static_file(&root, "assets").is_none());
+        assert!(get_static_file(&root, "assets/missing.txt").is_none());
+        assert!(get_static_file(&root, "_config.toml").is_none());
+        assert!(get_static_file(&root, "assets/../_config.toml").is_none());
+
+        // traversal, encoded or not
+        for path in [
+            "../secret.txt",
+            "%2e%2e/secret.txt",
+            "%2E%2E%2Fsecret.txt",
+            "assets/..%2f..%2fsecret.txt",
+            "/etc/passwd",
+            "%2Fetc%2Fpasswd",
+        ] {
+            assert!(get_static_file(&root, path).is_none(), "{}", path);
+        }
+
+        // symbolic links are followed only as long as they stay in the site
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(dir.join("secret.txt"), root.join("link.txt")).unwrap();
+            assert!(get_static_file(&root, "link.txt").is_none());
+            std::os::unix::fs::symlink(root.join("assets/my file.txt"), root.join("ok.txt"))
+                .unwrap();
+            assert!(get_static_file(&root, "ok.txt").is_some());
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,359 @@
+use lazy_static::lazy_static;
+use regex::Regex;
+
+/// What can't appear in an SVG image, once its character references are decoded,
+/// as it could run scripts, or embed other documents, when the image is opened on its own.
+const SVG_FORBIDDEN: &[&str] = &[
+    "<script",
+    "<foreignobject",
+    "<iframe",
+    "<embed",
+    "<object",
+    "<!entity",
+    "javascript:",
+    "vbscript:",
+    "data:text/html",
+    "data:application/",
+];
+
+/// The types of images [`strip_metadata`] strips metadata from.
+pub const STRIPPED_TYPES: &[&str] = &["image/jpeg", "image/png", "image/webp"];
+
+lazy_static! {
+    /// Event handler attributes, such as `onload="..."`.
+    static ref SVG_EVENT_ATTRIBUTE: Regex = Regex::new(r#"[\s"'/]on[a-z]+\s*="#).unwrap();
+}
+
+/// Whether the beginning of a file looks like an SVG image (which is XML, and not sniffed as an image).
+pub fn is_svg(head: &[u8]) -> bool {
+    let head = String::from_utf8_lossy(head);
+    let head = head.trim_start_matches('\u{feff}').trim_start();
+    (head.starts_with("<?xml") || head.starts_with("<svg") || head.starts_with("<!--"))
+        && head.contains("<svg")
+}
+
+/// Checks that an SVG image has no scripts (or anything else than can run one), or says why.
+/// This refuses some harmless images, such as ones mentioning scripts in their text, rather than risking any.
+pub fn check_svg(content: &[u8]) -> Result<(), &'static str> {
+    let content = std::str::from_utf8(content).map_err(|_| "SVG is not valid UTF-8")?;
+    let content = decode_char_refs(content).to_lowercase();
+    // browsers ignore these in the middle of a URL scheme, as in "java\tscript:"
+    let compact = content
+        .chars()
+        .filter(|c| !c.is_whitespace() && !c.is_control())
+        .collect::<String>();
+
+    if SVG_FORBIDDEN.iter().any(|f| compact.contains(f)) {
+        return Err("SVG can run scripts");
+    }
+    if SVG_EVENT_ATTRIBUTE.is_match(&content) {
+        return Err("SVG has event handlers");
+    }
+
+    Ok(())
+}
+
+/// Replaces numeric character references, such as `&#x6a;`, and the named ones that can hide a URL scheme.
+fn decode_char_refs(content: &str) -> String {
+    let mut decoded = String::with_capacity(content.len());
+    let mut rest = content;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let end = rest.find(';').filter(|end| *end <= 12);
+        let reference = end.map(|end| &rest[1..end]);
+        let c = match reference {
+            Some("colon") => Some(':'),
+            Some("tab") | Some("Tab") => Some('\t'),
+            Some("newline") | Some("NewLine") => Some('\n'),
+            Some(reference) => reference
+                .strip_prefix("#x")
+                .or_else(|| reference.strip_prefix("#X"))
+                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                .or_else(|| reference.strip_prefix('#')?.parse().ok())
+                .and_then(char::from_u32),
+            None => None,
+        };
+        match (c, end) {
+            (Some(c), Some(end)) => {
+                decoded.push(c);
+                rest = &rest[end + 1..];
+            }
+            _ => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+/// An image without its metadata (such as EXIF, which can hold where a photo was taken, or XMP),
+/// or `None` if there was none. JPEG images keep their orientation, so that they are still shown the right way up.
+pub fn strip_metadata(content_type: &str, content: &[u8]) -> Result<Option<Vec<u8>>, &'static str> {
+    let stripped = match content_type {
+        "image/jpeg" => strip_jpeg(content).ok_or("Invalid JPEG image")?,
+        "image/png" => strip_png(content).ok_or("Invalid PNG image")?,
+        "image/webp" => strip_webp(content).ok_or("Invalid WebP image")?,
+        _ => return Ok(None),
+    };
+
+    Ok((stripped != content).then_some(stripped))
+}
+
+fn strip_jpeg(content: &[u8]) -> Option<Vec<u8>> {
+    if !content.starts_with(&[0xff, 0xd8]) {
+        return None;
+    }
+    let mut stripped = vec![0xff, 0xd8];
+    let mut has_orientation = false;
+    let mut position = 2;
+    loop {
+        if *content.get(position)? != 0xff {
+            return None;
+        }
+        let marker = *content.get(position + 1)?;
+        match marker {
+            0xff => {
+                // fill byte
+                position += 1;
+                continue;
+            }
+            // the compressed image, and whatever follows it, is kept as it is
+            0xda => {
+                stripped.extend_from_slice(&content[position..]);
+                return Some(stripped);
+            }
+            0x01 | 0xd0..=0xd7 => {
+                stripped.extend_from_slice(&content[position..position + 2]);
+                position += 2;
+                continue;
+            }
+            _ => {}
+        }
+        let length = u16::from_be_bytes([*content.get(position + 2)?, *content.get(position + 3)?]);
+        if length < 2 {
+            return None;
+        }
+        let end = position + 2 + length as usize;
+        let segment = content.get(position..end)?;
+        match marker {
+            // EXIF and XMP
+            0xe1 => {
+                let orientation = segment[4..]
+                    .starts_with(b"Exif\0\0")
+                    .then(|| get_exif_orientation(&segment[10..]))
+                    .flatten();
+                if let Some(orientation) = orientation.filter(|_| !has_orientation) {
+                    stripped.extend_from_slice(&get_exif_orientation_segment(orientation));
+                    has_orientation = true;
+                }
+            }
+            // IPTC and comments
+            0xed | 0xfe => {}
+            _ => stripped.extend_from_slice(segment),
+        }
+        position = end;
+    }
+}
+
+/// The orientation (other than the default one) in the EXIF data of a JPEG image, if any.
+fn get_exif_orientation(tiff: &[u8]) -> Option<u16> {
+    let big_endian = match tiff.get(..2)? {
+        b"MM" => true,
+        b"II" => false,
+        _ => return None,
+    };
+    let u16_at = |position: usize| {
+        let bytes = [*tiff.get(position)?, *tiff.get(position + 1)?];
+        Some(match big_endian {
+            true => u16::from_be_bytes(bytes),
+            false => u16::from_le_bytes(bytes),
+        })
+    };
+    let offset = tiff.get(4..8)?;
+    let offset = match big_endian {
+        true => u32::from_be_bytes(offset.try_into().ok()?),
+        false => u32::from_le_bytes(offset.try_into().ok()?),
+    } as usize;
+
+    (0..u16_at(offset)? as usize)
+        .map(|i| offset + 2 + i * 12)
+        .find(|entry| u16_at(*entry) == Some(0x0112))
+        .and_then(|entry| u16_at(entry + 8))
+        .filter(|orientation| (2..=8).contains(orientation))
+}
+
+/// An EXIF segment holding just the orientation of a JPEG image.
+fn get_exif_orientation_segment(orientation: u16) -> Vec<u8> {
+    let mut segment = vec![0xff, 0xe1, 0, 34];
+    segment.extend_from_slice(b"Exif\0\0MM\0\x2a\0\0\0\x08");
+    // a single entry, a SHORT, and no other IFD
+    segment.extend_from_slice(&[0, 1, 0x01, 0x12, 0, 3, 0, 0, 0, 1]);
+    segment.extend_from_slice(&orientation.to_be_bytes());
+    segment.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+    segment
+}
+
+fn strip_png(content: &[u8]) -> Option<Vec<u8>> {
+    const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+    if !content.starts_with(SIGNATURE) {
+        return None;
+    }
+    let mut stripped = SIGNATURE.to_vec();
+    let mut position = SIGNATURE.len();
+    while position < content.len() {
+        let length = u32::from_be_bytes(content.get(position..position + 4)?.try_into().ok()?);
+        let end = position.checked_add(12 + length as usize)?;
+        let chunk = content.get(position..end)?;
+        if !matches!(
+            &chunk[4..8],
+            b"eXIf" | b"tEXt" | b"zTXt" | b"iTXt" | b"tIME"
+        ) {
+            stripped.extend_from_slice(chunk);
+        }
+        position = end;
+    }
+
+    Some(stripped)
+}
+
+fn strip_webp(content: &[u8]) -> Option<Vec<u8>> {
+    if content.get(..4)? != b"RIFF" || content.get(8..12)? != b"WEBP" {
+        return None;
+    }
+    let mut stripped = content[..12].to_vec();
+    let mut position = 12;
+    while position < content.len() {
+        let length = u32::from_le_bytes(content.get(position + 4..position + 8)?.try_into().ok()?);
+        // chunks are padded to an even length
+        let end = position.checked_add(8 + length as usize + (length as usize & 1))?;
+        let chunk = content.get(position..end.min(content.len()))?;
+        match &chunk[..4] {
+            b"EXIF" | b"XMP " => {}
+            b"VP8X" => {
+                let mut chunk = chunk.to_vec();
+                // the flags saying there is EXIF and XMP metadata
+                *chunk.get_mut(8)? &= !0x0c;
+                stripped.extend_from_slice(&chunk);
+            }
+            _ => stripped.extend_from_slice(chunk),
+        }
+        position = end;
+    }
+    let riff_length = u32::try_from(stripped.len() - 8).ok()?;
+    stripped[4..8].copy_from_slice(&riff_length.to_le_bytes());
+
+    Some(stripped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_svg() {
+        let svg = |body: &str| format!("<svg xmlns=\"http://www.w3.org/2000/svg\">{}</svg>", body);
+        assert!(is_svg(b"<?xml version=\"1.0\"?>\n<svg></svg>"));
+        assert!(!is_svg(b"<html><svg></svg></html>"));
+
+        assert!(check_svg(svg("<circle r=\"5\" fill=\"red\"/>").as_bytes()).is_ok());
+        assert!(check_svg(svg("<a href=\"https://example.com/\">on = off</a>").as_bytes()).is_ok());
+        assert!(check_svg(svg("<script>alert(1)</script>").as_bytes()).is_err());
+        assert!(check_svg(svg("<SCRIPT>alert(1)</SCRIPT>").as_bytes()).is_err());
+        assert!(check_svg(svg("<rect onclick=\"alert(1)\"/>").as_bytes()).is_err());
+        assert!(check_svg(svg("<rect\nONLOAD = 'alert(1)'/>").as_bytes()).is_err());
+        assert!(
+            check_svg(svg("<a href=\"&#x6a;ava&#9;script:alert(1)\">x</a>").as_bytes()).is_err()
+        );
+        assert!(check_svg(
+            svg("<set attributeName=\"href\" to=\"javascript&colon;alert(1)\"/>").as_bytes()
+        )
+        .is_err());
+        assert!(check_svg(svg("<foreignObject><div/></foreignObject>").as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_strip_jpeg() {
+        // an APP0, an EXIF APP1 (GPS and all), with the orientation 6, a comment, then the image
+        let mut exif = b"Exif\0\0II\x2a\0\x08\0\0\0\x02\0".to_vec();
+        exif.extend_from_slice(&[0x12, 0x01, 3, 0, 1, 0, 0, 0, 6, 0, 0, 0]);
+        exif.extend_from_slice(&[0x25, 0x88, 4, 0, 1, 0, 0, 0, 0x26, 0, 0, 0]);
+        exif.extend_from_slice(&[0, 0, 0, 0]);
+        let mut jpeg = vec![0xff, 0xd8, 0xff, 0xe0, 0, 4, 1, 2];
+        jpeg.extend_from_slice(&[0xff, 0xe1]);
+        jpeg.extend_from_slice(&(exif.len() as u16 + 2).to_be_bytes());
+        jpeg.extend_from_slice(&exif);
+        jpeg.extend_from_slice(&[0xff, 0xfe, 0, 5, b'h', b'i', b'!']);
+        jpeg.extend_from_slice(&[0xff, 0xda, 0, 2, 0xaa, 0xff, 0xd9]);
+
+        let stripped = strip_metadata("image/jpeg", &jpeg).unwrap().unwrap();
+        let mut expected = vec![0xff, 0xd8, 0xff, 0xe0, 0, 4, 1, 2];
+        expected.extend_from_slice(&get_exif_orientation_segment(6));
+        expected.extend_from_slice(&[0xff, 0xda, 0, 2, 0xaa, 0xff, 0xd9]);
+        assert_eq!(stripped, expected);
+        assert_eq!(get_exif_orientation(&stripped[18..]), Some(6));
+
+        // nothing to strip
+        let mut plain = vec![0xff, 0xd8, 0xff, 0xe0, 0, 4, 1, 2];
+        plain.extend_from_slice(&[0xff, 0xda, 0, 2, 0xaa, 0xff, 0xd9]);
+        assert_eq!(strip_metadata("image/jpeg", &plain), Ok(None));
+        assert!(strip_metadata("image/jpeg", &[0xff, 0xd8, 0xff, 0xe1, 0, 9]).is_err());
+    }
+
+    #[test]
+    fn test_strip_png() {
+        let chunk = |kind: &[u8], data: &[u8]| {
+            let mut chunk = (data.len() as u32).to_be_bytes().to_vec();
+            chunk.extend_from_slice(kind);
+            chunk.extend_from_slice(data);
+            chunk.extend_from_slice(&[0; 4]); // the CRC isn't checked
+            chunk
+        };
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        png.extend(chunk(b"IHDR", &[0; 13]));
+        png.extend(chunk(b"eXIf", b"MM\0\x2a"));
+        png.extend(chunk(b"tEXt", b"Author\0me"));
+        png.extend(chunk(b"IEND", b""));
+
+        let mut expected = b"\x89PNG\r\n\x1a\n".to_vec();
+        expected.extend(chunk(b"IHDR", &[0; 13]));
+        expected.extend(chunk(b"IEND", b""));
+        assert_eq!(strip_metadata("image/png", &png), Ok(Some(expected)));
+        assert!(strip_metadata("image/png", &png[..20]).is_err());
+        assert_eq!(strip_metadata("image/gif", b"GIF89a"), Ok(None));
+    }
+
+    #[test]
+    fn test_strip_webp() {
+        let chunk = |kind: &[u8], data: &[u8]| {
+            let mut chunk = kind.to_vec();
+            chunk.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            chunk.extend_from_slice(data);
+            if data.len() % 2 == 1 {
+                chunk.push(0);
+            }
+            chunk
+        };
+        let webp = |chunks: Vec<Vec<u8>>| {
+            let chunks = chunks.concat();
+            let mut webp = b"RIFF".to_vec();
+            webp.extend_from_slice(&(chunks.len() as u32 + 4).to_le_bytes());
+            webp.extend_from_slice(b"WEBP");
+            webp.extend(chunks);
+            webp
+        };
+        let original = webp(vec![
+            chunk(b"VP8X", &[0x0c, 0, 0, 0, 0, 0, 0, 0, 0, 0]),
+            chunk(b"VP8 ", &[1, 2, 3]),
+            chunk(b"EXIF", b"MM\0\x2a\0"),
+            chunk(b"XMP ", b"<x/>"),
+        ]);
+        let expected = webp(vec![
+            chunk(b"VP8X", &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0]),
+            chunk(b"VP8 ", &[1, 2, 3]),
+        ]);
+        assert_eq!(strip_metadata("image/webp", &original), Ok(Some(expected)));
+    }
+}
@@ -3,30 +3,58 @@
 
 use std::collections::HashMap;
 use std::path::{Path, PathBuf};
+use std::sync::RwLock;
 
 use globset::Glob;
 use grass::{from_path as compile_file, Options, OutputStyle};
+use tide::log;
 use walkdir::{DirEntry, WalkDir};
 
 // https://github.com/getzola/zola/blob/master/components/site/src/sass.rs
 
-pub fn compile_sass(sass_path: &PathBuf) -> Result<HashMap<String, String>, String> {
+/// Compiles all the non-partial sass files under `sass_path`,
+/// returning the compiled resources along with the errors of the files that failed to compile.
+pub fn compile_sass(sass_path: &Path) -> (HashMap<String, String>, Vec<String>) {
     let mut resources = HashMap::new();
+    let mut errors = vec![];
 
     let options = Options::default().style(OutputStyle::Compressed);
-    let files = get_non_partial_scss(&sass_path);
+    let files = get_non_partial_scss(sass_path);
 
     for file in files {
         match compile_file(&file, &options) {
             Ok(css) => {
-                let path = file.strip_prefix(&sass_path).unwrap().with_extension("css");
-                resources.insert(format!("/{}", path.display().to_string()), css);
+                let path = file.strip_prefix(sass_path).unwrap().with_extension("css");
+                resources.insert(format!("/{}", path.display()), css);
             }
-            _ => return Err(format!("Error compiling file: {}", file.display())),
+            // grass errors point to the offending file, line and column
+            Err(e) => errors.push(format!("Error compiling file: {}\n{}", file.display(), e)),
         }
     }
 
-    Ok(resources)
+    (resources, errors)
+}
+
+/// Compiles the sass under `sass_path` (if any) into `resources`, logging any errors.
+/// Files that fail to compile are left out, so they are not served until fixed,
+/// but the rest of the files are, and so is the rest of the site.
+pub fn load_sass(sass_path: &Path, resources: &RwLock<HashMap<String, String>>) -> Vec<String> {
+    if !sass_path.exists() {
+        return vec![];
+    }
+
+    let (compiled, errors) = compile_sass(sass_path);
+
+    let mut resources = resources.write().unwrap();
+    for (k, v) in compiled {
+        log::debug!("Loaded sass resource: {}", k);
+        resources.insert(k, v);
+    }
+    for e in &errors {
+        log::warn!("{}", e);
+    }
+
+    errors
 }
 
 fn is_partial_scss(entry: &DirEntry) -> bool {
@@ -44,7 +72,7 @@ fn get_non_partial_scss(sass_path: &Path) -> Vec<PathBuf> {
 
     WalkDir::new(sass_path)
         .into_iter()
-        .filter_entry(|e| !is_partial_scss(e))
+        .filter_entry(|e| e.depth() == 0 || !is_partial_scss(e)) // the root may well be `_sass`
         .filter_map(|e| e.ok())
         .map(|e| e.into_path())
         .filter(|e| glob.is_match(e))
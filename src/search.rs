@@ -0,0 +1,137 @@
+use chrono::NaiveDateTime;
+use serde::Serialize;
+
+/// How many results `/search` returns at most.
+pub const MAX_RESULTS: usize = 20;
+
+/// A post or page, as listed in `/search_index.json`, for themes to search client-side
+/// (using elasticlunr, fuse.js or the like) or for `/search` to search server-side.
+#[derive(Clone, Serialize)]
+pub struct SearchDocument {
+    pub title: String,
+    pub url: String,
+    pub date: NaiveDateTime,
+    pub summary: Option<String>,
+    /// The text of the content, without any markup.
+    pub body: String,
+}
+
+/// A match of a search, which is the document without its body.
+#[derive(Serialize)]
+pub struct SearchResult<'a> {
+    pub title: &'a str,
+    pub url: &'a str,
+    pub date: NaiveDateTime,
+    pub summary: Option<&'a str>,
+}
+
+/// The text of some markdown, as words separated by spaces.
+pub fn md_to_text(md_content: &str) -> String {
+    let mut text = String::new();
+    for event in pulldown_cmark::Parser::new(md_content) {
+        if let pulldown_cmark::Event::Text(t) | pulldown_cmark::Event::Code(t) = event {
+            for word in t.split_whitespace() {
+                if !text.is_empty() {
+                    text.push(' ');
+                }
+                text.push_str(word);
+            }
+        }
+    }
+    text
+}
+
+fn get_terms(query: &str) -> Vec<String> {
+    query
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|t| !t.is_empty())
+        .map(|t| t.to_lowercase())
+        .collect()
+}
+
+/// How well `document` matches all of `terms`, if it does: matches in the title count more than in the body.
+fn get_score(document: &SearchDocument, terms: &[String]) -> Option<usize> {
+    let title = document.title.to_lowercase();
+    let body = document.body.to_lowercase();
+    terms.iter().try_fold(0, |score, term| {
+        let matches =
+            10 * title.matches(term.as_str()).count() + body.matches(term.as_str()).count();
+        (matches > 0).then_some(score + matches)
+    })
+}
+
+/// The documents containing all the words of `query`, best matches (then newest) first.
+pub fn search<'a>(documents: &'a [SearchDocument], query: &str) -> Vec<SearchResult<'a>> {
+    let terms = get_terms(query);
+    if terms.is_empty() {
+        return vec![];
+    }
+
+    let mut matches = documents
+        .iter()
+        .filter_map(|d| Some((get_score(d, &terms)?, d)))
+        .collect::<Vec<_>>();
+    matches.sort_by(|(s1, d1), (s2, d2)| s2.cmp(s1).then(d2.date.cmp(&d1.date)));
+    matches
+        .into_iter()
+        .take(MAX_RESULTS)
+        .map(|(_, d)| SearchResult {
+            title: &d.title,
+            url: &d.url,
+            date: d.date,
+            summary: d.summary.as_deref(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn document(title: &str, body: &str, day: u32) -> SearchDocument {
+        SearchDocument {
+            title: title.to_string(),
+            url: format!("https://example.com/{}", day),
+            date: chrono::NaiveDate::from_ymd_opt(2024, 1, day)
+                .unwrap()
+                .and_hms_opt(0, 0, 0)
+                .unwrap(),
+            summary: None,
+            body: body.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_md_to_text() {
+        assert_eq!(
+            md_to_text("# Hello\n\nSome *emphasis*,\n`code` and [a link](https://example.com)."),
+            "Hello Some emphasis , code and a link ."
+        );
+    }
+
+    #[test]
+    fn test_search() {
+        let documents = [
+            document("Rust", "Writing a CMS in Rust.", 1),
+            document("Nostr", "A CMS for Nostr, written in rust.", 2),
+            document("Cooking", "Nothing to see here.", 3),
+        ];
+        let urls = |query| {
+            search(&documents, query)
+                .iter()
+                .map(|r| r.url.to_owned())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            urls("rust"),
+            ["https://example.com/1", "https://example.com/2"]
+        );
+        assert_eq!(
+            urls("CMS"),
+            ["https://example.com/2", "https://example.com/1"]
+        );
+        assert_eq!(urls("nostr rust"), ["https://example.com/2"]);
+        assert!(urls("rust cooking").is_empty());
+        assert!(urls(" ,").is_empty());
+    }
+}
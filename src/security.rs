@@ -0,0 +1,107 @@
+use serde::{Deserialize, Serialize};
+
+/// What the default `Content-Security-Policy` of the pages forbids doesn't depend on the theme:
+/// plugins, changing the base URL the relative links resolve against and being framed by other sites.
+pub const DEFAULT_CONTENT_SECURITY_POLICY: &str =
+    "object-src 'none'; base-uri 'self'; frame-ancestors 'self'";
+
+/// The `Content-Security-Policy` of the admin interface, which uses the CDNs it is loaded from
+/// and talks to any relay or API, but is never framed.
+pub const ADMIN_CONTENT_SECURITY_POLICY: &str = "default-src 'self'; \
+    script-src 'self' 'unsafe-inline' 'unsafe-eval' https://cdn.tailwindcss.com https://unpkg.com; \
+    style-src 'self' 'unsafe-inline' https://cdn.jsdelivr.net; \
+    img-src * data: blob:; connect-src * ws: wss:; \
+    object-src 'none'; base-uri 'self'; frame-ancestors 'none'";
+
+/// The security headers of the pages: the `[security_headers]` of `servus.toml`, for all the sites,
+/// or the one of a site's `_config.toml`, replacing it for that site. Empty values are not sent.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct SecurityHeadersConfig {
+    #[serde(default = "default_content_security_policy")]
+    pub content_security_policy: String,
+    #[serde(default = "default_referrer_policy")]
+    pub referrer_policy: String,
+    /// The `max-age` of `Strict-Transport-Security`, sent over TLS only, in seconds (0 for none).
+    #[serde(default = "default_hsts_max_age")]
+    pub hsts_max_age: u64,
+}
+
+fn default_content_security_policy() -> String {
+    DEFAULT_CONTENT_SECURITY_POLICY.to_string()
+}
+
+fn default_referrer_policy() -> String {
+    "strict-origin-when-cross-origin".to_string()
+}
+
+fn default_hsts_max_age() -> u64 {
+    31536000 // a year
+}
+
+impl Default for SecurityHeadersConfig {
+    fn default() -> Self {
+        SecurityHeadersConfig {
+            content_security_policy: default_content_security_policy(),
+            referrer_policy: default_referrer_policy(),
+            hsts_max_age: default_hsts_max_age(),
+        }
+    }
+}
+
+impl SecurityHeadersConfig {
+    /// The headers of a response, which are only all sent for HTML,
+    /// and `Strict-Transport-Security` only if the request was made over TLS.
+    pub fn get_headers(&self, is_html: bool, is_tls: bool) -> Vec<(&'static str, String)> {
+        let mut headers = vec![("X-Content-Type-Options", "nosniff".to_string())];
+        if is_tls && self.hsts_max_age > 0 {
+            headers.push((
+                "Strict-Transport-Security",
+                format!("max-age={}", self.hsts_max_age),
+            ));
+        }
+        if is_html {
+            headers.push((
+                "Content-Security-Policy",
+                self.content_security_policy.to_owned(),
+            ));
+            headers.push(("Referrer-Policy", self.referrer_policy.to_owned()));
+        }
+
+        headers.retain(|(_, value)| !value.is_empty());
+        headers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_headers() {
+        let names = |headers: Vec<(&'static str, String)>| {
+            headers.into_iter().map(|(n, _)| n).collect::<Vec<_>>()
+        };
+        let config = SecurityHeadersConfig::default();
+        assert_eq!(
+            names(config.get_headers(true, true)),
+            [
+                "X-Content-Type-Options",
+                "Strict-Transport-Security",
+                "Content-Security-Policy",
+                "Referrer-Policy"
+            ]
+        );
+        assert_eq!(
+            names(config.get_headers(false, false)),
+            ["X-Content-Type-Options"]
+        );
+
+        let config: SecurityHeadersConfig =
+            toml::from_str("content_security_policy = \"\"\nhsts_max_age = 0").unwrap();
+        assert_eq!(config.referrer_policy, default_referrer_policy());
+        assert_eq!(
+            names(config.get_headers(true, true)),
+            ["X-Content-Type-Options", "Referrer-Policy"]
+        );
+    }
+}
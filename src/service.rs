@@ -0,0 +1,196 @@
+use sd_notify::NotifyState;
+use serde_json::json;
+use signal_hook::{
+    consts::{SIGHUP, SIGINT, SIGTERM},
+    iterator::Signals,
+};
+use std::{
+    collections::HashMap,
+    fs,
+    future::Future,
+    io, process,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use tide::{log, Middleware, Next, Request};
+use tide_websockets::{Message, WebSocketConnection};
+
+/// How long to wait for the requests being handled to complete when stopping.
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Lets process supervisors (such as systemd with `Type=notify`) know when we are actually ready,
+// which can be a while after startup, since all the sites need to be loaded first.
+// Without NOTIFY_SOCKET (not running under systemd), notifying does nothing.
+
+/// Signals readiness, writing our PID to `pid_file` if given.
+pub fn notify_ready(pid_file: Option<&str>) -> io::Result<()> {
+    if let Some(pid_file) = pid_file {
+        fs::write(pid_file, format!("{}\n", process::id()))?;
+    }
+
+    if let Err(e) = sd_notify::notify(false, &[NotifyState::Ready]) {
+        log::warn!("Cannot notify readiness: {}", e);
+    }
+
+    Ok(())
+}
+
+fn notify_stopping(pid_file: Option<&str>) {
+    if let Err(e) = sd_notify::notify(false, &[NotifyState::Stopping]) {
+        log::warn!("Cannot notify stopping: {}", e);
+    }
+
+    if let Some(pid_file) = pid_file {
+        if let Err(e) = fs::remove_file(pid_file) {
+            log::warn!("Cannot remove PID file {}: {}", pid_file, e);
+        }
+    }
+}
+
+/// Resolves when we are asked to terminate, after signaling that we are stopping (and removing `pid_file`).
+/// Being asked again exits right away, without waiting for anything.
+pub fn handle_shutdown(pid_file: Option<String>) -> io::Result<impl Future<Output = ()>> {
+    let mut signals = Signals::new([SIGINT, SIGTERM])?;
+    let (sender, receiver) = async_std::channel::bounded(1);
+
+    thread::spawn(move || {
+        let mut signals = signals.forever();
+        if let Some(signal) = signals.next() {
+            log::info!("Received signal {}. Stopping...", signal);
+            notify_stopping(pid_file.as_deref());
+            let _ = sender.try_send(());
+        }
+        if let Some(signal) = signals.next() {
+            log::warn!("Received signal {} again. Exiting!", signal);
+            process::exit(1);
+        }
+    });
+
+    Ok(async move {
+        let _ = receiver.recv().await;
+    })
+}
+
+/// Calls `reload` whenever we are asked to reload (SIGHUP).
+pub fn handle_reload<F: Fn() + Send + 'static>(reload: F) -> io::Result<()> {
+    let mut signals = Signals::new([SIGHUP])?;
+
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            log::info!("Received signal {}. Reloading...", signal);
+            if let Err(e) = sd_notify::notify(false, &[NotifyState::Reloading]) {
+                log::warn!("Cannot notify reloading: {}", e);
+            }
+            reload();
+            if let Err(e) = sd_notify::notify(false, &[NotifyState::Ready]) {
+                log::warn!("Cannot notify readiness: {}", e);
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Polls `is_done` until it returns true, for at most `timeout`. Returns whether it did.
+async fn wait_until(is_done: impl Fn() -> bool, timeout: Duration) -> bool {
+    let start = Instant::now();
+    while !is_done() {
+        if start.elapsed() >= timeout {
+            return false;
+        }
+        async_std::task::sleep(Duration::from_millis(50)).await;
+    }
+
+    true
+}
+
+/// Keeps count of the requests being handled, so we can let them complete before exiting.
+#[derive(Clone, Default)]
+pub struct InFlightRequests(Arc<AtomicUsize>);
+
+struct InFlightGuard(Arc<AtomicUsize>);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl InFlightRequests {
+    pub fn count(&self) -> usize {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// Waits for all the requests being handled to complete, for at most `timeout`.
+    /// Returns whether they all did.
+    pub async fn drain(&self, timeout: Duration) -> bool {
+        wait_until(|| self.count() == 0, timeout).await
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for InFlightRequests {
+    async fn handle(&self, request: Request<State>, next: Next<'_, State>) -> tide::Result {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        let _guard = InFlightGuard(self.0.clone());
+
+        Ok(next.run(request).await)
+    }
+}
+
+/// Keeps track of the open websocket connections, which are not requests being handled
+/// as far as the middleware is concerned, so they can be closed when stopping.
+#[derive(Clone, Default)]
+pub struct WebSockets {
+    connections: Arc<Mutex<HashMap<usize, WebSocketConnection>>>,
+    next_id: Arc<AtomicUsize>,
+}
+
+/// Forgets the connection when its handler returns.
+pub struct WebSocketGuard {
+    connections: Arc<Mutex<HashMap<usize, WebSocketConnection>>>,
+    id: usize,
+}
+
+impl Drop for WebSocketGuard {
+    fn drop(&mut self) {
+        self.connections.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl WebSockets {
+    /// Keeps track of `ws` for as long as the returned guard lives.
+    pub fn register(&self, ws: &WebSocketConnection) -> WebSocketGuard {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.connections.lock().unwrap().insert(id, ws.clone());
+
+        WebSocketGuard {
+            connections: self.connections.clone(),
+            id,
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.connections.lock().unwrap().len()
+    }
+
+    /// Sends a NOTICE with `message` to all the clients, then asks them to close the connection.
+    /// Messages being handled are not interrupted, the handlers return once the clients close.
+    pub async fn close_all(&self, message: &str) {
+        let connections: Vec<_> = self.connections.lock().unwrap().values().cloned().collect();
+        for ws in connections {
+            // the client might be gone already
+            let _ = ws.send_json(&json!(["NOTICE", message])).await;
+            let _ = ws.send(Message::Close(None)).await;
+        }
+    }
+
+    /// Waits for all the connections to be closed, for at most `timeout`. Returns whether they all were.
+    pub async fn drain(&self, timeout: Duration) -> bool {
+        wait_until(|| self.count() == 0, timeout).await
+    }
+}
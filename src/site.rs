@@ -1,26 +1,46 @@
-use chrono::{NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc};
+use chrono::{
+    format::{Item, StrftimeItems},
+    DateTime, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc,
+};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
 use serde::{Deserialize, Serialize};
 use std::{
     collections::HashMap,
     fs,
     fs::File,
-    io::BufReader,
-    path::PathBuf,
+    io::{self, BufReader, Read},
+    path::{Component, Path, PathBuf},
     str,
-    sync::{Arc, RwLock},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, OnceLock, RwLock,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tide::{
+    http::{url::Host, Url},
+    log,
 };
-use tide::log;
+use utoipa::ToSchema;
 use walkdir::WalkDir;
 
 const DEFAULT_THEME: &str = "hyde";
-pub const SITE_PATH: &str = "./sites";
+
+/// Whether the content of sites is only loaded when first needed, rather than as the sites are loaded.
+static LAZY_LOADING: AtomicBool = AtomicBool::new(false);
+
+pub fn set_lazy_loading(lazy_loading: bool) {
+    LAZY_LOADING.store(lazy_loading, Ordering::Relaxed);
+}
 
 use crate::{
-    content, nostr,
-    resource::{ContentSource, Resource, ResourceKind},
-    template, theme,
-    theme::ThemeConfig,
-    utils::merge,
+    activitypub, content, cors, gallery, git, lightning, newsletter, nostr, paths,
+    resource::{self, ContentSource, RenderCacheStats, Resource, ResourceKind},
+    sass, security, template,
+    theme::{Theme, ThemeConfig},
+    upstream,
+    utils::{self, merge, Shared},
+    webhooks,
 };
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -32,14 +52,224 @@ pub struct ServusMetadata {
 pub struct Site {
     pub domain: String,
     pub config: SiteConfig,
-    pub data: Arc<RwLock<HashMap<String, serde_yaml::Value>>>,
-    pub events: Arc<RwLock<HashMap<String, EventRef>>>,
-    pub resources: Arc<RwLock<HashMap<String, Resource>>>,
-    pub tera: Arc<RwLock<tera::Tera>>, // TODO: try to move this to Theme
+    pub content: Arc<Shared<SiteContent>>,
+    pub upstream_cache: Arc<upstream::UpstreamCache>,
+    pub sass_resources: Arc<RwLock<HashMap<String, String>>>, // compiled from the site's `_sass`
+    pub assets: template::Assets,
+    pub tera: Arc<tera::Tera>, // the theme's, unless the site has templates of its own
+    pub loading: Arc<Mutex<()>>, // held while the content is (un)loaded or updated
+    pub last_used: Arc<AtomicU64>, // in milliseconds since the epoch
+}
+
+/// What the admin interface shows about a site, as returned by [`Site::get_overview`].
+#[derive(Serialize, ToSchema)]
+pub struct SiteOverview {
+    pub posts: usize,
+    pub pages: usize,
+    pub notes: usize,
+    pub drafts: usize,
+    pub events: usize,
+    pub last_event_at: Option<i64>,
+    pub blobs: usize,    // uploaded files
+    pub blobs_size: u64, // in bytes
+    pub render_cache: RenderCacheStats,
+}
+
+/// The content of a site. It is never modified in place, but replaced as a whole (see [`Shared`])
+/// whenever it changes, so that readers always see a consistent version of the site.
+#[derive(Clone, Default)]
+pub struct SiteContent {
+    pub data: HashMap<String, serde_yaml::Value>,
+    pub events: HashMap<String, EventRef>,
+    pub resources: HashMap<String, Resource>,
+    pub redirects: HashMap<String, String>, // old URL -> resource URL
+    pub links: HashMap<String, Vec<String>>, // resource URL -> linked paths and event coordinates
+    pub permalink_history: PermalinkHistory,
+    pub file_drafts: usize, // hand-written posts, pages and notes marked as drafts, which are not published
+    pub template_data: OnceLock<Arc<resource::TemplateData>>, // built on first render
+    pub render_cache: Arc<resource::RenderCache>,
+    pub is_unloaded: bool, // to be read from disk when needed (see [`set_lazy_loading`])
+}
+
+impl SiteContent {
+    /// Adds a resource at the URL given by the site's permalink patterns,
+    /// redirecting the default URL, the URLs given by previous patterns and any aliases to it.
+    fn insert_resource(
+        &mut self,
+        config: &SiteConfig,
+        resource: Resource,
+        aliases: &[String],
+        links: Vec<String>,
+    ) -> Option<String> {
+        let timezone = config.get_timezone();
+        let url = resource.get_resource_url(&config.permalinks, timezone)?;
+        if let Some(existing) = self.resources.get(&url) {
+            if existing.kind != resource.kind || existing.slug != resource.slug {
+                log::warn!(
+                    "URL collision: {} ({:?} {}) replaces {:?} {}.",
+                    url,
+                    resource.kind,
+                    resource.slug,
+                    existing.kind,
+                    existing.slug
+                );
+            }
+        }
+        for old_url in resource.get_old_urls(&self.permalink_history, &config.permalinks, timezone)
+        {
+            if old_url != url {
+                self.redirects.insert(old_url, url.to_owned());
+            }
+        }
+        for alias in aliases.iter().map(|a| normalize_path(a)) {
+            if alias != url {
+                self.redirects.insert(alias, url.to_owned());
+            }
+        }
+        self.links.insert(url.to_owned(), links);
+        self.resources.insert(url.to_owned(), resource);
+
+        Some(url)
+    }
+
+    /// Removes a resource along with the redirects to it (from its old URLs and aliases),
+    /// keeping (or restoring) those configured in `_config.toml`.
+    fn remove_resource(&mut self, config: &SiteConfig, url: &str) {
+        self.resources.remove(url);
+        self.links.remove(url);
+        self.redirects.retain(|_, to| to != url);
+        for (from, to) in get_config_redirects(config) {
+            self.redirects.entry(from).or_insert(to);
+        }
+    }
 }
 
 fn default_feed_filename() -> String {
-    return "atom.xml".to_string();
+    "atom.xml".to_string()
+}
+
+fn default_trailing_slash() -> bool {
+    true
+}
+
+fn is_default_trailing_slash(trailing_slash: &bool) -> bool {
+    *trailing_slash
+}
+
+/// URL patterns for each kind of resource, such as `/blog/{year}/{slug}/` or `/{slug}.html`.
+/// Available variables: `{year}`, `{month}`, `{day}`, `{kind}`, `{slug}` and `{d_tag}`.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct PermalinkConfig {
+    pub post: Option<String>,
+    pub page: Option<String>,
+    pub note: Option<String>,
+}
+
+impl PermalinkConfig {
+    fn is_empty(&self) -> bool {
+        self.post.is_none() && self.page.is_none() && self.note.is_none()
+    }
+}
+
+/// Which kinds of resources a feed lists, each kind being listed or not as by default for the feed unless specified.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct FeedConfig {
+    pub posts: Option<bool>,
+    pub pages: Option<bool>,
+    pub notes: Option<bool>,
+}
+
+impl FeedConfig {
+    fn is_empty(&self) -> bool {
+        self.posts.is_none() && self.pages.is_none() && self.notes.is_none()
+    }
+
+    /// Whether the feed lists resources of `kind`, given the kinds it lists by default.
+    pub fn includes(&self, kind: ResourceKind, defaults: &[ResourceKind]) -> bool {
+        let included = match kind {
+            ResourceKind::Post => self.posts,
+            ResourceKind::Page => self.pages,
+            ResourceKind::Note => self.notes,
+        };
+        included.unwrap_or(defaults.contains(&kind))
+    }
+}
+
+/// The feeds of a site: `/atom.xml`, listing posts and pages by default, and `/notes/atom.xml`, listing notes.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct FeedsConfig {
+    #[serde(default, skip_serializing_if = "FeedConfig::is_empty")]
+    pub atom: FeedConfig,
+    #[serde(default, skip_serializing_if = "FeedConfig::is_empty")]
+    pub notes: FeedConfig,
+}
+
+impl FeedsConfig {
+    fn is_empty(&self) -> bool {
+        self.atom.is_empty() && self.notes.is_empty()
+    }
+}
+
+/// Limits set by the server's operator, which the site's owner can't change.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, ToSchema)]
+pub struct Quota {
+    /// Disk space the events and the uploaded files can take, in bytes.
+    pub storage_bytes: Option<u64>,
+}
+
+impl Quota {
+    fn is_empty(&self) -> bool {
+        self.storage_bytes.is_none()
+    }
+}
+
+/// Every permalink pattern ever used by a site, for each kind of resource,
+/// so that URLs given by previous patterns can keep redirecting to the current ones.
+/// Kept in the site's `_permalinks.toml`.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct PermalinkHistory {
+    #[serde(default)]
+    pub post: Vec<String>,
+    #[serde(default)]
+    pub page: Vec<String>,
+    #[serde(default)]
+    pub note: Vec<String>,
+}
+
+impl PermalinkHistory {
+    /// Adds the current patterns to the history, returning whether any of them was new.
+    fn record(&mut self, permalinks: &PermalinkConfig) -> bool {
+        let mut changed = false;
+        for (history, pattern) in [
+            (&mut self.post, &permalinks.post),
+            (&mut self.page, &permalinks.page),
+            (&mut self.note, &permalinks.note),
+        ] {
+            if let Some(pattern) = pattern {
+                if !history.contains(pattern) {
+                    history.push(pattern.to_owned());
+                    changed = true;
+                }
+            }
+        }
+
+        changed
+    }
+}
+
+fn load_permalink_history(domain: &str, permalinks: &PermalinkConfig) -> PermalinkHistory {
+    let path = format!("{}/{}/_permalinks.toml", paths::sites(), domain);
+    let mut history: PermalinkHistory = fs::read_to_string(&path)
+        .ok()
+        .and_then(|s| toml::from_str(&s).ok())
+        .unwrap_or_default();
+    if history.record(permalinks) {
+        if let Err(e) = fs::write(&path, toml::to_string(&history).unwrap()) {
+            log::warn!("Cannot save {}: {}", path, e);
+        }
+    }
+
+    history
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -53,6 +283,113 @@ pub struct SiteConfig {
     #[serde(default = "default_feed_filename")]
     pub feed_filename: String, // required by some themes
 
+    /// Sent as `Content-Language` and passed to templates as `lang`.
+    pub default_language: Option<String>,
+
+    /// Charset HTML responses are encoded in.
+    pub charset: Option<String>,
+
+    /// Certificate and key to serve the site with, instead of the ones passed using `--ssl-cert` and `--ssl-key`.
+    pub ssl_cert: Option<String>,
+    pub ssl_key: Option<String>,
+
+    /// Rules appended to the generated robots.txt.
+    pub robots_txt: Option<String>,
+
+    /// Served as `/.well-known/security.txt` (see RFC 9116).
+    pub security_txt: Option<String>,
+
+    /// Number of pages listed by `paginator.pages` on each pager of the index,
+    /// the following pagers being served at `/page/2/` and so on. By default, all the pages are listed.
+    pub paginate_by: Option<usize>,
+
+    /// How long rendered pages are served from the cache, in seconds (0 disables the cache).
+    /// By default, pages are only rendered again once the content of the site changes.
+    pub render_cache_ttl: Option<u64>,
+
+    /// Whether to serve `/search_index.json` and `/search?q=`, for themes to offer a search of the posts and pages.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub build_search_index: bool,
+
+    /// Whether `/e/<note1...>` renders events the site doesn't have, fetched from the `upstream_relays`, using the theme.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub render_external_events: bool,
+
+    /// Whether uploaded images are stripped of their metadata (such as where a photo was taken),
+    /// and SVG images are accepted, once checked for scripts (see [`crate::sanitize`]).
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub sanitize_uploads: bool,
+
+    /// Whether URLs (other than those of files, such as `/{slug}.html`) end with a slash, as in `/posts/hello/`,
+    /// the other form being redirected to it. Defaults to true.
+    #[serde(
+        default = "default_trailing_slash",
+        skip_serializing_if = "is_default_trailing_slash"
+    )]
+    pub trailing_slash: bool,
+
+    /// Timezone of the site, such as "Europe/Berlin", which dates are shown in (and permalinks use),
+    /// and which dates without a time (such as those of posts named after their date) are in. Defaults to UTC.
+    pub timezone: Option<String>,
+
+    /// Format dates are shown in by default, by the `date` filter and as `page.formatted_date`,
+    /// such as "%B %-d, %Y" (see [`chrono::format::strftime`]). Defaults to "%Y-%m-%d".
+    pub date_format: Option<String>,
+
+    /// Relays to query for events requested from this site's relay but not stored locally.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub upstream_relays: Vec<String>,
+
+    #[serde(default, skip_serializing_if = "PermalinkConfig::is_empty")]
+    pub permalinks: PermalinkConfig,
+
+    #[serde(default, skip_serializing_if = "FeedsConfig::is_empty")]
+    pub feeds: FeedsConfig,
+
+    #[serde(default, skip_serializing_if = "Quota::is_empty")]
+    pub quota: Quota,
+
+    /// Other domains the site is served at, once verified (see [`add_alias`]).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub aliases: Vec<String>,
+
+    /// The domain (one of the aliases) pages are redirected to when requested using any other.
+    pub canonical_domain: Option<String>,
+
+    /// Old paths to be permanently redirected to new paths or URLs.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub redirects: HashMap<String, String>,
+
+    /// Whether (and where) the changes to the site are committed, see [`git::GitConfig`].
+    pub git: Option<git::GitConfig>,
+
+    /// Whether the site's posts can be followed from the fediverse, see [`activitypub::ActivityPubConfig`].
+    pub activitypub: Option<activitypub::ActivityPubConfig>,
+
+    /// Whether visitors can subscribe to the site's posts by email, see [`newsletter::NewsletterConfig`].
+    pub newsletter: Option<newsletter::NewsletterConfig>,
+
+    /// Whether the uploaded images are listed at `/gallery/`, see [`gallery::GalleryConfig`].
+    pub gallery: Option<gallery::GalleryConfig>,
+
+    /// Which other sites can use this one's APIs from the browser, instead of the server's `[cors]`.
+    pub cors: Option<cors::CorsConfig>,
+
+    /// The security headers of the pages, instead of the server's `[security_headers]`.
+    pub security_headers: Option<security::SecurityHeadersConfig>,
+
+    /// Other NIP-05 names at the site's domain (the owner being `_`), mapped to their pubkeys (hex or npub).
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub nip05: HashMap<String, String>,
+
+    /// URLs notified of changes to the content, see [`webhooks::WebhookConfig`].
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub webhooks: Vec<webhooks::WebhookConfig>,
+
+    /// Lightning addresses at the site's domain, by name (see [`lightning::LightningAddress`]).
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub lightning: HashMap<String, lightning::LightningAddress>,
+
     #[serde(flatten)]
     pub extra: HashMap<String, toml::Value>,
 }
@@ -60,9 +397,81 @@ pub struct SiteConfig {
 impl SiteConfig {
     // https://github.com/getzola/zola/blob/master/components/config/src/config/mod.rs
 
+    /// The timezone dates are shown in, if any (otherwise UTC).
+    /// An unknown timezone is reported as the site is loaded, then ignored.
+    pub fn get_timezone(&self) -> Option<chrono_tz::Tz> {
+        self.timezone.as_ref()?.parse().ok()
+    }
+
+    /// The format dates are shown in by default. An invalid format is reported as the site is loaded, then ignored.
+    pub fn get_date_format(&self) -> &str {
+        match &self.date_format {
+            Some(format) if is_valid_date_format(format) => format,
+            _ => DEFAULT_DATE_FORMAT,
+        }
+    }
+
+    /// `date` (in UTC) in the site's timezone.
+    pub fn to_local_date(&self, date: &NaiveDateTime) -> DateTime<chrono_tz::Tz> {
+        self.get_timezone()
+            .unwrap_or(chrono_tz::UTC)
+            .from_utc_datetime(date)
+    }
+
+    /// `date`, given in the site's timezone, in UTC. Local times skipped by a change to daylight saving time
+    /// are taken as they are.
+    pub fn local_date_to_utc(&self, date: NaiveDateTime) -> NaiveDateTime {
+        self.get_timezone()
+            .unwrap_or(chrono_tz::UTC)
+            .from_local_datetime(&date)
+            .earliest()
+            .map_or(date, |d| d.naive_utc())
+    }
+
+    /// Problems with the timezone or the date format, which are ignored.
+    fn check_dates(&self) -> Vec<String> {
+        let mut warnings = vec![];
+        if let Some(timezone) = &self.timezone {
+            if self.get_timezone().is_none() {
+                warnings.push(format!("Unknown timezone: {}. Using UTC!", timezone));
+            }
+        }
+        if let Some(format) = &self.date_format {
+            if !is_valid_date_format(format) {
+                warnings.push(format!(
+                    "Invalid date format: {}. Using {}!",
+                    format, DEFAULT_DATE_FORMAT
+                ));
+            }
+        }
+        warnings
+    }
+
+    /// The config as exposed to templates, without the paths of files on the server.
+    /// NB: not skipped when serializing, since that's also how the config is saved.
+    pub fn for_templates(&self) -> SiteConfig {
+        SiteConfig {
+            ssl_cert: None,
+            ssl_key: None,
+            webhooks: vec![],
+            newsletter: self
+                .newsletter
+                .as_ref()
+                .map(|n| newsletter::NewsletterConfig {
+                    smtp_url: None,
+                    ..n.clone()
+                }),
+            ..self.clone()
+        }
+    }
+
     /// Makes a url, taking into account that the base url might have a trailing slash
     pub fn make_permalink(&self, path: &str) -> String {
-        let trailing_bit = if path.ends_with('/') || path.ends_with("atom.xml") || path.is_empty() {
+        let trailing_bit = if !self.trailing_slash
+            || path.ends_with('/')
+            || path.ends_with("atom.xml")
+            || path.is_empty()
+        {
             ""
         } else {
             "/"
@@ -92,50 +501,320 @@ impl SiteConfig {
             merge(self.extra.get_mut(key).unwrap(), value).unwrap();
         }
     }
+
+    /// Overrides `[extra]` with the theme settings published by the admin UI.
+    pub fn apply_theme_settings(&mut self, settings: &toml::Value) {
+        let mut extra = settings.clone();
+        if let Some(existing) = self.extra.get("extra") {
+            // settings take priority, the existing values only fill in the gaps
+            if merge(&mut extra, existing).is_err() {
+                log::warn!("Cannot merge theme settings into [extra].");
+                return;
+            }
+        }
+        self.extra.insert("extra".to_string(), extra);
+    }
 }
 
-fn load_templates(site_config: &SiteConfig) -> tera::Tera {
-    println!("Loading templates...");
+/// Compiles the site's own sass from `_sass`, which is served alongside (and takes precedence over) the theme's.
+fn load_sass(domain: &str) -> Arc<RwLock<HashMap<String, String>>> {
+    let sass_resources = Arc::new(RwLock::new(HashMap::new()));
+    let errors = sass::load_sass(
+        Path::new(&format!("{}/{}/_sass", paths::sites(), domain)),
+        &sass_resources,
+    );
+    if !errors.is_empty() {
+        println!("Failed to compile {} sass file(s)!", errors.len());
+    }
+
+    sass_resources
+}
+
+/// The assets templates can refer to, with the site's own taking precedence over the theme's.
+fn load_assets(
+    domain: &str,
+    site_config: &SiteConfig,
+    themes: &HashMap<String, Theme>,
+    sass_resources: &Arc<RwLock<HashMap<String, String>>>,
+) -> template::Assets {
+    let theme_resources = themes
+        .get(&site_config.theme)
+        .map(|t| t.resources.clone())
+        .unwrap_or_default();
+
+    template::Assets::new(
+        format!("{}/{}", paths::sites(), domain),
+        vec![sass_resources.clone(), theme_resources],
+    )
+}
+
+/// The templates of the site's theme, shared with the other sites using it, unless the site has templates
+/// of its own, which take precedence over the ones with the same name in the theme.
+fn load_templates(domain: &str, theme: &Theme) -> Result<Arc<tera::Tera>, String> {
+    let site_templates_path = format!("{}/{}/_templates", paths::sites(), domain);
+    if !Path::new(&site_templates_path).exists() {
+        return Ok(theme.tera.clone());
+    }
 
-    let theme_path = format!("./themes/{}", site_config.theme);
+    println!("Loading templates...");
 
-    let mut tera = tera::Tera::new(&format!("{}/templates/**/*", theme_path)).unwrap();
+    // https://github.com/getzola/zola/blob/master/components/site/src/tpls.rs
+    let mut tera = tera::Tera::parse(&format!("{}/**/*", site_templates_path))
+        .map_err(template::format_error)?;
+    tera.extend(&theme.tera).map_err(template::format_error)?;
+    tera.build_inheritance_chains()
+        .map_err(template::format_error)?;
     tera.autoescape_on(vec![]);
-    tera.register_function("get_url", template::GetUrl::new(site_config.clone()));
 
     println!("Loaded {} templates!", tera.get_template_names().count());
 
-    tera
+    Ok(Arc::new(tera))
 }
 
 impl Site {
+    /// A snapshot of the site's content, unaffected by any later updates.
+    /// The content is read from disk first if it is not loaded.
+    pub fn get_content(&self) -> Arc<SiteContent> {
+        let content = self.content.load();
+        if !content.is_unloaded {
+            return content;
+        }
+
+        let _loading = self.loading.lock().unwrap();
+        self.ensure_loaded()
+    }
+
+    /// Reads the content from disk unless it is loaded already, with `loading` held.
+    fn ensure_loaded(&self) -> Arc<SiteContent> {
+        let content = self.content.load();
+        if !content.is_unloaded {
+            return content; // by another thread, while we were waiting
+        }
+        log::info!("Loading content of site: {}...", self.domain);
+        self.content.replace(self.read_content());
+
+        self.content.load()
+    }
+
+    pub fn is_loaded(&self) -> bool {
+        !self.content.load().is_unloaded
+    }
+
+    /// Drops the content, to be read from disk again when next needed.
+    pub fn unload(&self) {
+        let _loading = self.loading.lock().unwrap();
+        log::info!("Unloading content of site: {}...", self.domain);
+        self.content.replace(SiteContent {
+            is_unloaded: true,
+            ..Default::default()
+        });
+    }
+
+    /// Records that the site is being used, so that it is not unloaded before the sites used less recently.
+    pub fn touch(&self) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        self.last_used
+            .store(now.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// `path` relative to the site's directory.
+    fn get_relative_path<'a>(&self, path: &'a str) -> &'a str {
+        let site_path = format!("{}/{}/", paths::sites(), self.domain);
+        path.strip_prefix(&site_path).unwrap_or(path)
+    }
+
+    /// Commits the changes made to the site, if it has git enabled.
+    pub fn record_change(&self, message: String) {
+        if let Some(git) = &self.config.git {
+            git::record_change_in_background(&self.domain, git, message);
+        }
+    }
+
+    /// Whether the events and the uploaded files take more space than the site's quota allows.
+    pub fn is_over_quota(&self) -> bool {
+        self.config
+            .quota
+            .storage_bytes
+            .is_some_and(|storage_bytes| self.get_stats().1 > storage_bytes)
+    }
+
+    /// An overview of the site's content, uploaded files and render cache.
+    pub fn get_overview(&self) -> SiteOverview {
+        let content = self.get_content();
+        let count = |kind: ResourceKind| {
+            content
+                .resources
+                .values()
+                .filter(|r| r.kind == kind)
+                .count()
+        };
+        let files_path = format!("{}/{}/_content/files", paths::sites(), self.domain);
+        let blobs = fs::read_dir(files_path)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .filter(|e| {
+                let name = e.file_name().to_string_lossy().to_string();
+                name.len() == 64 && name.chars().all(|c| c.is_ascii_hexdigit())
+            })
+            .filter_map(|e| e.metadata().ok())
+            .map(|m| m.len())
+            .collect::<Vec<_>>();
+
+        SiteOverview {
+            posts: count(ResourceKind::Post),
+            pages: count(ResourceKind::Page),
+            notes: count(ResourceKind::Note),
+            drafts: content
+                .events
+                .values()
+                .filter(|e| e.kind == nostr::EVENT_KIND_LONG_FORM_DRAFT)
+                .count()
+                + content.file_drafts,
+            events: content.events.len(),
+            last_event_at: content.events.values().map(|e| e.created_at).max(),
+            blobs: blobs.len(),
+            blobs_size: blobs.iter().sum(),
+            render_cache: content.render_cache.get_stats(),
+        }
+    }
+
+    /// The number of events stored and the disk space taken by them and by the uploaded files, in bytes.
+    pub fn get_stats(&self) -> (usize, u64) {
+        let content = self.get_content();
+        let events_size = content
+            .events
+            .values()
+            .filter_map(|e| fs::metadata(&e.filename).ok())
+            .map(|m| m.len())
+            .sum::<u64>();
+
+        (content.events.len(), events_size + self.get_files_size())
+    }
+
+    /// The disk space taken by the uploaded files, in bytes.
+    pub fn get_files_size(&self) -> u64 {
+        let files_path = format!("{}/{}/_content/files", paths::sites(), self.domain);
+        WalkDir::new(files_path)
+            .into_iter()
+            .filter_map(|e| e.ok()?.metadata().ok())
+            .filter(|m| m.is_file())
+            .map(|m| m.len())
+            .sum::<u64>()
+    }
+
+    /// Applies `update` to a copy of the site's content, then swaps the copy in.
+    /// Other updates wait for this one, so `update` should not do any I/O.
+    fn update_content<T>(&self, update: impl FnOnce(&mut SiteContent) -> T) -> T {
+        let _loading = self.loading.lock().unwrap();
+        self.ensure_loaded();
+        self.content.update(|new_content| {
+            new_content.template_data = OnceLock::new(); // no longer up to date
+            new_content.render_cache = Arc::default();
+            update(new_content)
+        })
+    }
+
+    /// The site as it would be with `theme`, to preview it: the content is the same,
+    /// but pages are rendered using the theme's templates, into caches of their own.
+    pub fn with_theme(
+        &self,
+        theme_name: &str,
+        themes: &HashMap<String, Theme>,
+    ) -> Result<Site, String> {
+        let theme = themes
+            .get(theme_name)
+            .ok_or_else(|| format!("Unknown theme: {}", theme_name))?;
+        let mut config = load_config(&format!("{}/{}/_config.toml", paths::sites(), self.domain))?;
+        config.theme = theme_name.to_owned();
+        config.merge(&theme.config);
+
+        let mut content = (*self.get_content()).clone();
+        content.template_data = OnceLock::new(); // holds the templates of the current theme
+        content.render_cache = Arc::default();
+        let assets = load_assets(&self.domain, &config, themes, &self.sass_resources)
+            .with_preview_theme(theme_name);
+        let tera = load_templates(&self.domain, theme)?;
+
+        Ok(Site {
+            config,
+            content: Arc::new(Shared::new(content)),
+            assets,
+            tera,
+            ..self.clone()
+        })
+    }
+
+    /// (Re)loads the site's content from disk, replacing the current content only once fully loaded.
     pub fn load_resources(&self) {
-        let mut root = PathBuf::from(format!("{}/{}", SITE_PATH, self.domain));
+        let _loading = self.loading.lock().unwrap();
+        self.content.replace(self.read_content());
+    }
+
+    fn read_content(&self) -> SiteContent {
+        let mut site_content = SiteContent {
+            redirects: get_config_redirects(&self.config),
+            permalink_history: load_permalink_history(&self.domain, &self.config.permalinks),
+            ..Default::default()
+        };
+        let mut root = PathBuf::from(format!("{}/{}", paths::sites(), self.domain));
         root.push("_content/");
         if !root.as_path().exists() {
-            return;
+            return site_content;
         }
         for entry in WalkDir::new(&root) {
-            let path = entry.unwrap().into_path();
+            let path = match entry {
+                Ok(entry) => entry.into_path(),
+                Err(e) => {
+                    log::warn!("Skipping: {}", e);
+                    continue;
+                }
+            };
             if !path.is_file() {
                 continue;
             }
             let relative_path = path.strip_prefix(&root).unwrap();
-            if relative_path.starts_with("files/") {
+            if relative_path.starts_with("files/") || relative_path.starts_with("well-known/") {
                 continue;
             }
-            println!("Scanning file {}...", path.display());
-            let file = File::open(&path).unwrap();
+            if path.extension().is_some_and(|e| e == "part") {
+                log::warn!(
+                    "Skipping {}, which was not completely written.",
+                    path.display()
+                );
+                continue;
+            }
+            log::debug!("Scanning file {}...", path.display());
+            let file = match File::open(&path) {
+                Ok(file) => file,
+                Err(e) => {
+                    log::warn!("Skipping {}: {}", path.display(), e);
+                    continue;
+                }
+            };
             let mut reader = BufReader::new(file);
             let filename = path.to_str().unwrap().to_string();
-            let (front_matter, content) = content::read(&mut reader).unwrap();
+            let (front_matter, content) = match content::read(&mut reader) {
+                Ok(r) => r,
+                Err(e) => {
+                    log::warn!("Skipping {}: {}", path.display(), e);
+                    continue;
+                }
+            };
+            let event = nostr::parse_event(&front_matter, &content);
+            if event.is_none() && front_matter.contains_key("sig") {
+                log::warn!("Skipping {}: Invalid event", path.display());
+                continue;
+            }
             let mut kind: Option<ResourceKind> = None;
             let mut title: Option<String> = None;
             let mut date: Option<NaiveDateTime> = None;
             let mut slug: Option<String> = None;
+            let aliases: Vec<String>;
+            let noindex: bool;
             let content_source: ContentSource;
-            if let Some(event) = nostr::parse_event(&front_matter, &content) {
-                println!("Event: id={}.", &event.id);
+            if let Some(event) = event {
+                log::debug!("Event: id={}.", &event.id);
                 let event_ref = EventRef {
                     id: event.id.to_owned(),
                     created_at: event.created_at,
@@ -143,21 +822,17 @@ impl Site {
                     d_tag: event.get_d_tag(),
                     filename,
                 };
-                let mut events = self.events.write().unwrap();
-                events.insert(event.id.to_owned(), event_ref.clone());
+                site_content
+                    .events
+                    .insert(event.id.to_owned(), event_ref.clone());
 
                 kind = get_resource_kind(&event);
+                aliases = event.get_tag_values("alias");
+                noindex = is_event_noindex(&event);
                 if kind.is_some() {
                     title = event.get_tags_hash().get("title").cloned();
-                    if title.is_none() && front_matter.contains_key("title") {
-                        title = Some(
-                            front_matter
-                                .get("title")
-                                .unwrap()
-                                .as_str()
-                                .unwrap()
-                                .to_string(),
-                        );
+                    if title.is_none() {
+                        title = get_front_matter_title(&front_matter);
                     };
                     date = Some(event.get_date());
                     if let Some(long_form_slug) = event.get_d_tag() {
@@ -170,28 +845,35 @@ impl Site {
                 content_source = ContentSource::Event(event_ref.id.to_owned());
             } else {
                 let file_stem = relative_path.file_stem().unwrap().to_str().unwrap();
+                if is_front_matter_draft(&front_matter) && !relative_path.starts_with("data") {
+                    log::debug!("Skipping draft: {}.", path.display());
+                    site_content.file_drafts += 1;
+                    continue;
+                }
                 // TODO: extract path patterns from config
                 if relative_path.starts_with("data") {
-                    println!("Data: id={}.", file_stem);
-                    let data: serde_yaml::Value = serde_yaml::from_str(&content).unwrap();
-                    let mut site_data = self.data.write().unwrap();
-                    site_data.insert(file_stem.to_string(), data);
+                    log::debug!("Data: id={}.", file_stem);
+                    let data: serde_yaml::Value = match serde_yaml::from_str(&content) {
+                        Ok(data) => data,
+                        Err(e) => {
+                            log::warn!("Skipping {}: {}", path.display(), e);
+                            continue;
+                        }
+                    };
+                    site_content.data.insert(file_stem.to_string(), data);
                 } else if relative_path.starts_with("posts") {
-                    let date_part = &file_stem[0..10];
+                    let date_part = file_stem.get(0..10).unwrap_or_default();
                     if let Ok(d) = NaiveDate::parse_from_str(date_part, "%Y-%m-%d") {
-                        if front_matter.contains_key("title") {
+                        if let Some(t) = get_front_matter_title(&front_matter) {
                             kind = Some(ResourceKind::Post);
+                            // the start of the day where the site is
                             let midnight = NaiveTime::from_hms_opt(0, 0, 0).unwrap();
-                            title = Some(
-                                front_matter
-                                    .get("title")
-                                    .unwrap()
-                                    .as_str()
-                                    .unwrap()
-                                    .to_string(),
+                            title = Some(t);
+                            date = Some(
+                                self.config
+                                    .local_date_to_utc(NaiveDateTime::new(d, midnight)),
                             );
-                            date = Some(NaiveDateTime::new(d, midnight));
-                            slug = Some(file_stem[11..].to_owned());
+                            slug = Some(file_stem.get(11..).unwrap_or_default().to_owned());
                         } else {
                             println!("Post missing title: {}", file_stem);
                         }
@@ -199,35 +881,25 @@ impl Site {
                         println!("Cannot parse post date from filename: {}", file_stem);
                     };
                 } else if relative_path.starts_with("pages") {
-                    if front_matter.contains_key("title") {
+                    if let Some(t) = get_front_matter_title(&front_matter) {
                         kind = Some(ResourceKind::Page);
-                        date = front_matter.get("created_at").map(|c| {
-                            Utc.timestamp_opt(c.as_i64().unwrap(), 0)
-                                .unwrap()
-                                .naive_utc()
-                        });
+                        date = get_front_matter_created_at(&front_matter);
                         slug = Some(file_stem.to_owned());
-                        title = Some(
-                            front_matter
-                                .get("title")
-                                .unwrap()
-                                .as_str()
-                                .unwrap()
-                                .to_string(),
-                        );
+                        title = Some(t);
                     } else {
                         println!("Page missing title: {}", file_stem);
                     }
                 } else if relative_path.starts_with("notes") {
                     kind = Some(ResourceKind::Note);
-                    date = front_matter.get("created_at").map(|c| {
-                        Utc.timestamp_opt(c.as_i64().unwrap(), 0)
-                            .unwrap()
-                            .naive_utc()
-                    });
+                    date = get_front_matter_created_at(&front_matter);
                     slug = Some(file_stem.to_owned());
                 }
 
+                aliases = get_front_matter_aliases(&front_matter);
+                noindex = front_matter
+                    .get("noindex")
+                    .and_then(|n| n.as_bool())
+                    .unwrap_or(false);
                 content_source = ContentSource::File(filename);
             }
             if let (Some(kind), Some(date), Some(slug)) = (kind, date, slug) {
@@ -236,15 +908,19 @@ impl Site {
                     title,
                     date,
                     slug,
+                    noindex,
                     content_source,
                 };
-                if let Some(url) = resource.get_resource_url() {
-                    println!("Resource: url={}.", &url);
-                    let mut resources = self.resources.write().unwrap();
-                    resources.insert(url, resource);
+                let links = resource.get_links(&self.config, &site_content);
+                if let Some(url) =
+                    site_content.insert_resource(&self.config, resource, &aliases, links)
+                {
+                    log::debug!("Resource: url={}.", &url);
                 }
             }
         }
+
+        site_content
     }
 
     fn get_path(
@@ -255,10 +931,13 @@ impl Site {
         event_d_tag: Option<String>,
     ) -> Option<String> {
         // TODO: read all this from config
-        let mut path = PathBuf::from(format!("{}/{}", SITE_PATH, self.domain));
+        let mut path = PathBuf::from(format!("{}/{}", paths::sites(), self.domain));
         path.push("_content/");
         path.push(match (event_kind, resource_kind) {
             (nostr::EVENT_KIND_CUSTOM_DATA, _) => format!("data/{}.md", event_d_tag.unwrap()),
+            (nostr::EVENT_KIND_THEME_SETTINGS, _) => {
+                format!("settings/{}.md", event_d_tag.unwrap())
+            }
             (_, Some(ResourceKind::Post)) => format!("posts/{}.md", event_d_tag.unwrap()),
             (_, Some(ResourceKind::Page)) => format!("pages/{}.md", event_d_tag.unwrap()),
             (_, Some(ResourceKind::Note)) => format!("notes/{}.md", event_id),
@@ -268,6 +947,27 @@ impl Site {
         Some(path.display().to_string())
     }
 
+    /// The version of `event` stored already, if any: the event itself, or a newer version of it
+    /// (with the same kind and `d` tag), in which case storing it would only rewrite the file, or bring back an old version.
+    pub fn get_stored_version(&self, event: &nostr::Event) -> Option<StoredVersion> {
+        let content = self.get_content();
+        if content.events.contains_key(&event.id) {
+            return Some(StoredVersion::Same);
+        }
+        if !event.is_parameterized_replaceable() {
+            return None;
+        }
+        let d_tag = event.get_d_tag();
+        content
+            .events
+            .values()
+            .any(|event_ref| {
+                event_ref.is_replaced_by(event.kind, &d_tag)
+                    && !event_ref.is_older_than(event.created_at, &event.id)
+            })
+            .then_some(StoredVersion::Newer)
+    }
+
     pub fn add_content(&self, event: &nostr::Event) {
         let event_d_tag = event.get_d_tag();
         let kind = get_resource_kind(event);
@@ -280,7 +980,16 @@ impl Site {
         let filename = self
             .get_path(event.kind, &kind, &event.id, event_d_tag.clone())
             .unwrap();
-        event.write(&filename).unwrap();
+        let is_update = Path::new(&filename).exists();
+        if let Err(e) = event.write(&filename) {
+            log::error!("Cannot write {}: {}", filename, e);
+            return;
+        }
+        let change = format!(
+            "{} {}",
+            if is_update { "Update" } else { "Add" },
+            self.get_relative_path(&filename)
+        );
         let event_ref = EventRef {
             id: event.id.to_owned(),
             created_at: event.created_at,
@@ -288,147 +997,369 @@ impl Site {
             d_tag: event_d_tag.to_owned(),
             filename,
         };
+        let links = resource::get_content_links(&self.config, &event.content, Some(event));
 
-        let mut events = self.events.write().unwrap();
+        let resource = self.update_content(|content| {
+            let events = &mut content.events;
 
-        if event.is_parameterized_replaceable() {
-            let mut matched_event_id: Option<String> = None;
-            {
-                if event_d_tag.is_some() {
+            if event.is_parameterized_replaceable() {
+                let mut matched_event_id: Option<String> = None;
+                {
                     for event_ref in events.values() {
-                        if event_ref.d_tag == event_d_tag {
+                        if event_ref.is_replaced_by(event.kind, &event_d_tag) {
                             matched_event_id = Some(event_ref.id.to_owned());
                         }
                     }
                 }
+                if let Some(matched_event_id) = matched_event_id {
+                    log::info!("Removing (outdated) event: {}!", &matched_event_id);
+                    events.remove(&matched_event_id);
+                }
             }
-            if let Some(matched_event_id) = matched_event_id {
-                log::info!("Removing (outdated) event: {}!", &matched_event_id);
-                events.remove(&matched_event_id);
-            }
-        }
 
-        events.insert(event.id.to_owned(), event_ref.clone());
+            events.insert(event.id.to_owned(), event_ref.clone());
 
-        if let Some(kind) = kind {
             let resource = Resource {
-                kind,
+                kind: kind?,
                 title: event.get_tags_hash().get("title").cloned(),
                 date: event.get_date(),
                 slug,
+                noindex: is_event_noindex(event),
                 content_source: ContentSource::Event(event.id.to_owned()),
             };
 
-            if let Some(url) = resource.get_resource_url() {
-                // but not all posts have an URL (drafts don't)
-                let mut resources = self.resources.write().unwrap();
-                resources.insert(url.to_owned(), resource);
+            // but not all posts have an URL (drafts don't)
+            content.insert_resource(
+                &self.config,
+                resource.clone(),
+                &event.get_tag_values("alias"),
+                links,
+            );
+
+            Some(resource)
+        });
+        self.record_change(change);
+
+        if let Some(resource) = resource.filter(|_| event.kind != nostr::EVENT_KIND_LONG_FORM_DRAFT)
+        {
+            let action = if is_update { "updated" } else { "published" };
+            webhooks::notify_resource(self, &resource, action);
+        }
+
+        // drafts and updates are not federated, only newly published posts
+        if self.config.activitypub.is_some()
+            && event.kind == nostr::EVENT_KIND_LONG_FORM
+            && !is_update
+        {
+            activitypub::deliver_post_in_background(self, &event.id);
+        }
+        if self.config.newsletter.is_some()
+            && event.kind == nostr::EVENT_KIND_LONG_FORM
+            && !is_update
+        {
+            newsletter::send_post_in_background(self, &event.id);
+        }
+    }
+
+    /// The stored events matching `filter`, up to its `limit`.
+    pub fn query_events(&self, filter: &nostr::Filter) -> Vec<nostr::Event> {
+        let mut events = vec![];
+        if !self
+            .config
+            .pubkey
+            .as_ref()
+            .is_some_and(|p| filter.matches_author(p))
+        {
+            return events; // all the events stored are by the site's author
+        }
+        for event_ref in self.get_content().events.values() {
+            if filter.matches_id(&event_ref.id)
+                && filter.matches_kind(&event_ref.kind)
+                && filter.matches_time(&event_ref.created_at)
+            {
+                let Some((front_matter, content)) = event_ref.read() else {
+                    continue;
+                };
+                if let Some(event) = nostr::parse_event(&front_matter, &content) {
+                    if filter.matches_event(&event) {
+                        events.push(event);
+                        if filter.limit.is_some_and(|limit| events.len() >= limit) {
+                            break;
+                        }
+                    }
+                }
             }
         }
+
+        events
+    }
+
+    /// The settings for the site's current theme, published as an event
+    /// whose `d` tag is the theme name and whose content is a JSON object.
+    /// They are read from disk, since they are needed before the content is loaded.
+    pub fn get_theme_settings(&self) -> Option<toml::Value> {
+        let filename = self.get_path(
+            nostr::EVENT_KIND_THEME_SETTINGS,
+            &None,
+            "",
+            Some(self.config.theme.to_owned()),
+        )?;
+        let file = File::open(&filename).ok()?;
+        let (front_matter, content) = content::read(&mut BufReader::new(file))
+            .map_err(|e| log::warn!("Cannot read {}: {}", filename, e))
+            .ok()?;
+        let event = nostr::parse_event(&front_matter, &content)?;
+        let settings = serde_json::from_str::<serde_json::Value>(&event.content)
+            .ok()
+            .filter(|v| v.is_object())
+            .and_then(|v| toml::Value::try_from(v).ok());
+        if settings.is_none() {
+            log::warn!("Cannot parse theme settings: {}.", event.id);
+        }
+
+        settings
     }
 
     pub fn remove_content(&self, deletion_event: &nostr::Event) -> bool {
         let mut deleted_event_id: Option<String> = None;
         let mut deleted_event_kind: Option<u64> = None;
         let mut deleted_event_d_tag: Option<String> = None;
-        for tag in &deletion_event.tags {
-            if tag[0] == "e" {
-                deleted_event_id = Some(tag[1].to_owned());
-                log::debug!("DELETE 'e' {}", tag[1]);
-            }
-            if tag[0] == "a" {
-                let deleted_event_ref = tag[1].to_owned();
-                let parts = deleted_event_ref.split(':').collect::<Vec<_>>();
-                if parts.len() == 3 {
-                    if parts[1] != deletion_event.pubkey {
-                        // TODO: do we need to check the site owner here?
-                        return false;
-                    }
-                    deleted_event_kind = Some(parts[0].parse::<u64>().unwrap());
-                    deleted_event_d_tag = Some(parts[2].to_owned());
-                    log::debug!("DELETE 'a' {}", deleted_event_ref);
+        for tag in deletion_event.get_tags("e") {
+            deleted_event_id = Some(tag[1].to_owned());
+            log::debug!("DELETE 'e' {} (relay: {:?})", tag[1], tag.get(2));
+        }
+        for tag in deletion_event.get_tags("a") {
+            let deleted_event_ref = tag[1].to_owned();
+            let parts = deleted_event_ref.split(':').collect::<Vec<_>>();
+            if parts.len() == 3 {
+                if parts[1] != deletion_event.pubkey {
+                    // TODO: do we need to check the site owner here?
+                    return false;
                 }
+                let Ok(kind) = parts[0].parse::<u64>() else {
+                    log::info!("Invalid 'a' tag: {}.", deleted_event_ref);
+                    continue;
+                };
+                deleted_event_kind = Some(kind);
+                deleted_event_d_tag = Some(parts[2].to_owned());
+                log::debug!("DELETE 'a' {} (relay: {:?})", deleted_event_ref, tag.get(2));
             }
         }
 
-        let mut resource_url: Option<String> = None;
-        let mut resource_kind: Option<ResourceKind> = None;
-        {
-            let resources = self.resources.read().unwrap();
-            for (url, resource) in &*resources {
-                if let ContentSource::Event(event_id) = resource.content_source.clone() {
-                    let mut matched_resource = false;
-
-                    if deleted_event_kind.is_some() && deleted_event_d_tag.is_some() {
-                        let events = self.events.read().unwrap();
-                        let event_ref = events.get(&event_id).unwrap();
-                        if event_ref.kind == deleted_event_kind.unwrap()
-                            && event_ref.d_tag == deleted_event_d_tag
+        let mut removed_resource: Option<Resource> = None;
+        let path = self.update_content(|content| {
+            let mut resource_url: Option<String> = None;
+            let mut resource_kind: Option<ResourceKind> = None;
+            {
+                for (url, resource) in &content.resources {
+                    if let ContentSource::Event(event_id) = resource.content_source.clone() {
+                        let mut matched_resource = false;
+
+                        if let (Some(deleted_event_kind), Some(_)) =
+                            (deleted_event_kind, &deleted_event_d_tag)
                         {
+                            let event_ref = content.events.get(&event_id).unwrap();
+                            if event_ref.kind == deleted_event_kind
+                                && event_ref.d_tag == deleted_event_d_tag
+                            {
+                                matched_resource = true;
+                            }
+                        } else if deleted_event_id.is_some() && Some(event_id) == deleted_event_id {
                             matched_resource = true;
                         }
-                    } else if deleted_event_id.is_some() {
-                        if Some(event_id) == deleted_event_id {
-                            matched_resource = true;
-                        }
-                    }
 
-                    if matched_resource {
-                        resource_url = Some(url.to_owned());
-                        resource_kind = Some(resource.kind);
+                        if matched_resource {
+                            resource_url = Some(url.to_owned());
+                            resource_kind = Some(resource.kind);
+                            removed_resource = Some(resource.clone());
+                        }
                     }
                 }
             }
-        }
 
-        let mut matched_event_id: Option<String> = None;
-        let mut path: Option<String> = None;
-        {
-            let events = self.events.read().unwrap();
-            for (event_id, event_ref) in &*events {
-                let mut matched_event = false;
-                if deleted_event_kind.is_some() && deleted_event_d_tag.is_some() {
-                    if event_ref.kind == deleted_event_kind.unwrap()
-                        && event_ref.d_tag == deleted_event_d_tag
+            let mut matched_event_id: Option<String> = None;
+            let mut path: Option<String> = None;
+            {
+                for (event_id, event_ref) in &content.events {
+                    let mut matched_event = false;
+                    if let (Some(deleted_event_kind), Some(_)) =
+                        (deleted_event_kind, &deleted_event_d_tag)
+                    {
+                        if event_ref.kind == deleted_event_kind
+                            && event_ref.d_tag == deleted_event_d_tag
+                        {
+                            matched_event = true;
+                        }
+                    } else if deleted_event_id.is_some()
+                        && event_id == &deleted_event_id.clone().unwrap()
                     {
                         matched_event = true;
                     }
-                } else if deleted_event_id.is_some() {
-                    if event_id == &deleted_event_id.clone().unwrap() {
-                        matched_event = true;
+
+                    if matched_event {
+                        matched_event_id = Some(event_ref.id.to_owned());
+                        path = self.get_path(
+                            event_ref.kind,
+                            &resource_kind,
+                            event_id,
+                            event_ref.d_tag.clone(),
+                        );
                     }
                 }
+            }
 
-                if matched_event {
-                    matched_event_id = Some(event_ref.id.to_owned());
-                    path = self.get_path(
-                        event_ref.kind,
-                        &resource_kind,
-                        event_id,
-                        event_ref.d_tag.clone(),
-                    );
-                }
+            if let Some(resource_url) = resource_url {
+                log::info!("Removing resource: {}!", &resource_url);
+                content.remove_resource(&self.config, &resource_url);
             }
-        }
 
-        if let Some(resource_url) = resource_url {
-            log::info!("Removing resource: {}!", &resource_url);
-            self.resources.write().unwrap().remove(&resource_url);
-        }
+            if let Some(matched_event_id) = matched_event_id {
+                log::info!("Removing event: {}!", &matched_event_id);
+                content.events.remove(&matched_event_id);
+            }
 
-        if let Some(matched_event_id) = matched_event_id {
-            log::info!("Removing event: {}!", &matched_event_id);
-            self.events.write().unwrap().remove(&matched_event_id);
-        }
+            path
+        });
 
         if let Some(path) = path {
             log::info!("Removing file: {}!", &path);
-            fs::remove_file(path).is_ok()
+            let is_removed = fs::remove_file(&path).is_ok();
+            if is_removed {
+                self.record_change(format!("Delete {}", self.get_relative_path(&path)));
+                if let Some(resource) = removed_resource {
+                    webhooks::notify_resource(self, &resource, "deleted");
+                }
+            }
+            is_removed
         } else {
             log::info!("No file for this resource!");
             false
         }
     }
+
+    /// The file the post or page with `slug` is stored in, and whether it was published as an event.
+    pub fn get_source_file(&self, kind: ResourceKind, slug: &str) -> Option<(String, bool)> {
+        let content = self.get_content();
+        let resource = content
+            .resources
+            .values()
+            .find(|r| r.kind == kind && r.slug == slug)?;
+        match &resource.content_source {
+            ContentSource::File(filename) => Some((filename.to_owned(), false)),
+            ContentSource::Event(event_id) => {
+                Some((content.events.get(event_id)?.filename.to_owned(), true))
+            }
+            ContentSource::String(_) => None,
+        }
+    }
+
+    /// Saves `text`, made of front matter and markdown, as the post or page with `slug`.
+    /// Posts are stored as `posts/<date>-<slug>.md`, the date being the one in the front matter,
+    /// else the one of the post being replaced, else today.
+    /// Content published as an event can only be replaced by publishing another event.
+    pub fn save_file(&self, kind: ResourceKind, slug: &str, text: &str) -> io::Result<()> {
+        let invalid = |e: &str| io::Error::new(io::ErrorKind::InvalidInput, e.to_owned());
+        if !is_valid_slug(slug) {
+            return Err(invalid("Invalid slug"));
+        }
+        let (front_matter, content) =
+            content::read(&mut text.as_bytes()).map_err(|e| invalid(&e))?;
+        if nostr::parse_event(&front_matter, &content).is_some() {
+            return Err(invalid("Events are to be published to the relay"));
+        }
+        if !front_matter.get("title").is_some_and(|t| t.is_string()) {
+            return Err(invalid("Missing title"));
+        }
+
+        let existing = self.get_source_file(kind, slug);
+        if let Some((_, true)) = existing {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "Published as an event",
+            ));
+        }
+        let dir = format!("{}/{}/_content/", paths::sites(), self.domain);
+        let filename = match kind {
+            ResourceKind::Post => {
+                let parse_date = |d: &str| NaiveDate::parse_from_str(d.get(..10)?, "%Y-%m-%d").ok();
+                let date = front_matter
+                    .get("date")
+                    .and_then(|d| d.as_str())
+                    .and_then(parse_date)
+                    .or_else(|| {
+                        let (filename, _) = existing.as_ref()?;
+                        parse_date(Path::new(filename).file_name()?.to_str()?)
+                    })
+                    .unwrap_or_else(|| Utc::now().date_naive());
+                format!("{}posts/{}-{}.md", dir, date.format("%Y-%m-%d"), slug)
+            }
+            ResourceKind::Page => format!("{}pages/{}.md", dir, slug),
+            _ => return Err(invalid("Only posts and pages can be saved")),
+        };
+
+        fs::create_dir_all(Path::new(&filename).parent().unwrap())?;
+        fs::write(&filename, text)?;
+        if let Some((existing_filename, _)) = &existing {
+            if existing_filename != &filename {
+                fs::remove_file(existing_filename)?;
+            }
+        }
+        log::info!("Saved file: {}!", &filename);
+        self.load_resources();
+
+        let content = self.get_content();
+        if let Some(resource) = content
+            .resources
+            .values()
+            .find(|r| r.kind == kind && r.slug == slug)
+        {
+            let action = if existing.is_some() {
+                "updated"
+            } else {
+                "published"
+            };
+            webhooks::notify_resource(self, resource, action);
+        }
+
+        Ok(())
+    }
+
+    /// Deletes the post or page with `slug`.
+    /// Content published as an event can only be deleted by publishing a deletion event.
+    pub fn delete_file(&self, kind: ResourceKind, slug: &str) -> io::Result<()> {
+        match self.get_source_file(kind, slug) {
+            Some((filename, false)) => {
+                let resource = self
+                    .get_content()
+                    .resources
+                    .values()
+                    .find(|r| r.kind == kind && r.slug == slug)
+                    .cloned();
+                log::info!("Removing file: {}!", &filename);
+                fs::remove_file(filename)?;
+                self.load_resources();
+                if let Some(resource) = resource {
+                    webhooks::notify_resource(self, &resource, "deleted");
+                }
+                Ok(())
+            }
+            Some((_, true)) => Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "Published as an event",
+            )),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "No such post or page",
+            )),
+        }
+    }
+}
+
+/// What [`Site::get_stored_version`] finds.
+#[derive(Debug, PartialEq)]
+pub enum StoredVersion {
+    Same,
+    Newer,
 }
 
 #[derive(Clone, Serialize)]
@@ -441,83 +1372,685 @@ pub struct EventRef {
     pub filename: String,
 }
 
+fn is_long_form_kind(kind: u64) -> bool {
+    kind == nostr::EVENT_KIND_LONG_FORM || kind == nostr::EVENT_KIND_LONG_FORM_DRAFT
+}
+
 impl EventRef {
+    /// Whether a (parameterized replaceable) event of `kind` with `d_tag` replaces this one.
+    /// Publishing a draft replaces it (and the other way around), even though the kinds differ,
+    /// but events of unrelated kinds (such as a post and the theme settings) never replace each other.
+    fn is_replaced_by(&self, kind: u64, d_tag: &Option<String>) -> bool {
+        self.d_tag.is_some()
+            && self.d_tag == *d_tag
+            && (self.kind == kind || (is_long_form_kind(self.kind) && is_long_form_kind(kind)))
+    }
+
+    /// Whether this event is replaced by a version created at `created_at` with `id`,
+    /// the one with the lowest ID being kept when both were created at the same time (as NIP-01 says).
+    fn is_older_than(&self, created_at: i64, id: &str) -> bool {
+        (self.created_at, id) < (created_at, &self.id)
+    }
+
     pub fn read(&self) -> Option<(HashMap<String, serde_yaml::Value>, String)> {
-        let file = File::open(&self.filename).unwrap();
+        let file = File::open(&self.filename)
+            .map_err(|e| log::warn!("Cannot open {}: {}", self.filename, e))
+            .ok()?;
         let mut reader = BufReader::new(file);
 
         content::read(&mut reader)
+            .map_err(|e| log::warn!("Cannot read {}: {}", self.filename, e))
+            .ok()
     }
 }
 
-pub fn save_config(path: &str, config: SiteConfig) {
-    fs::write(path, toml::to_string(&config).unwrap()).unwrap();
+pub fn save_config(path: &str, config: SiteConfig) -> io::Result<()> {
+    let content = toml::to_string(&config).map_err(io::Error::other)?;
+    fs::write(path, content)
+}
+
+pub const DEFAULT_DATE_FORMAT: &str = "%Y-%m-%d";
+
+/// Whether dates can be formatted using `format` (chrono panics when given an invalid one).
+pub fn is_valid_date_format(format: &str) -> bool {
+    !StrftimeItems::new(format).any(|item| item == Item::Error)
+}
+
+pub fn load_config(config_path: &str) -> Result<SiteConfig, String> {
+    let content = fs::read_to_string(config_path)
+        .map_err(|e| format!("Cannot read {}: {}", config_path, e))?;
+
+    toml::from_str(&content).map_err(|e| format!("Invalid {}: {}", config_path, e))
+}
+
+/// Keys of `_config.toml` which can't be changed by the site's owner using the API,
+/// being about the server or about who owns the site, or having the server connect to other hosts
+/// (which, on a server hosting sites of several owners, could be ones of its private network).
+/// Keys of tables are given as `<table>.<key>`.
+const PROTECTED_CONFIG_KEYS: &[&str] = &[
+    "pubkey",
+    "ssl_cert",
+    "ssl_key",
+    "quota",
+    "aliases",
+    "canonical_domain",
+    "webhooks",
+    "upstream_relays",
+    "lightning",
+    "newsletter.smtp_url",
+    "git.remote",
+];
+
+/// The value of `key` (which may be `<table>.<key>`) in `table`.
+fn get_config_value<'a>(table: &'a toml::Table, key: &str) -> Option<&'a toml::Value> {
+    match key.split_once('.') {
+        Some((parent, key)) => table.get(parent)?.as_table()?.get(key),
+        None => table.get(key),
+    }
+}
+
+/// Sets `key` (which may be `<table>.<key>`) to `value` in `table`, removing it if None.
+fn set_config_value(table: &mut toml::Table, key: &str, value: Option<toml::Value>) {
+    let (table, key) = match key.split_once('.') {
+        Some((parent, key)) => match (table.get_mut(parent), &value) {
+            (Some(toml::Value::Table(parent)), _) => (parent, key),
+            (_, None) => return,
+            (_, Some(_)) => {
+                table.insert(parent.to_owned(), toml::Value::Table(toml::Table::new()));
+                (table[parent].as_table_mut().unwrap(), key)
+            }
+        },
+        None => (table, key),
+    };
+    match value {
+        Some(value) => table.insert(key.to_owned(), value),
+        None => table.remove(key),
+    };
+}
+
+/// Puts back the protected keys of `table` as they are in `previous`, returning the ones that were changed.
+fn restore_protected_keys(table: &mut toml::Table, previous: &toml::Table) -> Vec<&'static str> {
+    let mut changed = vec![];
+    for key in PROTECTED_CONFIG_KEYS {
+        let value = get_config_value(previous, key);
+        if get_config_value(table, key) != value {
+            set_config_value(table, key, value.cloned());
+            changed.push(*key);
+        }
+    }
+
+    changed
+}
+
+/// The site's `_config.toml`, as written, without the theme's config merged into it.
+pub fn read_config_table(domain: &str) -> io::Result<toml::Table> {
+    fs::read_to_string(format!("{}/{}/_config.toml", paths::sites(), domain))?
+        .parse()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
 }
 
-pub fn load_config(config_path: &str) -> Option<SiteConfig> {
-    if let Ok(content) = fs::read_to_string(config_path) {
-        Some(toml::from_str(&content).unwrap())
+/// Applies `changes` to the site's `_config.toml`, removing the keys set to null, then loads the site again.
+/// The config is left as it was if it ends up invalid or the site fails to load with it.
+pub fn update_config(
+    domain: &str,
+    changes: serde_json::Map<String, serde_json::Value>,
+    themes: &HashMap<String, Theme>,
+) -> io::Result<Site> {
+    let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidInput, e);
+    let previous = read_config_table(domain)?;
+    let mut table = previous.clone();
+    for (key, value) in changes {
+        if value.is_null() {
+            table.remove(&key);
+        } else {
+            let value = toml::Value::try_from(value)
+                .map_err(|e| invalid(format!("Invalid {}: {}", key, e)))?;
+            table.insert(key, value);
+        }
+    }
+    if let Some(key) = restore_protected_keys(&mut table, &previous).first() {
+        return Err(invalid(format!("Cannot change {}", key)));
+    }
+
+    let config: SiteConfig = toml::Value::Table(table.clone())
+        .try_into()
+        .map_err(|e| invalid(format!("Invalid config: {}", e)))?;
+    if !Url::parse(&config.base_url).is_ok_and(|u| matches!(u.scheme(), "http" | "https")) {
+        return Err(invalid(format!("Invalid base_url: {}", config.base_url)));
+    }
+    if !themes.contains_key(&config.theme) {
+        return Err(invalid(format!("Unknown theme: {}", config.theme)));
+    }
+
+    write_config_table(domain, table, themes)
+}
+
+/// Replaces the site's `_config.toml` with `table`, then loads the site again.
+/// The previous config is restored if the site fails to load with the new one.
+fn write_config_table(
+    domain: &str,
+    table: toml::Table,
+    themes: &HashMap<String, Theme>,
+) -> io::Result<Site> {
+    let config_path = format!("{}/{}/_config.toml", paths::sites(), domain);
+    let previous = fs::read_to_string(&config_path)?;
+    fs::write(&config_path, table.to_string())?;
+    let site = load_site(domain, themes).map_err(|e| {
+        let _ = fs::write(&config_path, previous);
+        io::Error::new(io::ErrorKind::InvalidInput, e)
+    })?;
+    site.record_change("Update _config.toml".to_owned());
+
+    Ok(site)
+}
+
+/// Puts back the keys the site's owner can't change (see [`PROTECTED_CONFIG_KEYS`]) as they were in `previous`,
+/// once the `_config.toml` was replaced by other means than the API, such as pulling it from git.
+pub fn restore_protected_config(domain: &str, previous: &toml::Table) -> io::Result<()> {
+    let mut table = read_config_table(domain)?;
+    if !restore_protected_keys(&mut table, previous).is_empty() {
+        log::warn!("Restoring the protected config of {}.", domain);
+        fs::write(
+            format!("{}/{}/_config.toml", paths::sites(), domain),
+            table.to_string(),
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Sets the site's quota in its `_config.toml`, then loads the site again.
+pub fn set_quota(domain: &str, quota: Quota, themes: &HashMap<String, Theme>) -> io::Result<Site> {
+    let mut table = read_config_table(domain)?;
+    if quota.is_empty() {
+        table.remove("quota");
     } else {
-        None
+        let value = toml::Value::try_from(quota)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+        table.insert("quota".to_owned(), value);
     }
+
+    write_config_table(domain, table, themes)
 }
 
-pub fn load_site(domain: &str) -> Site {
-    let path = format!("{}/{}", SITE_PATH, domain);
-    let config = load_config(&format!("{}/_config.toml", path));
-    if config.is_none() {
-        println!("No site config for site: {}. Skipping!", path);
+/// Adds `alias` to the domains the site is served at, then loads the site again.
+/// If `canonical`, pages are redirected to the alias, which the `base_url` is changed to.
+pub fn add_alias(
+    domain: &str,
+    alias: &str,
+    canonical: bool,
+    themes: &HashMap<String, Theme>,
+) -> io::Result<Site> {
+    let mut table = read_config_table(domain)?;
+    let aliases = table.entry("aliases").or_insert(toml::Value::Array(vec![]));
+    if let toml::Value::Array(aliases) = aliases {
+        if !aliases.iter().any(|a| a.as_str() == Some(alias)) {
+            aliases.push(alias.into());
+        }
+    }
+    if canonical {
+        let base_url = table.get("base_url").and_then(|b| b.as_str()).unwrap_or("");
+        let base_url = get_cloned_base_url(base_url, alias);
+        table.insert("base_url".to_owned(), base_url.into());
+        table.insert("canonical_domain".to_owned(), alias.into());
     }
 
-    let mut config = config.unwrap();
+    write_config_table(domain, table, themes)
+}
 
-    let theme_path = format!("./themes/{}", config.theme);
-    let theme_config = theme::load_config(&format!("{}/config.toml", theme_path)).unwrap();
+/// Stops serving the site at `alias`, then loads the site again.
+/// If pages were redirected to it, they no longer are and the `base_url` is changed back to the site's domain.
+pub fn remove_alias(
+    domain: &str,
+    alias: &str,
+    themes: &HashMap<String, Theme>,
+) -> io::Result<Site> {
+    let mut table = read_config_table(domain)?;
+    let Some(toml::Value::Array(aliases)) = table.get_mut("aliases") else {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "No such alias"));
+    };
+    let count = aliases.len();
+    aliases.retain(|a| a.as_str() != Some(alias));
+    if aliases.len() == count {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "No such alias"));
+    }
+    if aliases.is_empty() {
+        table.remove("aliases");
+    }
+    if table.get("canonical_domain").and_then(|c| c.as_str()) == Some(alias) {
+        table.remove("canonical_domain");
+        let base_url = table.get("base_url").and_then(|b| b.as_str()).unwrap_or("");
+        let base_url = get_cloned_base_url(base_url, domain);
+        table.insert("base_url".to_owned(), base_url.into());
+    }
+
+    write_config_table(domain, table, themes)
+}
 
-    config.merge(&theme_config);
+/// Loads a site's config, templates, sass and content.
+/// Fails if the config or the templates are broken, in which case nothing is loaded.
+pub fn load_site(domain: &str, themes: &HashMap<String, Theme>) -> Result<Site, String> {
+    let path = format!("{}/{}", paths::sites(), domain);
+    let mut config = load_config(&format!("{}/_config.toml", path))?;
+
+    let theme = themes
+        .get(&config.theme)
+        .ok_or_else(|| format!("Unknown theme: {}", config.theme))?;
+    config.merge(&theme.config);
+    for warning in config.check_dates() {
+        log::warn!("{}: {}", domain, warning);
+    }
 
-    let tera = load_templates(&config);
+    let sass_resources = load_sass(domain);
+    let assets = load_assets(domain, &config, themes, &sass_resources);
+    let tera = load_templates(domain, theme)?;
 
-    let site = Site {
+    let mut site = Site {
         domain: domain.to_owned(),
         config,
-        data: Arc::new(RwLock::new(HashMap::new())),
-        events: Arc::new(RwLock::new(HashMap::new())),
-        resources: Arc::new(RwLock::new(HashMap::new())),
-        tera: Arc::new(RwLock::new(tera)),
+        content: Arc::new(Shared::default()),
+        upstream_cache: Arc::new(upstream::UpstreamCache::default()),
+        sass_resources,
+        assets,
+        tera,
+        loading: Arc::default(),
+        last_used: Arc::default(),
     };
 
-    site.load_resources();
+    if LAZY_LOADING.load(Ordering::Relaxed) {
+        site.content.replace(SiteContent {
+            is_unloaded: true,
+            ..Default::default()
+        });
+    } else {
+        site.load_resources();
+    }
 
-    site
+    if let Some(settings) = site.get_theme_settings() {
+        site.config.apply_theme_settings(&settings);
+    }
+
+    Ok(site)
+}
+
+/// Problems found when checking a site, without loading it.
+#[derive(Default)]
+pub struct SiteCheck {
+    /// Problems preventing the site from loading.
+    pub errors: Vec<String>,
+    /// Problems with parts of the site, which is loaded without them.
+    pub warnings: Vec<String>,
+}
+
+/// Checks a site's config, templates, sass and the front matter of its content files,
+/// reporting all the problems found rather than stopping at the first one.
+pub fn check_site(domain: &str, themes: &HashMap<String, Theme>) -> SiteCheck {
+    let mut check = SiteCheck::default();
+    let path = format!("{}/{}", paths::sites(), domain);
+
+    let mut config = match load_config(&format!("{}/_config.toml", path)) {
+        Ok(config) => config,
+        Err(e) => {
+            check.errors.push(e);
+            return check;
+        }
+    };
+    match themes.get(&config.theme) {
+        Some(theme) => config.merge(&theme.config),
+        None => check
+            .errors
+            .push(format!("Unknown theme: {}", config.theme)),
+    }
+    check.warnings.extend(config.check_dates());
+
+    let sass_path = PathBuf::from(format!("{}/_sass", path));
+    if sass_path.exists() {
+        check.warnings.extend(sass::compile_sass(&sass_path).1);
+    }
+    if let Some(theme) = themes.get(&config.theme) {
+        if let Err(e) = load_templates(domain, theme) {
+            check.errors.push(e);
+        }
+    }
+
+    let root = PathBuf::from(format!("{}/_content", path));
+    for entry in WalkDir::new(&root).into_iter().filter_map(|e| e.ok()) {
+        let relative_path = entry.path().strip_prefix(&root).unwrap();
+        if !entry.file_type().is_file() || relative_path.starts_with("files/") {
+            continue;
+        }
+        let result = File::open(entry.path())
+            .map_err(|e| e.to_string())
+            .and_then(|f| content::read(&mut BufReader::new(f)));
+        if let Err(e) = result {
+            check
+                .warnings
+                .push(format!("{}: {}", entry.path().display(), e));
+        }
+    }
+
+    check
 }
 
-pub fn load_sites() -> HashMap<String, Site> {
-    let paths = match fs::read_dir(SITE_PATH) {
-        Ok(paths) => paths.map(|r| r.unwrap()).collect(),
+pub fn load_sites(themes: &HashMap<String, Theme>) -> HashMap<String, Site> {
+    let paths = match fs::read_dir(paths::sites()) {
+        Ok(paths) => paths
+            .map(|r| r.unwrap())
+            .filter(|p| !p.file_name().to_string_lossy().starts_with('.'))
+            .collect(),
         _ => vec![],
     };
 
-    let mut sites = HashMap::new();
-    for path in &paths {
-        let file_name = path.file_name();
-        let domain = file_name.to_str().unwrap();
+    // sites are independent of each other, so they are loaded in parallel
+    let sites = utils::par_map(paths, |path: fs::DirEntry| {
+        let domain = path.file_name().to_str().unwrap().to_string();
 
         log::info!("Found site: {}!", domain);
-        sites.insert(
-            path.file_name().to_str().unwrap().to_string(),
-            load_site(&domain),
-        );
-        log::debug!("Site loaded!");
-    }
+        match load_site(&domain, themes) {
+            Ok(site) => {
+                log::debug!("Site loaded: {}!", domain);
+                Some((domain, site))
+            }
+            Err(e) => {
+                log::error!("Failed to load site {}: {}. Skipping!", domain, e);
+                None
+            }
+        }
+    })
+    .into_iter()
+    .flatten()
+    .collect::<HashMap<_, _>>();
 
     println!("{} sites loaded!", sites.len());
 
     sites
 }
 
-pub fn create_site(domain: &str, admin_pubkey: Option<String>) -> Site {
-    let path = format!("{}/{}", SITE_PATH, domain);
+/// Unloads the least recently used of the `sites` whose content is loaded, so that at most `max_loaded` remain.
+pub fn unload_least_recently_used(sites: &HashMap<String, Site>, max_loaded: usize) {
+    let mut loaded = sites.values().filter(|s| s.is_loaded()).collect::<Vec<_>>();
+    if loaded.len() <= max_loaded {
+        return;
+    }
+    loaded.sort_by_key(|s| s.last_used.load(Ordering::Relaxed));
+    for site in &loaded[..loaded.len() - max_loaded] {
+        site.unload();
+    }
+}
+
+/// The site served at `domain`, either as its own domain or as one of its aliases.
+pub fn find_site<'a>(sites: &'a HashMap<String, Site>, domain: &str) -> Option<&'a Site> {
+    sites.get(domain).or_else(|| {
+        sites
+            .values()
+            .find(|s| s.config.aliases.iter().any(|a| a == domain))
+    })
+}
+
+/// Whether `domain` is a valid host name, and therefore safe to use as a directory name.
+pub fn is_valid_domain(domain: &str) -> bool {
+    domain.len() <= 253
+        && domain.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+/// Top-level domains that never resolve on the Internet (see RFC 2606 and RFC 6761).
+const RESERVED_TLDS: &[&str] = &[
+    "arpa",
+    "example",
+    "internal",
+    "invalid",
+    "local",
+    "localdomain",
+    "localhost",
+    "test",
+];
+
+/// `domain` lowercased, without a trailing dot and with its non-ASCII labels converted to punycode,
+/// which is how browsers send it in the `Host` header. `None` if it is not a host name at all.
+pub fn normalize_domain(domain: &str) -> Option<String> {
+    match Host::parse(domain.strip_suffix('.').unwrap_or(domain)) {
+        Ok(Host::Domain(domain)) => Some(domain),
+        _ => None,
+    }
+}
+
+/// Whether `domain` is directly under `parent`, and therefore covered by a wildcard certificate for `*.<parent>`.
+pub fn is_subdomain_of(domain: &str, parent: &str) -> bool {
+    domain
+        .strip_suffix(parent)
+        .and_then(|name| name.strip_suffix('.'))
+        .is_some_and(|name| !name.is_empty() && !name.contains('.'))
+}
+
+/// Whether `domain` is only meant for local use, as are names without a dot (such as `localhost`)
+/// and names under a reserved top-level domain (such as `.local` or `.test`).
+pub fn is_reserved_domain(domain: &str) -> bool {
+    match domain.rsplit_once('.') {
+        Some((_, tld)) => RESERVED_TLDS.contains(&tld),
+        None => true,
+    }
+}
+
+/// Whether `slug` can be used as the name of a content file, without getting out of its directory.
+pub fn is_valid_slug(slug: &str) -> bool {
+    !slug.is_empty()
+        && !slug.starts_with(['.', '-'])
+        && slug
+            .chars()
+            .all(|c| c.is_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+/// The base URL of a site cloned to `new_domain`, with everything but the host kept as it was.
+fn get_cloned_base_url(base_url: &str, new_domain: &str) -> String {
+    let mut url = match Url::parse(base_url) {
+        Ok(url) => url,
+        Err(_) => return format!("https://{}", new_domain),
+    };
+    if url.set_host(Some(new_domain)).is_err() {
+        return format!("https://{}", new_domain);
+    }
+
+    let mut new_base_url = url.to_string();
+    if !base_url.ends_with('/') && url.path() == "/" {
+        new_base_url.pop();
+    }
+    new_base_url
+}
+
+/// Packs the site's directory into a `.tar.gz` file in the archives directory, returning its path.
+pub fn archive_site(domain: &str) -> io::Result<String> {
+    fs::create_dir_all(paths::archives())?;
+    let archive_path = format!(
+        "{}/{}-{}.tar.gz",
+        paths::archives(),
+        domain,
+        Utc::now().format("%Y%m%d%H%M%S")
+    );
+    let file = File::create(&archive_path)?;
+    let mut archive = tar::Builder::new(GzEncoder::new(file, Compression::default()));
+    archive.append_dir_all(domain, format!("{}/{}", paths::sites(), domain))?;
+    archive.into_inner()?.finish()?.sync_all()?;
+
+    Ok(archive_path)
+}
+
+/// Packs the site's `_content` directory, but for the uploaded files, as a `.tar.gz`,
+/// so that it can be edited elsewhere and put back using [`import_content`].
+pub fn export_content(domain: &str) -> io::Result<Vec<u8>> {
+    let content_path = PathBuf::from(format!("{}/{}/_content", paths::sites(), domain));
+    let files_path = content_path.join("files");
+    let mut archive = tar::Builder::new(GzEncoder::new(vec![], Compression::default()));
+    if content_path.exists() {
+        for entry in WalkDir::new(&content_path)
+            .min_depth(1)
+            .sort_by_file_name()
+            .into_iter()
+            .filter_entry(|e| e.path() != files_path)
+        {
+            let entry = entry?;
+            let name = entry.path().strip_prefix(&content_path).unwrap();
+            if entry.file_type().is_dir() {
+                archive.append_dir(name, entry.path())?;
+            } else if entry.file_type().is_file() {
+                archive.append_path_with_name(entry.path(), name)?;
+            }
+        }
+    }
+
+    archive.into_inner()?.finish()
+}
+
+/// Extracts the `.tar.gz` read from `reader` to `path`, skipping the uploaded files.
+fn extract_content(reader: impl Read, path: &Path, max_size: Option<u64>) -> io::Result<()> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidInput, message);
+    let mut size = 0;
+    let mut archive = tar::Archive::new(GzDecoder::new(reader));
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path_in_archive = entry.path()?.into_owned();
+        if !path_in_archive
+            .components()
+            .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+        {
+            return Err(invalid(format!(
+                "Invalid path: {}",
+                path_in_archive.display()
+            )));
+        }
+        // as in `./posts/hello.md`, when packed using `tar -C <dir> -czf <archive> .`
+        let name = path_in_archive
+            .components()
+            .filter(|c| matches!(c, Component::Normal(_)))
+            .collect::<PathBuf>();
+        if name.as_os_str().is_empty() || name.starts_with("files") {
+            continue; // the uploaded files are managed using the Blossom and NIP-96 APIs
+        }
+        let target = path.join(&name);
+        match entry.header().entry_type() {
+            tar::EntryType::Directory => fs::create_dir_all(&target)?,
+            tar::EntryType::Regular => {
+                size += entry.size();
+                if max_size.is_some_and(|max_size| size > max_size) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::QuotaExceeded,
+                        "The content exceeds the storage quota",
+                    ));
+                }
+                if let Some(parent) = target.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                entry.unpack(&target)?;
+            }
+            _ => return Err(invalid(format!("Not a file: {}", name.display()))),
+        }
+    }
+
+    Ok(())
+}
+
+/// Replaces the site's `_content` directory, but for the uploaded files, by the content of a `.tar.gz`
+/// (as made by [`export_content`]), as long as its files don't take more than `max_size` bytes.
+/// Nothing is changed unless the whole archive can be extracted, and an archive that can't be is `InvalidInput`.
+pub fn import_content(domain: &str, reader: impl Read, max_size: Option<u64>) -> io::Result<()> {
+    let site_path = format!("{}/{}", paths::sites(), domain);
+    let content_path = PathBuf::from(format!("{}/_content", site_path));
+    let new_path = PathBuf::from(format!("{}/_content.new", site_path));
+    let old_path = PathBuf::from(format!("{}/_content.old", site_path));
+    if new_path.exists() {
+        fs::remove_dir_all(&new_path)?;
+    }
+    fs::create_dir_all(&new_path)?;
+    if let Err(e) = extract_content(reader, &new_path, max_size) {
+        fs::remove_dir_all(&new_path)?;
+        return Err(match e.kind() {
+            io::ErrorKind::QuotaExceeded | io::ErrorKind::InvalidInput => e,
+            _ => io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid archive: {}", e),
+            ),
+        });
+    }
+
+    if content_path.join("files").exists() {
+        fs::rename(content_path.join("files"), new_path.join("files"))?;
+    }
+    if content_path.exists() {
+        fs::rename(&content_path, &old_path)?;
+    }
+    fs::rename(&new_path, &content_path)?;
+    if old_path.exists() {
+        fs::remove_dir_all(&old_path)?;
+    }
+
+    Ok(())
+}
+
+/// Deletes the site's directory, once archived if `archive` is set, in which case the archive's path is returned.
+pub fn delete_site(domain: &str, archive: bool) -> io::Result<Option<String>> {
+    if !is_valid_domain(domain) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid domain: {}", domain),
+        ));
+    }
+    let archive_path = if archive {
+        Some(archive_site(domain)?)
+    } else {
+        None
+    };
+    fs::remove_dir_all(format!("{}/{}", paths::sites(), domain))?;
+
+    Ok(archive_path)
+}
+
+/// Copies config and content of a site to a new domain, rewriting the `base_url`.
+pub fn clone_site(domain: &str, new_domain: &str) -> io::Result<()> {
+    if !is_valid_domain(new_domain) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid domain: {}", new_domain),
+        ));
+    }
+
+    let path = PathBuf::from(format!("{}/{}", paths::sites(), domain));
+    let new_path = PathBuf::from(format!("{}/{}", paths::sites(), new_domain));
+    if new_path.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("Site already exists: {}", new_domain),
+        ));
+    }
+
+    let config_path = format!("{}/_config.toml", path.display());
+    let mut config: SiteConfig = toml::from_str(&fs::read_to_string(&config_path)?)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    config.base_url = get_cloned_base_url(&config.base_url, new_domain);
+
+    for entry in WalkDir::new(&path) {
+        let entry = entry?;
+        let target = new_path.join(entry.path().strip_prefix(&path).unwrap());
+        if entry.file_type().is_dir() {
+            fs::create_dir_all(&target)?;
+        } else {
+            fs::copy(entry.path(), &target)?;
+        }
+    }
+
+    save_config(&format!("{}/_config.toml", new_path.display()), config)
+}
+
+pub fn create_site(
+    domain: &str,
+    admin_pubkey: Option<String>,
+    themes: &HashMap<String, Theme>,
+) -> Result<Site, String> {
+    let path = format!("{}/{}", paths::sites(), domain);
     fs::create_dir_all(&path).unwrap();
 
     let config_content = format!(
@@ -528,32 +2061,66 @@ pub fn create_site(domain: &str, admin_pubkey: Option<String>) -> Site {
         DEFAULT_THEME
     );
     fs::write(
-        format!("{}/{}/_config.toml", SITE_PATH, domain),
+        format!("{}/{}/_config.toml", paths::sites(), domain),
         &config_content,
     )
     .unwrap();
 
-    let mut config = load_config(&format!("{}/_config.toml", path)).unwrap();
+    load_site(domain, themes)
+}
 
-    let theme_path = format!("./themes/{}", config.theme);
-    let theme_config = theme::load_config(&format!("{}/config.toml", theme_path)).unwrap();
+/// Events can be kept out of search engines and feeds using a `["noindex", "true"]` tag.
+fn is_event_noindex(event: &nostr::Event) -> bool {
+    event.get_tag("noindex").is_some_and(|n| n == "true")
+}
 
-    config.merge(&theme_config);
+/// Paths are looked up with a leading slash and no trailing slash.
+pub fn normalize_path(path: &str) -> String {
+    format!("/{}", path.trim_matches('/'))
+}
 
-    let tera = load_templates(&config);
+fn get_config_redirects(config: &SiteConfig) -> HashMap<String, String> {
+    config
+        .redirects
+        .iter()
+        .map(|(from, to)| (normalize_path(from), to.to_owned()))
+        .collect()
+}
 
-    let site = Site {
-        domain: domain.to_owned(),
-        config,
-        data: Arc::new(RwLock::new(HashMap::new())),
-        events: Arc::new(RwLock::new(HashMap::new())),
-        resources: Arc::new(RwLock::new(HashMap::new())),
-        tera: Arc::new(RwLock::new(tera)),
-    };
+/// The `title` of a file, which YAML reads as a number if that is what it looks like.
+fn get_front_matter_title(front_matter: &HashMap<String, serde_yaml::Value>) -> Option<String> {
+    match front_matter.get("title")? {
+        serde_yaml::Value::String(title) => Some(title.to_owned()),
+        serde_yaml::Value::Number(title) => Some(title.to_string()),
+        _ => None,
+    }
+}
+
+fn get_front_matter_created_at(
+    front_matter: &HashMap<String, serde_yaml::Value>,
+) -> Option<NaiveDateTime> {
+    let created_at = front_matter.get("created_at")?.as_i64()?;
+    Some(Utc.timestamp_opt(created_at, 0).single()?.naive_utc())
+}
 
-    site.load_resources();
+fn is_front_matter_draft(front_matter: &HashMap<String, serde_yaml::Value>) -> bool {
+    front_matter
+        .get("draft")
+        .and_then(|d| d.as_bool())
+        .unwrap_or(false)
+}
 
-    site
+fn get_front_matter_aliases(front_matter: &HashMap<String, serde_yaml::Value>) -> Vec<String> {
+    front_matter
+        .get("aliases")
+        .and_then(|a| a.as_sequence())
+        .map(|a| {
+            a.iter()
+                .filter_map(|a| a.as_str())
+                .map(|a| a.to_owned())
+                .collect()
+        })
+        .unwrap_or_default()
 }
 
 fn get_resource_kind(event: &nostr::Event) -> Option<ResourceKind> {
@@ -570,3 +2137,270 @@ fn get_resource_kind(event: &nostr::Event) -> Option<ResourceKind> {
         _ => None,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_remove_resource_keeps_config_redirects() {
+        let config: SiteConfig = toml::from_str(
+            "base_url = \"https://example.com\"\ntheme = \"hyde\"\n[redirects]\n\"/old\" = \"/about\"",
+        )
+        .unwrap();
+        let mut content = SiteContent {
+            redirects: get_config_redirects(&config),
+            ..Default::default()
+        };
+        let resource = Resource {
+            kind: ResourceKind::Page,
+            slug: "about".to_string(),
+            title: None,
+            date: NaiveDate::from_ymd_opt(2024, 1, 1)
+                .unwrap()
+                .and_hms_opt(0, 0, 0)
+                .unwrap(),
+            noindex: false,
+            content_source: ContentSource::String("".to_string()),
+        };
+        let url = content
+            .insert_resource(&config, resource, &["/about-us".to_string()], vec![])
+            .unwrap();
+        assert_eq!(content.redirects.len(), 2);
+
+        content.remove_resource(&config, &url);
+        assert!(content.resources.is_empty());
+        assert!(!content.redirects.contains_key("/about-us"));
+        assert_eq!(content.redirects.get("/old"), Some(&"/about".to_string()));
+    }
+
+    #[test]
+    fn test_unload_least_recently_used() {
+        let site = |domain: &str, last_used, is_unloaded| {
+            let site = Site {
+                domain: domain.to_owned(),
+                config: toml::from_str("base_url = \"https://example.com\"\ntheme = \"hyde\"")
+                    .unwrap(),
+                content: Arc::new(Shared::new(SiteContent {
+                    is_unloaded,
+                    ..Default::default()
+                })),
+                upstream_cache: Arc::default(),
+                sass_resources: Arc::default(),
+                assets: template::Assets::new(String::new(), vec![]),
+                tera: Arc::default(),
+                loading: Arc::default(),
+                last_used: Arc::new(AtomicU64::new(last_used)),
+            };
+            (domain.to_owned(), site)
+        };
+        let sites = HashMap::from([
+            site("a.com", 1, false),
+            site("b.com", 3, false),
+            site("c.com", 2, false),
+            site("d.com", 0, true),
+        ]);
+
+        unload_least_recently_used(&sites, 3);
+        assert!(sites.values().filter(|s| s.is_loaded()).count() == 3);
+
+        unload_least_recently_used(&sites, 2);
+        assert!(!sites["a.com"].is_loaded());
+        assert!(sites["b.com"].is_loaded());
+        assert!(sites["c.com"].is_loaded());
+        assert!(!sites["d.com"].is_loaded());
+
+        sites["c.com"].touch();
+        unload_least_recently_used(&sites, 1);
+        assert!(!sites["b.com"].is_loaded());
+        assert!(sites["c.com"].is_loaded());
+    }
+
+    #[test]
+    fn test_event_ref_is_replaced_by() {
+        let event_ref = |kind, d_tag: &str| EventRef {
+            id: "id".to_string(),
+            created_at: 0,
+            kind,
+            d_tag: Some(d_tag.to_string()),
+            filename: "".to_string(),
+        };
+        let hello = Some("hello".to_string());
+
+        // a draft being published, then updated
+        assert!(event_ref(nostr::EVENT_KIND_LONG_FORM_DRAFT, "hello")
+            .is_replaced_by(nostr::EVENT_KIND_LONG_FORM, &hello));
+        assert!(event_ref(nostr::EVENT_KIND_LONG_FORM, "hello")
+            .is_replaced_by(nostr::EVENT_KIND_LONG_FORM, &hello));
+        // a post back to draft
+        assert!(event_ref(nostr::EVENT_KIND_LONG_FORM, "hello")
+            .is_replaced_by(nostr::EVENT_KIND_LONG_FORM_DRAFT, &hello));
+
+        assert!(!event_ref(nostr::EVENT_KIND_LONG_FORM, "other")
+            .is_replaced_by(nostr::EVENT_KIND_LONG_FORM, &hello));
+        // the settings of a theme called like a post
+        assert!(!event_ref(nostr::EVENT_KIND_LONG_FORM, "hello")
+            .is_replaced_by(nostr::EVENT_KIND_THEME_SETTINGS, &hello));
+        assert!(!event_ref(nostr::EVENT_KIND_THEME_SETTINGS, "hello")
+            .is_replaced_by(nostr::EVENT_KIND_LONG_FORM, &hello));
+    }
+
+    #[test]
+    fn test_event_ref_is_older_than() {
+        let event_ref = EventRef {
+            id: "bb".to_string(),
+            created_at: 10,
+            kind: nostr::EVENT_KIND_LONG_FORM,
+            d_tag: Some("hello".to_string()),
+            filename: "".to_string(),
+        };
+        assert!(event_ref.is_older_than(11, "cc"));
+        assert!(!event_ref.is_older_than(9, "aa"));
+        // at the same time, the lowest ID wins
+        assert!(event_ref.is_older_than(10, "aa"));
+        assert!(!event_ref.is_older_than(10, "cc"));
+    }
+
+    #[test]
+    fn test_is_valid_domain() {
+        assert!(is_valid_domain("localhost"));
+        assert!(is_valid_domain("example.com"));
+        assert!(is_valid_domain("my-blog.example.com"));
+        assert!(!is_valid_domain(""));
+        assert!(!is_valid_domain("../example.com"));
+        assert!(!is_valid_domain("example.com/posts"));
+        assert!(!is_valid_domain("example..com"));
+        assert!(!is_valid_domain("-example.com"));
+        assert!(!is_valid_domain("example.com:4884"));
+
+        assert_eq!(normalize_domain("Example.COM.").unwrap(), "example.com");
+        assert_eq!(
+            normalize_domain("bücher.example.com").unwrap(),
+            "xn--bcher-kva.example.com"
+        );
+        assert!(normalize_domain("127.0.0.1").is_none());
+        assert!(normalize_domain("").is_none());
+        assert!(normalize_domain("../../etc").is_none_or(|d| !is_valid_domain(&d)));
+
+        assert!(is_reserved_domain("localhost"));
+        assert!(is_reserved_domain("blog.localhost"));
+        assert!(is_reserved_domain("printer.local"));
+        assert!(is_reserved_domain("admin"));
+        assert!(!is_reserved_domain("example.com"));
+        assert!(!is_reserved_domain("localhost.example.com"));
+
+        assert!(is_subdomain_of("blog.myservus.net", "myservus.net"));
+        assert!(!is_subdomain_of("myservus.net", "myservus.net"));
+        assert!(!is_subdomain_of("a.blog.myservus.net", "myservus.net"));
+        assert!(!is_subdomain_of("blogmyservus.net", "myservus.net"));
+    }
+
+    #[test]
+    fn test_is_valid_slug() {
+        assert!(is_valid_slug("hello-world"));
+        assert!(is_valid_slug("2024_recap.v2"));
+        assert!(!is_valid_slug(""));
+        assert!(!is_valid_slug(".."));
+        assert!(!is_valid_slug(".hidden"));
+        assert!(!is_valid_slug("posts/hello"));
+        assert!(!is_valid_slug("hello world"));
+    }
+
+    #[test]
+    fn test_get_cloned_base_url() {
+        assert_eq!(
+            get_cloned_base_url("https://example.com", "new.com"),
+            "https://new.com"
+        );
+        assert_eq!(
+            get_cloned_base_url("https://example.com/", "new.com"),
+            "https://new.com/"
+        );
+        // only the host is replaced, even when the old domain is also part of the path
+        assert_eq!(
+            get_cloned_base_url("http://example.com:4884/example.com", "new.com"),
+            "http://new.com:4884/example.com"
+        );
+        assert_eq!(get_cloned_base_url("", "new.com"), "https://new.com");
+    }
+
+    fn build_archive(entries: &[(&str, &str)]) -> Vec<u8> {
+        let mut archive = tar::Builder::new(GzEncoder::new(vec![], Compression::default()));
+        for (name, content) in entries {
+            let mut header = tar::Header::new_gnu();
+            // NB: set directly, since `set_path` refuses paths such as `../evil.md`
+            header.as_old_mut().name[..name.len()].copy_from_slice(name.as_bytes());
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            archive.append(&header, content.as_bytes()).unwrap();
+        }
+        archive.into_inner().unwrap().finish().unwrap()
+    }
+
+    #[test]
+    fn test_extract_content() {
+        let dir = std::env::temp_dir().join(format!("servus-extract-{}", std::process::id()));
+        let archive = build_archive(&[
+            ("./posts/hello.md", "Hello!"),
+            ("pages/about.md", "About"),
+            ("files/abc", "not content"),
+        ]);
+        extract_content(&archive[..], &dir, None).unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.join("posts/hello.md")).unwrap(),
+            "Hello!"
+        );
+        assert!(dir.join("pages/about.md").exists());
+        assert!(!dir.join("files").exists());
+
+        let error = extract_content(&archive[..], &dir, Some(10)).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::QuotaExceeded);
+        let archive = build_archive(&[("../evil.md", "Evil")]);
+        let error = extract_content(&archive[..], &dir, None).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert!(!dir.join("../evil.md").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_restore_protected_keys() {
+        let previous: toml::Table = toml::from_str(
+            r#"
+title = "Blog"
+pubkey = "abc"
+[git]
+remote = "https://example.com/blog.git"
+auto_push = true
+"#,
+        )
+        .unwrap();
+        let mut table: toml::Table = toml::from_str(
+            r#"
+title = "My blog"
+upstream_relays = ["ws://127.0.0.1:7777"]
+[git]
+remote = "http://169.254.169.254/"
+[newsletter]
+from = "me@example.com"
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            restore_protected_keys(&mut table, &previous),
+            ["pubkey", "upstream_relays", "git.remote"]
+        );
+        assert_eq!(table["title"].as_str(), Some("My blog"));
+        assert_eq!(table["pubkey"].as_str(), Some("abc"));
+        assert!(!table.contains_key("upstream_relays"));
+        assert_eq!(
+            get_config_value(&table, "git.remote").and_then(|r| r.as_str()),
+            Some("https://example.com/blog.git")
+        );
+        assert!(get_config_value(&table, "git.auto_push").is_none());
+        assert!(get_config_value(&table, "newsletter.smtp_url").is_none());
+        assert!(restore_protected_keys(&mut table, &previous).is_empty());
+    }
+}
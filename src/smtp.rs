@@ -0,0 +1,283 @@
+use async_std::{future, net::TcpStream};
+use async_tls::TlsConnector;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use futures_util::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use http_types::Url;
+use percent_encoding::percent_decode_str;
+use std::{io, str, time::Duration};
+
+/// How long to wait for the SMTP server to answer each command.
+const SMTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+const BOUNDARY: &str = "servus-alternative-boundary";
+
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+fn invalid_input(error: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, error)
+}
+
+fn timed_out(_: future::TimeoutError) -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, "Timed out")
+}
+
+/// An email, with a plain text body and optionally an HTML one.
+pub struct Message {
+    pub from: String,
+    pub to: String,
+    pub subject: String,
+    pub headers: Vec<(String, String)>,
+    pub text: String,
+    pub html: Option<String>,
+}
+
+/// The address of a mailbox such as `Name <address>`.
+fn get_address(mailbox: &str) -> &str {
+    match (mailbox.rfind('<'), mailbox.rfind('>')) {
+        (Some(start), Some(end)) if start < end => &mailbox[start + 1..end],
+        _ => mailbox.trim(),
+    }
+}
+
+/// `value`, encoded (see RFC 2047) unless it is plain ASCII.
+fn encode_header(value: &str) -> String {
+    if value.chars().all(|c| c.is_ascii() && !c.is_ascii_control()) {
+        value.to_owned()
+    } else {
+        format!("=?utf-8?B?{}?=", STANDARD.encode(value))
+    }
+}
+
+/// `content`, in base64, in lines short enough for any server.
+fn encode_body(content: &str) -> String {
+    STANDARD
+        .encode(content)
+        .as_bytes()
+        .chunks(76)
+        .map(|line| format!("{}\r\n", str::from_utf8(line).unwrap()))
+        .collect()
+}
+
+impl Message {
+    /// The message as sent after `DATA` (before dot-stuffing).
+    fn format(&self, domain: &str) -> String {
+        let mut message = format!(
+            "From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\nMessage-ID: <{:032x}@{}>\r\nMIME-Version: 1.0\r\n",
+            self.from,
+            self.to,
+            encode_header(&self.subject),
+            chrono::Utc::now().to_rfc2822(),
+            rand::random::<u128>(),
+            domain,
+        );
+        for (name, value) in &self.headers {
+            message.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        let text_part = format!(
+            "Content-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: base64\r\n\r\n{}",
+            encode_body(&self.text)
+        );
+        match &self.html {
+            None => message.push_str(&text_part),
+            Some(html) => message.push_str(&format!(
+                "Content-Type: multipart/alternative; boundary=\"{b}\"\r\n\r\n--{b}\r\n{}--{b}\r\nContent-Type: text/html; charset=utf-8\r\nContent-Transfer-Encoding: base64\r\n\r\n{}--{b}--\r\n",
+                text_part,
+                encode_body(html),
+                b = BOUNDARY,
+            )),
+        }
+        message
+    }
+}
+
+/// A connection to an SMTP server, that messages can be sent through one after another.
+pub struct Client {
+    stream: BufReader<Box<dyn Stream>>,
+    domain: String, // the one we introduced ourselves as
+}
+
+impl Client {
+    /// Connects to the server at `url`, such as `smtps://<user>:<password>@smtp.example.com`
+    /// (TLS, on port 465 by default) or `smtp://...` (STARTTLS if the server supports it, on port 587 by default),
+    /// introducing ourselves as `domain`.
+    pub async fn connect(url: &str, domain: &str) -> io::Result<Self> {
+        let url = Url::parse(url).map_err(|e| invalid_input(&e.to_string()))?;
+        let host = url
+            .host_str()
+            .ok_or_else(|| invalid_input("No SMTP host"))?
+            .to_owned();
+        let is_tls = match url.scheme() {
+            "smtps" => true,
+            "smtp" => false,
+            _ => return Err(invalid_input("Unsupported SMTP URL")),
+        };
+        let port = url.port().unwrap_or(if is_tls { 465 } else { 587 });
+
+        let tcp_stream = future::timeout(SMTP_TIMEOUT, TcpStream::connect((host.as_str(), port)))
+            .await
+            .map_err(timed_out)??;
+        let stream: Box<dyn Stream> = if is_tls {
+            Box::new(TlsConnector::new().connect(&host, tcp_stream).await?)
+        } else {
+            Box::new(tcp_stream)
+        };
+        let mut client = Client {
+            stream: BufReader::new(stream),
+            domain: domain.to_owned(),
+        };
+        client.read_reply(220).await?;
+        let extensions = client.command(&format!("EHLO {}", domain), 250).await?;
+
+        let mut is_secure = is_tls;
+        if !is_tls
+            && extensions
+                .iter()
+                .any(|e| e.eq_ignore_ascii_case("STARTTLS"))
+        {
+            client.command("STARTTLS", 220).await?;
+            let stream = client.stream.into_inner();
+            client.stream =
+                BufReader::new(Box::new(TlsConnector::new().connect(&host, stream).await?));
+            client.command(&format!("EHLO {}", domain), 250).await?;
+            is_secure = true;
+        }
+
+        if !url.username().is_empty() {
+            // credentials are never sent in the clear
+            if !is_secure {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "The SMTP server doesn't support STARTTLS",
+                ));
+            }
+            let decode = |s| percent_decode_str(s).decode_utf8_lossy().to_string();
+            let credentials = format!(
+                "\0{}\0{}",
+                decode(url.username()),
+                decode(url.password().unwrap_or_default())
+            );
+            client
+                .command(&format!("AUTH PLAIN {}", STANDARD.encode(credentials)), 235)
+                .await?;
+        }
+
+        Ok(client)
+    }
+
+    /// Reads a (possibly multiline) reply, failing unless its code is of the same class as `expected`,
+    /// and returns the text of its lines.
+    async fn read_reply(&mut self, expected: u16) -> io::Result<Vec<String>> {
+        let mut lines = vec![];
+        loop {
+            let mut line = String::new();
+            future::timeout(SMTP_TIMEOUT, self.stream.read_line(&mut line))
+                .await
+                .map_err(timed_out)??;
+            if line.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "Connection closed by the SMTP server",
+                ));
+            }
+            let line = line.trim_end();
+            let code = line
+                .get(..3)
+                .and_then(|c| c.parse::<u16>().ok())
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Invalid SMTP reply"))?;
+            lines.push(line.get(4..).unwrap_or_default().to_owned());
+            if line.as_bytes().get(3) != Some(&b'-') {
+                if code / 100 != expected / 100 {
+                    return Err(io::Error::other(line.to_owned()));
+                }
+                return Ok(lines);
+            }
+        }
+    }
+
+    async fn command(&mut self, command: &str, expected: u16) -> io::Result<Vec<String>> {
+        self.stream
+            .write_all(format!("{}\r\n", command).as_bytes())
+            .await?;
+        self.stream.flush().await?;
+        self.read_reply(expected).await
+    }
+
+    pub async fn send(&mut self, message: &Message) -> io::Result<()> {
+        self.command(&format!("MAIL FROM:<{}>", get_address(&message.from)), 250)
+            .await?;
+        if let Err(e) = self
+            .command(&format!("RCPT TO:<{}>", get_address(&message.to)), 250)
+            .await
+        {
+            // so that the next message can be sent
+            self.command("RSET", 250).await?;
+            return Err(e);
+        }
+        self.command("DATA", 354).await?;
+        let mut data = String::new();
+        let formatted = message.format(&self.domain);
+        for line in formatted.trim_end_matches("\r\n").split("\r\n") {
+            if line.starts_with('.') {
+                data.push('.');
+            }
+            data.push_str(line);
+            data.push_str("\r\n");
+        }
+        data.push_str(".\r\n");
+        self.stream.write_all(data.as_bytes()).await?;
+        self.stream.flush().await?;
+        self.read_reply(250).await.map(|_| ())
+    }
+
+    pub async fn quit(mut self) {
+        self.command("QUIT", 221).await.ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_address() {
+        assert_eq!(
+            get_address("My blog <news@example.com>"),
+            "news@example.com"
+        );
+        assert_eq!(get_address(" news@example.com "), "news@example.com");
+    }
+
+    #[test]
+    fn test_encode_header() {
+        assert_eq!(encode_header("Hello world"), "Hello world");
+        assert_eq!(encode_header("Héllo"), "=?utf-8?B?SMOpbGxv?=");
+        assert_eq!(
+            encode_header("Hello\r\nBcc: x@example.com"),
+            "=?utf-8?B?SGVsbG8NCkJjYzogeEBleGFtcGxlLmNvbQ==?="
+        );
+    }
+
+    #[test]
+    fn test_format() {
+        let message = Message {
+            from: "My blog <news@example.com>".to_string(),
+            to: "reader@example.org".to_string(),
+            subject: "Hello".to_string(),
+            headers: vec![("X-Test".to_string(), "1".to_string())],
+            text: "Hello!".to_string(),
+            html: Some("<p>Hello!</p>".to_string()),
+        };
+        let formatted = message.format("example.com");
+        assert!(formatted.starts_with(
+            "From: My blog <news@example.com>\r\nTo: reader@example.org\r\nSubject: Hello\r\n"
+        ));
+        assert!(formatted.contains("@example.com>\r\nMIME-Version: 1.0\r\nX-Test: 1\r\n"));
+        assert!(formatted.contains(&format!(
+            "--{}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: base64\r\n\r\nSGVsbG8h\r\n",
+            BOUNDARY
+        )));
+        assert!(formatted.contains("\r\n\r\nPHA+SGVsbG8hPC9wPg==\r\n"));
+        assert!(formatted.ends_with(&format!("--{}--\r\n", BOUNDARY)));
+    }
+}
@@ -1,10 +1,22 @@
 // * Code taken from [Zola](https://www.getzola.org/) and adapted.
 // * Zola's MIT license applies. See: https://github.com/getzola/zola/blob/master/LICENSE
 
-use std::collections::HashMap;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use sha2::{Digest, Sha256, Sha384, Sha512};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Component, Path},
+    sync::{Arc, RwLock},
+};
 use tera::{from_value, to_value, Function as TeraFn, Result as TeraResult, Value as TeraValue};
+use walkdir::WalkDir;
 
-use crate::site::SiteConfig;
+use crate::{
+    filters,
+    site::{normalize_path, SiteConfig},
+    utils,
+};
 
 // https://github.com/getzola/zola/blob/master/components/templates/src/global_fns/macros.rs
 
@@ -32,15 +44,190 @@ macro_rules! optional_arg {
     };
 }
 
+// Built-in partial that themes can `{% include %}` in their `<head>`
+// to get OpenGraph, Twitter card and Nostr meta tags for the current page.
+
+pub const SOCIAL_META_TEMPLATE_NAME: &str = "servus/social_meta.html";
+pub const SOCIAL_META_TEMPLATE: &str = r#"{% if page %}
+{% if page.noindex %}<meta name="robots" content="noindex">{% endif %}
+<meta property="og:type" content="{% if page.slug == "index" %}website{% else %}article{% endif %}">
+<meta property="og:url" content="{{ page.permalink }}">
+{% if config.title %}<meta property="og:site_name" content="{{ config.title | escape_xml }}">{% endif %}
+{% if page.title %}<meta property="og:title" content="{{ page.title | escape_xml }}">
+<meta name="twitter:title" content="{{ page.title | escape_xml }}">{% endif %}
+{% if page.summary %}<meta name="description" content="{{ page.summary | escape_xml }}">
+<meta property="og:description" content="{{ page.summary | escape_xml }}">
+<meta name="twitter:description" content="{{ page.summary | escape_xml }}">{% endif %}
+{% if page.slug != "index" %}<meta property="article:published_time" content="{{ page.local_date }}">{% endif %}
+{% if page.image %}<meta property="og:image" content="{{ page.image | escape_xml }}">
+<meta name="twitter:card" content="summary_large_image">
+<meta name="twitter:image" content="{{ page.image | escape_xml }}">{% else %}<meta name="twitter:card" content="summary">{% endif %}
+{% if page.author %}<meta name="nostr:author" content="{{ page.author }}">
+<link rel="me" href="nostr:{{ page.author }}">{% endif %}
+{% endif %}"#;
+
+// Built-in partial laying out the images of the gallery (see [`crate::gallery`]) as a grid of thumbnails,
+// which themes can `{% include %}` in their own `gallery.html`, and which is otherwise rendered as the page's content.
+
+pub const GALLERY_TEMPLATE_NAME: &str = "servus/gallery.html";
+pub const GALLERY_TEMPLATE: &str = r#"<div class="servus-gallery" style="display: grid; grid-template-columns: repeat({{ gallery.columns }}, 1fr); gap: 0.5em;">
+{% for image in gallery.images %}<a href="{{ image.url }}"><img src="{{ image.thumbnail_url }}" loading="lazy" alt=""{% if image.width and image.height %} width="{{ image.width }}" height="{{ image.height }}"{% endif %} style="width: 100%; height: auto;"></a>
+{% endfor %}</div>"#;
+
+/// Tera errors only describe what went wrong in their source, so include the whole chain.
+pub fn format_error(e: tera::Error) -> String {
+    let mut message = e.to_string();
+    let mut source = std::error::Error::source(&e);
+    while let Some(e) = source {
+        message.push_str(&format!(": {}", e));
+        source = e.source();
+    }
+
+    message
+}
+
+/// Registers what templates get regardless of the site (the filters Servus adds to Tera's).
+pub fn register_filters(tera: &mut tera::Tera) {
+    tera.register_filter("markdown", filters::MarkdownFilter);
+    tera.register_filter("truncate_html", filters::TruncateHtmlFilter);
+}
+
+/// Registers the functions (and filters) of the templates that depend on the site being rendered,
+/// which cannot be registered once for all the sites using the theme.
+pub fn register_site_functions(tera: &mut tera::Tera, site_config: &SiteConfig, assets: &Assets) {
+    tera.register_function("get_url", GetUrl::new(site_config.clone(), assets.clone()));
+    tera.register_function("get_hash", GetHash::new(assets.clone()));
+    tera.register_filter(
+        "date",
+        filters::DateFilter::new(site_config.get_timezone(), site_config.get_date_format()),
+    );
+}
+
+/// Whether `path` stays within the directory it is relative to.
+fn is_relative_path(path: &str) -> bool {
+    Path::new(path)
+        .components()
+        .all(|c| matches!(c, Component::Normal(_)))
+}
+
+/// The hash appended as `?h=` to cache-busted URLs.
+pub fn get_cachebust_hash(content: &[u8]) -> String {
+    utils::sha256_hex(content)[..20].to_string()
+}
+
+/// Files that templates can refer to: compiled resources (such as the site's or the theme's sass)
+/// or static files of the site.
+#[derive(Clone)]
+pub struct Assets {
+    site_path: String,
+    resources: Vec<Arc<RwLock<HashMap<String, String>>>>,
+    hashes: Arc<HashMap<String, String>>, // path -> cache-busting hash
+    preview_theme: Option<String>,
+}
+
+/// Whether a file of the site can be served as is (`_content`, `_config.toml`... can't).
+fn is_static_file(entry: &walkdir::DirEntry) -> bool {
+    entry.depth() == 0
+        || !entry
+            .file_name()
+            .to_str()
+            .is_some_and(|n| n.starts_with('_') || n.starts_with('.'))
+}
+
+impl Assets {
+    /// NB: the hashes of all the assets are computed here, once, rather than whenever they are used.
+    /// The resources take precedence over static files, just like when serving them.
+    pub fn new(site_path: String, resources: Vec<Arc<RwLock<HashMap<String, String>>>>) -> Self {
+        let mut hashes = HashMap::new();
+        for resources in &resources {
+            for (path, content) in resources.read().unwrap().iter() {
+                hashes
+                    .entry(path.to_owned())
+                    .or_insert_with(|| get_cachebust_hash(content.as_bytes()));
+            }
+        }
+        for entry in WalkDir::new(&site_path)
+            .into_iter()
+            .filter_entry(is_static_file)
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_type().is_file())
+        {
+            let path = entry.path().strip_prefix(&site_path).unwrap();
+            if let Ok(content) = fs::read(entry.path()) {
+                hashes
+                    .entry(format!("/{}", path.display()))
+                    .or_insert_with(|| get_cachebust_hash(&content));
+            }
+        }
+
+        Self {
+            site_path,
+            resources,
+            hashes: Arc::new(hashes),
+            preview_theme: None,
+        }
+    }
+
+    /// The assets of a site being previewed with `theme`, whose compiled resources
+    /// are then requested with `?preview_theme=`, so that they are served from that theme.
+    pub fn with_preview_theme(self, theme: &str) -> Self {
+        Self {
+            preview_theme: Some(theme.to_owned()),
+            ..self
+        }
+    }
+
+    /// What to add to the URL of the asset at `path` so that it is served from the theme being previewed.
+    fn get_preview_query(&self, path: &str) -> Option<String> {
+        let theme = self.preview_theme.as_ref()?;
+        let resource_path = format!("/{}", path.trim_start_matches('/'));
+        self.resources
+            .iter()
+            .any(|r| r.read().unwrap().contains_key(&resource_path))
+            .then(|| format!("preview_theme={}", theme))
+    }
+
+    fn read(&self, path: &str) -> Option<Vec<u8>> {
+        let path = path.trim_start_matches('/');
+        if !is_relative_path(path) {
+            return None;
+        }
+
+        let resource_path = format!("/{}", path);
+        let content = self
+            .resources
+            .iter()
+            .find_map(|r| r.read().unwrap().get(&resource_path).cloned());
+        match content {
+            Some(content) => Some(content.into_bytes()),
+            None => fs::read(format!("{}/{}", self.site_path, path)).ok(),
+        }
+    }
+
+    /// The hash to cache-bust the asset at `path` with, if it exists.
+    pub fn get_cachebust_hash(&self, path: &str) -> Option<String> {
+        let resource_path = format!("/{}", path.trim_start_matches('/'));
+        match self.hashes.get(&resource_path) {
+            Some(hash) => Some(hash.to_owned()),
+            // a file added since the site was loaded
+            None => self.read(path).map(|content| get_cachebust_hash(&content)),
+        }
+    }
+}
+
 // https://github.com/getzola/zola/blob/master/components/templates/src/global_fns/files.rs
 
 pub struct GetUrl {
     site_config: SiteConfig,
+    assets: Assets,
 }
 
 impl GetUrl {
-    pub fn new(site_config: SiteConfig) -> Self {
-        Self { site_config }
+    pub fn new(site_config: SiteConfig, assets: Assets) -> Self {
+        Self {
+            site_config,
+            assets,
+        }
     }
 }
 
@@ -57,19 +244,30 @@ impl TeraFn for GetUrl {
             "`get_url`: `trailing_slash` must be a boolean (true or false)"
         )
         .unwrap_or(false);
+        let cachebust = optional_arg!(
+            bool,
+            args.get("cachebust"),
+            "`get_url`: `cachebust` must be a boolean (true or false)"
+        )
+        .unwrap_or(false);
 
         // anything else
-        let mut segments = vec![];
-
-        segments.push(path);
-
-        let path = segments.join("/");
-
         let mut permalink = self.site_config.make_permalink(&path);
         if !trailing_slash && permalink.ends_with('/') {
             permalink.pop(); // Removes the slash
         }
 
+        if cachebust {
+            match self.assets.get_cachebust_hash(&path) {
+                Some(hash) => permalink = format!("{}?h={}", permalink, hash),
+                None => return Err(format!("Could not find or open file {}", path).into()),
+            };
+        }
+        if let Some(query) = self.assets.get_preview_query(&path) {
+            let separator = if permalink.contains('?') { '&' } else { '?' };
+            permalink = format!("{}{}{}", permalink, separator, query);
+        }
+
         Ok(to_value(permalink).unwrap())
     }
 
@@ -77,3 +275,282 @@ impl TeraFn for GetUrl {
         true
     }
 }
+
+pub struct GetHash {
+    assets: Assets,
+}
+
+impl GetHash {
+    pub fn new(assets: Assets) -> Self {
+        Self { assets }
+    }
+}
+
+fn compute_hash<D: Digest>(content: &[u8], as_base64: bool) -> String {
+    let hash = D::digest(content);
+    if as_base64 {
+        STANDARD.encode(hash)
+    } else {
+        hash.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+impl TeraFn for GetHash {
+    fn call(&self, args: &HashMap<String, TeraValue>) -> TeraResult<TeraValue> {
+        let path = optional_arg!(
+            String,
+            args.get("path"),
+            "`get_hash` requires either a `path` or a `literal` argument with a string value"
+        );
+        let literal = optional_arg!(
+            String,
+            args.get("literal"),
+            "`get_hash` requires either a `path` or a `literal` argument with a string value"
+        );
+
+        let content = match (path, literal) {
+            (Some(_), Some(_)) => {
+                return Err(
+                    "`get_hash`: must have only one of `path` or `literal` argument".into(),
+                );
+            }
+            (None, None) => {
+                return Err(
+                    "`get_hash`: must have at least one of `path` or `literal` argument".into(),
+                );
+            }
+            (Some(path), None) => match self.assets.read(&path) {
+                Some(content) => content,
+                None => return Err(format!("Could not find or open file {}", path).into()),
+            },
+            (None, Some(literal)) => literal.into_bytes(),
+        };
+
+        let sha_type = optional_arg!(
+            u16,
+            args.get("sha_type"),
+            "`get_hash`: `sha_type` must be 256, 384 or 512"
+        )
+        .unwrap_or(384);
+        let base64 = optional_arg!(
+            bool,
+            args.get("base64"),
+            "`get_hash`: `base64` must be true or false"
+        )
+        .unwrap_or(true);
+
+        let hash = match sha_type {
+            256 => compute_hash::<Sha256>(&content, base64),
+            384 => compute_hash::<Sha384>(&content, base64),
+            512 => compute_hash::<Sha512>(&content, base64),
+            _ => return Err("`get_hash`: Invalid sha value".into()),
+        };
+
+        Ok(to_value(hash).unwrap())
+    }
+
+    fn is_safe(&self) -> bool {
+        true
+    }
+}
+
+// https://github.com/getzola/zola/blob/master/components/templates/src/global_fns/content.rs
+
+/// Looks up pages by their URL (`/posts/hello`) or by their path relative to `_content` (`posts/2024-01-01-hello.md`).
+pub struct GetPage {
+    pages: HashMap<String, TeraValue>,
+}
+
+impl GetPage {
+    pub fn new(pages: HashMap<String, TeraValue>) -> Self {
+        Self { pages }
+    }
+}
+
+impl TeraFn for GetPage {
+    fn call(&self, args: &HashMap<String, TeraValue>) -> TeraResult<TeraValue> {
+        let path = required_arg!(
+            String,
+            args.get("path"),
+            "`get_page` requires a `path` argument with a string value"
+        );
+        match self
+            .pages
+            .get(&path)
+            .or_else(|| self.pages.get(&normalize_path(&path)))
+        {
+            Some(page) => Ok(page.clone()),
+            None => Err(format!("Page `{}` not found.", path).into()),
+        }
+    }
+}
+
+/// Looks up sections by their path relative to `_content` (`_index.md`, `posts/_index.md`, ...).
+pub struct GetSection {
+    sections: HashMap<String, TeraValue>,
+}
+
+impl GetSection {
+    pub fn new(sections: HashMap<String, TeraValue>) -> Self {
+        Self { sections }
+    }
+}
+
+impl TeraFn for GetSection {
+    fn call(&self, args: &HashMap<String, TeraValue>) -> TeraResult<TeraValue> {
+        let path = required_arg!(
+            String,
+            args.get("path"),
+            "`get_section` requires a `path` argument with a string value"
+        );
+        let metadata_only = optional_arg!(
+            bool,
+            args.get("metadata_only"),
+            "`get_section`: `metadata_only` must be a boolean (true or false)"
+        )
+        .unwrap_or(false);
+
+        let mut section = match self.sections.get(path.trim_start_matches('/')) {
+            Some(section) => section.clone(),
+            None => return Err(format!("Section `{}` not found.", path).into()),
+        };
+        if metadata_only {
+            if let Some(pages) = section.get_mut("pages") {
+                *pages = TeraValue::Array(vec![]);
+            }
+        }
+
+        Ok(section)
+    }
+}
+
+pub struct GetTaxonomy {
+    taxonomies: HashMap<String, TeraValue>,
+}
+
+impl GetTaxonomy {
+    pub fn new(taxonomies: HashMap<String, TeraValue>) -> Self {
+        Self { taxonomies }
+    }
+}
+
+impl TeraFn for GetTaxonomy {
+    fn call(&self, args: &HashMap<String, TeraValue>) -> TeraResult<TeraValue> {
+        let kind = required_arg!(
+            String,
+            args.get("kind"),
+            "`get_taxonomy` requires a `kind` argument with a string value"
+        );
+        let required = optional_arg!(
+            bool,
+            args.get("required"),
+            "`get_taxonomy`: `required` must be a boolean (true or false)"
+        )
+        .unwrap_or(true);
+
+        match self.taxonomies.get(&kind) {
+            Some(taxonomy) => Ok(taxonomy.clone()),
+            None if !required => Ok(TeraValue::Null),
+            None => Err(format!(
+                "`get_taxonomy` received an unknown taxonomy as kind: {}",
+                kind
+            )
+            .into()),
+        }
+    }
+}
+
+// https://github.com/getzola/zola/blob/master/components/templates/src/global_fns/load_data.rs
+
+/// Loads data published to the site (`data/<name>`, from `_content/data/`)
+/// or from a file of the site, parsed according to its format.
+pub struct LoadData {
+    site_path: String,
+    data: HashMap<String, TeraValue>,
+}
+
+impl LoadData {
+    pub fn new(site_path: String, data: HashMap<String, TeraValue>) -> Self {
+        Self { site_path, data }
+    }
+
+    fn get_data(&self, path: &str) -> Option<TeraValue> {
+        let name = Path::new(path.strip_prefix("data/")?)
+            .file_stem()?
+            .to_str()?;
+        self.data.get(name).cloned()
+    }
+
+    fn read_file(&self, path: &str) -> Option<String> {
+        // files starting with "_" or "." are never served, so they shouldn't be loaded either
+        if !is_relative_path(path) || path.split('/').any(|p| p.starts_with(['_', '.'])) {
+            return None;
+        }
+
+        fs::read_to_string(format!("{}/{}", self.site_path, path)).ok()
+    }
+}
+
+fn parse_data(content: &str, format: &str) -> TeraResult<TeraValue> {
+    match format {
+        "json" => serde_json::from_str(content).map_err(|e| e.to_string().into()),
+        "toml" => toml::from_str::<toml::Value>(content)
+            .map_err(|e| e.to_string().into())
+            .and_then(|v| Ok(to_value(v)?)),
+        "yaml" | "yml" => serde_yaml::from_str::<serde_yaml::Value>(content)
+            .map_err(|e| e.to_string().into())
+            .and_then(|v| Ok(to_value(v)?)),
+        "plain" => Ok(to_value(content).unwrap()),
+        _ => Err(format!("`load_data`: unsupported format: {}", format).into()),
+    }
+}
+
+impl TeraFn for LoadData {
+    fn call(&self, args: &HashMap<String, TeraValue>) -> TeraResult<TeraValue> {
+        let path = optional_arg!(
+            String,
+            args.get("path"),
+            "`load_data`: `path` must be a string"
+        );
+        let literal = optional_arg!(
+            String,
+            args.get("literal"),
+            "`load_data`: `literal` must be a string"
+        );
+        let format = optional_arg!(
+            String,
+            args.get("format"),
+            "`load_data`: `format` must be a string (json, toml, yaml or plain)"
+        );
+        let required = optional_arg!(
+            bool,
+            args.get("required"),
+            "`load_data`: `required` must be a boolean (true or false)"
+        )
+        .unwrap_or(true);
+
+        match (path, literal) {
+            (Some(path), None) => {
+                let path = path.trim_start_matches('/');
+                if let Some(data) = self.get_data(path) {
+                    return Ok(data);
+                }
+                match self.read_file(path) {
+                    Some(content) => {
+                        let format = format.unwrap_or_else(|| {
+                            match Path::new(path).extension().and_then(|e| e.to_str()) {
+                                Some(e @ ("json" | "toml" | "yaml" | "yml")) => e.to_owned(),
+                                _ => "plain".to_owned(),
+                            }
+                        });
+                        parse_data(&content, &format)
+                    }
+                    None if !required => Ok(TeraValue::Null),
+                    None => Err(format!("Could not find or open file {}", path).into()),
+                }
+            }
+            (None, Some(literal)) => parse_data(&literal, &format.unwrap_or("plain".to_owned())),
+            _ => Err("`load_data`: requires EITHER a `path` or a `literal` argument".into()),
+        }
+    }
+}
@@ -1,13 +1,29 @@
+use git2::{build::CheckoutBuilder, Branch, FetchOptions, Repository};
 use serde::{Deserialize, Serialize};
 use std::{
     collections::HashMap,
-    fs,
-    path::PathBuf,
+    env, fs,
+    path::{Path, PathBuf},
+    process,
     sync::{Arc, RwLock},
 };
 use tide::log;
+use walkdir::WalkDir;
 
-use crate::sass;
+use crate::{paths, sass, template, utils};
+
+pub const THEMES_REPO: &str = "https://github.com/servus-social/themes";
+
+/// Templates Servus renders, which every theme needs to provide.
+const REQUIRED_TEMPLATES: [&str; 2] = ["index.html", "page.html"];
+
+/// Zola template functions Servus doesn't (yet) support.
+const UNSUPPORTED_FUNCTIONS: [&str; 4] = [
+    "resize_image",
+    "get_image_metadata",
+    "get_taxonomy_url",
+    "trans",
+];
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct ThemeConfig {
@@ -23,70 +39,345 @@ pub fn load_config(config_path: &str) -> Option<ThemeConfig> {
     }
 }
 
+#[derive(Clone)]
 pub struct Theme {
     pub path: String,
     pub config: ThemeConfig,
     pub resources: Arc<RwLock<HashMap<String, String>>>,
+    pub tera: Arc<tera::Tera>, // compiled once, for all the sites using the theme
 }
 
 impl Theme {
-    pub fn load_sass(&self) -> Result<(), String> {
+    pub fn load_sass(&self) -> Vec<String> {
         let mut sass_path = PathBuf::from(&self.path);
         sass_path.push("sass/");
-        if !sass_path.as_path().exists() {
-            return Ok(());
-        }
 
-        let mut resources = self.resources.write().unwrap();
+        sass::load_sass(&sass_path, &self.resources)
+    }
+}
+
+/// Compiles the theme's templates, along with the ones Servus provides.
+/// What depends on the site (see [`template::register_site_functions`]) is registered as pages are rendered.
+pub fn load_templates(theme_path: &str) -> Result<tera::Tera, String> {
+    let mut tera = tera::Tera::parse(&format!("{}/templates/**/*", theme_path))
+        .map_err(template::format_error)?;
+    tera.build_inheritance_chains()
+        .map_err(template::format_error)?;
+    tera.autoescape_on(vec![]);
+    tera.add_raw_templates(vec![
+        (
+            template::SOCIAL_META_TEMPLATE_NAME,
+            template::SOCIAL_META_TEMPLATE,
+        ),
+        (template::GALLERY_TEMPLATE_NAME, template::GALLERY_TEMPLATE),
+    ])
+    .map_err(template::format_error)?;
+    template::register_filters(&mut tera);
+
+    Ok(tera)
+}
 
-        for (k, v) in &sass::compile_sass(&sass_path)? {
-            log::debug!("Loaded theme resource: {}", k);
-            resources.insert(k.to_owned(), v.to_string());
+pub fn load_theme(theme_path: &str) -> Option<Theme> {
+    let config = load_config(&format!("{}/config.toml", theme_path));
+    if config.is_none() {
+        log::warn!("No config for theme: {}. Skipping!", theme_path);
+        return None;
+    }
+    let config = config.unwrap();
+    let tera = match load_templates(theme_path) {
+        Ok(tera) => tera,
+        Err(e) => {
+            log::warn!(
+                "Cannot load templates of theme: {}: {}. Skipping!",
+                theme_path,
+                e
+            );
+            return None;
         }
+    };
 
-        Ok(())
+    let theme = Theme {
+        path: theme_path.to_owned(),
+        config,
+        resources: Arc::new(RwLock::new(HashMap::new())),
+        tera: Arc::new(tera),
+    };
+
+    let errors = theme.load_sass();
+    if !errors.is_empty() {
+        log::warn!(
+            "Failed to compile {} sass file(s) for theme: {}.",
+            errors.len(),
+            theme_path
+        );
     }
+
+    Some(theme)
 }
 
 pub fn load_themes() -> HashMap<String, Theme> {
-    let paths = match fs::read_dir("./themes") {
+    let paths = match fs::read_dir(paths::themes()) {
         Ok(paths) => paths.map(|r| r.unwrap()).collect(),
         _ => vec![],
     };
 
-    let mut themes = HashMap::new();
-    for path in &paths {
-        log::info!("Found theme: {}", path.file_name().to_str().unwrap());
+    // compiling the sass of the themes takes a while, so they are loaded in parallel
+    let themes = utils::par_map(paths, |path: fs::DirEntry| {
+        let name = path.file_name().to_str().unwrap().to_string();
+        log::info!("Found theme: {}", name);
+
+        let theme = load_theme(&path.path().display().to_string())?;
+        log::debug!("Theme loaded: {}!", name);
 
-        let theme_path = path.path().display().to_string();
+        Some((name, theme))
+    })
+    .into_iter()
+    .flatten()
+    .collect::<HashMap<_, _>>();
 
-        let config = load_config(&format!("{}/config.toml", theme_path));
-        if config.is_none() {
-            log::warn!("No config for theme: {}. Skipping!", theme_path);
-            continue;
+    log::info!("{} themes loaded!", themes.len());
+
+    themes
+}
+
+/// The result of checking whether a theme can be used by Servus.
+#[derive(Default)]
+pub struct ThemeCheck {
+    /// Problems preventing the theme from being used.
+    pub errors: Vec<String>,
+    /// Features of the theme that won't work with Servus.
+    pub warnings: Vec<String>,
+}
+
+pub fn check_theme(theme_path: &Path) -> ThemeCheck {
+    let mut check = ThemeCheck::default();
+
+    match fs::read_to_string(theme_path.join("config.toml")) {
+        Ok(content) => {
+            if let Err(e) = toml::from_str::<ThemeConfig>(&content) {
+                check.errors.push(format!("Invalid config.toml: {}", e));
+            }
+        }
+        Err(_) => check.errors.push("Missing config.toml".to_string()),
+    }
+
+    let sass_path = theme_path.join("sass");
+    if sass_path.exists() {
+        check.errors.extend(sass::compile_sass(&sass_path).1);
+    }
+
+    if let Err(e) = load_templates(&theme_path.display().to_string()) {
+        check.errors.push(e);
+    }
+
+    let templates_path = theme_path.join("templates");
+    for template in REQUIRED_TEMPLATES {
+        if !templates_path.join(template).exists() {
+            check
+                .errors
+                .push(format!("Missing template: templates/{}", template));
         }
-        let config = config.unwrap();
+    }
 
-        let theme = Theme {
-            path: theme_path.clone(),
-            config,
-            resources: Arc::new(RwLock::new(HashMap::new())),
+    for entry in WalkDir::new(&templates_path)
+        .into_iter()
+        .filter_map(|e| e.ok())
+    {
+        let content = match fs::read_to_string(entry.path()) {
+            Ok(content) => content,
+            _ => continue,
         };
+        for function in UNSUPPORTED_FUNCTIONS {
+            if content.contains(&format!("{}(", function)) {
+                check.warnings.push(format!(
+                    "{} uses `{}`, which is not supported",
+                    entry.path().strip_prefix(theme_path).unwrap().display(),
+                    function
+                ));
+            }
+        }
+    }
 
-        if let Err(e) = theme.load_sass() {
-            log::warn!(
-                "Failed to load sass for theme: {}. Skipping! Error: {}",
-                theme_path,
-                e
-            )
+    check
+}
+
+/// The URL of a theme of the Servus themes repo, by name.
+/// Only the latest commit of the repo is fetched, without checking out anything,
+/// since all we need is its `.gitmodules`.
+fn find_theme_url(name: &str) -> Result<String, String> {
+    let repo_path = env::temp_dir().join(format!("servus-themes-{}", process::id()));
+    let result = fetch_gitmodules(&repo_path).and_then(|gitmodules| {
+        get_submodule_url(&gitmodules, name).ok_or(format!("No such theme: {}", name))
+    });
+    let _ = fs::remove_dir_all(&repo_path);
+
+    result
+}
+
+fn fetch_gitmodules(repo_path: &Path) -> Result<String, String> {
+    let repo = Repository::init_bare(repo_path).map_err(|e| e.to_string())?;
+    let mut remote = repo
+        .remote_anonymous(&format!("{}.git", THEMES_REPO))
+        .map_err(|e| e.to_string())?;
+    let mut options = FetchOptions::new();
+    options.depth(1);
+    remote
+        .fetch(&["HEAD"], Some(&mut options), None)
+        .map_err(|e| format!("Failed to fetch themes repo: {}", e))?;
+
+    let tree = repo
+        .find_reference("FETCH_HEAD")
+        .and_then(|r| r.peel_to_tree())
+        .map_err(|e| e.to_string())?;
+    let blob = tree
+        .get_path(Path::new(".gitmodules"))
+        .and_then(|e| e.to_object(&repo))
+        .and_then(|o| o.peel_to_blob())
+        .map_err(|_| "No themes found".to_string())?;
+
+    Ok(String::from_utf8_lossy(blob.content()).into_owned())
+}
+
+/// The URL of the submodule named `name` (or found at path `name`) in the content of a `.gitmodules` file.
+fn get_submodule_url(gitmodules: &str, name: &str) -> Option<String> {
+    // (name, path, url) of each submodule
+    let mut submodules: Vec<(&str, Option<&str>, Option<&str>)> = vec![];
+    for line in gitmodules.lines().map(|l| l.trim()) {
+        if let Some(section) = line.strip_prefix("[submodule ") {
+            submodules.push((section.trim_end_matches(']').trim_matches('"'), None, None));
+        } else if let (Some((key, value)), Some(submodule)) =
+            (line.split_once('='), submodules.last_mut())
+        {
+            match key.trim() {
+                "path" => submodule.1 = Some(value.trim()),
+                "url" => submodule.2 = Some(value.trim()),
+                _ => {}
+            }
         }
+    }
 
-        log::debug!("Theme loaded: {}!", path.file_name().to_str().unwrap());
+    submodules
+        .into_iter()
+        .find(|(n, path, _)| *n == name || *path == Some(name))
+        .and_then(|(_, _, url)| url.map(|u| u.to_owned()))
+}
 
-        themes.insert(path.file_name().to_str().unwrap().to_string(), theme);
+/// Clones the Servus themes repo, with all the themes it contains, into the themes directory.
+pub fn fetch_themes() -> Result<(), String> {
+    let url = format!("{}.git", THEMES_REPO);
+    let repo = Repository::clone(&url, paths::themes())
+        .map_err(|e| format!("Failed to clone themes repo: {}", e))?;
+    for mut submodule in repo.submodules().map_err(|e| e.to_string())? {
+        let name = submodule.path().display().to_string();
+        log::info!("Cloning theme: {}...", name);
+        if let Err(e) = submodule.update(true, None) {
+            log::warn!("Failed to clone theme {}: {}", name, e);
+        }
     }
 
-    log::info!("{} themes loaded!", themes.len());
+    Ok(())
+}
 
-    themes
+/// Clones a theme, given its git URL or its name in the Servus themes repo,
+/// keeping it only if it can be used by Servus. Returns the name and the check of the theme.
+pub fn install_theme(source: &str) -> Result<(String, ThemeCheck), String> {
+    let url = if source.contains("://") || source.starts_with("git@") {
+        source.to_owned()
+    } else {
+        find_theme_url(source)?
+    };
+    let name = url
+        .trim_end_matches('/')
+        .trim_end_matches(".git")
+        .rsplit(['/', ':'])
+        .next()
+        .unwrap()
+        .to_owned();
+    let theme_path = Path::new(paths::themes()).join(&name);
+    if theme_path.exists() {
+        return Err(format!("Theme already exists: {}", name));
+    }
+
+    Repository::clone(&url, &theme_path).map_err(|e| format!("Failed to clone {}: {}", url, e))?;
+
+    let check = check_theme(&theme_path);
+    if !check.errors.is_empty() {
+        let _ = fs::remove_dir_all(&theme_path);
+        return Err(format!(
+            "Not a valid theme: {}. {}",
+            name,
+            check.errors.join(". ")
+        ));
+    }
+
+    Ok((name, check))
+}
+
+/// Pulls the latest version of a theme from the upstream of its current branch,
+/// as long as it doesn't diverge from the local one.
+pub fn update_theme(name: &str) -> Result<bool, String> {
+    let repo = Repository::open(Path::new(paths::themes()).join(name))
+        .map_err(|_| format!("Not a git repository: {}", name))?;
+
+    let head = repo.head().map_err(|e| e.to_string())?;
+    let branch_name = match head.name() {
+        Some(branch_name) if head.is_branch() => branch_name.to_owned(),
+        _ => return Err("Not on a branch, not updating".to_string()),
+    };
+    let remote_name = repo
+        .branch_upstream_remote(&branch_name)
+        .map_err(|_| format!("No upstream for branch: {}", branch_name))?;
+    let mut remote = repo
+        .find_remote(remote_name.as_str().unwrap_or("origin"))
+        .map_err(|e| e.to_string())?;
+    remote
+        .fetch(&[] as &[&str], None, None)
+        .map_err(|e| format!("Failed to fetch: {}", e))?;
+    let upstream = Branch::wrap(head)
+        .upstream()
+        .map_err(|_| format!("No upstream for branch: {}", branch_name))?;
+    let upstream_commit = repo
+        .reference_to_annotated_commit(upstream.get())
+        .map_err(|e| e.to_string())?;
+
+    let (analysis, _) = repo
+        .merge_analysis(&[&upstream_commit])
+        .map_err(|e| e.to_string())?;
+    if analysis.is_up_to_date() {
+        Ok(false)
+    } else if analysis.is_fast_forward() {
+        let target = repo
+            .find_object(upstream_commit.id(), None)
+            .map_err(|e| e.to_string())?;
+        repo.checkout_tree(&target, Some(CheckoutBuilder::new().safe()))
+            .map_err(|e| format!("Failed to update: {}", e))?;
+        repo.find_reference(&branch_name)
+            .and_then(|mut r| r.set_target(upstream_commit.id(), "servus: update theme"))
+            .map_err(|e| e.to_string())?;
+        Ok(true)
+    } else {
+        Err("Local changes diverge from the remote, not updating".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_submodule_url() {
+        let gitmodules =
+            "[submodule \"hyde\"]\n\tpath = hyde\n\turl = https://github.com/getzola/hyde.git\n\
+            [submodule \"themes/even\"]\n\turl = https://github.com/getzola/even\n\tpath = even\n";
+        assert_eq!(
+            get_submodule_url(gitmodules, "hyde"),
+            Some("https://github.com/getzola/hyde.git".to_string())
+        );
+        assert_eq!(
+            get_submodule_url(gitmodules, "even"),
+            Some("https://github.com/getzola/even".to_string())
+        );
+        assert_eq!(get_submodule_url(gitmodules, "url"), None);
+        assert_eq!(get_submodule_url(gitmodules, "nope"), None);
+    }
 }
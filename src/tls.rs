@@ -0,0 +1,396 @@
+use async_std::{
+    net::{TcpStream, ToSocketAddrs},
+    stream::StreamExt,
+    task,
+};
+use futures_util::AsyncWriteExt;
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, BufReader, Seek, SeekFrom},
+    net::IpAddr,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime},
+};
+use tide::{log, utils::async_trait};
+use tide_acme::rustls_acme::{
+    acme::ACME_TLS_ALPN_NAME, caches::DirCache, AcmeConfig, ResolvesServerCertAcme,
+};
+use tide_rustls::async_rustls::{
+    rustls::{
+        internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys},
+        sign::{self, CertifiedKey},
+        ClientHello, NoClientAuth, ResolvesServerCert, ServerConfig, Session,
+    },
+    server::TlsStream,
+    TlsAcceptor,
+};
+use tide_rustls::CustomTlsAcceptor;
+
+use crate::{
+    paths,
+    site::{self, Site},
+    utils::Shared,
+};
+
+const CERT_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+const DNS_CHECK_INTERVAL: Duration = Duration::from_secs(600);
+
+fn invalid_input(error: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, error)
+}
+
+// https://github.com/http-rs/tide-rustls/blob/main/src/tls_listener.rs
+
+fn load_certified_key(cert_path: &str, key_path: &str) -> io::Result<CertifiedKey> {
+    let certs = certs(&mut BufReader::new(File::open(cert_path)?))
+        .map_err(|_| invalid_input("invalid cert"))?;
+
+    let mut reader = BufReader::new(File::open(key_path)?);
+    let mut keys = pkcs8_private_keys(&mut reader).unwrap_or_default();
+    if keys.is_empty() {
+        reader.seek(SeekFrom::Start(0))?;
+        keys = rsa_private_keys(&mut reader).unwrap_or_default();
+    }
+    let key = keys.first().ok_or_else(|| invalid_input("invalid key"))?;
+    let signing_key = sign::any_supported_type(key).map_err(|_| invalid_input("invalid key"))?;
+
+    Ok(CertifiedKey::new(certs, Arc::new(signing_key)))
+}
+
+fn get_modified(path: &str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// A certificate, reloaded whenever its files change (such as when renewed by certbot).
+struct ReloadingCert {
+    cert_path: String,
+    key_path: String,
+    modified: Mutex<(Option<SystemTime>, Option<SystemTime>)>,
+    certified_key: RwLock<Option<CertifiedKey>>, // none until the files can be loaded
+}
+
+impl ReloadingCert {
+    /// Loads the certificate, which is retried whenever the files change if it fails.
+    fn load(cert_path: &str, key_path: &str) -> Self {
+        let certified_key = match load_certified_key(cert_path, key_path) {
+            Ok(certified_key) => Some(certified_key),
+            Err(e) => {
+                log::error!("Cannot load TLS certificate {}: {}", cert_path, e);
+                None
+            }
+        };
+
+        Self {
+            cert_path: cert_path.to_owned(),
+            key_path: key_path.to_owned(),
+            modified: Mutex::new((get_modified(cert_path), get_modified(key_path))),
+            certified_key: RwLock::new(certified_key),
+        }
+    }
+
+    fn new(cert_path: &str, key_path: &str) -> io::Result<Self> {
+        Ok(Self {
+            cert_path: cert_path.to_owned(),
+            key_path: key_path.to_owned(),
+            modified: Mutex::new((get_modified(cert_path), get_modified(key_path))),
+            certified_key: RwLock::new(Some(load_certified_key(cert_path, key_path)?)),
+        })
+    }
+
+    fn get(&self) -> Option<CertifiedKey> {
+        self.certified_key.read().unwrap().clone()
+    }
+
+    fn reload_if_modified(&self) {
+        let new_modified = (get_modified(&self.cert_path), get_modified(&self.key_path));
+        let mut modified = self.modified.lock().unwrap();
+        if new_modified == *modified {
+            return;
+        }
+        *modified = new_modified;
+        match load_certified_key(&self.cert_path, &self.key_path) {
+            Ok(certified_key) => {
+                *self.certified_key.write().unwrap() = Some(certified_key);
+                log::info!("Reloaded TLS certificate: {}.", self.cert_path);
+            }
+            Err(e) => {
+                // keep serving the old certificate, maybe we caught the files mid-update
+                log::warn!("Cannot reload TLS certificate {}: {}", self.cert_path, e);
+            }
+        }
+    }
+}
+
+/// Serves the certificate of the site requested by the client (using SNI), if it has its own
+/// (`ssl_cert` and `ssl_key` in its config), otherwise the wildcard one if the site is a subdomain of the tenant domain,
+/// one obtained using ACME, if enabled, or the default one. Certificates are reloaded whenever their files change,
+/// and sites are looked up as they are requested, so sites added or edited later get their certificates too,
+/// all without restarting the server or dropping connections.
+pub struct ReloadingCertResolver {
+    sites: Arc<Shared<HashMap<String, Site>>>,
+    default: Option<ReloadingCert>,
+    /// A wildcard certificate, and the domain whose subdomains it is served for.
+    wildcard: Option<(String, ReloadingCert)>,
+    acme: Option<Arc<AcmeResolver>>,
+    domains: RwLock<HashMap<String, Arc<ReloadingCert>>>,
+}
+
+impl ReloadingCertResolver {
+    pub fn new(sites: Arc<Shared<HashMap<String, Site>>>, acme: Option<Arc<AcmeResolver>>) -> Self {
+        Self {
+            sites,
+            default: None,
+            wildcard: None,
+            acme,
+            domains: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub fn set_default(&mut self, cert_path: &str, key_path: &str) -> io::Result<()> {
+        self.default = Some(ReloadingCert::new(cert_path, key_path)?);
+
+        Ok(())
+    }
+
+    pub fn set_wildcard(
+        &mut self,
+        domain: &str,
+        cert_path: &str,
+        key_path: &str,
+    ) -> io::Result<()> {
+        self.wildcard = Some((domain.to_owned(), ReloadingCert::new(cert_path, key_path)?));
+
+        Ok(())
+    }
+
+    /// Whether there is any certificate to serve, other than the per-site ones.
+    pub fn is_enabled(&self) -> bool {
+        self.default.is_some() || self.wildcard.is_some() || self.acme.is_some()
+    }
+
+    /// The site's own certificate, if it has one, (re)loaded if its paths changed since we last looked.
+    fn get_site_cert(&self, domain: &str) -> Option<Arc<ReloadingCert>> {
+        let (cert_path, key_path) = {
+            let sites = self.sites.load();
+            let config = &sites.get(domain)?.config;
+            (config.ssl_cert.clone()?, config.ssl_key.clone()?)
+        };
+        if let Some(cert) = self.domains.read().unwrap().get(domain) {
+            if cert.cert_path == cert_path && cert.key_path == key_path {
+                return Some(cert.clone());
+            }
+        }
+
+        log::info!("Loading TLS certificate for {}: {}.", domain, cert_path);
+        let cert = Arc::new(ReloadingCert::load(&cert_path, &key_path));
+        self.domains
+            .write()
+            .unwrap()
+            .insert(domain.to_owned(), cert.clone());
+
+        Some(cert)
+    }
+
+    /// Loads the certificates of the sites having their own, so that any errors show up right away.
+    pub fn load_site_certs(&self) {
+        let domains = self.sites.load().keys().cloned().collect::<Vec<_>>();
+        for domain in domains {
+            self.get_site_cert(&domain);
+        }
+    }
+
+    /// Checks the files periodically, reloading the certificates when they are modified.
+    pub fn watch(self: Arc<Self>) {
+        task::spawn(async move {
+            loop {
+                task::sleep(CERT_CHECK_INTERVAL).await;
+                let domains = self
+                    .domains
+                    .read()
+                    .unwrap()
+                    .values()
+                    .cloned()
+                    .collect::<Vec<_>>();
+                for cert in self
+                    .default
+                    .iter()
+                    .chain(self.wildcard.iter().map(|(_, c)| c))
+                    .chain(domains.iter().map(|c| c.as_ref()))
+                {
+                    cert.reload_if_modified();
+                }
+            }
+        });
+    }
+}
+
+impl ResolvesServerCert for ReloadingCertResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<CertifiedKey> {
+        let is_acme_challenge = client_hello
+            .alpn()
+            .is_some_and(|alpn| alpn.contains(&ACME_TLS_ALPN_NAME));
+        if let (Some(acme), true) = (&self.acme, is_acme_challenge) {
+            return acme.resolve(client_hello);
+        }
+
+        let domain = client_hello
+            .server_name()
+            .map(|n| <&str>::from(n).to_owned());
+        if let Some(certified_key) = domain
+            .as_ref()
+            .and_then(|d| self.get_site_cert(d))
+            .and_then(|c| c.get())
+        {
+            return Some(certified_key);
+        }
+        if let (Some(domain), Some((wildcard_domain, cert))) = (&domain, &self.wildcard) {
+            if site::is_subdomain_of(domain, wildcard_domain) {
+                return cert.get();
+            }
+        }
+
+        match &self.acme {
+            Some(acme) => acme.resolve(client_hello),
+            None => self.default.as_ref()?.get(),
+        }
+    }
+}
+
+/// Whether `domain` resolves to any of `ips`.
+async fn resolves_to(domain: &str, ips: &[IpAddr]) -> bool {
+    match (domain, 443).to_socket_addrs().await {
+        Ok(mut addrs) => addrs.any(|addr| ips.contains(&addr.ip())),
+        Err(_) => false,
+    }
+}
+
+/// Obtains certificates from Let's Encrypt, one per domain, so that domains can be added at any time
+/// (such as when a site is created) without touching the certificates of the other domains.
+pub struct AcmeResolver {
+    directory_url: String,
+    contact_email: String,
+    sites: Arc<Shared<HashMap<String, Site>>>,
+    domains: RwLock<HashMap<String, Arc<ResolvesServerCertAcme>>>,
+    /// The server's public IPs, if known, which domains must resolve to before certificates are ordered for them.
+    public_ips: Vec<IpAddr>,
+}
+
+impl AcmeResolver {
+    pub fn new(
+        directory_url: &str,
+        contact_email: &str,
+        sites: Arc<Shared<HashMap<String, Site>>>,
+        public_ips: Vec<IpAddr>,
+    ) -> Self {
+        Self {
+            directory_url: directory_url.to_owned(),
+            contact_email: contact_email.to_owned(),
+            sites,
+            domains: RwLock::new(HashMap::new()),
+            public_ips,
+        }
+    }
+
+    /// Starts managing the certificate of `domain`, unless already doing so.
+    /// The certificate is loaded from the cache or ordered in the background.
+    pub fn add_domain(&self, domain: &str) -> Arc<ResolvesServerCertAcme> {
+        if let Some(resolver) = self.domains.read().unwrap().get(domain) {
+            return resolver.clone();
+        }
+
+        let mut domains = self.domains.write().unwrap();
+        if let Some(resolver) = domains.get(domain) {
+            return resolver.clone();
+        }
+
+        let mut state = AcmeConfig::new([domain])
+            .cache(DirCache::new(paths::acme_cache().to_owned()))
+            .directory(&self.directory_url)
+            .contact_push(format!("mailto:{}", self.contact_email))
+            .state();
+        let resolver = state.resolver();
+        domains.insert(domain.to_owned(), resolver.clone());
+
+        log::info!("Managing TLS certificate for {}.", domain);
+        let domain = domain.to_owned();
+        let public_ips = self.public_ips.clone();
+        task::spawn(async move {
+            // ordering a certificate for a domain pointing elsewhere would fail anyway,
+            // and count against the rate limits of Let's Encrypt
+            if !public_ips.is_empty() {
+                while !resolves_to(&domain, &public_ips).await {
+                    log::warn!(
+                        "{} doesn't resolve to this server, not ordering a certificate yet.",
+                        domain
+                    );
+                    task::sleep(DNS_CHECK_INTERVAL).await;
+                }
+            }
+            loop {
+                match state.next().await.unwrap() {
+                    Ok(event) => log::info!("ACME {}: {:?}", domain, event),
+                    Err(e) => log::warn!("ACME {}: {}", domain, e),
+                }
+            }
+        });
+
+        resolver
+    }
+
+    #[cfg(test)]
+    pub fn has_domain(&self, domain: &str) -> bool {
+        self.domains.read().unwrap().contains_key(domain)
+    }
+}
+
+impl ResolvesServerCert for AcmeResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<CertifiedKey> {
+        let domain = <&str>::from(client_hello.server_name()?).to_owned();
+        let resolver = self.domains.read().unwrap().get(&domain).cloned();
+        let resolver = match resolver {
+            Some(resolver) => resolver,
+            // a site that was added since we last looked (by the API, or on disk, while watching)
+            None if site::find_site(&self.sites.load(), &domain).is_some() => {
+                self.add_domain(&domain)
+            }
+            None => return None,
+        };
+
+        resolver.resolve(client_hello)
+    }
+}
+
+/// Accepts TLS connections, answering the TLS-ALPN-01 challenges for the [AcmeResolver],
+/// which are not passed on to the app.
+pub struct AcmeAcceptor(TlsAcceptor);
+
+impl AcmeAcceptor {
+    pub fn new(resolver: Arc<ReloadingCertResolver>) -> Self {
+        let mut config = make_server_config(resolver);
+        config.alpn_protocols.push(ACME_TLS_ALPN_NAME.to_vec());
+
+        Self(TlsAcceptor::from(Arc::new(config)))
+    }
+}
+
+#[async_trait]
+impl CustomTlsAcceptor for AcmeAcceptor {
+    async fn accept(&self, stream: TcpStream) -> io::Result<Option<TlsStream<TcpStream>>> {
+        let mut tls = self.0.accept(stream).await?;
+        if tls.get_ref().1.get_alpn_protocol() == Some(ACME_TLS_ALPN_NAME) {
+            log::info!("Received TLS-ALPN-01 validation request.");
+            tls.close().await?;
+            return Ok(None);
+        }
+
+        Ok(Some(tls))
+    }
+}
+
+pub fn make_server_config(resolver: Arc<dyn ResolvesServerCert>) -> ServerConfig {
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config.cert_resolver = resolver;
+
+    config
+}
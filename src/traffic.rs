@@ -0,0 +1,357 @@
+use async_std::task;
+use chrono::{DateTime, Days, NaiveDate, Utc};
+use clap::ValueEnum;
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Write},
+    mem,
+    ops::AddAssign,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use utoipa::ToSchema;
+
+use crate::paths;
+
+/// How often what was counted is saved, which is also how much is lost if the server crashes.
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+const CSV_HEADER: &str = "date,requests,bytes_sent,bytes_received";
+
+/// What goes into the access logs, if anything.
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum AccessLog {
+    /// Only count the requests and bytes served, per day
+    #[default]
+    Off,
+    /// Also log each request, without the address of the client
+    Anonymous,
+    /// Also log each request, with the address of the client
+    Full,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, ToSchema)]
+pub struct Counters {
+    pub requests: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+impl AddAssign for Counters {
+    fn add_assign(&mut self, other: Self) {
+        self.requests += other.requests;
+        self.bytes_sent += other.bytes_sent;
+        self.bytes_received += other.bytes_received;
+    }
+}
+
+/// The traffic of a site on a day (UTC), as `YYYY-MM-DD`.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct DailyTraffic {
+    pub date: String,
+    #[serde(flatten)]
+    pub counters: Counters,
+}
+
+/// The traffic of a site today and over the last 30 days (including today), as shown by the admin interface.
+#[derive(Debug, PartialEq, Serialize, ToSchema)]
+pub struct TrafficSummary {
+    pub today: Counters,
+    pub last_30_days: Counters,
+}
+
+/// A request made to a site, as it is counted and logged.
+pub struct Hit<'a> {
+    pub time: DateTime<Utc>,
+    pub client_ip: Option<String>,
+    pub method: &'a str,
+    pub path: &'a str,
+    pub status: u16,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub referer: Option<&'a str>,
+    pub user_agent: Option<&'a str>,
+}
+
+impl Hit<'_> {
+    /// The line logged for the request, in the "combined" log format most log analyzers understand.
+    fn to_log_line(&self, access_log: AccessLog) -> String {
+        let client_ip = match access_log {
+            AccessLog::Full => self.client_ip.as_deref().unwrap_or("-"),
+            _ => "-",
+        };
+        let quote = |value: Option<&str>| value.unwrap_or("-").replace('"', "\\\"");
+
+        format!(
+            "{} - - [{}] \"{} {} HTTP/1.1\" {} {} \"{}\" \"{}\"\n",
+            client_ip,
+            self.time.format("%d/%b/%Y:%H:%M:%S %z"),
+            self.method,
+            quote(Some(self.path)),
+            self.status,
+            self.bytes_sent,
+            quote(self.referer),
+            quote(self.user_agent),
+        )
+    }
+}
+
+#[derive(Default)]
+struct Unsaved {
+    traffic: HashMap<(String, String), Counters>, // (domain, date) -> counters
+    log_lines: HashMap<String, String>,           // domain -> lines
+}
+
+/// The requests and bytes served per site and per day, kept in memory until saved (see [`Traffic::save`])
+/// to `traffic/<domain>.csv` in the data dir, next to the access logs, if any.
+/// NB: no addresses are kept unless the access logs are [`AccessLog::Full`].
+#[derive(Clone, Default)]
+pub struct Traffic {
+    access_log: AccessLog,
+    unsaved: Arc<Mutex<Unsaved>>,
+}
+
+fn get_dir() -> String {
+    format!("{}/traffic", paths::data())
+}
+
+pub fn get_csv_path(domain: &str) -> String {
+    format!("{}/{}.csv", get_dir(), domain)
+}
+
+fn get_log_path(domain: &str) -> String {
+    format!("{}/{}.log", get_dir(), domain)
+}
+
+fn load(domain: &str) -> io::Result<Vec<DailyTraffic>> {
+    match fs::read_to_string(get_csv_path(domain)) {
+        Ok(csv) => Ok(parse_csv(&csv)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(vec![]),
+        Err(e) => Err(e),
+    }
+}
+
+/// Adds `counters` to the traffic of `date`, keeping the days in order.
+fn merge(days: &mut Vec<DailyTraffic>, date: &str, counters: Counters) {
+    match days.binary_search_by(|d| d.date.as_str().cmp(date)) {
+        Ok(i) => days[i].counters += counters,
+        Err(i) => days.insert(
+            i,
+            DailyTraffic {
+                date: date.to_owned(),
+                counters,
+            },
+        ),
+    }
+}
+
+pub fn to_csv(days: &[DailyTraffic]) -> String {
+    let mut csv = format!("{}\n", CSV_HEADER);
+    for day in days {
+        csv.push_str(&format!(
+            "{},{},{},{}\n",
+            day.date, day.counters.requests, day.counters.bytes_sent, day.counters.bytes_received
+        ));
+    }
+
+    csv
+}
+
+/// The days in `csv`, skipping the header and any line that doesn't make sense.
+fn parse_csv(csv: &str) -> Vec<DailyTraffic> {
+    let mut days = vec![];
+    for line in csv.lines() {
+        let fields = line.split(',').map(|f| f.trim()).collect::<Vec<_>>();
+        let [date, requests, bytes_sent, bytes_received] = fields[..] else {
+            continue;
+        };
+        if let (Ok(requests), Ok(bytes_sent), Ok(bytes_received)) =
+            (requests.parse(), bytes_sent.parse(), bytes_received.parse())
+        {
+            let counters = Counters {
+                requests,
+                bytes_sent,
+                bytes_received,
+            };
+            merge(&mut days, date, counters);
+        }
+    }
+
+    days
+}
+
+/// The sum of the traffic of the days from `since` (as `YYYY-MM-DD`) on.
+fn sum_since(days: &[DailyTraffic], since: &str) -> Counters {
+    let mut total = Counters::default();
+    for day in days.iter().filter(|d| d.date.as_str() >= since) {
+        total += day.counters;
+    }
+
+    total
+}
+
+pub fn get_summary(days: &[DailyTraffic], today: NaiveDate) -> TrafficSummary {
+    let since = |date: NaiveDate| sum_since(days, &date.format("%Y-%m-%d").to_string());
+
+    TrafficSummary {
+        today: since(today),
+        last_30_days: since(today - Days::new(29)),
+    }
+}
+
+impl Traffic {
+    pub fn new(access_log: AccessLog) -> Self {
+        Self {
+            access_log,
+            ..Default::default()
+        }
+    }
+
+    pub fn record(&self, domain: &str, hit: &Hit) {
+        let date = hit.time.format("%Y-%m-%d").to_string();
+        let counters = Counters {
+            requests: 1,
+            bytes_sent: hit.bytes_sent,
+            bytes_received: hit.bytes_received,
+        };
+        let log_line = match self.access_log {
+            AccessLog::Off => None,
+            access_log => Some(hit.to_log_line(access_log)),
+        };
+
+        let mut unsaved = self.unsaved.lock().unwrap();
+        *unsaved
+            .traffic
+            .entry((domain.to_owned(), date))
+            .or_default() += counters;
+        if let Some(log_line) = log_line {
+            unsaved
+                .log_lines
+                .entry(domain.to_owned())
+                .or_default()
+                .push_str(&log_line);
+        }
+    }
+
+    /// The traffic of the site, per day, including what was not saved yet.
+    pub fn get(&self, domain: &str) -> io::Result<Vec<DailyTraffic>> {
+        let mut days = load(domain)?;
+        let unsaved = self.unsaved.lock().unwrap();
+        for ((d, date), counters) in &unsaved.traffic {
+            if d == domain {
+                merge(&mut days, date, *counters);
+            }
+        }
+
+        Ok(days)
+    }
+
+    /// Adds what was counted since the last time to the saved traffic and appends to the access logs.
+    /// What cannot be saved is lost, rather than kept growing in memory.
+    pub fn save(&self) {
+        let unsaved = mem::take(&mut *self.unsaved.lock().unwrap());
+        if unsaved.traffic.is_empty() {
+            return;
+        }
+        if let Err(e) = fs::create_dir_all(get_dir()) {
+            log::warn!("Cannot save the traffic: {}", e);
+            return;
+        }
+
+        let mut traffic = HashMap::<String, Vec<(String, Counters)>>::new();
+        for ((domain, date), counters) in unsaved.traffic {
+            traffic.entry(domain).or_default().push((date, counters));
+        }
+        for (domain, counters) in traffic {
+            let result = load(&domain).and_then(|mut days| {
+                for (date, counters) in counters {
+                    merge(&mut days, &date, counters);
+                }
+                fs::write(get_csv_path(&domain), to_csv(&days))
+            });
+            if let Err(e) = result {
+                log::warn!("Cannot save the traffic of {}: {}", domain, e);
+            }
+        }
+        for (domain, log_lines) in unsaved.log_lines {
+            let result = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(get_log_path(&domain))
+                .and_then(|mut file| file.write_all(log_lines.as_bytes()));
+            if let Err(e) = result {
+                log::warn!("Cannot write the access log of {}: {}", domain, e);
+            }
+        }
+    }
+
+    /// Saves what was counted every [`SAVE_INTERVAL`], in the background.
+    pub fn save_periodically(&self) {
+        let traffic = self.clone();
+        task::spawn(async move {
+            loop {
+                task::sleep(SAVE_INTERVAL).await;
+                let traffic = traffic.clone();
+                task::spawn_blocking(move || traffic.save()).await;
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_csv() {
+        let csv = "date,requests,bytes_sent,bytes_received\n\
+                   2024-05-02,3,300,0\n\
+                   2024-05-01,1,100,10\n\
+                   not,a,valid,line\n\
+                   2024-05-02,1,50,5\n";
+        let days = parse_csv(csv);
+        assert_eq!(days.len(), 2);
+        assert_eq!(days[0].date, "2024-05-01");
+        assert_eq!(
+            days[1].counters,
+            Counters {
+                requests: 4,
+                bytes_sent: 350,
+                bytes_received: 5
+            }
+        );
+        assert_eq!(parse_csv(&to_csv(&days)), days);
+
+        assert_eq!(sum_since(&days, "2024-05-01").requests, 5);
+        assert_eq!(sum_since(&days, "2024-05-02").bytes_sent, 350);
+        assert_eq!(sum_since(&days, "2024-06-01"), Counters::default());
+
+        let summary = get_summary(&days, NaiveDate::from_ymd_opt(2024, 5, 31).unwrap());
+        assert_eq!(summary.today, Counters::default());
+        assert_eq!(summary.last_30_days.requests, 4);
+    }
+
+    #[test]
+    fn test_log_line() {
+        let hit = Hit {
+            time: DateTime::from_timestamp(1714608000, 0).unwrap(),
+            client_ip: Some("192.0.2.1".to_owned()),
+            method: "GET",
+            path: "/posts/hello",
+            status: 200,
+            bytes_sent: 1234,
+            bytes_received: 0,
+            referer: None,
+            user_agent: Some("Mozilla/5.0 \"test\""),
+        };
+        assert_eq!(
+            hit.to_log_line(AccessLog::Anonymous),
+            "- - - [02/May/2024:00:00:00 +0000] \"GET /posts/hello HTTP/1.1\" 200 1234 \"-\" \"Mozilla/5.0 \\\"test\\\"\"\n"
+        );
+        assert!(hit
+            .to_log_line(AccessLog::Full)
+            .starts_with("192.0.2.1 - - "));
+    }
+}
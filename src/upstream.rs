@@ -0,0 +1,235 @@
+use async_std::{future, net::TcpStream};
+use async_tungstenite::{tungstenite::Message as WsMessage, WebSocketStream};
+use futures_util::{future::join_all, AsyncRead, AsyncWrite, SinkExt, StreamExt};
+use serde_json::{json, Value as JsonValue};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tide::{http::Url, log};
+use tide_rustls::async_rustls::{rustls::ClientConfig, TlsConnector};
+
+use crate::nostr::{Event, Filter};
+
+/// How long to wait for all the upstream relays together.
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long the results of a filter are served from the cache before asking upstream again.
+const CACHE_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// How many filters to keep the results of, evicting the least recently used ones.
+const CACHE_MAX_FILTERS: usize = 1000;
+
+/// How many events to keep per filter, if the filter itself doesn't have a lower limit.
+const CACHE_MAX_EVENTS: usize = 500;
+
+trait RelayIo: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> RelayIo for T {}
+
+type RelayStream = WebSocketStream<Box<dyn RelayIo>>;
+
+async fn connect(relay_url: &str) -> Option<RelayStream> {
+    let url = Url::parse(relay_url).ok()?;
+    let host = url.host_str()?.to_owned();
+    let port = url.port_or_known_default()?;
+    let tcp = TcpStream::connect((host.as_str(), port)).await.ok()?;
+    let stream: Box<dyn RelayIo> = if url.scheme() == "wss" {
+        let mut config = ClientConfig::new();
+        config
+            .root_store
+            .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+        let dns_name = webpki::DNSNameRef::try_from_ascii_str(&host).ok()?;
+        let connector = TlsConnector::from(Arc::new(config));
+        Box::new(connector.connect(dns_name, tcp).await.ok()?)
+    } else {
+        Box::new(tcp)
+    };
+    let (ws, _) = async_tungstenite::client_async(relay_url, stream)
+        .await
+        .ok()?;
+
+    Some(ws)
+}
+
+async fn fetch_from_relay(relay_url: &str, sub_id: &str, filters: &[Filter]) -> Vec<Event> {
+    let mut events = vec![];
+
+    let mut ws = match connect(relay_url).await {
+        Some(ws) => ws,
+        None => {
+            log::warn!("Cannot connect to upstream relay: {}.", relay_url);
+            return events;
+        }
+    };
+
+    let mut req = vec![json!("REQ"), json!(sub_id)];
+    for filter in filters {
+        req.push(json!(filter));
+    }
+    if ws
+        .send(WsMessage::Text(JsonValue::Array(req).to_string()))
+        .await
+        .is_err()
+    {
+        return events;
+    }
+
+    while let Some(Ok(WsMessage::Text(message))) = ws.next().await {
+        let data: Vec<JsonValue> = match serde_json::from_str(&message) {
+            Ok(data) => data,
+            Err(_) => continue,
+        };
+        match data.first().and_then(|t| t.as_str()) {
+            Some("EVENT") => {
+                if let Some(Ok(event)) = data
+                    .get(2)
+                    .map(|e| serde_json::from_value::<Event>(e.clone()))
+                {
+                    if event.validate_sig().is_ok() {
+                        events.push(event);
+                    }
+                }
+            }
+            Some("EOSE") | Some("CLOSED") => break,
+            _ => {}
+        }
+    }
+
+    let _ = ws
+        .send(WsMessage::Text(json!(["CLOSE", sub_id]).to_string()))
+        .await;
+    let _ = ws.close(None).await;
+
+    events
+}
+
+/// Queries the given upstream relays (all at once) for events matching `filters`,
+/// returning all the (valid, deduplicated) events received before EOSE or the timeout.
+pub async fn fetch_events(relays: &[String], sub_id: &str, filters: &[Filter]) -> Vec<Event> {
+    let results = join_all(relays.iter().map(|relay_url| async move {
+        log::info!("Querying upstream relay: {}.", relay_url);
+        match future::timeout(
+            UPSTREAM_TIMEOUT,
+            fetch_from_relay(relay_url, sub_id, filters),
+        )
+        .await
+        {
+            Ok(relay_events) => relay_events,
+            Err(_) => {
+                log::warn!("Timeout querying upstream relay: {}.", relay_url);
+                vec![]
+            }
+        }
+    }))
+    .await;
+
+    let mut events: HashMap<String, Event> = HashMap::new();
+    for event in results.into_iter().flatten() {
+        events.insert(event.id.to_owned(), event);
+    }
+
+    events.into_values().collect()
+}
+
+struct CacheEntry {
+    fetched_at: Instant,
+    last_used: Instant,
+    events: Vec<Event>,
+}
+
+/// The events received from upstream relays, per filter, so that repeated REQs
+/// don't hit the upstream relays again. Bounded in both size and age.
+#[derive(Default)]
+pub struct UpstreamCache {
+    entries: Mutex<HashMap<String, CacheEntry>>,
+}
+
+fn get_cache_key(filter: &Filter) -> String {
+    // going through a Value sorts the keys, including the tag filters
+    serde_json::to_value(filter).unwrap().to_string()
+}
+
+impl UpstreamCache {
+    /// The cached events for `filter`, unless never fetched or expired.
+    pub fn get(&self, filter: &Filter) -> Option<Vec<Event>> {
+        let key = get_cache_key(filter);
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.get_mut(&key)?;
+        if entry.fetched_at.elapsed() > CACHE_TTL {
+            entries.remove(&key);
+            return None;
+        }
+        entry.last_used = Instant::now();
+
+        Some(entry.events.clone())
+    }
+
+    pub fn insert(&self, filter: &Filter, events: Vec<Event>) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= CACHE_MAX_FILTERS {
+            entries.retain(|_, e| e.fetched_at.elapsed() <= CACHE_TTL);
+        }
+        if entries.len() >= CACHE_MAX_FILTERS {
+            let lru_key = entries
+                .iter()
+                .min_by_key(|(_, e)| e.last_used)
+                .map(|(k, _)| k.to_owned())
+                .unwrap();
+            entries.remove(&lru_key);
+        }
+
+        let now = Instant::now();
+        entries.insert(
+            get_cache_key(filter),
+            CacheEntry {
+                fetched_at: now,
+                last_used: now,
+                events,
+            },
+        );
+    }
+}
+
+/// Gets the events matching each of the `filters`, from the cache if possible,
+/// asking the upstream relays only for the filters not (or no longer) cached.
+pub async fn get_events(
+    cache: &UpstreamCache,
+    relays: &[String],
+    sub_id: &str,
+    filters: &[Filter],
+) -> Vec<Event> {
+    let mut events = vec![];
+    let mut missing_filters = vec![];
+    for filter in filters {
+        match cache.get(filter) {
+            Some(cached_events) => events.extend(cached_events),
+            None => missing_filters.push(filter.clone()),
+        }
+    }
+
+    if missing_filters.is_empty() {
+        return events;
+    }
+
+    let fetched_events = fetch_events(relays, sub_id, &missing_filters).await;
+    for filter in &missing_filters {
+        // relays don't always honor the filters, so make sure we only pass on what was asked for
+        let mut filter_events = fetched_events
+            .iter()
+            .filter(|e| filter.matches_event(e))
+            .cloned()
+            .collect::<Vec<_>>();
+        filter_events.sort_by_key(|e| std::cmp::Reverse(e.created_at));
+        filter_events.truncate(
+            filter
+                .limit
+                .unwrap_or(CACHE_MAX_EVENTS)
+                .min(CACHE_MAX_EVENTS),
+        );
+        cache.insert(filter, filter_events.clone());
+        events.extend(filter_events);
+    }
+
+    events
+}
@@ -1,8 +1,136 @@
 // * Code taken from [Zola](https://www.getzola.org/) and adapted.
 // * Zola's MIT license applies. See: https://github.com/getzola/zola/blob/master/LICENSE
 
+use sha2::{Digest, Sha256};
+use std::{
+    net::IpAddr,
+    sync::{Arc, Mutex, RwLock},
+    thread,
+};
 use toml::Value as TomlValue;
 
+/// A value which is never modified in place, but replaced as a whole whenever it changes,
+/// so that readers get a consistent snapshot of it and are only blocked while a new snapshot is swapped in,
+/// however long it takes to prepare it or to read the snapshot.
+#[derive(Default)]
+pub struct Shared<T> {
+    current: RwLock<Arc<T>>,
+    update: Mutex<()>, // serializes updates, so that none of them is lost
+}
+
+impl<T> Shared<T> {
+    pub fn new(value: T) -> Self {
+        Self {
+            current: RwLock::new(Arc::new(value)),
+            update: Mutex::new(()),
+        }
+    }
+
+    /// The current snapshot, unaffected by any later updates.
+    pub fn load(&self) -> Arc<T> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Replaces the value, regardless of its current snapshot.
+    pub fn replace(&self, value: T) {
+        let _update = self.update.lock().unwrap();
+        *self.current.write().unwrap() = Arc::new(value);
+    }
+}
+
+impl<T: Clone> Shared<T> {
+    /// Applies `update` to a copy of the current snapshot, then swaps the copy in.
+    /// Other updates wait for this one, so `update` should be quick.
+    pub fn update<R>(&self, update: impl FnOnce(&mut T) -> R) -> R {
+        let _update = self.update.lock().unwrap();
+        let mut value = T::clone(&self.current.read().unwrap());
+        let result = update(&mut value);
+        *self.current.write().unwrap() = Arc::new(value);
+
+        result
+    }
+}
+
+/// The SHA-256 of `content`, hex encoded.
+pub fn sha256_hex(content: &[u8]) -> String {
+    to_hex(&Sha256::digest(content))
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Whether `ip` is reachable on the internet, as opposed to loopback, private, link-local
+/// (such as that of cloud metadata services) and other special-purpose addresses.
+pub fn is_global_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                || a == 0
+                || (a == 100 && b & 0xc0 == 64) // shared address space
+                || (a == 192 && b == 0 && c == 0) // IETF protocol assignments
+                || (a == 198 && b & 0xfe == 18) // benchmarking
+                || a >= 240) // reserved
+        }
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_global_ip(IpAddr::V4(ip));
+            }
+            if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+                // NAT64, reaching the IPv4 address it ends with
+                let [_, _, _, _, _, _, high, low] = segments;
+                return is_global_ip(IpAddr::V4(((high as u32) << 16 | low as u32).into()));
+            }
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_multicast()
+                || segments[..6] == [0; 6] // IPv4-compatible
+                || segments[0] & 0xfe00 == 0xfc00 // unique local
+                || segments[0] & 0xffc0 == 0xfe80 // link-local
+                || (segments[0] == 0x2001 && segments[1] == 0xdb8)) // documentation
+        }
+    }
+}
+
+/// Applies `f` to all the `items`, on as many threads as there are CPUs, returning the results in order.
+pub fn par_map<T: Send, R: Send>(items: Vec<T>, f: impl Fn(T) -> R + Sync) -> Vec<R> {
+    let threads = thread::available_parallelism()
+        .map_or(1, |n| n.get())
+        .min(items.len());
+    let items = Mutex::new(items.into_iter().enumerate());
+    let mut results = thread::scope(|scope| {
+        let workers = (0..threads)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut results = vec![];
+                    while let Some((i, item)) = {
+                        let next = items.lock().unwrap().next();
+                        next
+                    } {
+                        results.push((i, f(item)));
+                    }
+                    results
+                })
+            })
+            .collect::<Vec<_>>();
+        workers
+            .into_iter()
+            .flat_map(|w| w.join().unwrap())
+            .collect::<Vec<_>>()
+    });
+    results.sort_by_key(|(i, _)| *i);
+
+    results.into_iter().map(|(_, r)| r).collect()
+}
+
 #[derive(Debug)]
 pub struct MergeError;
 
@@ -31,3 +159,57 @@ pub fn merge(into: &mut TomlValue, from: &TomlValue) -> Result<(), MergeError> {
         _ => Err(MergeError),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_global_ip() {
+        for ip in ["93.184.215.14", "2606:2800:21f:cb07:6820:80da:af6b:8b2c"] {
+            assert!(is_global_ip(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "255.255.255.255",
+            "::1",
+            "::",
+            "fe80::1",
+            "fd00::1",
+            "::ffff:127.0.0.1",
+            "::ffff:169.254.169.254",
+            "64:ff9b::a9fe:a9fe",
+        ] {
+            assert!(!is_global_ip(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn test_shared() {
+        let shared = Shared::new(vec![1]);
+        let snapshot = shared.load();
+        shared.update(|v| v.push(2));
+        assert_eq!(*snapshot, vec![1]);
+        assert_eq!(*shared.load(), vec![1, 2]);
+
+        shared.replace(vec![3]);
+        assert_eq!(*shared.load(), vec![3]);
+        assert_eq!(shared.update(|v| v.len()), 1);
+    }
+
+    #[test]
+    fn test_par_map() {
+        let items = (0..100).collect::<Vec<u32>>();
+        assert_eq!(
+            par_map(items, |i| i * 2),
+            (0..100).map(|i| i * 2).collect::<Vec<_>>()
+        );
+        assert!(par_map(Vec::<u32>::new(), |i| i).is_empty());
+    }
+}
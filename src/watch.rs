@@ -0,0 +1,220 @@
+use notify::{EventKind, RecursiveMode, Watcher};
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    io::BufReader,
+    path::{Component, Path, PathBuf},
+    sync::{mpsc, Arc},
+    thread,
+    time::Duration,
+};
+use tide::log;
+
+use crate::{
+    content, nostr, paths,
+    site::{self, Site},
+    theme::{self, Theme},
+    utils::{self, Shared},
+};
+
+// wait for things to settle, editors tend to touch files more than once when saving
+const DEBOUNCE_DELAY: Duration = Duration::from_millis(200);
+
+#[derive(Eq, Hash, PartialEq)]
+enum Change {
+    Theme(String),
+    Site(String),
+    Content(String, PathBuf), // a file under the site's `_content`, relative to the site
+}
+
+/// `path` relative to `dir`, whether notify reports it as given or canonicalized.
+fn strip_dir<'a>(path: &'a Path, dir: &str) -> Option<&'a Path> {
+    path.strip_prefix(dir)
+        .ok()
+        .or_else(|| path.strip_prefix(fs::canonicalize(dir).ok()?).ok())
+}
+
+fn get_change(path: &Path) -> Option<Change> {
+    let (root, path) = if let Some(path) = strip_dir(path, paths::themes()) {
+        ("themes", path)
+    } else {
+        ("sites", strip_dir(path, paths::sites())?)
+    };
+    let mut parts = path.components().filter_map(|c| match c {
+        Component::Normal(c) => c.to_str(),
+        _ => None,
+    });
+    let name = parts.next()?.to_owned();
+    let rest = parts.collect::<Vec<_>>();
+    match root {
+        "themes" => Some(Change::Theme(name)),
+        // uploaded files (and their thumbnails) don't affect the rendered site, and neither do hidden files
+        // (such as the site's `.git`), nor hidden directories next to the sites (such as a site being restored)
+        _ if rest.starts_with(&["_content", "files"]) || rest.first() == Some(&"_thumbnails") => {
+            None
+        }
+        _ if name.starts_with('.') || rest.first().is_some_and(|p| p.starts_with('.')) => None,
+        _ if rest.first() == Some(&"_content") => {
+            Some(Change::Content(name, rest.iter().collect()))
+        }
+        _ => Some(Change::Site(name)),
+    }
+}
+
+fn collect_changes(event: notify::Result<notify::Event>, changes: &mut HashSet<Change>) {
+    if let Ok(event) = event {
+        if !matches!(event.kind, EventKind::Access(_)) {
+            changes.extend(event.paths.iter().filter_map(|p| get_change(p)));
+        }
+    }
+}
+
+/// Whether the change to the file at `path` was made by the site itself, as events are saved or deleted,
+/// and is therefore already reflected in its content (or the content is not loaded at all).
+fn is_applied(site: &Site, path: &Path) -> bool {
+    if !site.is_loaded() {
+        return true; // it will be read as the content is loaded
+    }
+    let filename = format!("{}/{}/{}", paths::sites(), site.domain, path.display());
+    let known_id = site
+        .get_content()
+        .events
+        .values()
+        .find(|e| e.filename == filename)
+        .map(|e| e.id.to_owned());
+
+    match known_id {
+        Some(id) => is_published_event(&filename, &id),
+        None => !Path::new(&filename).exists(),
+    }
+}
+
+/// Whether the file at `filename` holds the event `id` just as it was published, as the site saves it.
+/// Editing the file by hand changes what its ID was computed from (unless it is signed again).
+fn is_published_event(filename: &str, id: &str) -> bool {
+    let Ok(file) = fs::File::open(filename) else {
+        return false;
+    };
+    let Ok((front_matter, text)) = content::read(&mut BufReader::new(file)) else {
+        return false;
+    };
+    nostr::parse_event(&front_matter, &text)
+        .is_some_and(|event| event.id == id && event.validate_sig().is_ok())
+}
+
+/// Reads the site at `domain` again, templates, config and content, unless it was deleted.
+/// Whether it was reloaded (if it fails to, it keeps its previous version).
+pub fn reload_site(
+    domain: &str,
+    themes: &Arc<Shared<HashMap<String, Theme>>>,
+    sites: &Arc<Shared<HashMap<String, Site>>>,
+) -> bool {
+    if !Path::new(&format!("{}/{}/_config.toml", paths::sites(), domain)).exists() {
+        return false;
+    }
+
+    log::info!("Reloading site: {}...", domain);
+    // NB: a broken template or config should not bring down the site, which keeps its previous version
+    match site::load_site(domain, &themes.load()) {
+        Ok(site) => {
+            sites.update(|sites| sites.insert(domain.to_owned(), site));
+            true
+        }
+        Err(e) => {
+            log::warn!("Failed to reload site {}: {}", domain, e);
+            false
+        }
+    }
+}
+
+fn reload(
+    changes: HashSet<Change>,
+    themes: &Arc<Shared<HashMap<String, Theme>>>,
+    sites: &Arc<Shared<HashMap<String, Site>>>,
+) {
+    let mut domains = HashSet::new();
+    for change in changes {
+        match change {
+            Change::Theme(name) => {
+                log::info!("Reloading theme: {}...", name);
+                if let Some(theme) = theme::load_theme(&format!("{}/{}", paths::themes(), name)) {
+                    themes.update(|themes| themes.insert(name.to_owned(), theme));
+                }
+                domains.extend(
+                    sites
+                        .load()
+                        .values()
+                        .filter(|s| s.config.theme == name)
+                        .map(|s| s.domain.to_owned()),
+                );
+            }
+            Change::Site(domain) => {
+                domains.insert(domain);
+            }
+            Change::Content(domain, path) => {
+                let is_applied = sites
+                    .load()
+                    .get(&domain)
+                    .is_some_and(|s| is_applied(s, &path));
+                if !is_applied {
+                    domains.insert(domain);
+                }
+            }
+        }
+    }
+
+    for domain in domains {
+        reload_site(&domain, themes, sites);
+    }
+}
+
+/// Reloads all the themes and sites, including the ones added since they were loaded.
+/// Sites that fail to reload keep their previous version, like when watching.
+pub fn reload_all(
+    themes: &Arc<Shared<HashMap<String, Theme>>>,
+    sites: &Arc<Shared<HashMap<String, Site>>>,
+) {
+    let loaded = theme::load_themes();
+    themes.update(|themes| themes.extend(loaded));
+
+    let domains = match fs::read_dir(paths::sites()) {
+        Ok(paths) => paths
+            .filter_map(|p| p.ok()?.file_name().into_string().ok())
+            .collect(),
+        _ => vec![],
+    };
+    utils::par_map(domains, |domain: String| {
+        reload_site(&domain, themes, sites);
+    });
+}
+
+/// Watches themes and sites, reloading them (templates, sass, config and content) when they change.
+pub fn watch(
+    themes: Arc<Shared<HashMap<String, Theme>>>,
+    sites: Arc<Shared<HashMap<String, Site>>>,
+) {
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx).unwrap();
+    for path in [paths::themes(), paths::sites()] {
+        if Path::new(path).exists() {
+            watcher
+                .watch(Path::new(path), RecursiveMode::Recursive)
+                .unwrap();
+        }
+    }
+
+    thread::spawn(move || {
+        let _watcher = watcher; // stops watching when dropped
+
+        while let Ok(event) = rx.recv() {
+            let mut changes = HashSet::new();
+            collect_changes(event, &mut changes);
+            while let Ok(event) = rx.recv_timeout(DEBOUNCE_DELAY) {
+                collect_changes(event, &mut changes);
+            }
+            reload(changes, &themes, &sites);
+        }
+    });
+
+    log::info!("Watching themes and sites for changes...");
+}
@@ -0,0 +1,161 @@
+use async_std::{future, task};
+use bitcoin_hashes::{hmac, sha256, GeneralHash, HashEngine};
+use http_client::{h1::H1Client, HttpClient};
+use http_types::{Method, Request as HttpRequest, Url};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value as JsonValue};
+use std::time::Duration;
+use tide::log;
+
+use crate::{
+    resource::{Resource, ResourceKind},
+    site::Site,
+};
+
+/// How long to wait for the server a notification is sent to.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// One of the `[[webhooks]]` of a site's `_config.toml`: a URL notified of changes to the site's content.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct WebhookConfig {
+    pub url: String,
+    /// Used to sign the notifications (see [`sign`]), so that the receiver can tell they are genuine.
+    pub secret: Option<String>,
+    /// Events to be notified of, such as `post.published` or `post.*`. By default, all of them.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub events: Vec<String>,
+}
+
+/// Whether `event` is one of `events`, such as `post.published` or `post.*`, or any event if there are none.
+pub fn matches(events: &[String], event: &str) -> bool {
+    events.is_empty()
+        || events.iter().any(|e| match e.strip_suffix(".*") {
+            Some(prefix) => event.split('.').next() == Some(prefix),
+            None => e == event,
+        })
+}
+
+impl WebhookConfig {
+    fn wants(&self, event: &str) -> bool {
+        matches(&self.events, event)
+    }
+}
+
+/// The `X-Servus-Signature` of `body`: its HMAC-SHA256 using `secret`, in hex.
+fn sign(secret: &str, body: &[u8]) -> String {
+    let mut engine = hmac::HmacEngine::<sha256::Hash>::new(secret.as_bytes());
+    engine.input(body);
+    format!("sha256={}", hmac::Hmac::<sha256::Hash>::from_engine(engine))
+}
+
+fn get_kind_name(kind: ResourceKind) -> &'static str {
+    match kind {
+        ResourceKind::Post => "post",
+        ResourceKind::Page => "page",
+        ResourceKind::Note => "note",
+    }
+}
+
+async fn send(webhook: &WebhookConfig, event: &str, body: &[u8]) -> Result<(), String> {
+    let url = Url::parse(&webhook.url).map_err(|e| e.to_string())?;
+    if !["https", "http"].contains(&url.scheme()) {
+        return Err("Unsupported URL".to_string());
+    }
+    let mut request = HttpRequest::new(Method::Post, url);
+    request.insert_header("Content-Type", "application/json");
+    request.insert_header("X-Servus-Event", event);
+    if let Some(secret) = &webhook.secret {
+        request.insert_header("X-Servus-Signature", sign(secret, body));
+    }
+    request.set_body(body);
+
+    let response = future::timeout(WEBHOOK_TIMEOUT, H1Client::new().send(request))
+        .await
+        .map_err(|_| "Timed out".to_string())?
+        .map_err(|e| e.to_string())?;
+    if !response.status().is_success() {
+        return Err(response.status().to_string());
+    }
+
+    Ok(())
+}
+
+/// POSTs `event` (such as `post.published`), along with `data`, to each of the site's webhooks wanting it, in the background.
+pub fn notify(site: &Site, event: &str, data: JsonValue) {
+    let webhooks = site
+        .config
+        .webhooks
+        .iter()
+        .filter(|w| w.wants(event))
+        .cloned()
+        .collect::<Vec<_>>();
+    if webhooks.is_empty() {
+        return;
+    }
+
+    let event = event.to_owned();
+    let body = json!({
+        "event": event,
+        "site": site.domain,
+        "created_at": chrono::Utc::now().timestamp(),
+        "data": data,
+    })
+    .to_string();
+    let domain = site.domain.to_owned();
+    task::spawn(async move {
+        for webhook in webhooks {
+            if let Err(e) = send(&webhook, &event, body.as_bytes()).await {
+                log::warn!(
+                    "Cannot notify webhook {} of {} on {}: {}",
+                    webhook.url,
+                    event,
+                    domain,
+                    e
+                );
+            }
+        }
+    });
+}
+
+/// Notifies the site's webhooks of `resource` being `action` (`published`, `updated` or `deleted`).
+pub fn notify_resource(site: &Site, resource: &Resource, action: &str) {
+    let kind = get_kind_name(resource.kind);
+    notify(
+        site,
+        &format!("{}.{}", kind, action),
+        json!({
+            "kind": kind,
+            "slug": resource.slug,
+            "title": resource.title,
+            "url": resource.get_permalink(&site.config),
+        }),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign() {
+        // RFC 4231, test case 2
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn test_wants() {
+        let webhook = |events: &[&str]| WebhookConfig {
+            url: "https://example.com/hook".to_string(),
+            secret: None,
+            events: events.iter().map(|e| e.to_string()).collect(),
+        };
+        assert!(webhook(&[]).wants("blob.uploaded"));
+        assert!(webhook(&["post.*"]).wants("post.deleted"));
+        assert!(!webhook(&["post.*"]).wants("page.deleted"));
+        assert!(webhook(&["post.published", "blob.uploaded"]).wants("blob.uploaded"));
+        assert!(!webhook(&["post.published"]).wants("post.updated"));
+    }
+}